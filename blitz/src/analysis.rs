@@ -0,0 +1,297 @@
+//! Measures how an image is exposed, so that settings can be suggested for it. The suggestions,
+//! and the levels that auto-contrast applies, are ordinary settings which the user can see and
+//! tweak.
+
+use crate::diagnostics::histogram::Bins;
+use crate::render::develop_coarse;
+use crate::render_settings::{Curve, CurvePoint, Levels, LevelsSpace, RenderSettings, ToneCurve};
+use libraw::raw_image::RawImage;
+use libraw::util::timing::StageTimer;
+use ndarray::s;
+use palette::encoding::{Srgb, TransferFn};
+use palette::{Hsv, LinSrgb};
+use std::cmp::Ordering;
+
+/// How many pixels the image is measured at, along its longest edge.
+const ANALYSIS_SIZE: usize = 512;
+
+/// The number of bins in `Analysis::histogram`.
+const HISTOGRAM_BINS: usize = 256;
+
+/// Pixels with any channel at or above this are counted as clipped.
+const CLIP_LEVEL: f32 = 0.99;
+
+/// Luminances are measured from here up, about 13 stops below white, so that black pixels don't
+/// send logarithms to infinity.
+const LUMINANCE_FLOOR: f32 = 1e-4;
+
+/// The percentiles taken as the image's darkest and brightest, so that a few outliers don't count.
+const SHADOW_PERCENTILE: f32 = 0.5;
+const HIGHLIGHT_PERCENTILE: f32 = 99.5;
+
+/// The percentiles of brightness which auto-contrast stretches to black and white.
+const AUTO_CONTRAST_BLACK_PERCENTILE: f32 = 5.0;
+const AUTO_CONTRAST_WHITE_PERCENTILE: f32 = 95.0;
+
+/// Where `suggest_settings` puts the key.
+const MIDDLE_GREY: f32 = 0.18;
+const MIN_EXPOSURE: f32 = 0.25;
+const MAX_EXPOSURE: f32 = 16.0;
+
+/// The furthest `suggest_settings` will move the black point.
+const MAX_BLACK_POINT: f32 = 0.05;
+
+/// Scene dynamic ranges, in stops, below which `suggest_settings` adds contrast, and above which
+/// it lifts the shadows.
+const LOW_DYNAMIC_RANGE: f32 = 6.0;
+const HIGH_DYNAMIC_RANGE: f32 = 10.0;
+
+/// The brightness distribution of an image, measured before exposure and tone adjustments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// The luminance of every measured pixel, in order.
+    luminance: Vec<f32>,
+    /// The HSV value of every measured pixel, which is what `Levels` work on, in order.
+    values: Vec<f32>,
+    /// Of the linear image, as in `RenderHistograms::linear`.
+    pub histogram: Bins,
+    /// The fraction of pixels with at least one channel clipped, from 0 to 1.
+    pub clipped_fraction: f32,
+    /// The mean log luminance: a typical brightness, which isn't thrown off by small, very
+    /// bright areas the way the mean is.
+    pub key: f32,
+    /// Stops between the darkest and brightest parts of the image.
+    pub dynamic_range: f32,
+}
+
+/// Measures `img` with its as-shot white balance and vignetting corrected.
+pub fn analyze(img: &dyn RawImage) -> Analysis {
+    let settings = RenderSettings {
+        lens_corrections: RenderSettings::auto().lens_corrections,
+        ..RenderSettings::default()
+    };
+    analyze_with_settings(img, &settings)
+}
+
+/// Measures `img` with the white balance and lens corrections in `settings`. Exposure is ignored,
+/// so that the analysis describes the image as shot.
+pub fn analyze_with_settings(img: &dyn RawImage, settings: &RenderSettings) -> Analysis {
+    let _timer = StageTimer::new("Analysis");
+    let settings = RenderSettings {
+        exposure_basis: 1.0,
+        ..settings.clone()
+    };
+    let ri = img.render_info();
+    let (developed, block) = develop_coarse(img, &ri, &settings, ANALYSIS_SIZE);
+    let crop = ri.crop_rect;
+    let cropped = developed.slice(s![
+        crop.left / block..crop.right / block,
+        crop.top / block..crop.bottom / block
+    ]);
+    Analysis::from_pixels(cropped.iter().copied())
+}
+
+/// The levels that rendering `img` with `settings` will use. With auto-contrast on, those are
+/// `settings.levels` with the input points measured from the image; otherwise they're exactly
+/// `settings.levels`.
+pub fn resolve_levels(img: &dyn RawImage, settings: &RenderSettings) -> Levels {
+    if settings.auto_contrast {
+        analyze_with_settings(img, settings).auto_levels(&settings.levels, settings.exposure_basis)
+    } else {
+        settings.levels
+    }
+}
+
+fn sort(values: &mut [f32]) {
+    values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+}
+
+/// The value which `percentile` percent of `sorted` are at or below.
+fn percentile_of(sorted: &[f32], percentile: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let last = sorted.len() - 1;
+    let idx = (percentile / 100.0 * last as f32).round() as usize;
+    sorted[idx.min(last)]
+}
+
+impl Analysis {
+    fn from_pixels(pixels: impl Iterator<Item = Hsv>) -> Self {
+        let mut histogram = Bins::new(HISTOGRAM_BINS);
+        let mut luminance = vec![];
+        let mut values = vec![];
+        let mut clipped = 0;
+        for hsv in pixels {
+            let rgb: LinSrgb = hsv.into();
+            histogram.record([rgb.red, rgb.green, rgb.blue]);
+            if hsv.value >= CLIP_LEVEL {
+                clipped += 1;
+            }
+            let lum = 0.2126 * rgb.red + 0.7152 * rgb.green + 0.0722 * rgb.blue;
+            if lum.is_finite() {
+                luminance.push(lum.max(0.0));
+            }
+            if hsv.value.is_finite() {
+                values.push(hsv.value.max(0.0));
+            }
+        }
+        sort(&mut luminance);
+        sort(&mut values);
+
+        let count = luminance.len().max(1) as f32;
+        let log_sum: f32 = luminance
+            .iter()
+            .map(|&lum| lum.max(LUMINANCE_FLOOR).ln())
+            .sum();
+        let mut analysis = Analysis {
+            luminance,
+            values,
+            histogram,
+            clipped_fraction: clipped as f32 / count,
+            key: (log_sum / count).exp(),
+            dynamic_range: 0.0,
+        };
+        let darkest = analysis.percentile(SHADOW_PERCENTILE).max(LUMINANCE_FLOOR);
+        let brightest = analysis
+            .percentile(HIGHLIGHT_PERCENTILE)
+            .max(LUMINANCE_FLOOR);
+        analysis.dynamic_range = (brightest / darkest).log2();
+        analysis
+    }
+
+    /// The luminance which `percentile` percent of pixels are at or below.
+    pub fn percentile(&self, percentile: f32) -> f32 {
+        percentile_of(&self.luminance, percentile)
+    }
+
+    /// The HSV value which `percentile` percent of pixels are at or below, after `exposure` and in
+    /// `space`, for comparing with `Levels`.
+    pub fn value_percentile(&self, percentile: f32, exposure: f32, space: LevelsSpace) -> f32 {
+        let value = percentile_of(&self.values, percentile) * exposure;
+        match space {
+            LevelsSpace::Linear => value,
+            LevelsSpace::Display => Srgb::from_linear(value),
+        }
+    }
+
+    /// What auto-contrast does to `levels` at `exposure`: it stretches the image so that the
+    /// darkest 5% of pixels are at or below the output black, and the brightest 5% at or above
+    /// the output white. Everything but the input points is kept from `levels`.
+    pub fn auto_levels(&self, levels: &Levels, exposure: f32) -> Levels {
+        let point = |percentile| self.value_percentile(percentile, exposure, levels.space);
+        let input_black = point(AUTO_CONTRAST_BLACK_PERCENTILE);
+        let input_white = point(AUTO_CONTRAST_WHITE_PERCENTILE).max(input_black + f32::EPSILON);
+        Levels {
+            input_black,
+            input_white,
+            ..*levels
+        }
+    }
+
+    /// Suggests settings for the image: an exposure which puts the key at middle grey, levels
+    /// which fit its darkest and brightest parts between black and white, and a tone curve which
+    /// adds contrast to flat scenes and lifts the shadows of contrasty ones. Everything else is
+    /// as in `RenderSettings::auto`, but with auto-contrast turned off.
+    pub fn suggest_settings(&self) -> RenderSettings {
+        let exposure = (MIDDLE_GREY / self.key).clamp(MIN_EXPOSURE, MAX_EXPOSURE);
+        let point = |percentile| self.value_percentile(percentile, exposure, LevelsSpace::Linear);
+        let input_black = point(SHADOW_PERCENTILE).min(MAX_BLACK_POINT);
+        let input_white = point(HIGHLIGHT_PERCENTILE).max(input_black + MIDDLE_GREY);
+
+        // -1 for the flattest scenes, up to 1 for the most contrasty.
+        let strength = ((self.dynamic_range - LOW_DYNAMIC_RANGE)
+            / (HIGH_DYNAMIC_RANGE - LOW_DYNAMIC_RANGE)
+            * 2.0
+            - 1.0)
+            .clamp(-1.0, 1.0);
+        let tone_curve = if strength.abs() < 0.1 {
+            ToneCurve::default()
+        } else {
+            // Raising the quarter-tones and lowering the three-quarter-tones takes contrast away;
+            // the opposite adds it.
+            ToneCurve {
+                luminance: Curve::new(&[
+                    CurvePoint::new(0.0, 0.0),
+                    CurvePoint::new(0.25, 0.25 + 0.08 * strength),
+                    CurvePoint::new(0.75, 0.75 - 0.04 * strength),
+                    CurvePoint::new(1.0, 1.0),
+                ]),
+                ..ToneCurve::default()
+            }
+        };
+
+        RenderSettings {
+            exposure_basis: exposure,
+            auto_contrast: false,
+            levels: Levels {
+                input_black,
+                input_white,
+                ..Levels::default()
+            },
+            tone_curve,
+            ..RenderSettings::auto()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn grey(value: f32) -> Hsv {
+        Hsv::new(0.0, 0.0, value)
+    }
+
+    #[test]
+    fn measures_luminance() {
+        // Half the pixels at 1/16, half at 1/4, and one clipped.
+        let pixels = (0..999)
+            .map(|i| grey(if i % 2 == 0 { 0.0625 } else { 0.25 }))
+            .chain(std::iter::once(grey(1.0)));
+        let analysis = Analysis::from_pixels(pixels);
+
+        assert_eq!(analysis.clipped_fraction, 0.001);
+        assert_eq!(analysis.percentile(0.0), 0.0625);
+        assert_eq!(analysis.percentile(100.0), 1.0);
+        // Halfway between 1/16 and 1/4 in stops.
+        assert!((analysis.key - 0.125).abs() < 0.001, "{}", analysis.key);
+        assert!((analysis.dynamic_range - 2.0).abs() < 0.001);
+        assert_eq!(analysis.histogram.luminance.iter().sum::<u64>(), 1000);
+    }
+
+    #[test]
+    fn suggests_settings() {
+        let pixels = (0..1000).map(|i| grey(0.01 + 0.04 * (i % 10) as f32 / 9.0));
+        let analysis = Analysis::from_pixels(pixels);
+        let settings = analysis.suggest_settings();
+
+        // A dark, flat scene gets brightened and given more contrast.
+        assert!(settings.exposure_basis > 4.0);
+        assert!(!settings.auto_contrast);
+        let levels = settings.levels;
+        assert!(levels.input_black > 0.0 && levels.input_black <= MAX_BLACK_POINT);
+        assert!(levels.input_white > levels.input_black);
+        let curve = &settings.tone_curve.luminance;
+        assert!(curve.apply(0.25) < 0.25 && curve.apply(0.75) > 0.75);
+    }
+
+    #[test]
+    fn auto_levels_keep_everything_but_the_input_points() {
+        let analysis = Analysis::from_pixels((0..=100).map(|i| grey(i as f32 / 100.0)));
+        let base = Levels {
+            gamma: 1.2,
+            ..Levels::default()
+        };
+        let levels = analysis.auto_levels(&base, 2.0);
+        assert_eq!((levels.input_black, levels.input_white), (0.1, 1.9));
+        assert_eq!(levels.gamma, 1.2);
+
+        let display = Levels {
+            space: LevelsSpace::Display,
+            ..base
+        };
+        let levels = analysis.auto_levels(&display, 1.0);
+        assert!(levels.input_black > 0.2 && levels.input_white < 1.0);
+    }
+}
@@ -1,12 +1,18 @@
 use itertools::Itertools;
-use nalgebra::Matrix3;
+use libraw::dng::Calibration;
+use log::{debug, log_enabled, Level};
+use nalgebra::{Matrix3, Vector3};
 
 pub type ColorspaceMatrix = nalgebra::Matrix3<f32>;
 
+/// Logs `val` at debug level, one row per line.
 pub fn dump_mat(label: &str, val: &Matrix3<f32>) {
-    println!("{}: ", label);
+    if !log_enabled!(Level::Debug) {
+        return;
+    }
+    debug!("{}: ", label);
     for row in val.row_iter() {
-        println!(
+        debug!(
             "{}",
             row.iter()
                 .map(|x| format!("{:.6}", x))
@@ -16,16 +22,62 @@ pub fn dump_mat(label: &str, val: &Matrix3<f32>) {
     }
 }
 
-pub fn cam_rgb_linear() -> ColorspaceMatrix {
-    // Value from libraw
+/// Maps XYZ to (non-white-balanced) camera values. Value from libraw.
+pub fn cam_from_xyz() -> ColorspaceMatrix {
     #[rustfmt::skip]
     let cam_from_xyz = Matrix3::new(
         11434.0, -4948.0, -1210.0,
         -3746.0, 12042.0,  1903.0,
          -666.0,  1479.0,  5235.0,
     ) / 10_000.0;
+    cam_from_xyz
+}
+
+/// EXIF LightSource code for D65.
+const D65: u16 = 21;
+
+/// D50 white in XYZ, which is what forward matrices map neutral colours to.
+fn d50_white() -> Vector3<f32> {
+    Vector3::new(0.9642, 1.0, 0.8249)
+}
 
-    let cam_from_rgb = cam_from_xyz * xyz_from_rgblin();
+/// Picks which of a file's calibrations to use: the one for D65 if there is one, since we mostly
+/// shoot in daylight, otherwise the last.
+fn daylight_calibration(calibrations: &[Calibration]) -> Option<&Calibration> {
+    calibrations
+        .iter()
+        .find(|c| c.illuminant == D65)
+        .or_else(|| calibrations.last())
+}
+
+/// Maps XYZ to camera values, from the file's own calibration if it has one.
+pub fn file_cam_from_xyz(calibrations: &[Calibration]) -> ColorspaceMatrix {
+    daylight_calibration(calibrations)
+        .map(|c| Matrix3::from_row_slice(&c.color_matrix))
+        .unwrap_or_else(cam_from_xyz)
+}
+
+/// Maps white-balanced camera values to XYZ D50, from the file's own calibration if it has one.
+pub fn forward_matrix(calibrations: &[Calibration]) -> ColorspaceMatrix {
+    let calibration = match daylight_calibration(calibrations) {
+        Some(calibration) => calibration,
+        None => return dng_cam2_to_xyz(),
+    };
+    if let Some(forward) = &calibration.forward_matrix {
+        return Matrix3::from_row_slice(forward);
+    }
+    // Without a forward matrix, invert the colour matrix, scaled so that neutral (white-balanced)
+    // camera values still map to D50 white.
+    let cam_from_xyz = Matrix3::from_row_slice(&calibration.color_matrix);
+    let neutral = cam_from_xyz * d50_white();
+    match cam_from_xyz.try_inverse() {
+        Some(xyz_from_cam) => xyz_from_cam * Matrix3::from_diagonal(&neutral),
+        None => dng_cam2_to_xyz(),
+    }
+}
+
+pub fn cam_rgb_linear() -> ColorspaceMatrix {
+    let cam_from_rgb = cam_from_xyz() * xyz_from_rgblin();
     dump_mat("cam_from_rgb", &cam_from_rgb);
     // line norm
     let rows_normalized: Vec<_> = cam_from_rgb.row_iter().map(|row| row / row.sum()).collect();
@@ -96,3 +148,27 @@ pub fn dng_cam1_to_xyz() -> ColorspaceMatrix {
     );
     mat
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn colour_matrix_alone_maps_neutral_to_d50() {
+        let mut color_matrix = [0.0; 9];
+        color_matrix.copy_from_slice(cam_from_xyz().transpose().as_slice());
+        let calibrations = [Calibration {
+            illuminant: D65,
+            color_matrix,
+            forward_matrix: None,
+        }];
+        let xyz = forward_matrix(&calibrations) * Vector3::new(1.0, 1.0, 1.0);
+        assert!((xyz - d50_white()).norm() < 1e-4, "{}", xyz);
+    }
+
+    #[test]
+    fn falls_back_to_fuji_calibration() {
+        assert_eq!(file_cam_from_xyz(&[]), cam_from_xyz());
+        assert_eq!(forward_matrix(&[]), dng_cam2_to_xyz());
+    }
+}
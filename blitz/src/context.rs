@@ -0,0 +1,58 @@
+pub use libraw::util::progress::{CancellationToken, Cancelled, ProgressReporter};
+
+type ProgressCallback = dyn Fn(&str, f32) + Send + Sync;
+
+/// Carries state for a single render that isn't part of the settings: where to send progress
+/// updates, and whether the render should be abandoned.
+pub struct RenderContext {
+    on_progress: Option<Box<ProgressCallback>>,
+    cancellation: CancellationToken,
+}
+
+impl RenderContext {
+    pub fn new() -> Self {
+        RenderContext {
+            on_progress: None,
+            cancellation: CancellationToken::new(),
+        }
+    }
+
+    /// Calls `on_progress` with the stage name and the fraction of that stage which is complete.
+    /// It's called from worker threads, possibly several at once.
+    pub fn with_progress(
+        mut self,
+        on_progress: impl Fn(&str, f32) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Uses `token` to decide whether the render has been cancelled. Keep a clone of the token
+    /// to cancel the render from another thread.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+}
+
+impl Default for RenderContext {
+    fn default() -> Self {
+        RenderContext::new()
+    }
+}
+
+impl ProgressReporter for RenderContext {
+    fn report(&self, stage: &str, fraction: f32) {
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(stage, fraction);
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+}
@@ -94,3 +94,82 @@ impl<T: Copy + Num> Demosaic<T> for Passthru {
         }
     }
 }
+
+/// Returns the smallest block size `n` such that every `n`x`n` block aligned to the colour filter
+/// pattern contains all three colours. This is 3 for X-Trans and 2 for Bayer.
+pub fn superpixel_size(mapping: &FilterMap) -> usize {
+    let (width, height) = mapping.dim();
+    (1..=width.min(height))
+        .filter(|n| width % n == 0 && height % n == 0)
+        .find(|&n| {
+            (0..width).step_by(n).all(|bx| {
+                (0..height).step_by(n).all(|by| {
+                    let mut seen = [false; 3];
+                    for x in bx..bx + n {
+                        for y in by..by + n {
+                            seen[mapping[(x, y)].idx()] = true;
+                        }
+                    }
+                    seen.iter().all(|&s| s)
+                })
+            })
+        })
+        .unwrap_or_else(|| width.min(height))
+}
+
+/// Produces the output pixel at (`x`, `y`) by averaging each colour over the `size`x`size` block
+/// of sensor pixels that it covers. This avoids interpolation entirely, so it's much cheaper than
+/// the other demosaicing algorithms, at the cost of resolution.
+///
+/// `size` should be a multiple of `superpixel_size`, and the grid should start on a boundary of
+/// the colour filter pattern.
+pub fn superpixel(
+    img_grid: &ArrayView2<f32>,
+    mapping: &FilterMap,
+    size: usize,
+    x: usize,
+    y: usize,
+) -> Pixel<f32> {
+    let mut sums = [0f32; 3];
+    let mut counts = [0u32; 3];
+    for sx in x * size..(x + 1) * size {
+        for sy in y * size..(y + 1) * size {
+            let color = mapping.index_wrapped(sx, sy);
+            sums[color.idx()] += img_grid[(sx, sy)];
+            counts[color.idx()] += 1;
+        }
+    }
+    let avg = |color: Color| sums[color.idx()] / counts[color.idx()].max(1) as f32;
+    Pixel {
+        red: avg(Color::Red),
+        green: avg(Color::Green),
+        blue: avg(Color::Blue),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::demosaic::superpixel_size;
+    use libraw::Color::{Blue, Green, Red};
+    use ndarray::{Array2, ShapeBuilder};
+
+    #[test]
+    fn superpixel_size_xtrans() {
+        let mapping = Array2::from_shape_vec(
+            (6, 6).set_f(true),
+            vec![
+                Green, Green, Red, Green, Green, Blue, Green, Green, Blue, Green, Green, Red, Blue,
+                Red, Green, Red, Blue, Green, Green, Green, Blue, Green, Green, Red, Green, Green,
+                Red, Green, Green, Blue, Red, Blue, Green, Blue, Red, Green,
+            ],
+        )
+        .unwrap();
+        assert_eq!(superpixel_size(&mapping), 3);
+    }
+
+    #[test]
+    fn superpixel_size_bayer() {
+        let mapping = Array2::from_shape_vec((2, 2), vec![Red, Green, Green, Blue]).unwrap();
+        assert_eq!(superpixel_size(&mapping), 2);
+    }
+}
@@ -1,11 +1,138 @@
-use hdrhistogram;
 use image::{GenericImage, Pixel, Rgba, RgbaImage};
-use imageproc::drawing::{draw_filled_rect_mut, Canvas};
-use imageproc::rect::Rect;
+use imageproc::drawing::Canvas;
 use itertools::Itertools;
+use rayon::prelude::*;
+
+/// The number of bins in a `Histogram`: one per 8-bit value.
+const HISTOGRAM_BINS: usize = 256;
 
 pub struct Histogram {
-    channel_histograms: Vec<hdrhistogram::Histogram<u32>>,
+    bins: Bins,
+}
+
+/// How bin counts are turned into bar heights.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CountScale {
+    Linear,
+    /// Scales by `ln(1 + count)`, so that small counts, like a few clipped pixels, stay visible.
+    Log,
+}
+
+/// Counts of values in equal-width bins covering 0 to 1, for each channel and for luminance.
+/// Values outside that range are counted in the first or last bin.
+///
+/// `T` is `u64` for counts, or `f32` for heights scaled by `Bins::scale`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bins<T = u64> {
+    pub red: Vec<T>,
+    pub green: Vec<T>,
+    pub blue: Vec<T>,
+    /// Rec. 709 luminance, computed from the same values as the channels.
+    pub luminance: Vec<T>,
+}
+
+impl Bins {
+    pub fn new(bin_count: usize) -> Self {
+        assert!(bin_count > 0, "Histograms need at least one bin");
+        Bins {
+            red: vec![0; bin_count],
+            green: vec![0; bin_count],
+            blue: vec![0; bin_count],
+            luminance: vec![0; bin_count],
+        }
+    }
+
+    /// Counts an RGB value, with each channel from 0 to 1.
+    pub fn record(&mut self, [red, green, blue]: [f32; 3]) {
+        let bin_count = self.bin_count();
+        let bin = |val: f32| ((val * bin_count as f32) as usize).min(bin_count - 1);
+        let luminance = 0.2126 * red + 0.7152 * green + 0.0722 * blue;
+        self.red[bin(red)] += 1;
+        self.green[bin(green)] += 1;
+        self.blue[bin(blue)] += 1;
+        self.luminance[bin(luminance)] += 1;
+    }
+
+    /// Adds the counts from `other`, which must have the same number of bins.
+    pub fn add(&mut self, other: &Bins) {
+        for (ours, theirs) in self.channels_mut().zip_eq(other.channels()) {
+            for (a, b) in ours.iter_mut().zip_eq(theirs) {
+                *a += b;
+            }
+        }
+    }
+
+    /// Counts every value from `pixels`, in parallel.
+    pub fn from_par_iter(bin_count: usize, pixels: impl ParallelIterator<Item = [f32; 3]>) -> Self {
+        pixels
+            .fold(
+                || Bins::new(bin_count),
+                |mut bins, pixel| {
+                    bins.record(pixel);
+                    bins
+                },
+            )
+            .reduce(
+                || Bins::new(bin_count),
+                |mut a, b| {
+                    a.add(&b);
+                    a
+                },
+            )
+    }
+
+    /// Converts the counts into heights from 0 to 1, relative to the largest bin in any channel.
+    pub fn scale(&self, scale: CountScale) -> Bins<f32> {
+        let transform = |count: u64| match scale {
+            CountScale::Linear => count as f32,
+            CountScale::Log => (count as f32).ln_1p(),
+        };
+        let largest = self.channels().flatten().copied().max().unwrap_or(0);
+        let largest = transform(largest).max(f32::MIN_POSITIVE);
+        let heights = |counts: &[u64]| {
+            counts
+                .iter()
+                .map(|&count| transform(count) / largest)
+                .collect()
+        };
+        Bins {
+            red: heights(&self.red),
+            green: heights(&self.green),
+            blue: heights(&self.blue),
+            luminance: heights(&self.luminance),
+        }
+    }
+
+    fn channels_mut(&mut self) -> impl Iterator<Item = &mut Vec<u64>> {
+        vec![
+            &mut self.red,
+            &mut self.green,
+            &mut self.blue,
+            &mut self.luminance,
+        ]
+        .into_iter()
+    }
+}
+
+impl<T> Bins<T> {
+    pub fn bin_count(&self) -> usize {
+        self.red.len()
+    }
+
+    /// Red, green, blue, then luminance.
+    pub fn channels(&self) -> impl Iterator<Item = &Vec<T>> {
+        vec![&self.red, &self.green, &self.blue, &self.luminance].into_iter()
+    }
+}
+
+/// Histograms from both ends of a render.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderHistograms {
+    /// Of the linear image, after white balance and conversion to sRGB primaries but before any
+    /// tone adjustments. It covers the camera's crop, but not the user's.
+    pub linear: Bins,
+    /// Of the final, gamma-encoded image.
+    pub output: Bins,
 }
 
 /// A canvas that adds pixels when drawing.
@@ -48,35 +175,34 @@ const COLORS: [Rgba<u8>; 3] = [
 ];
 
 impl Histogram {
+    pub fn bins(&self) -> &Bins {
+        &self.bins
+    }
+
     pub fn to_img(&self, width: u32, height: u32) -> RgbaImage {
-        // TODO: this doesn't really work well for any value _other_ than 256.
-        assert!(width >= 256);
+        self.bins.to_img(width, height, CountScale::Linear)
+    }
+}
+
+impl Bins {
+    /// Draws the colour channels as overlapping bars. Each column shows the largest of the bins
+    /// it covers, so narrow images still show spikes.
+    pub fn to_img(&self, width: u32, height: u32, scale: CountScale) -> RgbaImage {
         let img = RgbaImage::new(width, height);
         let mut canvas = BlendAdd(img);
-        let largest = self.channel_histograms[0]
-            .iter_linear(2)
-            .map(|x| x.count_since_last_iteration())
-            .max()
-            .unwrap();
-        for (hist, color) in self
-            .channel_histograms
-            .iter()
-            .zip_eq(COLORS.iter().copied())
-        {
-            let mut start_x = 0;
-            for it in hist.iter_linear(2) {
-                let end_x = it.value_iterated_to();
-                let bar_height = it.count_since_last_iteration() * height as u64 / largest;
-                let bar_width = (end_x - start_x) * 256 / width as u64;
-                //println!("xpos: {}, bar_height: {}", start_x, bar_height);
-                if bar_height != 0 {
-                    draw_filled_rect_mut(
-                        &mut canvas,
-                        Rect::at((start_x) as i32, (height - 1 - bar_height as u32) as i32)
-                            .of_size(bar_width as u32, bar_height as u32),
-                        color,
-                    );
-                    start_x = end_x;
+        let heights = self.scale(scale);
+        let bin_count = self.bin_count() as u64;
+        for (bars, color) in heights.channels().zip(COLORS.iter().copied()) {
+            for x in 0..width {
+                let start = (x as u64 * bin_count / width as u64) as usize;
+                let end = ((x as u64 + 1) * bin_count).div_ceil(width as u64) as usize;
+                let bar = bars[start..end.max(start + 1)]
+                    .iter()
+                    .copied()
+                    .fold(0.0, f32::max);
+                let bar_height = (bar * height as f32).round() as u32;
+                for y in height - bar_height..height {
+                    canvas.draw_pixel(x, y, color);
                 }
             }
         }
@@ -86,23 +212,63 @@ impl Histogram {
 
 pub trait ToHistogram {
     fn histogram(&self) -> Histogram;
+
+    /// Counts the image's values into `bin_count` bins.
+    fn bins(&self, bin_count: usize) -> Bins;
 }
 
 impl ToHistogram for image::RgbImage {
     fn histogram(&self) -> Histogram {
-        // One per channel. TODO: make this generic somehow
-        let mut hists = (0..3)
-            .map(|_| hdrhistogram::Histogram::<u32>::new_with_bounds(1, 256, 3).unwrap())
-            .collect_vec();
-
-        for val in self.pixels() {
-            for (hist, channel_val) in hists.iter_mut().zip_eq(val.0.iter().copied()) {
-                hist.record(channel_val as u64).unwrap();
-            }
+        Histogram {
+            bins: self.bins(HISTOGRAM_BINS),
         }
+    }
 
-        Histogram {
-            channel_histograms: hists,
+    fn bins(&self, bin_count: usize) -> Bins {
+        let pixels = self.as_raw().par_chunks_exact(3).map(|px| {
+            let val = |i: usize| px[i] as f32 / 255.0;
+            [val(0), val(1), val(2)]
+        });
+        Bins::from_par_iter(bin_count, pixels)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_values_into_bins() {
+        let mut bins = Bins::new(4);
+        bins.record([0.0, 0.5, 1.0]);
+        bins.record([-1.0, 0.3, 2.0]);
+        assert_eq!(bins.red, [2, 0, 0, 0]);
+        assert_eq!(bins.green, [0, 1, 1, 0]);
+        assert_eq!(bins.blue, [0, 0, 0, 2]);
+        assert_eq!(bins.luminance, [1, 1, 0, 0]);
+
+        let heights = bins.scale(CountScale::Linear);
+        assert_eq!(heights.red, [1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(heights.green, [0.0, 0.5, 0.5, 0.0]);
+        let heights = bins.scale(CountScale::Log);
+        assert!(heights.green[1] > 0.5 && heights.green[1] < 1.0);
+        assert_eq!(Bins::new(3).scale(CountScale::Log).red, [0.0; 3]);
+    }
+
+    #[test]
+    fn images_of_any_width_can_be_drawn() {
+        let img = image::RgbImage::from_fn(16, 16, |x, y| image::Rgb([(x * 16) as u8, 0, y as u8]));
+        let histogram = img.histogram();
+        assert_eq!(histogram.bins().red.iter().sum::<u64>(), 256);
+        for &width in &[1, 100, 256, 1000] {
+            let drawn = histogram.to_img(width, 64);
+            assert_eq!(drawn.dimensions(), (width, 64));
+            // None of the pixels have any green, so the first column is a full-height green bar.
+            assert_eq!(drawn.get_pixel(0, 0)[1], 255);
         }
+
+        let bins = img.bins(16);
+        assert_eq!(bins.red, vec![16; 16]);
+        assert_eq!(bins.green[0], 256);
     }
 }
@@ -1 +1,2 @@
 pub mod histogram;
+pub mod overlay;
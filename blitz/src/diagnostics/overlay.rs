@@ -0,0 +1,202 @@
+//! Masks to draw over a rendered image, showing where detail has been lost and how it's exposed.
+//!
+//! Every overlay is the same size as the rendered image, after the camera's crop, the user's crop
+//! and orientation. Pixels with nothing to show are transparent.
+
+use crate::render::{crop_and_orient, filter_map};
+use crate::render_settings::RenderSettings;
+use image::{ImageBuffer, Rgba, RgbaImage};
+use libraw::griditer::IndexWrapped2;
+use libraw::raw_image::RawImage;
+use libraw::util::timing::StageTimer;
+use libraw::Color;
+use palette::{LinSrgb, Srgb};
+use rayon::prelude::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Overlay {
+    /// Photosites which were saturated on the sensor, coloured by the channels affected.
+    RawSaturation,
+    /// Output pixels with a channel at white (red) or black (blue).
+    Clipping,
+    /// A false-colour map of exposure zones.
+    ExposureZones,
+}
+
+impl Overlay {
+    /// Draws this overlay for `rendered`, which was rendered from `img` with `settings`.
+    pub fn draw(
+        self,
+        img: &dyn RawImage,
+        settings: &RenderSettings,
+        rendered: &image::RgbImage,
+    ) -> RgbaImage {
+        match self {
+            Overlay::RawSaturation => raw_saturation(img, settings),
+            Overlay::Clipping => clipping(rendered),
+            Overlay::ExposureZones => exposure_zones(rendered),
+        }
+    }
+}
+
+const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+/// Marks output pixels whose neighbourhood on the sensor includes a saturated photosite. Raw
+/// values are compared before black subtraction and white balance, so this shows what the sensor
+/// itself couldn't record, even if the render has pulled it back below white.
+///
+/// Each channel of the mask is set if a photosite of that colour is saturated, so clipped
+/// highlights show up white, and a clipped red channel alone shows up red.
+pub fn raw_saturation(img: &dyn RawImage, settings: &RenderSettings) -> RgbaImage {
+    let _timer = StageTimer::new("Raw saturation overlay");
+    let ri = img.render_info();
+    let mapping = filter_map(&ri);
+    let (width, height) = (ri.width as usize, ri.height as usize);
+    let threshold = ri.saturation_threshold();
+
+    let crop = ri.crop_rect;
+    let (crop_width, crop_height) = crop.size();
+    let mut mask = vec![0u8; crop_width * crop_height * 4];
+    mask.par_chunks_mut(crop_width * 4)
+        .enumerate()
+        .for_each(|(row, out)| {
+            let y = crop.top + row;
+            for (col, px) in out.chunks_exact_mut(4).enumerate() {
+                let x = crop.left + col;
+                let mut saturated = [false; 3];
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        if ri.raw_data[nx + ny * width] >= threshold {
+                            saturated[mapping.index_wrapped(nx, ny).idx()] = true;
+                        }
+                    }
+                }
+                if saturated.contains(&true) {
+                    for color in &[Color::Red, Color::Green, Color::Blue] {
+                        px[color.idx()] = if saturated[color.idx()] { 255 } else { 0 };
+                    }
+                    px[3] = 255;
+                }
+            }
+        });
+
+    let mask = ImageBuffer::from_raw(crop_width as u32, crop_height as u32, mask)
+        .expect("mask is the size of the crop");
+    crop_and_orient(mask, settings)
+}
+
+/// Marks output pixels with any channel at white in red, and any channel at black in blue.
+pub fn clipping(rendered: &image::RgbImage) -> RgbaImage {
+    map_pixels(rendered, |px| {
+        if px.contains(&u8::MAX) {
+            Rgba([255, 0, 0, 255])
+        } else if px.contains(&0) {
+            Rgba([0, 0, 255, 255])
+        } else {
+            TRANSPARENT
+        }
+    })
+}
+
+/// One colour per zone, from zone 0 (black) to zone X (white). Zone V is middle grey, and is left
+/// grey.
+const ZONE_COLORS: [Rgba<u8>; 11] = [
+    Rgba([40, 0, 60, 255]),
+    Rgba([80, 0, 140, 255]),
+    Rgba([0, 40, 200, 255]),
+    Rgba([0, 120, 220, 255]),
+    Rgba([0, 180, 120, 255]),
+    Rgba([128, 128, 128, 255]),
+    Rgba([140, 200, 0, 255]),
+    Rgba([240, 220, 0, 255]),
+    Rgba([255, 140, 0, 255]),
+    Rgba([230, 30, 0, 255]),
+    Rgba([255, 255, 255, 255]),
+];
+
+/// The zone, from 0 to 10, of an sRGB-encoded pixel. Zones are tenths of CIE lightness, which
+/// puts 18% grey in zone V, as in Ansel Adams' zone system.
+pub fn exposure_zone(px: [u8; 3]) -> usize {
+    let srgb = Srgb::new(px[0], px[1], px[2]).into_format::<f32>();
+    let linear: LinSrgb = srgb.into_linear();
+    let luminance = 0.2126 * linear.red + 0.7152 * linear.green + 0.0722 * linear.blue;
+    let f = if luminance > 0.008_856 {
+        luminance.cbrt()
+    } else {
+        7.787 * luminance + 16.0 / 116.0
+    };
+    let lightness = 116.0 * f - 16.0;
+    (lightness / 10.0).round().clamp(0.0, 10.0) as usize
+}
+
+/// Colours every output pixel by its exposure zone.
+pub fn exposure_zones(rendered: &image::RgbImage) -> RgbaImage {
+    map_pixels(rendered, |px| ZONE_COLORS[exposure_zone(px)])
+}
+
+fn map_pixels(rendered: &image::RgbImage, f: impl Fn([u8; 3]) -> Rgba<u8> + Sync) -> RgbaImage {
+    let pixels: Vec<u8> = rendered
+        .as_raw()
+        .par_chunks_exact(3)
+        .flat_map_iter(|px| f([px[0], px[1], px[2]]).0.to_vec())
+        .collect();
+    ImageBuffer::from_raw(rendered.width(), rendered.height(), pixels)
+        .expect("one output pixel per input pixel")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render_settings::Orientation;
+    use libraw::raf::CropRect;
+    use libraw::raw_image::TestSensor;
+
+    #[test]
+    fn raw_saturation_is_aligned_with_the_crop() {
+        // A 6x6 sensor, cropped to the 2x4 pixels in the middle.
+        let mut sensor = TestSensor::bayer(6, 6, 14);
+        sensor.crop_rect = CropRect {
+            left: 2,
+            right: 4,
+            top: 1,
+            bottom: 5,
+        };
+        sensor.data = vec![1000; 36];
+        // A red photosite just outside the crop, to the right of its bottom-right corner.
+        sensor.data[4 + 4 * 6] = 16383;
+        let mut settings = RenderSettings::default();
+
+        let mask = raw_saturation(&sensor, &settings);
+        assert_eq!(mask.dimensions(), (2, 4));
+        // Only the crop's pixels next to it see it: (3, 3) and (3, 4) on the sensor.
+        assert_eq!(*mask.get_pixel(1, 3), Rgba([255, 0, 0, 255]));
+        assert_eq!(*mask.get_pixel(1, 2), Rgba([255, 0, 0, 255]));
+        assert_eq!(*mask.get_pixel(0, 1), TRANSPARENT);
+
+        settings.orientation = Orientation::Rotate180;
+        let mask = raw_saturation(&sensor, &settings);
+        assert_eq!(*mask.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn clipping_and_zones() {
+        let rendered = image::RgbImage::from_raw(
+            4,
+            1,
+            vec![255, 255, 255, 0, 0, 0, 118, 118, 118, 200, 10, 10],
+        )
+        .unwrap();
+        let mask = clipping(&rendered);
+        assert_eq!(*mask.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(*mask.get_pixel(1, 0), Rgba([0, 0, 255, 255]));
+        assert_eq!(*mask.get_pixel(2, 0), TRANSPARENT);
+
+        // 118 is about 18% grey once decoded.
+        let zones: Vec<_> = rendered.pixels().map(|px| exposure_zone(px.0)).collect();
+        assert_eq!(zones, [10, 0, 5, 4]);
+        assert_eq!(
+            exposure_zones(&rendered).get_pixel(2, 0).0,
+            [128, 128, 128, 255]
+        );
+    }
+}
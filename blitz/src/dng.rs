@@ -0,0 +1,42 @@
+//! Supplies the colour and lens calibration that `libraw`'s DNG writer can't work out for itself.
+
+use crate::camera_specific_junk::{cam_from_xyz, dng_cam2_to_xyz, ColorspaceMatrix};
+use crate::vignette_correction;
+use libraw::dng::{Calibration, Compression, DngOptions, RadialVignette};
+use libraw::raf::ParsedRafFile;
+
+/// EXIF LightSource code for D65.
+const D65: u16 = 21;
+
+fn row_major(mat: &ColorspaceMatrix) -> [f32; 9] {
+    let mut out = [0.0; 9];
+    for (i, x) in out.iter_mut().enumerate() {
+        *x = mat[(i / 3, i % 3)];
+    }
+    out
+}
+
+/// Returns options for writing `raf` as a DNG, using the same calibration as our own renderer.
+pub fn dng_options(raf: &ParsedRafFile, compression: Compression) -> DngOptions {
+    let attenuation = raf.vignette_attenuation();
+    let mut coefficients = [0.0; 5];
+    for (out, k) in coefficients
+        .iter_mut()
+        .zip(&vignette_correction::from_fuji_tags(attenuation).coefficients())
+    {
+        *out = *k as f64;
+    }
+    DngOptions {
+        compression,
+        calibrations: vec![Calibration {
+            illuminant: D65,
+            color_matrix: row_major(&cam_from_xyz()),
+            forward_matrix: Some(row_major(&dng_cam2_to_xyz())),
+        }],
+        vignette: Some(RadialVignette {
+            coefficients,
+            // The first entry holds the distance (in pixels) that the curve is measured against.
+            radius: attenuation[0].0 as f64,
+        }),
+    }
+}
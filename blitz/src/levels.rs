@@ -87,3 +87,16 @@ where
     let rgb = image::Rgb([(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]);
     rgb
 }
+
+pub fn to_rgb16<T>(hsl: &T) -> image::Rgb<u16>
+where
+    T: Into<Srgb> + Copy,
+{
+    let srgb: Srgb = (*hsl).into();
+    let (r, g, b) = srgb.into_components();
+    image::Rgb([
+        (r * 65535.0) as u16,
+        (g * 65535.0) as u16,
+        (b * 65535.0) as u16,
+    ])
+}
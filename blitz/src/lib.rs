@@ -1,9 +1,18 @@
+pub mod analysis;
 pub mod camera_specific_junk;
 pub mod common;
+pub mod context;
 pub mod demosaic;
 pub mod diagnostics;
+pub mod dng;
 pub mod levels;
 pub mod render;
 pub mod render_settings;
+pub mod sidecar;
 pub mod tasks;
 pub mod vignette_correction;
+pub mod white_balance;
+pub mod xmp;
+
+#[macro_use]
+extern crate quick_error;
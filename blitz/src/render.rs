@@ -1,31 +1,316 @@
 extern crate nalgebra as na;
 
-use image::ImageBuffer;
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Rgb};
 use itertools::Itertools;
+use log::debug;
 use ndarray::prelude::*;
 use ndarray::Array2;
 use ordered_float::NotNan;
-use palette::Hsv;
+use palette::{Hsv, LinSrgb};
+use rayon::prelude::*;
 
-use libraw::raf::ParsedRafFile;
+use libraw::griditer::FilterMap;
+use libraw::raf::{CropRect, RenderInfo};
+use libraw::raw_image::RawImage;
+use libraw::util::timing::StageTimer;
 
-use crate::camera_specific_junk::dng_cam2_to_xyz;
+use crate::analysis::resolve_levels;
+use crate::camera_specific_junk::{file_cam_from_xyz, forward_matrix};
 use crate::common::Pixel;
-use crate::demosaic::{Demosaic, Nearest};
-use crate::levels::{cam_to_hsv, make_black_sub_task, to_rgb};
-use crate::render_settings::RenderSettings;
-use crate::tasks::{par_index_map_raiso, par_index_map_siso, SingleInputSingleOutput};
+use crate::context::{Cancelled, RenderContext};
+use crate::demosaic::{superpixel, superpixel_size, Demosaic, Nearest};
+use crate::diagnostics::histogram::{Bins, RenderHistograms, ToHistogram};
+use crate::levels::{cam_to_hsv, make_black_sub_task, to_rgb, to_rgb16};
+use crate::render_settings::{Levels, Orientation, RenderSettings, WhiteBalance};
+use crate::tasks::{
+    par_index_map_raiso, par_index_map_raiso_sized, par_index_map_siso, SingleInputSingleOutput,
+};
 use crate::vignette_correction;
+use crate::white_balance::camera_multipliers_for;
 
-pub fn render_raw(img: &ParsedRafFile) -> image::RgbImage {
+pub fn render_raw(img: &dyn RawImage) -> image::RgbImage {
     render_raw_with_settings(img, &Default::default())
 }
 
-pub fn render_raw_with_settings(img: &ParsedRafFile, settings: &RenderSettings) -> image::RgbImage {
-    println!("Settings: {:?}", settings);
+pub fn render_raw_with_settings(img: &dyn RawImage, settings: &RenderSettings) -> image::RgbImage {
+    render_raw_with_context(img, settings, &RenderContext::default())
+        .expect("Default context can't be cancelled")
+}
+
+/// Like `render_raw_with_settings`, but reports progress to `ctx` as it goes, and returns early if
+/// `ctx` is cancelled.
+pub fn render_raw_with_context(
+    img: &dyn RawImage,
+    settings: &RenderSettings,
+    ctx: &RenderContext,
+) -> Result<image::RgbImage, Cancelled> {
+    render_full(img, settings, ctx, to_rgb, |_| ())
+}
+
+/// Like `render_raw_with_context`, but also counts the values of the linear and output images
+/// into `bin_count` bins each.
+pub fn render_raw_with_histograms(
+    img: &dyn RawImage,
+    settings: &RenderSettings,
+    ctx: &RenderContext,
+    bin_count: usize,
+) -> Result<(image::RgbImage, RenderHistograms), Cancelled> {
+    let mut linear = None;
+    let rendered = render_full(img, settings, ctx, to_rgb, |developed| {
+        let _timer = StageTimer::new("Linear histogram");
+        let pixels = developed
+            .axis_iter(Axis(1))
+            .into_par_iter()
+            .flat_map_iter(|column| {
+                column.into_iter().map(|hsv| {
+                    let rgb: LinSrgb = (*hsv).into();
+                    [rgb.red, rgb.green, rgb.blue]
+                })
+            });
+        linear = Some(Bins::from_par_iter(bin_count, pixels));
+    })?;
+    let output = {
+        let _timer = StageTimer::new("Output histogram");
+        rendered.bins(bin_count)
+    };
+    let histograms = RenderHistograms {
+        linear: linear.expect("render_full always develops the image"),
+        output,
+    };
+    Ok((rendered, histograms))
+}
+
+/// Like `render_raw_with_context`, but with 16 bits per channel, for output formats which can
+/// make use of the extra precision.
+pub fn render_raw_16bit_with_context(
+    img: &dyn RawImage,
+    settings: &RenderSettings,
+    ctx: &RenderContext,
+) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, Cancelled> {
+    render_full(img, settings, ctx, to_rgb16, |_| ())
+}
+
+/// Renders the whole image. `on_developed` is given the camera's crop of the image as it comes out
+/// of `develop`, before any tone adjustments.
+fn render_full<P>(
+    img: &dyn RawImage,
+    settings: &RenderSettings,
+    ctx: &RenderContext,
+    to_pixel: impl Fn(&Hsv) -> P + Sync,
+    on_developed: impl FnOnce(ArrayView2<Hsv>),
+) -> Result<ImageBuffer<P, Vec<P::Subpixel>>, Cancelled>
+where
+    P: image::Pixel + Send + Sync + 'static,
+{
+    let _timer = StageTimer::new("Render");
+    debug!("Settings: {:?}", settings);
+    let ri = &img.render_info();
+
+    let levels = resolve_levels(img, settings);
+    let img_hsv = develop(ctx, img, ri, settings, sensor_rect(ri), Sampling::Full)?;
+    let crop = ri.crop_rect;
+    on_developed(img_hsv.slice(s![crop.left..crop.right, crop.top..crop.bottom]));
+    let img = finish(ctx, img_hsv, levels, settings, to_pixel)?;
+
+    // Last step: crop and convert.
+    let (output_width, output_height) = ri.crop_rect.size();
+    debug!("Cropped to {}x{} pixels", output_width, output_height);
+    let buf = ImageBuffer::from_fn(output_width as u32, output_height as u32, |x, y| {
+        img[(
+            ri.crop_rect.left + x as usize,
+            ri.crop_rect.top + y as usize,
+        )]
+    });
+
+    Ok(crop_and_orient(buf, settings))
+}
+
+/// Renders a downscaled version of the image, no larger than `max_dimension` pixels along its
+/// longest edge. A `max_dimension` of 0 is treated as 1.
+///
+/// Rather than interpolating every sensor pixel, this averages each colour over blocks of sensor
+/// pixels (3x3 "superpixels" for X-Trans, 2x2 for Bayer), which is much faster. All other steps
+/// are the same as `render_raw_with_settings`, so the preview should match the final output.
+pub fn render_preview(
+    img: &dyn RawImage,
+    settings: &RenderSettings,
+    max_dimension: u32,
+) -> image::RgbImage {
+    let _timer = StageTimer::new("Render preview");
+    let max_dimension = max_dimension.max(1);
+    let ri = &img.render_info();
+    let (crop_width, crop_height) = ri.crop_rect.size();
+    let block = block_size_for_scale(
+        ri,
+        max_dimension as f32 / crop_width.max(crop_height) as f32,
+        (crop_width, crop_height),
+    );
+
+    let ctx = RenderContext::default();
+    let img_hsv = develop(
+        &ctx,
+        img,
+        ri,
+        settings,
+        sensor_rect(ri),
+        Sampling::Binned(block),
+    )
+    .expect("Default context can't be cancelled");
+    let levels = resolve_levels(img, settings);
+    let img = finish(&ctx, img_hsv, levels, settings, to_rgb)
+        .expect("Default context can't be cancelled");
+
+    let crop = ri.crop_rect;
+    let buf = ImageBuffer::from_fn(
+        (crop_width / block) as u32,
+        (crop_height / block) as u32,
+        |x, y| {
+            img[(
+                crop.left / block + x as usize,
+                crop.top / block + y as usize,
+            )]
+        },
+    );
+    fit_within(crop_and_orient(buf, settings), max_dimension, max_dimension)
+}
+
+/// Renders only the region `rect` of the output image, scaled by `scale`.
+///
+/// `rect` is relative to the camera's crop, and ignores the crop and orientation in `settings`.
+/// `scale` is clamped to at most 1; there's no point rendering at more than the sensor's
+/// resolution. A scale which isn't positive renders the region as a single pixel, and an empty
+/// region (or one entirely outside the crop) gives an empty image.
+pub fn render_region(
+    img: &dyn RawImage,
+    settings: &RenderSettings,
+    rect: CropRect,
+    scale: f32,
+) -> image::RgbImage {
+    let _timer = StageTimer::new("Render region");
     let ri = &img.render_info();
+    let ctx = RenderContext::default();
+    let crop = ri.crop_rect;
+    let window = CropRect {
+        left: (crop.left + rect.left).min(crop.right),
+        right: (crop.left + rect.right.max(rect.left)).min(crop.right),
+        top: (crop.top + rect.top).min(crop.bottom),
+        bottom: (crop.top + rect.bottom.max(rect.top)).min(crop.bottom),
+    };
+    let (region_width, region_height) = window.size();
+    if region_width == 0 || region_height == 0 {
+        return image::RgbImage::new(0, 0);
+    }
+    let scale = if scale > 0.0 {
+        scale.min(1.0)
+    } else {
+        1.0 / region_width.max(region_height) as f32
+    };
+
+    // Auto-contrast depends on the whole image, not just the region.
+    let levels = resolve_levels(img, settings);
 
-    let mapping = Array2::from_shape_vec((6, 6).set_f(true), ri.xtrans_mapping.clone()).unwrap();
+    let superpixel = superpixel_size(&filter_map(ri));
+    let sampling =
+        if scale * superpixel as f32 > 1.0 || region_width.min(region_height) < superpixel {
+            // Binning would throw away more resolution than we're asking for, or the region is too
+            // small to hold a single block.
+            Sampling::Full
+        } else {
+            Sampling::Binned(block_size_for_scale(ri, scale, window.size()))
+        };
+    let img_hsv = develop(&ctx, img, ri, settings, window, sampling)
+        .expect("Default context can't be cancelled");
+    let img = finish(&ctx, img_hsv, levels, settings, to_rgb)
+        .expect("Default context can't be cancelled");
+
+    let (width, height) = img.dim();
+    let buf = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        img[(x as usize, y as usize)]
+    });
+    fit_within(
+        buf,
+        ((region_width as f32 * scale).round() as u32).max(1),
+        ((region_height as f32 * scale).round() as u32).max(1),
+    )
+}
+
+/// Develops the whole sensor at low resolution, to around `max_dimension` pixels along its longest
+/// edge, for measuring the image rather than showing it. Returns the image and the block size,
+/// which sensor positions need dividing by.
+pub(crate) fn develop_coarse(
+    img: &dyn RawImage,
+    ri: &RenderInfo,
+    settings: &RenderSettings,
+    max_dimension: usize,
+) -> (Array2<Hsv>, usize) {
+    let (width, height) = (ri.width as usize, ri.height as usize);
+    let block = block_size_for_scale(
+        ri,
+        max_dimension as f32 / width.max(height) as f32,
+        (width, height),
+    );
+    let coarse = develop(
+        &RenderContext::default(),
+        img,
+        ri,
+        settings,
+        sensor_rect(ri),
+        Sampling::Binned(block),
+    )
+    .expect("Default context can't be cancelled");
+    (coarse, block)
+}
+
+/// How sensor pixels are turned into output pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sampling {
+    /// Demosaic every sensor pixel into an output pixel.
+    Full,
+    /// Average each colour over blocks of n x n sensor pixels, producing one output pixel per
+    /// block.
+    Binned(usize),
+}
+
+fn sensor_rect(ri: &RenderInfo) -> CropRect {
+    CropRect {
+        left: 0,
+        right: ri.width as usize,
+        top: 0,
+        bottom: ri.height as usize,
+    }
+}
+
+pub fn filter_map(ri: &RenderInfo) -> FilterMap {
+    Array2::from_shape_vec((6, 6).set_f(true), ri.xtrans_mapping.clone()).unwrap()
+}
+
+/// Picks the largest block size (a multiple of the superpixel size) which still produces at least
+/// `scale` times as many pixels as the sensor has. Blocks never get bigger than the shorter side
+/// of `area`, the size of what's being rendered, so there's always at least one of them (as long
+/// as `area` can fit a superpixel).
+fn block_size_for_scale(ri: &RenderInfo, scale: f32, area: (usize, usize)) -> usize {
+    let superpixel = superpixel_size(&filter_map(ri));
+    let largest = (area.0.min(area.1) / superpixel).max(1);
+    let multiple = 1.0 / (scale * superpixel as f32);
+    // Also catches scales of zero (an infinite multiple) and NaN, which would otherwise saturate.
+    if scale.is_nan() || scale <= 0.0 || multiple >= largest as f32 {
+        return superpixel * largest;
+    }
+    superpixel * (multiple.floor() as usize).max(1)
+}
+
+/// Runs everything up to and including conversion to HSV on the sensor pixels within `window`.
+/// The result covers exactly `window`, divided by the block size when binning.
+fn develop(
+    ctx: &RenderContext,
+    raf: &dyn RawImage,
+    ri: &RenderInfo,
+    settings: &RenderSettings,
+    window: CropRect,
+    sampling: Sampling,
+) -> Result<Array2<Hsv>, Cancelled> {
+    let mapping = filter_map(ri);
 
     let src = ArrayView2::from_shape(
         (ri.width as usize, ri.height as usize).set_f(true),
@@ -33,14 +318,36 @@ pub fn render_raw_with_settings(img: &ParsedRafFile, settings: &RenderSettings)
     )
     .unwrap();
 
+    // Expand the window so that it starts on a boundary of the colour filter pattern (which means
+    // we can keep using the same mapping), and so that demosaicing has neighbouring pixels to work
+    // with at the edges.
+    let period = mapping.nrows();
+    let margin = match sampling {
+        Sampling::Full => 1,
+        Sampling::Binned(_) => 0,
+    };
+    let left = window.left.saturating_sub(margin) / period * period;
+    let top = window.top.saturating_sub(margin) / period * period;
+    let right = (window.right + margin).min(ri.width as usize);
+    let bottom = (window.bottom + margin).min(ri.height as usize);
+    let src = src.slice(s![left..right, top..bottom]);
+
     // Some setup
-    let max = (1 << 14) as f32;
-    let wb = ri.white_bal;
-    let scale_factors = make_normalized_wb_coefs([wb.red as f32, wb.green as f32, wb.blue as f32]);
-    let matrix = dng_cam2_to_xyz();
+    let max = (1 << ri.bit_depth) as f32;
+    let wb_coefs = match settings.white_balance {
+        WhiteBalance::AsShot => {
+            let wb = ri.white_bal;
+            [wb.red as f32, wb.green as f32, wb.blue as f32]
+        }
+        WhiteBalance::Custom { temperature, tint } => {
+            camera_multipliers_for(&file_cam_from_xyz(raf.calibrations()), temperature, tint)
+        }
+    };
+    let scale_factors = make_normalized_wb_coefs(wb_coefs);
+    let matrix = forward_matrix(raf.calibrations());
 
     // Define steps
-    let devignette = make_devignetter(img);
+    let devignette = make_devignetter(raf);
     let black_sub = make_black_sub_task(ri.black_levels.clone());
     let convert_to_float = |_: usize, _: usize, val: u16| val as f32 / max;
     let apply_wb = move |pixel: &Pixel<f32>| Pixel {
@@ -49,19 +356,13 @@ pub fn render_raw_with_settings(img: &ParsedRafFile, settings: &RenderSettings)
         blue: pixel.blue * scale_factors[2],
     };
 
-    let apply_curve = |pixel: &Hsv| {
-        let val = pixel.value;
-        let factor = settings.tone_curve.spline.clamped_sample(val).unwrap();
-        let mut ret = pixel.clone();
-        ret.value *= factor;
-        ret
-    };
-
     let convert_to_hsv = |pixel: &Pixel<_>| cam_to_hsv(&matrix, pixel);
 
     // Run steps
-    // This is the "operating on single values" phase.
-    let img = par_index_map_siso(&src, |x, y, val| {
+    // This is the "operating on single values" phase. Positions are relative to the window, so
+    // translate them back to sensor positions for the position-dependent steps.
+    let img = par_index_map_siso(ctx, "Preprocessing", &src, |x, y, val| {
+        let (x, y) = (x + left, y + top);
         let val = if settings.lens_corrections.vignette {
             devignette(x, y, val)
         } else {
@@ -69,75 +370,125 @@ pub fn render_raw_with_settings(img: &ParsedRafFile, settings: &RenderSettings)
         };
         let val = black_sub(x, y, val);
         let val = convert_to_float(x, y, val);
-        let val = val * (settings.exposure_basis);
-        val
-    });
+        val * settings.exposure_basis
+    })?;
 
     // This is "demosaic" and then "operate on single values again".
-    let img = par_index_map_raiso(&img.view(), |x, y, data: &ArrayView2<_>| {
-        let val = Nearest::demosaic(data, &mapping, x, y);
-        let val = apply_wb(&val);
-        // NOTE: we used to clamp here, but it looks like we don't need it anymore because we're
-        // round-tripping through HSV?
-        let val = convert_to_hsv(&val);
-        val
-    });
-
-    let img = if settings.auto_contrast {
-        // collect information
-        let mut hist = hdrhistogram::Histogram::<u32>::new(3).unwrap();
-        for pix in img.view() {
-            hist.record((pix.value * std::u32::MAX as f32) as u64)
-                .unwrap();
+    match sampling {
+        Sampling::Full => {
+            let img = par_index_map_raiso(ctx, "Demosaicing", &img.view(), |x, y, data| {
+                let val = Nearest::demosaic(data, &mapping, x, y);
+                let val = apply_wb(&val);
+                // NOTE: we used to clamp here, but it looks like we don't need it anymore because we're
+                // round-tripping through HSV?
+                convert_to_hsv(&val)
+            })?;
+            let (x_start, y_start) = (window.left - left, window.top - top);
+            let (width, height) = window.size();
+            Ok(img
+                .slice(s![x_start..x_start + width, y_start..y_start + height])
+                .to_owned())
         }
-
-        let val_at = |quant| {
-            println!(
-                "  {:4}%: {}",
-                (quant * 100.) as u32,
-                hist.value_at_quantile(quant) as f32 / std::u32::MAX as f32
+        Sampling::Binned(block) => {
+            let (width, height) = ((right - left) / block, (bottom - top) / block);
+            let img = par_index_map_raiso_sized(
+                ctx,
+                "Demosaicing",
+                &img.view(),
+                (width, height),
+                |x, y, data: &ArrayView2<_>| {
+                    let val = superpixel(data, &mapping, block, x, y);
+                    let val = apply_wb(&val);
+                    convert_to_hsv(&val)
+                },
+            )?;
+            let (x_start, y_start) = ((window.left - left) / block, (window.top - top) / block);
+            let (width, height) = window.size();
+            let (width, height) = (
+                (width / block).min(img.nrows().saturating_sub(x_start)),
+                (height / block).min(img.ncols().saturating_sub(y_start)),
             );
-        };
-        val_at(0.);
-        val_at(0.01);
-        val_at(0.05);
-        val_at(0.5);
-        val_at(0.95);
-        val_at(0.99);
-        val_at(1.);
-
-        let s_min = hist.value_at_quantile(0.05) as f32 / std::u32::MAX as f32;
-        let s_max = hist.value_at_quantile(0.95) as f32 / std::u32::MAX as f32;
-
-        // apply auto-contrast-stretching
-        // TODO: I think this is wrong and doesn't account for the log/lin boundary sufficiently.
-        // It depends on what the Hsv type is doing.
-        par_index_map_siso(&img.view(), |_x, _y, mut val: Hsv<_>| {
-            val.value = (val.value - s_min) / (s_max - s_min);
-            val
-        })
+            Ok(img
+                .slice(s![x_start..x_start + width, y_start..y_start + height])
+                .to_owned())
+        }
+    }
+}
+
+/// Runs the steps after `develop` and converts to RGB. `levels` are the ones `resolve_levels`
+/// gave for `settings`. `to_pixel` does the final conversion.
+fn finish<P: Copy + Send + Sync>(
+    ctx: &RenderContext,
+    img: Array2<Hsv>,
+    levels: Levels,
+    settings: &RenderSettings,
+    to_pixel: impl Fn(&Hsv) -> P + Sync,
+) -> Result<Array2<P>, Cancelled> {
+    let img = if levels.is_identity() {
+        img
     } else {
+        debug!("Levels: {:?}", levels);
+        par_index_map_siso(ctx, "Levels", &img.view(), |_x, _y, mut val: Hsv<_>| {
+            val.value = levels.apply(val.value);
+            val
+        })?
+    };
+
+    let img = if settings.tone_curve.is_identity() {
         img
+    } else {
+        par_index_map_siso(ctx, "Tone curve", &img.view(), |_x, _y, val: Hsv<_>| {
+            let rgb: LinSrgb = val.into();
+            let [red, green, blue] = settings.tone_curve.apply([rgb.red, rgb.green, rgb.blue]);
+            Hsv::from(LinSrgb::new(red, green, blue))
+        })?
     };
 
-    let img = par_index_map_siso(&img.view(), |_x, _y, mut val: Hsv<_>| {
+    par_index_map_siso(ctx, "Colour", &img.view(), |_x, _y, mut val: Hsv<_>| {
         val.saturation += settings.saturation_boost;
         val.saturation = val.saturation.max(0.).min(1.);
-        to_rgb(&val)
-    });
+        to_pixel(&val)
+    })
+}
 
-    // Last step: crop and convert.
-    let (output_width, output_height) = ri.crop_rect.size();
-    println!("Cropped to {}x{} pixels", output_width, output_height);
-    let buf = ImageBuffer::from_fn(output_width as u32, output_height as u32, |x, y| {
-        img[(
-            ri.crop_rect.left + x as usize,
-            ri.crop_rect.top + y as usize,
-        )]
-    });
+/// Applies the user's crop, then rotates/flips the image according to `settings`.
+pub(crate) fn crop_and_orient<P: image::Pixel + 'static>(
+    img: ImageBuffer<P, Vec<P::Subpixel>>,
+    settings: &RenderSettings,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let mut img = img;
+    if let Some(crop) = settings.crop {
+        let (left, top, width, height) = crop.to_pixels(img.width(), img.height());
+        img = imageops::crop(&mut img, left, top, width, height).to_image();
+    }
+    match settings.orientation {
+        Orientation::Normal => img,
+        Orientation::FlipHorizontal => imageops::flip_horizontal(&img),
+        Orientation::Rotate180 => imageops::rotate180(&img),
+        Orientation::FlipVertical => imageops::flip_vertical(&img),
+        Orientation::Transpose => imageops::flip_horizontal(&imageops::rotate90(&img)),
+        Orientation::Rotate90 => imageops::rotate90(&img),
+        Orientation::Transverse => imageops::flip_vertical(&imageops::rotate90(&img)),
+        Orientation::Rotate270 => imageops::rotate270(&img),
+    }
+}
 
-    println!("Done rendering");
-    buf
+/// Shrinks `img` to fit within `max_width` x `max_height`, preserving its aspect ratio. Images
+/// which already fit are returned unchanged.
+pub fn fit_within<P: image::Pixel + 'static>(
+    img: ImageBuffer<P, Vec<P::Subpixel>>,
+    max_width: u32,
+    max_height: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (width, height) = img.dimensions();
+    if width <= max_width && height <= max_height {
+        return img;
+    }
+    let _timer = StageTimer::new("Resize");
+    let ratio = (max_width as f32 / width as f32).min(max_height as f32 / height as f32);
+    let new_width = ((width as f32 * ratio).round() as u32).max(1);
+    let new_height = ((height as f32 * ratio).round() as u32).max(1);
+    imageops::resize(&img, new_width, new_height, FilterType::Triangle)
 }
 
 trait Sized {
@@ -165,11 +516,18 @@ impl<'a, T> Sized for ArrayViewMut2<'a, T> {
     }
 }
 
-fn make_devignetter(raf: &ParsedRafFile) -> impl SingleInputSingleOutput<u16, u16> {
-    let devignette = vignette_correction::from_fuji_tags(raf.vignette_attenuation());
+fn make_devignetter(raf: &dyn RawImage) -> impl SingleInputSingleOutput<u16, u16> {
+    // Only RAFs carry Fuji's vignetting curve; other files pass through unchanged.
+    let devignette = raf
+        .vignette_attenuation()
+        .map(vignette_correction::from_fuji_tags);
     let w = raf.render_info().width as i32;
     let h = raf.render_info().height as i32;
     let dvg = move |x: usize, y: usize, val: u16| {
+        let devignette = match &devignette {
+            Some(devignette) => devignette,
+            None => return val,
+        };
         let x = x as i32;
         let y = y as i32;
         let x = (x - (w / 2)) as f32;
@@ -186,7 +544,7 @@ fn make_devignetter(raf: &ParsedRafFile) -> impl SingleInputSingleOutput<u16, u1
 ///  this in order to make the image clip intentionally, otherwise we get things
 ///  that look pink.
 fn make_normalized_wb_coefs(coefs: [f32; 3]) -> [f32; 3] {
-    println!("coefs {:?}", coefs);
+    debug!("White balance coefficients: {:?}", coefs);
     let minval = coefs
         .iter()
         .cloned()
@@ -197,3 +555,107 @@ fn make_normalized_wb_coefs(coefs: [f32; 3]) -> [f32; 3] {
         .into_inner();
     [coefs[0] / minval, coefs[1] / minval, coefs[2] / minval]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libraw::raw_image::TestSensor;
+
+    const WIDTH: usize = 48;
+    const HEIGHT: usize = 36;
+
+    /// A 48x36 X-Trans sensor with a different value at nearly every photosite, so that any
+    /// misalignment shows. (Full-resolution demosaicing only handles X-Trans.)
+    fn sensor() -> TestSensor {
+        let mut sensor = TestSensor::xtrans(WIDTH, HEIGHT, 14);
+        sensor.data = (0..WIDTH * HEIGHT)
+            .map(|i| (1000 + (i % WIDTH) * 150 + (i / WIDTH) * 90 + (i % 7) * 40) as u16)
+            .collect();
+        sensor
+    }
+
+    fn rect(left: usize, top: usize, width: usize, height: usize) -> CropRect {
+        CropRect {
+            left,
+            right: left + width,
+            top,
+            bottom: top + height,
+        }
+    }
+
+    #[test]
+    fn preview_fits_within_max_dimension() {
+        let sensor = sensor();
+        let settings = RenderSettings::default();
+
+        assert_eq!(render_preview(&sensor, &settings, 12).dimensions(), (12, 9));
+        // Blocks can't be smaller than a superpixel, so this is as big as previews get.
+        assert_eq!(
+            render_preview(&sensor, &settings, 36).dimensions(),
+            (16, 12)
+        );
+        assert_eq!(render_preview(&sensor, &settings, 1).dimensions(), (1, 1));
+        assert_eq!(render_preview(&sensor, &settings, 0).dimensions(), (1, 1));
+    }
+
+    #[test]
+    fn region_matches_the_full_render() {
+        let mut sensor = sensor();
+        sensor.crop_rect = rect(2, 1, 44, 34);
+        let settings = RenderSettings::default();
+        let full = render_raw_with_settings(&sensor, &settings);
+        assert_eq!(full.dimensions(), (44, 34));
+
+        // Starting off the colour filter pattern's boundaries, relative to both crop and sensor.
+        let region = render_region(&sensor, &settings, rect(5, 3, 20, 14), 1.0);
+        let expected = imageops::crop_imm(&full, 5, 3, 20, 14).to_image();
+        assert_eq!(region, expected);
+
+        // Scales above 1 don't upscale.
+        let region = render_region(&sensor, &settings, rect(5, 3, 20, 14), 4.0);
+        assert_eq!(region, expected);
+    }
+
+    #[test]
+    fn scaled_regions() {
+        let sensor = sensor();
+        let settings = RenderSettings::default();
+
+        // Binned, in blocks of 3.
+        let region = render_region(&sensor, &settings, rect(4, 2, 18, 12), 1.0 / 3.0);
+        assert_eq!(region.dimensions(), (6, 4));
+        // Demosaiced, then shrunk.
+        let region = render_region(&sensor, &settings, rect(4, 2, 18, 12), 0.5);
+        assert_eq!(region.dimensions(), (9, 6));
+        // Clipped to the crop.
+        let region = render_region(&sensor, &settings, rect(36, 30, 20, 20), 1.0 / 3.0);
+        assert_eq!(region.dimensions(), (4, 2));
+    }
+
+    #[test]
+    fn degenerate_regions() {
+        let sensor = sensor();
+        let settings = RenderSettings::default();
+        let region = rect(4, 2, 18, 12);
+
+        for &scale in &[0.0, -1.0, f32::NAN, 0.001] {
+            let rendered = render_region(&sensor, &settings, region, scale);
+            assert_eq!(rendered.dimensions(), (1, 1), "scale {}", scale);
+        }
+        let tiny = render_region(&sensor, &settings, rect(7, 7, 1, 1), 0.5);
+        assert_eq!(tiny.dimensions(), (1, 1));
+
+        let empty = render_region(&sensor, &settings, rect(4, 2, 0, 12), 1.0);
+        assert_eq!(empty.dimensions(), (0, 0));
+        let outside = render_region(&sensor, &settings, rect(100, 100, 10, 10), 1.0);
+        assert_eq!(outside.dimensions(), (0, 0));
+        let backwards = CropRect {
+            left: 10,
+            right: 5,
+            top: 0,
+            bottom: 10,
+        };
+        let backwards = render_region(&sensor, &settings, backwards, 1.0);
+        assert_eq!(backwards.dimensions(), (0, 0));
+    }
+}
@@ -1,84 +1,387 @@
-use itertools::Itertools;
-use splines;
-use splines::{Interpolation, Key, Spline};
-use std::iter::{once, repeat};
+use palette::encoding::{Srgb, TransferFn};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
-pub struct ToneCurve {
-    pub spline: Spline<f32, f32>,
+pub use crate::white_balance::WhiteBalance;
+
+/// A control point of a `Curve`, with both coordinates from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    pub x: f32,
+    pub y: f32,
 }
 
-impl ToneCurve {
-    pub fn new(points: &[f32]) -> Self {
-        // SPLINE TIME
-        // https://en.wikipedia.org/wiki/Cubic_Hermite_spline#Catmull%E2%80%93Rom_spline
-        // TODO: figure out what's going on with the edges, they're unhappy here.
-        // Start and end at 1 because this is multiplicative.
-        let x_inc = 1.0 / points.len() as f32;
-        let start = Key::new(0., 1., Interpolation::Cosine);
-        let end = Key::new(1., 1., Interpolation::default());
+impl CurvePoint {
+    pub fn new(x: f32, y: f32) -> Self {
+        CurvePoint { x, y }
+    }
+}
 
-        // for 4 points, this should be 12.5, 37.5, 62.5, 87.5, i.e. split into 4 even bands
-        let xs = (0..points.len()).map(|x| (x as f32 + 0.5) * x_inc);
+/// A curve mapping 0-1 to 0-1, drawn smoothly through its control points. Between points it's a
+/// monotone cubic (Fritsch-Carlson), so it never overshoots: where the points only rise, so does
+/// the curve. Before the first point and after the last it's flat.
+///
+/// Only the control points are serialized; the tangents are recomputed when deserializing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Vec<CurvePoint>", into = "Vec<CurvePoint>")]
+pub struct Curve {
+    points: Vec<CurvePoint>,
+    /// The slope of the curve at each point.
+    tangents: Vec<f32>,
+}
 
-        let iter = once(start)
-            .chain(
-                xs.zip_eq(points.iter().copied())
-                    .map(|(x, y)| Key::new(x, y, Interpolation::CatmullRom)),
-            )
-            .chain(repeat(end).take(2));
-        let spline = Spline::from_iter(iter);
+impl Curve {
+    /// Makes a curve through `points`, which are clamped to 0-1 and sorted. Where several share an
+    /// x, only the last is kept. No points gives the identity curve.
+    pub fn new(points: &[CurvePoint]) -> Self {
+        let mut points: Vec<_> = points
+            .iter()
+            .filter(|p| p.x.is_finite() && p.y.is_finite())
+            .map(|p| CurvePoint::new(p.x.clamp(0.0, 1.0), p.y.clamp(0.0, 1.0)))
+            .collect();
+        if points.is_empty() {
+            return Curve::default();
+        }
+        // Stable, so that the last of several points at one x ends up last.
+        points.sort_by(|a, b| a.x.partial_cmp(&b.x).expect("points are finite"));
+        points.reverse();
+        points.dedup_by(|a, b| a.x == b.x);
+        points.reverse();
 
-        ToneCurve { spline }
+        let tangents = monotone_tangents(&points);
+        Curve { points, tangents }
+    }
+
+    /// The control points, in order of x.
+    pub fn points(&self) -> &[CurvePoint] {
+        &self.points
+    }
+
+    pub fn apply(&self, x: f32) -> f32 {
+        let points = &self.points;
+        let (first, last) = (points[0], points[points.len() - 1]);
+        if x.is_nan() || x <= first.x {
+            return first.y;
+        }
+        if x >= last.x {
+            return last.y;
+        }
+        // The segment from points[k] to points[k + 1] contains x.
+        let k = points.partition_point(|p| p.x <= x) - 1;
+        let (p0, p1) = (points[k], points[k + 1]);
+        let h = p1.x - p0.x;
+        let t = (x - p0.x) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        let y = (2.0 * t3 - 3.0 * t2 + 1.0) * p0.y
+            + (t3 - 2.0 * t2 + t) * h * self.tangents[k]
+            + (-2.0 * t3 + 3.0 * t2) * p1.y
+            + (t3 - t2) * h * self.tangents[k + 1];
+        y.clamp(0.0, 1.0)
     }
 
-    pub fn apply(&self, val: f32) -> f32 {
-        self.spline.sample(val).unwrap_or(f32::NAN)
+    /// Whether `apply` leaves every value unchanged, so can be skipped.
+    pub fn is_identity(&self) -> bool {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        first.x == 0.0 && last.x == 1.0 && self.points.iter().all(|p| p.x == p.y)
     }
 }
 
-impl Default for ToneCurve {
+/// Fritsch-Carlson tangents for a monotone cubic through `points`, which are sorted with distinct
+/// x.
+fn monotone_tangents(points: &[CurvePoint]) -> Vec<f32> {
+    let n = points.len();
+    if n < 2 {
+        return vec![0.0; n];
+    }
+    let secants: Vec<f32> = points
+        .windows(2)
+        .map(|w| (w[1].y - w[0].y) / (w[1].x - w[0].x))
+        .collect();
+
+    let mut tangents = Vec::with_capacity(n);
+    tangents.push(secants[0]);
+    for w in secants.windows(2) {
+        // Flat at local extrema, so the curve doesn't overshoot them.
+        tangents.push(if w[0] * w[1] <= 0.0 {
+            0.0
+        } else {
+            (w[0] + w[1]) / 2.0
+        });
+    }
+    tangents.push(secants[n - 2]);
+
+    for (k, &secant) in secants.iter().enumerate() {
+        if secant == 0.0 {
+            tangents[k] = 0.0;
+            tangents[k + 1] = 0.0;
+            continue;
+        }
+        let a = tangents[k] / secant;
+        let b = tangents[k + 1] / secant;
+        let length = a.hypot(b);
+        if length > 3.0 {
+            let scale = 3.0 / length;
+            tangents[k] = scale * a * secant;
+            tangents[k + 1] = scale * b * secant;
+        }
+    }
+    tangents
+}
+
+impl Default for Curve {
     fn default() -> Self {
-        let spl = Spline::from_vec(vec![
-            // Flat curve
-            Key::new(0.0, 1.0, Interpolation::Linear),
-            Key::new(1.0, 1.0, Interpolation::Linear),
-        ]);
-        ToneCurve { spline: spl }
+        Curve::new(&[CurvePoint::new(0.0, 0.0), CurvePoint::new(1.0, 1.0)])
+    }
+}
+
+impl PartialEq for Curve {
+    fn eq(&self, other: &Self) -> bool {
+        self.points == other.points
     }
 }
 
-#[derive(Debug, Clone)]
+impl From<Vec<CurvePoint>> for Curve {
+    fn from(points: Vec<CurvePoint>) -> Self {
+        Curve::new(&points)
+    }
+}
+
+impl From<Curve> for Vec<CurvePoint> {
+    fn from(curve: Curve) -> Self {
+        curve.points
+    }
+}
+
+/// Curves over gamma-encoded sRGB, as shown on screen: one over luminance, which brightens or
+/// darkens colours without changing their hue, then one for each channel.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToneCurve {
+    pub luminance: Curve,
+    pub red: Curve,
+    pub green: Curve,
+    pub blue: Curve,
+}
+
+impl ToneCurve {
+    /// Applies the curves to a linear sRGB colour, returning a linear sRGB colour.
+    pub fn apply(&self, mut rgb: [f32; 3]) -> [f32; 3] {
+        if !self.luminance.is_identity() {
+            let luminance = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+            let target = Srgb::into_linear(self.luminance.apply(Srgb::from_linear(luminance)));
+            if luminance > 0.0 {
+                let scale = target / luminance;
+                rgb.iter_mut().for_each(|channel| *channel *= scale);
+            } else {
+                // Black has no hue to keep, so it's lifted to grey.
+                rgb = [target; 3];
+            }
+        }
+        for (channel, curve) in rgb.iter_mut().zip(&[&self.red, &self.green, &self.blue]) {
+            if !curve.is_identity() {
+                *channel = Srgb::into_linear(curve.apply(Srgb::from_linear(*channel)));
+            }
+        }
+        rgb
+    }
+
+    /// Whether `apply` leaves every colour unchanged, so can be skipped.
+    pub fn is_identity(&self) -> bool {
+        self.luminance.is_identity()
+            && self.red.is_identity()
+            && self.green.is_identity()
+            && self.blue.is_identity()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RenderSettings {
     pub tone_curve: ToneCurve,
     pub exposure_basis: f32,
+    /// Sets the input black and white points of `levels` from the image, instead of using the ones
+    /// given. `analysis::resolve_levels` shows what they come out as.
     pub auto_contrast: bool,
+    pub levels: Levels,
     pub saturation_boost: f32,
     pub lens_corrections: LensCorrections,
+    pub white_balance: WhiteBalance,
+    /// Applied after the camera's own crop. `None` keeps the whole image.
+    pub crop: Option<Crop>,
+    pub orientation: Orientation,
+    pub annotations: Annotations,
+}
+
+/// The brightness scale that `Levels` work in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LevelsSpace {
+    /// Linear light, as the sensor records it. Moving the black point here barely touches the
+    /// midtones.
+    Linear,
+    /// Gamma-encoded sRGB, as shown on screen, like the levels in most other editors.
+    Display,
+}
+
+/// Remaps brightness, like the levels tool in other editors: `input_black` and below become
+/// `output_black`, `input_white` becomes `output_white`, and `gamma` bends the values in between.
+///
+/// Brightness is the HSV value (the brightest channel) after exposure, measured in `space`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Levels {
+    pub input_black: f32,
+    pub input_white: f32,
+    /// Above 1 brightens the midtones, and below 1 darkens them.
+    pub gamma: f32,
+    pub output_black: f32,
+    pub output_white: f32,
+    pub space: LevelsSpace,
 }
 
-#[derive(Debug, Clone)]
+/// The narrowest gap `Levels::apply` allows between the input black and white points.
+const MIN_INPUT_RANGE: f32 = 1e-4;
+/// The lowest gamma `Levels::apply` allows.
+const MIN_GAMMA: f32 = 0.01;
+
+impl Levels {
+    /// Remaps a linear brightness, returning a linear brightness. A white point at or below the
+    /// black point, or a gamma of 0 or less, would give infinities, so they're treated as the
+    /// nearest settings that don't.
+    pub fn apply(&self, value: f32) -> f32 {
+        let value = match self.space {
+            LevelsSpace::Linear => value,
+            LevelsSpace::Display => Srgb::from_linear(value),
+        };
+        let range = (self.input_white - self.input_black).max(MIN_INPUT_RANGE);
+        let gamma = self.gamma.max(MIN_GAMMA);
+        let t = ((value - self.input_black) / range).max(0.0);
+        let out = self.output_black + t.powf(1.0 / gamma) * (self.output_white - self.output_black);
+        match self.space {
+            LevelsSpace::Linear => out,
+            LevelsSpace::Display => Srgb::into_linear(out),
+        }
+    }
+
+    /// Whether `apply` leaves every value unchanged, so can be skipped.
+    pub fn is_identity(&self) -> bool {
+        let defaults = Levels::default();
+        self.input_black == defaults.input_black
+            && self.input_white == defaults.input_white
+            && self.gamma == defaults.gamma
+            && self.output_black == defaults.output_black
+            && self.output_white == defaults.output_white
+    }
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Levels {
+            input_black: 0.0,
+            input_white: 1.0,
+            gamma: 1.0,
+            output_black: 0.0,
+            output_white: 1.0,
+            space: LevelsSpace::Linear,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct LensCorrections {
     pub vignette: bool,
 }
 
+/// A crop rectangle, with each edge given as a fraction of the image's width or height (so the
+/// whole image is 0, 0, 1, 1). Edges are in the image's coordinates before it's rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Crop {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+impl Crop {
+    /// Returns the pixel rectangle this covers in an image of the given size, as
+    /// `(left, top, width, height)`. Always at least one pixel, unless the image is empty.
+    pub fn to_pixels(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let scale = |frac: f32, size: u32| (frac.clamp(0., 1.) * size as f32).round() as u32;
+        let left = scale(self.left, width).min(width.saturating_sub(1));
+        let top = scale(self.top, height).min(height.saturating_sub(1));
+        let right = scale(self.right, width).max(left + 1).min(width);
+        let bottom = scale(self.bottom, height).max(top + 1).min(height);
+        (left, top, right - left, bottom - top)
+    }
+}
+
+/// How the image should be rotated or flipped for display, named after the EXIF orientations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Orientation {
+    #[default]
+    Normal,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    /// Converts from the EXIF/TIFF orientation tag value, 1-8.
+    pub fn from_exif(value: u16) -> Option<Self> {
+        use Orientation::*;
+        Some(match value {
+            1 => Normal,
+            2 => FlipHorizontal,
+            3 => Rotate180,
+            4 => FlipVertical,
+            5 => Transpose,
+            6 => Rotate90,
+            7 => Transverse,
+            8 => Rotate270,
+            _ => return None,
+        })
+    }
+
+    pub fn to_exif(self) -> u16 {
+        self as u16 + 1
+    }
+}
+
+/// Culling information which doesn't affect the render, but which travels with the edits.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Annotations {
+    /// 0 to 5 stars, or -1 for rejected.
+    pub rating: i8,
+    /// A colour label, e.g. "Red".
+    pub label: Option<String>,
+    pub keywords: Vec<String>,
+}
+
 impl Default for RenderSettings {
     fn default() -> Self {
         RenderSettings {
             tone_curve: ToneCurve::default(),
             exposure_basis: 1.0,
             auto_contrast: false,
+            levels: Levels::default(),
             saturation_boost: 0.,
-            lens_corrections: LensCorrections { vignette: false },
+            lens_corrections: LensCorrections::default(),
+            white_balance: WhiteBalance::default(),
+            crop: None,
+            orientation: Orientation::default(),
+            annotations: Annotations::default(),
         }
     }
 }
 
 impl RenderSettings {
-    /// These are default settings that result in automatic "nice" adjustments.
-    /// Long term, it would be better to not have 'auto' settings, but to have
-    /// an analysis step and a settings apply step, and the analysis step could
-    /// make settings that are nice for the image.
+    /// Settings which make automatic "nice" adjustments to any image, using auto-contrast.
+    /// For settings suited to a particular image, use `analysis::analyze` and then
+    /// `Analysis::suggest_settings`.
     pub fn auto() -> Self {
         RenderSettings {
             tone_curve: ToneCurve::default(),
@@ -86,6 +389,164 @@ impl RenderSettings {
             auto_contrast: true,
             saturation_boost: 0.2,
             lens_corrections: LensCorrections { vignette: true },
+            ..RenderSettings::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let levels = Levels {
+            input_black: 0.25,
+            input_white: 0.75,
+            ..Levels::default()
+        };
+        assert_eq!(levels.apply(0.5), 0.5);
+        assert_eq!(levels.apply(0.125), 0.0);
+        assert!(Levels::default().is_identity());
+        assert!(!levels.is_identity());
+
+        let gamma = Levels {
+            gamma: 2.0,
+            output_black: 0.5,
+            ..Levels::default()
+        };
+        assert_eq!(gamma.apply(0.25), 0.75);
+
+        let display = Levels {
+            space: LevelsSpace::Display,
+            ..Levels::default()
+        };
+        assert!(display.is_identity());
+        assert!((display.apply(0.18) - 0.18).abs() < 1e-6);
+        // Input points are display values, so this doubles what would be shown on screen.
+        let display = Levels {
+            input_white: 0.5,
+            ..display
+        };
+        assert!((display.apply(Srgb::into_linear(0.25)) - Srgb::into_linear(0.5)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn crop_to_pixels() {
+        let crop = Crop {
+            left: 0.25,
+            top: -1.0,
+            right: 0.75,
+            bottom: 0.5,
+        };
+        assert_eq!(crop.to_pixels(100, 40), (25, 0, 50, 20));
+        // Collapsed crops still keep a pixel, except of empty images.
+        let collapsed = Crop {
+            left: 1.0,
+            top: 1.0,
+            right: 0.0,
+            bottom: 0.0,
+        };
+        assert_eq!(collapsed.to_pixels(100, 40), (99, 39, 1, 1));
+        assert_eq!(collapsed.to_pixels(0, 0), (0, 0, 0, 0));
+        assert_eq!(crop.to_pixels(0, 40), (0, 0, 0, 20));
+    }
+
+    #[test]
+    fn degenerate_levels_stay_finite() {
+        let levels = [
+            Levels {
+                input_black: 0.5,
+                input_white: 0.5,
+                ..Levels::default()
+            },
+            Levels {
+                input_black: 0.75,
+                input_white: 0.25,
+                ..Levels::default()
+            },
+            Levels {
+                gamma: 0.0,
+                ..Levels::default()
+            },
+            Levels {
+                gamma: -1.0,
+                space: LevelsSpace::Display,
+                ..Levels::default()
+            },
+        ];
+        for levels in &levels {
+            for &value in &[0.0, 0.25, 0.5, 0.75, 1.0] {
+                let out = levels.apply(value);
+                assert!(out.is_finite(), "{:?} gave {} for {}", levels, out, value);
+            }
+        }
+        // Equal points become a threshold.
+        assert_eq!(levels[0].apply(0.25), 0.0);
+        assert!(levels[0].apply(0.75) > 1.0);
+    }
+
+    fn points(points: &[(f32, f32)]) -> Vec<CurvePoint> {
+        points.iter().map(|&(x, y)| CurvePoint::new(x, y)).collect()
+    }
+
+    #[test]
+    fn curves_pass_through_their_points() {
+        let curve = Curve::new(&points(&[(0.75, 0.9), (0.0, 0.1), (0.25, 0.2), (1.5, 1.0)]));
+        assert_eq!(
+            curve.points(),
+            &points(&[(0.0, 0.1), (0.25, 0.2), (0.75, 0.9), (1.0, 1.0)])[..]
+        );
+        for p in curve.points() {
+            assert!((curve.apply(p.x) - p.y).abs() < 1e-6);
+        }
+        assert_eq!(curve.apply(-1.0), 0.1);
+        assert_eq!(curve.apply(2.0), 1.0);
+        assert!(!curve.is_identity());
+
+        // The last of several points at the same x wins.
+        let curve = Curve::new(&points(&[(0.5, 0.2), (0.5, 0.4)]));
+        assert_eq!(curve.points(), &points(&[(0.5, 0.4)])[..]);
+        assert_eq!(curve.apply(0.0), 0.4);
+
+        assert!(Curve::new(&[]).is_identity());
+        assert!(Curve::new(&points(&[(0.0, 0.0), (0.5, 0.5), (1.0, 1.0)])).is_identity());
+        assert_eq!(Curve::default().apply(0.25), 0.25);
+    }
+
+    #[test]
+    fn curves_are_monotone() {
+        // A steep step, which a Catmull-Rom spline would overshoot on either side.
+        let curve = Curve::new(&points(&[(0.0, 0.0), (0.4, 0.1), (0.5, 0.9), (1.0, 1.0)]));
+        let samples: Vec<_> = (0..=100).map(|i| curve.apply(i as f32 / 100.0)).collect();
+        for w in samples.windows(2) {
+            assert!(w[1] >= w[0], "{:?}", samples);
         }
+
+        // Flat sections stay flat.
+        let curve = Curve::new(&points(&[(0.0, 0.0), (0.3, 0.5), (0.7, 0.5), (1.0, 1.0)]));
+        assert_eq!(curve.apply(0.5), 0.5);
+    }
+
+    #[test]
+    fn tone_curves_keep_hue() {
+        let brighten = ToneCurve {
+            luminance: Curve::new(&points(&[(0.0, 0.0), (0.5, 0.75), (1.0, 1.0)])),
+            ..ToneCurve::default()
+        };
+        let [r, g, b] = brighten.apply([0.2, 0.1, 0.05]);
+        assert!(r > 0.2);
+        assert!((r / g - 2.0).abs() < 1e-5 && (g / b - 2.0).abs() < 1e-5);
+        assert_eq!(
+            ToneCurve::default().apply([0.2, 0.1, 0.05]),
+            [0.2, 0.1, 0.05]
+        );
+        assert!(ToneCurve::default().is_identity());
+
+        let no_blue = ToneCurve {
+            blue: Curve::new(&points(&[(0.0, 0.0), (1.0, 0.0)])),
+            ..ToneCurve::default()
+        };
+        assert_eq!(no_blue.apply([0.2, 0.1, 0.05]), [0.2, 0.1, 0.0]);
     }
 }
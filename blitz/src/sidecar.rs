@@ -0,0 +1,217 @@
+//! Stores edits next to the raw file, as `IMG.RAF.blitz.json`, so that they survive between
+//! sessions.
+//!
+//! Sidecars carry a schema version. When the settings change shape, bump `CURRENT_VERSION` and
+//! add a step to `migrate` which upgrades documents written by the previous version.
+
+use crate::render_settings::{Curve, CurvePoint, RenderSettings, ToneCurve};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The schema version written by this build.
+pub const CURRENT_VERSION: u32 = 2;
+
+const EXTENSION: &str = ".blitz.json";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SidecarError {
+        Io(err: io::Error) {
+            from()
+            display("I/O error: {}", err)
+        }
+        Json(err: serde_json::Error) {
+            from()
+            display("Invalid sidecar: {}", err)
+        }
+        UnsupportedVersion(version: u64) {
+            display("Sidecar version {} is newer than this build supports ({})", version, CURRENT_VERSION)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    version: u32,
+    settings: RenderSettings,
+}
+
+/// Returns where the sidecar for `raw_path` lives, e.g. `IMG.RAF` -> `IMG.RAF.blitz.json`.
+pub fn sidecar_path(raw_path: &Path) -> PathBuf {
+    let mut name = OsString::from(raw_path.as_os_str());
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Loads the settings saved next to `raw_path`, or `None` if there aren't any.
+pub fn load_sidecar(raw_path: &Path) -> Result<Option<RenderSettings>, SidecarError> {
+    let path = sidecar_path(raw_path);
+    let json = match fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    debug!("Loading sidecar {}", path.display());
+    from_json(&json).map(Some)
+}
+
+/// Saves `settings` next to `raw_path`, replacing any existing sidecar.
+pub fn save_sidecar(raw_path: &Path, settings: &RenderSettings) -> Result<(), SidecarError> {
+    let path = sidecar_path(raw_path);
+    debug!("Saving sidecar {}", path.display());
+    // Write to a temporary file first, so that a crash halfway through doesn't lose the old edits.
+    let mut tmp_name = OsString::from(path.as_os_str());
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    fs::write(&tmp_path, to_json(settings)?)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Serializes `settings` as a sidecar document at the current version.
+pub fn to_json(settings: &RenderSettings) -> Result<String, SidecarError> {
+    let sidecar = Sidecar {
+        version: CURRENT_VERSION,
+        settings: settings.clone(),
+    };
+    Ok(serde_json::to_string_pretty(&sidecar)?)
+}
+
+/// Parses a sidecar document, upgrading it from older versions if necessary.
+pub fn from_json(json: &str) -> Result<RenderSettings, SidecarError> {
+    let doc = migrate(serde_json::from_str(json)?)?;
+    let sidecar: Sidecar = serde_json::from_value(doc)?;
+    Ok(sidecar.settings)
+}
+
+/// Upgrades `doc` one version at a time until it's at `CURRENT_VERSION`.
+fn migrate(mut doc: Value) -> Result<Value, SidecarError> {
+    let version = doc.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > CURRENT_VERSION as u64 {
+        return Err(SidecarError::UnsupportedVersion(version));
+    }
+    if version < 2 {
+        doc = v1_to_v2(doc)?;
+    }
+    Ok(doc)
+}
+
+/// Version 1 tone curves were brightness multipliers, spread evenly across 0 to 1. Version 2 has
+/// control points for luminance and each channel, so each multiplier becomes a luminance point.
+fn v1_to_v2(mut doc: Value) -> Result<Value, SidecarError> {
+    let settings = match doc.get_mut("settings").and_then(Value::as_object_mut) {
+        Some(settings) => settings,
+        None => return Ok(doc),
+    };
+    let factors: Vec<f32> = match settings.get("tone_curve").and_then(Value::as_array) {
+        Some(factors) => factors
+            .iter()
+            .filter_map(|factor| factor.as_f64().map(|factor| factor as f32))
+            .collect(),
+        None => return Ok(doc),
+    };
+
+    let step = 1.0 / factors.len() as f32;
+    let points: Vec<_> = factors
+        .iter()
+        .enumerate()
+        .map(|(i, factor)| {
+            let x = (i as f32 + 0.5) * step;
+            CurvePoint::new(x, x * factor)
+        })
+        .collect();
+    let luminance = if points.is_empty() {
+        Curve::default()
+    } else {
+        let ends = [CurvePoint::new(0.0, 0.0), CurvePoint::new(1.0, 1.0)];
+        Curve::new(&[&ends[..], &points[..]].concat())
+    };
+    let tone_curve = ToneCurve {
+        luminance,
+        ..ToneCurve::default()
+    };
+    settings.insert("tone_curve".to_string(), serde_json::to_value(tone_curve)?);
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut settings = RenderSettings::auto();
+        settings.tone_curve.luminance =
+            Curve::new(&[CurvePoint::new(0.0, 0.1), CurvePoint::new(0.5, 0.6)]);
+        settings.tone_curve.blue = Curve::new(&[CurvePoint::new(1.0, 0.9)]);
+        settings.exposure_basis = 1.4;
+
+        let json = to_json(&settings).unwrap();
+        assert_eq!(from_json(&json).unwrap(), settings);
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let settings = from_json(r#"{"version": 1, "settings": {"auto_contrast": true}}"#).unwrap();
+        assert_eq!(
+            settings,
+            RenderSettings {
+                auto_contrast: true,
+                ..RenderSettings::default()
+            }
+        );
+    }
+
+    #[test]
+    fn version_1_tone_curves_become_luminance_points() {
+        let settings =
+            from_json(r#"{"version": 1, "settings": {"tone_curve": [2.0, 1.0]}}"#).unwrap();
+        let points = settings.tone_curve.luminance.points();
+        assert_eq!(
+            points,
+            &[
+                CurvePoint::new(0.0, 0.0),
+                CurvePoint::new(0.25, 0.5),
+                CurvePoint::new(0.75, 0.75),
+                CurvePoint::new(1.0, 1.0),
+            ]
+        );
+        assert!(settings.tone_curve.red.is_identity());
+
+        let settings = from_json(r#"{"version": 1, "settings": {"tone_curve": []}}"#).unwrap();
+        assert!(settings.tone_curve.is_identity());
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let result = from_json(r#"{"version": 99, "settings": {}}"#);
+        assert!(matches!(result, Err(SidecarError::UnsupportedVersion(99))));
+    }
+
+    #[test]
+    fn sidecar_path_appends_extension() {
+        assert_eq!(
+            sidecar_path(Path::new("/photos/DSCF2279.RAF")),
+            PathBuf::from("/photos/DSCF2279.RAF.blitz.json")
+        );
+    }
+
+    #[test]
+    fn save_then_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw_path = dir.path().join("DSCF2279.RAF");
+        let settings = RenderSettings::auto();
+
+        save_sidecar(&raw_path, &settings).unwrap();
+        let loaded = load_sidecar(&raw_path).unwrap();
+        fs::remove_file(sidecar_path(&raw_path)).unwrap();
+
+        assert_eq!(loaded, Some(settings));
+        assert_eq!(load_sidecar(&raw_path).unwrap(), None);
+    }
+}
@@ -1,6 +1,9 @@
+use crate::context::{Cancelled, ProgressReporter, RenderContext};
 use itertools::Itertools;
+use libraw::util::timing::StageTimer;
 use ndarray::{Array2, ArrayView2, Axis};
 use rayon::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub trait SingleInputSingleOutput<In, Out = In>: Fn(usize, usize, In) -> Out + Send + Sync {}
 impl<T, In, Out> SingleInputSingleOutput<In, Out> for T where
@@ -17,63 +20,118 @@ impl<T, In, Out> RandomAccessInputSingleOutput<In, Out> for T where
 {
 }
 
+/// Tracks how many chunks of a stage are done, and reports that to the `RenderContext`.
+struct ChunkProgress<'a> {
+    ctx: &'a RenderContext,
+    stage: &'a str,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl<'a> ChunkProgress<'a> {
+    fn new(ctx: &'a RenderContext, stage: &'a str, total: usize) -> Self {
+        ChunkProgress {
+            ctx,
+            stage,
+            total,
+            done: AtomicUsize::new(0),
+        }
+    }
+
+    fn chunk_done(&self) {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        self.ctx.report(self.stage, done as f32 / self.total as f32);
+    }
+}
+
+/// Runs `func` over every value of `data` in parallel, checking for cancellation between chunks.
 pub fn par_index_map_siso<In: Sync + Copy, Out: Copy + Sync + Send>(
+    ctx: &RenderContext,
+    stage: &str,
     data: &ArrayView2<In>,
     func: impl SingleInputSingleOutput<In, Out>,
-) -> Array2<Out> {
+) -> Result<Array2<Out>, Cancelled> {
+    let _timer = StageTimer::new(stage);
     // We'll initialize all values by zipping this with the other iterator.
     let mut out = unsafe { Array2::uninitialized(data.raw_dim()) };
 
     // TODO: make this a constant / controlled by context or something
     let chunks = 8 * 4;
-    let lines_per_chunk = data.len_of(Axis(0)) / chunks;
+    // Small images (e.g. previews) can have fewer lines than chunks.
+    let lines_per_chunk = (data.len_of(Axis(1)) / chunks).max(1);
 
     let input_chunks = data
         .axis_chunks_iter(Axis(1), lines_per_chunk)
         .collect_vec();
 
     let output_chunks = out.axis_chunks_iter_mut(Axis(1), lines_per_chunk);
+    let progress = ChunkProgress::new(ctx, stage, input_chunks.len());
 
     input_chunks
         .par_iter()
         .zip(output_chunks)
         .enumerate()
-        .for_each(|(chunk_idx, (input_chunk, mut output_chunk))| {
+        .try_for_each(|(chunk_idx, (input_chunk, mut output_chunk))| {
+            ctx.check()?;
             for (&input, ((x, y), out_ref)) in
                 input_chunk.iter().zip(output_chunk.indexed_iter_mut())
             {
                 *out_ref = func(x, y + chunk_idx * lines_per_chunk, input);
             }
-        });
+            progress.chunk_done();
+            Ok(())
+        })?;
 
-    out
+    Ok(out)
 }
 
+/// Runs `func` for every position in `data` in parallel, checking for cancellation between
+/// chunks.
 pub fn par_index_map_raiso<In: Sync + Copy, Out: Copy + Sync + Send>(
+    ctx: &RenderContext,
+    stage: &str,
     data: &ArrayView2<In>,
     func: impl RandomAccessInputSingleOutput<In, Out>,
-) -> Array2<Out> {
+) -> Result<Array2<Out>, Cancelled> {
+    par_index_map_raiso_sized(ctx, stage, data, data.dim(), func)
+}
+
+/// Like `par_index_map_raiso`, but the output has dimensions `out_dim` rather than the dimensions
+/// of the input. This is useful for steps that shrink the image, e.g. binning.
+pub fn par_index_map_raiso_sized<In: Sync + Copy, Out: Copy + Sync + Send>(
+    ctx: &RenderContext,
+    stage: &str,
+    data: &ArrayView2<In>,
+    out_dim: (usize, usize),
+    func: impl RandomAccessInputSingleOutput<In, Out>,
+) -> Result<Array2<Out>, Cancelled> {
+    let _timer = StageTimer::new(stage);
     // We'll initialize all values by zipping this with the other iterator.
-    let mut out = unsafe { Array2::uninitialized(data.raw_dim()) };
+    let mut out = unsafe { Array2::uninitialized(out_dim) };
 
     // TODO: make this a constant / controlled by context or something
     let chunks = 8 * 4;
-    let lines_per_chunk = data.len_of(Axis(0)) / chunks;
+    // Small images (e.g. previews) can have fewer lines than chunks.
+    let lines_per_chunk = (out.len_of(Axis(1)) / chunks).max(1);
 
     let mut output_chunks = out
         .axis_chunks_iter_mut(Axis(1), lines_per_chunk)
         .collect_vec();
+    let progress = ChunkProgress::new(ctx, stage, output_chunks.len());
 
     output_chunks
         .par_iter_mut()
         .enumerate()
-        .for_each(|(chunk_idx, output_chunk)| {
+        .try_for_each(|(chunk_idx, output_chunk)| {
+            ctx.check()?;
             for ((x, y), out_ref) in output_chunk.indexed_iter_mut() {
                 *out_ref = func(x, y + chunk_idx * lines_per_chunk, data);
             }
-        });
+            progress.chunk_done();
+            Ok(())
+        })?;
 
-    out
+    Ok(out)
 }
 
 // Ok, here's the thought process:
@@ -11,6 +11,10 @@ const OUTPUT_COEFS: usize = 5;
 pub struct VignetteCorrection([f32; OUTPUT_COEFS]);
 
 impl VignetteCorrection {
+    pub fn coefficients(&self) -> [f32; OUTPUT_COEFS] {
+        self.0
+    }
+
     pub fn apply_gain(&self, center_distance: f32, value: f32) -> f32 {
         self.compute_gain(center_distance) * value
     }
@@ -0,0 +1,103 @@
+use crate::camera_specific_junk::{cam_from_xyz, ColorspaceMatrix};
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+/// Colour temperatures outside this range (in Kelvin) are clamped, since the approximation of the
+/// Planckian locus we use isn't accurate outside it.
+const TEMPERATURE_RANGE: (f32, f32) = (2000.0, 15000.0);
+
+/// How much one unit of tint moves the white point away from the Planckian locus, in CIE 1960
+/// (u, v) units. This roughly matches the scale of the tint slider in other editors, where +150 is
+/// very magenta and -150 very green.
+const TINT_SCALE: f32 = 1.0 / 3000.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode")]
+pub enum WhiteBalance {
+    /// Use the coefficients the camera recorded.
+    #[default]
+    AsShot,
+    /// Neutralise light of the given colour temperature (in Kelvin), shifted towards magenta
+    /// (positive tint) or green (negative tint).
+    Custom { temperature: f32, tint: f32 },
+}
+
+/// Returns the CIE 1960 (u, v) coordinates of a black body at `temperature` Kelvin.
+///
+/// Uses Krystek's rational approximation of the Planckian locus.
+fn planckian_uv(temperature: f32) -> (f32, f32) {
+    let t = temperature as f64;
+    let u = (0.860_117_757 + 1.541_182_54e-4 * t + 1.286_412_12e-7 * t * t)
+        / (1.0 + 8.424_202_35e-4 * t + 7.081_451_63e-7 * t * t);
+    let v = (0.317_398_726 + 4.228_062_45e-5 * t + 4.204_816_91e-8 * t * t)
+        / (1.0 - 2.897_418_16e-5 * t + 1.614_560_53e-7 * t * t);
+    (u as f32, v as f32)
+}
+
+/// Returns the XYZ colour (with Y = 1) of light at `temperature` Kelvin with the given `tint`.
+pub fn white_point_xyz(temperature: f32, tint: f32) -> Vector3<f32> {
+    let temperature = temperature
+        .max(TEMPERATURE_RANGE.0)
+        .min(TEMPERATURE_RANGE.1);
+    let (u, v) = planckian_uv(temperature);
+
+    // Tint moves perpendicular to the locus. Positive tint is magenta, which is below the locus
+    // (smaller v).
+    let (u_next, v_next) = planckian_uv(temperature + 1.0);
+    let (du, dv) = (u_next - u, v_next - v);
+    let len = (du * du + dv * dv).sqrt();
+    let offset = -tint * TINT_SCALE;
+    let (u, v) = (u + offset * dv / len, v - offset * du / len);
+
+    // CIE 1960 UCS -> xy -> XYZ
+    let denominator = 2.0 * u - 8.0 * v + 4.0;
+    let (x, y) = (3.0 * u / denominator, 2.0 * v / denominator);
+    Vector3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// Returns the multipliers which make light at `temperature` Kelvin with the given `tint` neutral
+/// in camera space. Like the coefficients the camera records, they're only meaningful relative to
+/// each other.
+pub fn camera_multipliers(temperature: f32, tint: f32) -> [f32; 3] {
+    camera_multipliers_for(&cam_from_xyz(), temperature, tint)
+}
+
+/// Like `camera_multipliers`, for a camera whose response is described by `cam_from_xyz`.
+pub fn camera_multipliers_for(
+    cam_from_xyz: &ColorspaceMatrix,
+    temperature: f32,
+    tint: f32,
+) -> [f32; 3] {
+    let cam = cam_from_xyz * white_point_xyz(temperature, tint);
+    [1.0 / cam[0], 1.0 / cam[1], 1.0 / cam[2]]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chromaticity(xyz: Vector3<f32>) -> (f32, f32) {
+        let sum = xyz.sum();
+        (xyz[0] / sum, xyz[1] / sum)
+    }
+
+    #[test]
+    fn white_point_near_d65() {
+        let (x, y) = chromaticity(white_point_xyz(6504.0, 0.0));
+        assert!((x - 0.3127).abs() < 0.01, "x = {}", x);
+        assert!((y - 0.3290).abs() < 0.01, "y = {}", y);
+    }
+
+    #[test]
+    fn tint_moves_towards_magenta() {
+        let (_, y_neutral) = chromaticity(white_point_xyz(5000.0, 0.0));
+        let (_, y_magenta) = chromaticity(white_point_xyz(5000.0, 50.0));
+        assert!(y_magenta < y_neutral);
+    }
+
+    #[test]
+    fn warm_light_boosts_blue() {
+        let ratio = |m: [f32; 3]| m[2] / m[0];
+        assert!(ratio(camera_multipliers(3000.0, 0.0)) > ratio(camera_multipliers(6500.0, 0.0)));
+    }
+}
@@ -0,0 +1,747 @@
+//! Reads and writes XMP sidecars, so that edits and culling decisions survive moving files between
+//! blitz and other editors.
+//!
+//! Only the basics are mapped onto `RenderSettings`: rating, label, keywords, orientation, crop,
+//! white balance, exposure, saturation, and the point tone curves (luminance, red, green and blue)
+//! from the Camera Raw (`crs:`) namespace. Everything else in an existing sidecar is ignored when
+//! reading, and kept as it is when saving over it.
+
+use crate::render_settings::{
+    Crop, Curve, CurvePoint, Orientation, RenderSettings, ToneCurve, WhiteBalance,
+};
+use log::{debug, warn};
+use roxmltree::{Document, Node};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const XMP_NS: &str = "http://ns.adobe.com/xap/1.0/";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const TIFF_NS: &str = "http://ns.adobe.com/tiff/1.0/";
+const CRS_NS: &str = "http://ns.adobe.com/camera-raw-settings/1.0/";
+
+/// The `crs:` point curves: luminance, red, green, then blue. Each is a sequence of "x, y" points,
+/// from 0 to 255.
+const TONE_CURVES: [&str; 4] = [
+    "ToneCurvePV2012",
+    "ToneCurvePV2012Red",
+    "ToneCurvePV2012Green",
+    "ToneCurvePV2012Blue",
+];
+const TONE_CURVE_SCALE: f32 = 255.;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum XmpError {
+        Io(err: io::Error) {
+            from()
+            display("I/O error: {}", err)
+        }
+        Xml(err: roxmltree::Error) {
+            from()
+            display("Invalid XMP: {}", err)
+        }
+    }
+}
+
+/// Returns the places other editors keep sidecars for `raw_path`, in the order we look for them:
+/// `IMG.xmp` (Lightroom) and `IMG.RAF.xmp` (darktable).
+pub fn xmp_paths(raw_path: &Path) -> [PathBuf; 2] {
+    let mut appended = raw_path.as_os_str().to_owned();
+    appended.push(".xmp");
+    [raw_path.with_extension("xmp"), PathBuf::from(appended)]
+}
+
+/// Applies the XMP sidecar next to `raw_path`, if there is one, on top of `settings`. Returns
+/// whether a sidecar was found.
+pub fn load_xmp(raw_path: &Path, settings: &mut RenderSettings) -> Result<bool, XmpError> {
+    for path in xmp_paths(raw_path).iter() {
+        let xml = match fs::read_to_string(path) {
+            Ok(xml) => xml,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        debug!("Loading XMP sidecar {}", path.display());
+        apply_xmp(&xml, settings)?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Writes `settings` to an XMP sidecar next to `raw_path`, and returns where it was written. If
+/// there's already a sidecar, only the properties we write are replaced in it. A sidecar which
+/// can't be parsed is left alone, and an error returned, rather than losing another editor's work.
+pub fn save_xmp(raw_path: &Path, settings: &RenderSettings) -> Result<PathBuf, XmpError> {
+    let [adobe, darktable] = xmp_paths(raw_path);
+    let path = if !adobe.exists() && darktable.exists() {
+        darktable
+    } else {
+        adobe
+    };
+    debug!("Saving XMP sidecar {}", path.display());
+    let xml = match fs::read_to_string(&path) {
+        Ok(existing) => merge_xmp(&existing, settings)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => to_xmp(settings),
+        Err(e) => return Err(e.into()),
+    };
+    fs::write(&path, xml)?;
+    Ok(path)
+}
+
+/// Updates `settings` with whichever of the fields we understand are present in `xml`.
+pub fn apply_xmp(xml: &str, settings: &mut RenderSettings) -> Result<(), XmpError> {
+    let doc = Document::parse(xml)?;
+    for desc in descriptions(&doc) {
+        apply_description(desc, settings);
+    }
+    Ok(())
+}
+
+/// The descriptions of the resource itself. Descriptions nested in properties, like the settings
+/// inside a Camera Raw profile, aren't included.
+fn descriptions<'a>(doc: &'a Document) -> impl Iterator<Item = Node<'a, 'a>> {
+    doc.descendants().filter(|node| {
+        node.has_tag_name((RDF_NS, "Description"))
+            && node
+                .parent_element()
+                .is_some_and(|parent| parent.has_tag_name((RDF_NS, "RDF")))
+    })
+}
+
+fn apply_description(desc: Node, settings: &mut RenderSettings) {
+    let annotations = &mut settings.annotations;
+    if let Some(rating) = parse::<i8>(desc, XMP_NS, "Rating") {
+        annotations.rating = rating.clamp(-1, 5);
+    }
+    if let Some(label) = property(desc, XMP_NS, "Label") {
+        annotations.label = Some(label.to_string()).filter(|l| !l.is_empty());
+    }
+    if let Some(subject) = desc
+        .children()
+        .find(|node| node.has_tag_name((DC_NS, "subject")))
+    {
+        annotations.keywords = subject
+            .descendants()
+            .filter(|node| node.has_tag_name((RDF_NS, "li")))
+            .filter_map(|node| node.text())
+            .map(|text| text.trim().to_string())
+            .collect();
+    }
+
+    if let Some(value) = parse::<u16>(desc, TIFF_NS, "Orientation") {
+        match Orientation::from_exif(value) {
+            Some(orientation) => settings.orientation = orientation,
+            None => warn!("Ignoring unknown orientation {}", value),
+        }
+    }
+
+    if let Some(has_crop) = parse_bool(desc, "HasCrop") {
+        settings.crop = if has_crop {
+            Some(Crop {
+                left: parse(desc, CRS_NS, "CropLeft").unwrap_or(0.),
+                top: parse(desc, CRS_NS, "CropTop").unwrap_or(0.),
+                right: parse(desc, CRS_NS, "CropRight").unwrap_or(1.),
+                bottom: parse(desc, CRS_NS, "CropBottom").unwrap_or(1.),
+            })
+        } else {
+            None
+        };
+    }
+
+    let temperature = parse::<f32>(desc, CRS_NS, "Temperature");
+    match (property(desc, CRS_NS, "WhiteBalance"), temperature) {
+        (Some("As Shot"), _) => settings.white_balance = WhiteBalance::AsShot,
+        // Presets like "Daylight" are written along with the temperature they stand for.
+        (_, Some(temperature)) => {
+            settings.white_balance = WhiteBalance::Custom {
+                temperature,
+                tint: parse(desc, CRS_NS, "Tint").unwrap_or(0.),
+            }
+        }
+        _ => {}
+    }
+
+    let exposure = parse::<f32>(desc, CRS_NS, "Exposure2012")
+        .or_else(|| parse::<f32>(desc, CRS_NS, "Exposure"));
+    if let Some(exposure) = exposure {
+        settings.exposure_basis = 2f32.powf(exposure);
+    }
+    if let Some(saturation) = parse::<f32>(desc, CRS_NS, "Saturation") {
+        settings.saturation_boost = saturation / 100.;
+    }
+    if let Some(auto_tone) = parse_bool(desc, "AutoTone") {
+        settings.auto_contrast = auto_tone;
+    }
+    if let Some(enabled) = parse::<u8>(desc, CRS_NS, "LensProfileEnable") {
+        settings.lens_corrections.vignette = enabled != 0;
+    }
+
+    for (name, curve) in TONE_CURVES
+        .iter()
+        .zip(curves_mut(&mut settings.tone_curve).iter_mut())
+    {
+        if let Some(points) = parse_curve(desc, name) {
+            **curve = Curve::new(&points);
+        }
+    }
+}
+
+fn curves_mut(tone_curve: &mut ToneCurve) -> [&mut Curve; 4] {
+    [
+        &mut tone_curve.luminance,
+        &mut tone_curve.red,
+        &mut tone_curve.green,
+        &mut tone_curve.blue,
+    ]
+}
+
+/// Reads a point curve, which is written as an `rdf:Seq` of "x, y" points.
+fn parse_curve(desc: Node, name: &str) -> Option<Vec<CurvePoint>> {
+    let seq = desc
+        .children()
+        .find(|node| node.has_tag_name((CRS_NS, name)))?;
+    let points = seq
+        .descendants()
+        .filter(|node| node.has_tag_name((RDF_NS, "li")))
+        .filter_map(|node| {
+            let text = node.text().unwrap_or("");
+            let mut coords = text.split(',').map(|coord| coord.trim().parse::<f32>());
+            match (coords.next(), coords.next(), coords.next()) {
+                (Some(Ok(x)), Some(Ok(y)), None) => {
+                    Some(CurvePoint::new(x / TONE_CURVE_SCALE, y / TONE_CURVE_SCALE))
+                }
+                _ => {
+                    warn!("Ignoring invalid {} point \"{}\"", name, text);
+                    None
+                }
+            }
+        })
+        .collect();
+    Some(points)
+}
+
+/// Finds a simple property, which may be written either as an attribute of the description or as
+/// a child element.
+fn property<'a>(desc: Node<'a, '_>, ns: &str, name: &str) -> Option<&'a str> {
+    desc.attribute((ns, name)).or_else(|| {
+        desc.children()
+            .find(|node| node.has_tag_name((ns, name)))
+            .map(|node| node.text().unwrap_or(""))
+    })
+}
+
+fn parse<T: FromStr>(desc: Node, ns: &str, name: &str) -> Option<T> {
+    let value = property(desc, ns, name)?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        warn!("Ignoring invalid XMP value {}=\"{}\"", name, value);
+    }
+    parsed
+}
+
+fn parse_bool(desc: Node, name: &str) -> Option<bool> {
+    match property(desc, CRS_NS, name)?.trim() {
+        "True" | "true" | "1" => Some(true),
+        "False" | "false" | "0" => Some(false),
+        value => {
+            warn!("Ignoring invalid XMP value {}=\"{}\"", name, value);
+            None
+        }
+    }
+}
+
+/// The namespaces we write, with the prefixes we give them in new packets.
+const NAMESPACES: [(&str, &str); 5] = [
+    ("rdf", RDF_NS),
+    ("xmp", XMP_NS),
+    ("dc", DC_NS),
+    ("tiff", TIFF_NS),
+    ("crs", CRS_NS),
+];
+
+/// The properties which `properties` may write, other than the tone curves. When saving over an
+/// existing sidecar, these are replaced and everything else is kept.
+const OWN_PROPERTIES: [(&str, &str); 17] = [
+    (XMP_NS, "Rating"),
+    (XMP_NS, "Label"),
+    (DC_NS, "subject"),
+    (TIFF_NS, "Orientation"),
+    (CRS_NS, "WhiteBalance"),
+    (CRS_NS, "Temperature"),
+    (CRS_NS, "Tint"),
+    (CRS_NS, "Exposure2012"),
+    (CRS_NS, "Saturation"),
+    (CRS_NS, "ToneCurveName2012"),
+    (CRS_NS, "AutoTone"),
+    (CRS_NS, "LensProfileEnable"),
+    (CRS_NS, "HasCrop"),
+    (CRS_NS, "CropLeft"),
+    (CRS_NS, "CropTop"),
+    (CRS_NS, "CropRight"),
+    (CRS_NS, "CropBottom"),
+];
+
+fn is_own_property(ns: &str, name: &str) -> bool {
+    OWN_PROPERTIES.contains(&(ns, name)) || (ns == CRS_NS && TONE_CURVES.contains(&name))
+}
+
+enum PropertyValue {
+    Simple(String),
+    /// An unordered `rdf:Bag`.
+    Bag(Vec<String>),
+    /// An ordered `rdf:Seq`.
+    Seq(Vec<String>),
+}
+
+struct Property {
+    ns: &'static str,
+    name: &'static str,
+    value: PropertyValue,
+}
+
+/// The properties which describe `settings`.
+fn properties(settings: &RenderSettings) -> Vec<Property> {
+    let mut props = vec![];
+    let mut simple = |ns, name, value: String| {
+        props.push(Property {
+            ns,
+            name,
+            value: PropertyValue::Simple(value),
+        })
+    };
+    let bool_str = |b: bool| if b { "True" } else { "False" }.to_string();
+
+    let annotations = &settings.annotations;
+    simple(XMP_NS, "Rating", annotations.rating.to_string());
+    if let Some(label) = &annotations.label {
+        simple(XMP_NS, "Label", label.clone());
+    }
+    simple(
+        TIFF_NS,
+        "Orientation",
+        settings.orientation.to_exif().to_string(),
+    );
+
+    match settings.white_balance {
+        WhiteBalance::AsShot => simple(CRS_NS, "WhiteBalance", "As Shot".to_string()),
+        WhiteBalance::Custom { temperature, tint } => {
+            simple(CRS_NS, "WhiteBalance", "Custom".to_string());
+            simple(CRS_NS, "Temperature", format!("{:.0}", temperature));
+            simple(CRS_NS, "Tint", format!("{:+.0}", tint));
+        }
+    }
+    simple(
+        CRS_NS,
+        "Exposure2012",
+        format!("{:+.2}", settings.exposure_basis.log2()),
+    );
+    simple(
+        CRS_NS,
+        "Saturation",
+        format!("{:+.0}", settings.saturation_boost * 100.),
+    );
+    if !settings.tone_curve.is_identity() {
+        simple(CRS_NS, "ToneCurveName2012", "Custom".to_string());
+    }
+    simple(CRS_NS, "AutoTone", bool_str(settings.auto_contrast));
+    simple(
+        CRS_NS,
+        "LensProfileEnable",
+        (settings.lens_corrections.vignette as u8).to_string(),
+    );
+    simple(CRS_NS, "HasCrop", bool_str(settings.crop.is_some()));
+    if let Some(crop) = settings.crop {
+        simple(CRS_NS, "CropLeft", crop.left.to_string());
+        simple(CRS_NS, "CropTop", crop.top.to_string());
+        simple(CRS_NS, "CropRight", crop.right.to_string());
+        simple(CRS_NS, "CropBottom", crop.bottom.to_string());
+    }
+
+    if !annotations.keywords.is_empty() {
+        props.push(Property {
+            ns: DC_NS,
+            name: "subject",
+            value: PropertyValue::Bag(annotations.keywords.clone()),
+        });
+    }
+    let tone_curve = &settings.tone_curve;
+    let curves = [
+        &tone_curve.luminance,
+        &tone_curve.red,
+        &tone_curve.green,
+        &tone_curve.blue,
+    ];
+    for (&name, curve) in TONE_CURVES.iter().zip(curves.iter()) {
+        if curve.is_identity() {
+            continue;
+        }
+        let points = curve
+            .points()
+            .iter()
+            .map(|point| {
+                format!(
+                    "{:.0}, {:.0}",
+                    point.x * TONE_CURVE_SCALE,
+                    point.y * TONE_CURVE_SCALE
+                )
+            })
+            .collect();
+        props.push(Property {
+            ns: CRS_NS,
+            name,
+            value: PropertyValue::Seq(points),
+        });
+    }
+    props
+}
+
+/// Writes `props` as the attributes and the child elements of an `rdf:Description`, naming each
+/// namespace with `prefix`. Each attribute starts on a new line.
+fn write_properties(props: &[Property], prefix: impl Fn(&str) -> String) -> (String, String) {
+    let mut attrs = String::new();
+    let mut children = String::new();
+    let rdf = prefix(RDF_NS);
+    for prop in props {
+        let name = format!("{}:{}", prefix(prop.ns), prop.name);
+        let (container, items) = match &prop.value {
+            PropertyValue::Simple(value) => {
+                write!(attrs, "\n    {}=\"{}\"", name, escape(value)).unwrap();
+                continue;
+            }
+            PropertyValue::Bag(items) => ("Bag", items),
+            PropertyValue::Seq(items) => ("Seq", items),
+        };
+        writeln!(children, "   <{}>\n    <{}:{}>", name, rdf, container).unwrap();
+        for item in items {
+            writeln!(children, "     <{0}:li>{1}</{0}:li>", rdf, escape(item)).unwrap();
+        }
+        writeln!(children, "    </{}:{}>\n   </{}>", rdf, container, name).unwrap();
+    }
+    (attrs, children)
+}
+
+fn default_prefix(ns: &str) -> &'static str {
+    NAMESPACES
+        .iter()
+        .find(|&&(_, uri)| uri == ns)
+        .map(|&(prefix, _)| prefix)
+        .expect("we only write our own namespaces")
+}
+
+/// Serializes `settings` as an XMP packet.
+pub fn to_xmp(settings: &RenderSettings) -> String {
+    let (attrs, children) =
+        write_properties(&properties(settings), |ns| default_prefix(ns).to_string());
+
+    let mut xml = String::new();
+    xml.push_str("<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n");
+    xml.push_str("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\" x:xmptk=\"blitz\">\n");
+    writeln!(xml, " <rdf:RDF xmlns:rdf=\"{}\">", RDF_NS).unwrap();
+    xml.push_str("  <rdf:Description rdf:about=\"\"");
+    for (prefix, ns) in &NAMESPACES[1..] {
+        write!(xml, "\n    xmlns:{}=\"{}\"", prefix, ns).unwrap();
+    }
+    xml.push_str(&attrs);
+    xml.push_str("\n   >\n");
+    xml.push_str(&children);
+    xml.push_str("  </rdf:Description>\n");
+    xml.push_str(" </rdf:RDF>\n");
+    xml.push_str("</x:xmpmeta>\n");
+    xml.push_str("<?xpacket end=\"w\"?>\n");
+    xml
+}
+
+/// Updates the XMP packet `existing` with `settings`: the properties we write are replaced, and
+/// everything else, such as edits only other editors understand, is kept as it is.
+pub fn merge_xmp(existing: &str, settings: &RenderSettings) -> Result<String, XmpError> {
+    let doc = Document::parse(existing)?;
+    let descriptions: Vec<_> = descriptions(&doc).collect();
+    let target = match descriptions.first() {
+        Some(&target) => target,
+        None => {
+            warn!("No rdf:Description in existing XMP; replacing it");
+            return Ok(to_xmp(settings));
+        }
+    };
+
+    // Non-overlapping byte ranges of `existing`, and what to replace each with.
+    let mut edits: Vec<(Range<usize>, String)> = vec![];
+    // Along with the whitespace before it, so that no blank lines are left behind.
+    let remove = |edits: &mut Vec<_>, range: Range<usize>| {
+        let start = existing[..range.start].trim_end().len();
+        edits.push((start..range.end, String::new()));
+    };
+    for desc in &descriptions {
+        for attr in desc.attributes() {
+            if attr
+                .namespace()
+                .is_some_and(|ns| is_own_property(ns, attr.name()))
+            {
+                // Up to and including the closing quote.
+                remove(&mut edits, attr.range().start..attr.value_range().end + 1);
+            }
+        }
+        for child in desc.children().filter(Node::is_element) {
+            let name = child.tag_name();
+            if name
+                .namespace()
+                .is_some_and(|ns| is_own_property(ns, name.name()))
+            {
+                remove(&mut edits, child.range());
+            }
+        }
+    }
+
+    // Use whatever prefixes the packet already has, and declare any namespaces it doesn't.
+    let mut declarations = String::new();
+    let prefix = |ns: &str| match target.lookup_prefix(ns) {
+        Some(prefix) => prefix.to_string(),
+        None => default_prefix(ns).to_string(),
+    };
+    for &(prefix, ns) in NAMESPACES.iter() {
+        if target.lookup_prefix(ns).is_none() {
+            write!(declarations, "\n    xmlns:{}=\"{}\"", prefix, ns).unwrap();
+        }
+    }
+    let (attrs, children) = write_properties(&properties(settings), prefix);
+
+    // The start tag ends at the first '>' after its attributes, which may themselves contain '>'.
+    let range = target.range();
+    let after_attrs = target
+        .attributes()
+        .iter()
+        .map(|attr| attr.value_range().end + 1)
+        .max()
+        .unwrap_or(range.start);
+    let tag_end = after_attrs
+        + existing[after_attrs..]
+            .find('>')
+            .expect("the tag was parsed");
+    if existing[..tag_end].ends_with('/') {
+        let tag_name = existing[range.start + 1..]
+            .split(|c: char| c.is_whitespace() || c == '/' || c == '>')
+            .next()
+            .unwrap_or_default();
+        edits.push((
+            tag_end - 1..tag_end + 1,
+            format!(
+                "{}{}\n   >\n{}  </{}>",
+                declarations, attrs, children, tag_name
+            ),
+        ));
+    } else {
+        edits.push((tag_end..tag_end, format!("{}{}", declarations, attrs)));
+        let end_tag = range.start + existing[range.clone()].rfind("</").expect("not empty");
+        let indent = existing[..end_tag].len() - existing[..end_tag].trim_end_matches(' ').len();
+        edits.push((end_tag - indent..end_tag - indent, children));
+    }
+
+    let mut xml = existing.to_string();
+    edits.sort_by_key(|(range, _)| (range.start, range.end));
+    for (range, replacement) in edits.into_iter().rev() {
+        xml.replace_range(range, &replacement);
+    }
+    Ok(xml)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render_settings::Annotations;
+
+    #[test]
+    fn round_trip() {
+        let settings = RenderSettings {
+            // Points on the 0-255 grid that XMP curves are written on.
+            tone_curve: ToneCurve {
+                luminance: Curve::new(&[
+                    CurvePoint::new(0.0, 0.0),
+                    CurvePoint::new(0.2, 0.4),
+                    CurvePoint::new(1.0, 1.0),
+                ]),
+                red: Curve::new(&[CurvePoint::new(0.0, 0.2), CurvePoint::new(1.0, 1.0)]),
+                ..ToneCurve::default()
+            },
+            exposure_basis: 2.0,
+            auto_contrast: true,
+            saturation_boost: 0.2,
+            white_balance: WhiteBalance::Custom {
+                temperature: 5500.,
+                tint: -10.,
+            },
+            crop: Some(Crop {
+                left: 0.125,
+                top: 0.25,
+                right: 0.875,
+                bottom: 0.75,
+            }),
+            orientation: Orientation::Rotate90,
+            annotations: Annotations {
+                rating: 4,
+                label: Some("Red & Blue".to_string()),
+                keywords: vec!["beach".to_string(), "<sunset>".to_string()],
+            },
+            ..RenderSettings::default()
+        };
+
+        let mut read = RenderSettings::default();
+        apply_xmp(&to_xmp(&settings), &mut read).unwrap();
+        assert_eq!(read, settings);
+    }
+
+    #[test]
+    fn reads_lightroom_attributes() {
+        let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:crs="http://ns.adobe.com/camera-raw-settings/1.0/"
+    xmp:Rating="-1"
+    crs:WhiteBalance="Daylight"
+    crs:Temperature="5500"
+    crs:Tint="+10"
+    crs:Exposure2012="+1.00">
+   <crs:ToneCurvePV2012>
+    <rdf:Seq>
+     <rdf:li>0, 0</rdf:li>
+     <rdf:li>64, 96</rdf:li>
+     <rdf:li>255, 255</rdf:li>
+    </rdf:Seq>
+   </crs:ToneCurvePV2012>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let mut settings = RenderSettings::default();
+        apply_xmp(xml, &mut settings).unwrap();
+
+        assert_eq!(settings.annotations.rating, -1);
+        assert_eq!(
+            settings.white_balance,
+            WhiteBalance::Custom {
+                temperature: 5500.,
+                tint: 10.
+            }
+        );
+        assert_eq!(settings.exposure_basis, 2.0);
+        assert_eq!(
+            settings.tone_curve.luminance.points(),
+            &[
+                CurvePoint::new(0.0, 0.0),
+                CurvePoint::new(64. / 255., 96. / 255.),
+                CurvePoint::new(1.0, 1.0),
+            ]
+        );
+        assert!(settings.tone_curve.red.is_identity());
+        // Fields that aren't mentioned are left alone.
+        assert_eq!(settings.orientation, Orientation::Normal);
+        assert_eq!(settings.crop, None);
+    }
+
+    #[test]
+    fn reads_darktable_elements() {
+        let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:tiff="http://ns.adobe.com/tiff/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/">
+   <xmp:Rating>3</xmp:Rating>
+   <tiff:Orientation>8</tiff:Orientation>
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>holiday</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let mut settings = RenderSettings::default();
+        apply_xmp(xml, &mut settings).unwrap();
+
+        assert_eq!(settings.annotations.rating, 3);
+        assert_eq!(settings.annotations.keywords, vec!["holiday".to_string()]);
+        assert_eq!(settings.orientation, Orientation::Rotate270);
+    }
+
+    #[test]
+    fn saving_keeps_other_editors_properties() {
+        let existing = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xap="http://ns.adobe.com/xap/1.0/"
+    xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/"
+    xmlns:crs="http://ns.adobe.com/camera-raw-settings/1.0/"
+    xap:Rating="1"
+    xap:Label="Green"
+    photoshop:DateCreated="2020-05-01T10:00:00"
+    crs:Exposure2012="-1.00"
+    crs:Contrast2012="+20">
+   <crs:ToneCurvePV2012>
+    <rdf:Seq>
+     <rdf:li>0, 0</rdf:li>
+     <rdf:li>255, 200</rdf:li>
+    </rdf:Seq>
+   </crs:ToneCurvePV2012>
+   <crs:Look>
+    <rdf:Description crs:Name="Adobe Color" crs:Exposure2012="+0.50"/>
+   </crs:Look>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let mut settings = RenderSettings::default();
+        settings.annotations.rating = 4;
+        settings.annotations.keywords = vec!["beach".to_string()];
+        let merged = merge_xmp(existing, &settings).unwrap();
+
+        let mut read = RenderSettings::default();
+        apply_xmp(&merged, &mut read).unwrap();
+        assert_eq!(read, settings);
+        // The packet's own prefixes are used, and anything we don't write is kept.
+        assert!(merged.contains(r#"xap:Rating="4""#), "{}", merged);
+        assert!(!merged.contains("xap:Label"));
+        assert!(!merged.contains("ToneCurvePV2012"));
+        assert!(merged.contains(r#"crs:Contrast2012="+20""#));
+        assert!(merged.contains(r#"photoshop:DateCreated="2020-05-01T10:00:00""#));
+        // Including properties of the same name nested inside other properties.
+        assert!(merged
+            .contains(r#"<rdf:Description crs:Name="Adobe Color" crs:Exposure2012="+0.50"/>"#));
+        // Saving again changes nothing.
+        assert_eq!(merge_xmp(&merged, &settings).unwrap(), merged);
+    }
+
+    #[test]
+    fn saving_into_an_empty_description() {
+        let existing = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:crs="http://ns.adobe.com/camera-raw-settings/1.0/"
+    crs:Contrast2012="+20"/>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let mut settings = RenderSettings::auto();
+        settings.annotations.keywords = vec!["beach".to_string()];
+        let merged = merge_xmp(existing, &settings).unwrap();
+
+        let mut read = RenderSettings::default();
+        apply_xmp(&merged, &mut read).unwrap();
+        assert_eq!(read, settings);
+        assert!(merged.contains(r#"crs:Contrast2012="+20""#));
+    }
+
+    #[test]
+    fn xmp_paths_cover_both_conventions() {
+        let [adobe, darktable] = xmp_paths(Path::new("/photos/DSCF2279.RAF"));
+        assert_eq!(adobe, PathBuf::from("/photos/DSCF2279.xmp"));
+        assert_eq!(darktable, PathBuf::from("/photos/DSCF2279.RAF.xmp"));
+    }
+}
@@ -1,29 +1,228 @@
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use image::imageops::FilterType::Lanczos3;
 use image::{imageops, DynamicImage, ImageFormat};
 
 use blitz::diagnostics::histogram::ToHistogram;
+use blitz::diagnostics::overlay::Overlay;
 
+use blitz::analysis;
 use blitz::render;
 use blitz::render_settings::RenderSettings;
+use blitz::sidecar;
+use blitz::xmp;
+use blitzbin::convert::{self, ConvertOptions, OutputFormat};
 use blitzbin::diagnostics::TermImage;
 use blitzbin::pathutils;
+use blitzbin::rawstats::RawStats;
+use blitzbin::terminal::Backend;
+use libraw::dng::Compression;
 use libraw::raf::RafFile;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
 struct Flags {
     open: bool,
     stats: bool,
+    overlay: Option<Overlay>,
+    display: Backend,
+}
+
+/// Everything `main` dispatches on.
+const SUBCOMMANDS: &[&str] = &["render", "convert", "analyze", "rawstats", "xmp"];
+
+/// `blitz FILE` rendered a file before there were subcommands, so anything which doesn't start
+/// with one (or ask for help) is still treated as `render`.
+fn with_default_subcommand(mut args: Vec<OsString>) -> Vec<OsString> {
+    let explicit = match args.get(1).map(|first| first.to_str()) {
+        None => return args,
+        Some(Some(first)) => {
+            SUBCOMMANDS.contains(&first)
+                || ["help", "-h", "--help", "-V", "--version"].contains(&first)
+        }
+        // Not UTF-8, so not one of ours.
+        Some(None) => false,
+    };
+    if !explicit {
+        args.insert(1, "render".into());
+    }
+    args
 }
 
 fn main() {
+    env_logger::init();
+
+    let default_jobs = convert::DEFAULT_JOBS.to_string();
     let matches = App::new("Blitz")
-        .arg(Arg::with_name("open").long("open"))
-        .arg(Arg::with_name("stats").long("stats"))
-        .arg(Arg::with_name("INPUT").required(true).index(1))
-        .get_matches();
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("render")
+                .about("Renders one file to a TIFF in Downloads, and displays it")
+                .arg(Arg::with_name("open").long("open"))
+                .arg(Arg::with_name("stats").long("stats"))
+                .arg(
+                    Arg::with_name("overlay")
+                        .long("overlay")
+                        .help("Also save the render with a diagnostic overlay")
+                        .long_help(
+                            "Also save the render with a diagnostic overlay: \"clipping\" marks \
+                             output pixels at white in red and at black in blue, \"raw\" marks \
+                             where the sensor saturated, coloured by channel, and \"zones\" \
+                             shows exposure zones in false colour",
+                        )
+                        .possible_values(&["clipping", "raw", "zones"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no display")
+                        .long("no-display")
+                        .help("Don't show images in the terminal"),
+                )
+                .arg(Arg::with_name("INPUT").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts raw files to JPEG, PNG, TIFF or DNG")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Raw files, directories of raw files, or glob patterns")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Directory to write into")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .help("Output filename, without extension. Can use {stem}, {parent} and {index}")
+                        .default_value("{stem}"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .possible_values(&["jpeg", "png", "tiff", "dng"])
+                        .default_value("jpeg"),
+                )
+                .arg(
+                    Arg::with_name("quality")
+                        .short("q")
+                        .long("quality")
+                        .help("JPEG quality, 1-100")
+                        .default_value("90"),
+                )
+                .arg(
+                    Arg::with_name("bit depth")
+                        .long("bit-depth")
+                        .help("Bits per channel for PNG and TIFF")
+                        .possible_values(&["8", "16"])
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::with_name("uncompressed")
+                        .long("uncompressed")
+                        .help("Store DNG raw data without lossless JPEG compression"),
+                )
+                .arg(
+                    Arg::with_name("resize")
+                        .long("resize")
+                        .help("Shrink to fit within this many pixels along the longest edge")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("settings")
+                        .long("settings")
+                        .help("Settings file (in sidecar format) to use instead of the defaults")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ignore sidecars")
+                        .long("ignore-sidecars")
+                        .help("Don't apply edits saved next to the raw files"),
+                )
+                .arg(
+                    Arg::with_name("skip existing")
+                        .long("skip-existing")
+                        .help("Don't overwrite outputs which already exist"),
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .help("How many files to convert at once")
+                        .long_help(
+                            "How many files to convert at once. Each file is rendered using every \
+                             CPU either way, but needs a few hundred MB of memory while it's being \
+                             converted.",
+                        )
+                        .default_value(&default_jobs),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Measures one file's exposure, and prints settings suggested for it")
+                .long_about(
+                    "Measures one file's exposure, and prints settings suggested for it. The \
+                     settings are printed in sidecar format, so they can be saved, edited, and \
+                     passed to `convert --settings`.",
+                )
+                .arg(Arg::with_name("INPUT").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("rawstats")
+                .about("Shows statistics and histograms of one file's raw sensor data")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Also save the histograms to this file, as PNG or SVG")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no display")
+                        .long("no-display")
+                        .help("Don't show the histograms in the terminal"),
+                )
+                .arg(Arg::with_name("INPUT").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("xmp")
+                .about("Saves files' edits to XMP sidecars, for other editors to read")
+                .long_about(
+                    "Saves the edits in each file's blitz sidecar to an XMP sidecar, for other \
+                     editors to read. If there's already an XMP sidecar, only the properties \
+                     blitz understands are replaced in it.",
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Raw files")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .get_matches_from(with_default_subcommand(env::args_os().collect()));
 
+    match matches.subcommand() {
+        ("render", Some(opts)) => cmd_render(opts),
+        ("convert", Some(opts)) => cmd_convert(opts),
+        ("analyze", Some(opts)) => cmd_analyze(opts),
+        ("rawstats", Some(opts)) => cmd_rawstats(opts),
+        ("xmp", Some(opts)) => cmd_xmp(opts),
+        _ => unreachable!("Must match subcommand"),
+    }
+}
+
+fn cmd_render(matches: &ArgMatches) {
     let input = matches.value_of("INPUT").unwrap();
-    let flags = make_flags(&matches);
+    let flags = make_flags(matches);
 
     load_and_maybe_render(input, &flags);
 }
@@ -31,26 +230,198 @@ fn main() {
 fn make_flags(matches: &ArgMatches) -> Flags {
     let open = matches.occurrences_of("open") == 1;
     let stats = matches.occurrences_of("stats") == 1;
-    Flags { open, stats }
+    let overlay = matches.value_of("overlay").map(|overlay| match overlay {
+        "clipping" => Overlay::Clipping,
+        "raw" => Overlay::RawSaturation,
+        "zones" => Overlay::ExposureZones,
+        _ => unreachable!("clap checks possible values"),
+    });
+    let display = if matches.is_present("no display") {
+        Backend::None
+    } else {
+        Backend::detect()
+    };
+    Flags {
+        open,
+        stats,
+        overlay,
+        display,
+    }
+}
+
+fn cmd_convert(matches: &ArgMatches) {
+    let options = match make_convert_options(matches) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    let inputs: Vec<_> = matches.values_of("INPUT").unwrap().collect();
+    let (files, mut failures) = convert::expand_inputs(&inputs);
+    if let Err(e) = fs::create_dir_all(&options.output_dir) {
+        eprintln!("Couldn't create {}: {}", options.output_dir.display(), e);
+        process::exit(2);
+    }
+
+    let summary = convert::convert_all(&options, &files);
+    failures.extend(summary.failures);
+    println!(
+        "Converted {}, skipped {}, failed {}",
+        summary.converted,
+        summary.skipped,
+        failures.len()
+    );
+    if !failures.is_empty() {
+        eprintln!("Failures:");
+        for (path, error) in &failures {
+            eprintln!("  {}: {}", path.display(), error);
+        }
+        process::exit(1);
+    }
+}
+
+fn make_convert_options(matches: &ArgMatches) -> Result<ConvertOptions, String> {
+    let sixteen_bit = matches.value_of("bit depth") == Some("16");
+    let format = match matches.value_of("format").unwrap() {
+        "jpeg" => {
+            let quality = matches.value_of("quality").unwrap();
+            match quality.parse() {
+                Ok(quality) if (1..=100).contains(&quality) => OutputFormat::Jpeg { quality },
+                _ => return Err(format!("Invalid JPEG quality: {}", quality)),
+            }
+        }
+        "png" => OutputFormat::Png { sixteen_bit },
+        "tiff" => OutputFormat::Tiff { sixteen_bit },
+        "dng" => OutputFormat::Dng {
+            compression: if matches.is_present("uncompressed") {
+                Compression::Uncompressed
+            } else {
+                Compression::LosslessJpeg
+            },
+        },
+        _ => unreachable!("clap checks possible values"),
+    };
+    let max_dimension = match matches.value_of("resize") {
+        Some(size) => Some(
+            size.parse()
+                .map_err(|_| format!("Invalid size: {}", size))?,
+        ),
+        None => None,
+    };
+    let jobs = matches.value_of("jobs").unwrap();
+    let jobs = match jobs.parse() {
+        Ok(jobs) if jobs > 0 => jobs,
+        _ => return Err(format!("Invalid number of jobs: {}", jobs)),
+    };
+    let settings = match matches.value_of("settings") {
+        Some(path) => {
+            let json =
+                fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+            sidecar::from_json(&json).map_err(|e| format!("Couldn't read {}: {}", path, e))?
+        }
+        None => RenderSettings::auto(),
+    };
+
+    Ok(ConvertOptions {
+        output_dir: PathBuf::from(matches.value_of("output").unwrap()),
+        name_template: matches.value_of("name").unwrap().to_string(),
+        format,
+        max_dimension,
+        settings,
+        use_sidecars: matches.occurrences_of("ignore sidecars") == 0,
+        skip_existing: matches.occurrences_of("skip existing") > 0,
+        jobs,
+    })
+}
+
+fn cmd_analyze(matches: &ArgMatches) {
+    let input = matches.value_of("INPUT").unwrap();
+    let parsed = RafFile::open(input).and_then(|file| Ok(analysis::analyze(&file.parse_raw()?)));
+    let analysis = match parsed {
+        Ok(analysis) => analysis,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {:?}", input, e);
+            process::exit(1);
+        }
+    };
+    eprintln!("Key: {:.4}", analysis.key);
+    eprintln!("Dynamic range: {:.1} stops", analysis.dynamic_range);
+    eprintln!("Clipped: {:.2}%", 100.0 * analysis.clipped_fraction);
+    println!(
+        "{}",
+        sidecar::to_json(&analysis.suggest_settings()).unwrap()
+    );
+}
+
+fn cmd_rawstats(matches: &ArgMatches) {
+    let input = matches.value_of("INPUT").unwrap();
+    let parsed = RafFile::open(input).and_then(|file| {
+        let details = file.parse_raw()?;
+        Ok(RawStats::compute(&details.render_info()))
+    });
+    let stats = match parsed {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {:?}", input, e);
+            process::exit(1);
+        }
+    };
+    println!("{}", stats);
+
+    if let Some(output) = matches.value_of("output") {
+        if let Err(e) = stats.save_histograms(Path::new(output)) {
+            eprintln!("Couldn't save histograms: {}", e);
+            process::exit(1);
+        }
+        println!("Saved histograms to {}", output);
+    }
+    if !matches.is_present("no display") {
+        stats.to_svg().display();
+    }
 }
 
 fn load_and_maybe_render(img_file: &str, flags: &Flags) {
     println!("Loading RAW data: native");
-    let file = RafFile::open(img_file).unwrap();
+    let path = Path::new(img_file);
+    let file = RafFile::open(path).unwrap();
     println!(
         "Opened file: {}",
-        file.path().file_name().and_then(|x| x.to_str()).unwrap()
+        path.file_name().and_then(|x| x.to_str()).unwrap()
     );
     println!("Parsing...");
     let details = file.parse_raw().unwrap();
     println!("Parsed.");
 
     let raw_preview_filename = pathutils::get_output_path("native");
-    let rendered = render::render_raw_with_settings(&details, &RenderSettings::auto());
+    let settings = match sidecar::load_sidecar(path) {
+        Ok(Some(settings)) => {
+            println!(
+                "Applying edits from {}",
+                sidecar::sidecar_path(path).display()
+            );
+            settings
+        }
+        Ok(None) => {
+            // Fall back to edits from other editors, if there are any.
+            let mut settings = RenderSettings::auto();
+            match xmp::load_xmp(path, &mut settings) {
+                Ok(true) => println!("Applying edits from XMP sidecar"),
+                Ok(false) => {}
+                Err(e) => eprintln!("Ignoring XMP sidecar: {}", e),
+            }
+            settings
+        }
+        Err(e) => {
+            eprintln!("Ignoring sidecar: {}", e);
+            RenderSettings::auto()
+        }
+    };
+    let rendered = render::render_raw_with_settings(&details, &settings);
     if flags.stats {
         println!("Stats");
         let img = rendered.histogram().to_img(256, 128);
-        DynamicImage::ImageRgba8(img).display();
+        DynamicImage::ImageRgba8(img).display_with(flags.display);
     }
     println!("Saving");
     rendered
@@ -60,11 +431,61 @@ fn load_and_maybe_render(img_file: &str, flags: &Flags) {
     println!("Done saving");
     if flags.open {
         pathutils::open_preview(&raw_preview_filename);
-    } else {
+    } else if flags.display != Backend::None {
         println!("Resizing...");
         let img = imageops::resize(&rendered, 563, 375, Lanczos3);
         println!("Displaying...");
-        DynamicImage::ImageRgb8(img).display();
+        DynamicImage::ImageRgb8(img).display_with(flags.display);
+    }
+    if !flags.open {
         println!("Saved to {}", raw_preview_filename.to_str().unwrap());
     }
+
+    if let Some(overlay) = flags.overlay {
+        let mut composite = DynamicImage::ImageRgb8(rendered.clone()).into_rgba8();
+        imageops::overlay(
+            &mut composite,
+            &overlay.draw(&details, &settings, &rendered),
+            0,
+            0,
+        );
+        let overlay_filename = pathutils::get_output_path("overlay");
+        composite
+            .save_with_format(&overlay_filename, ImageFormat::Tiff)
+            .unwrap();
+        println!("Saved overlay to {}", overlay_filename.to_str().unwrap());
+        if flags.display != Backend::None {
+            let img = imageops::resize(&composite, 563, 375, Lanczos3);
+            DynamicImage::ImageRgba8(img).display_with(flags.display);
+        }
+    }
+}
+
+fn cmd_xmp(matches: &ArgMatches) {
+    let mut failed = false;
+    for input in matches.values_of("INPUT").unwrap() {
+        let path = Path::new(input);
+        let settings = match sidecar::load_sidecar(path) {
+            Ok(Some(settings)) => settings,
+            Ok(None) => {
+                println!("{}: no edits to save", input);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}: {}", input, e);
+                failed = true;
+                continue;
+            }
+        };
+        match xmp::save_xmp(path, &settings) {
+            Ok(xmp_path) => println!("{}: saved {}", input, xmp_path.display()),
+            Err(e) => {
+                eprintln!("{}: {}", input, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
 }
@@ -96,8 +96,8 @@ fn process_tiff_container(
     print_all_data: bool,
     tiff_file: &TiffFile,
 ) {
-    for (id, ifd) in tiff_file.ifds.iter().enumerate() {
-        println!("IFD #{}", id);
+    for (id, (ifd, origin)) in tiff_file.ifds.iter().zip(&tiff_file.origins).enumerate() {
+        println!("IFD #{} at {}, from {:?}", id, origin.offset, origin.parent);
         format_and_print_ifd(context, tags, &tiff_file, &ifd, print_all_data);
         println!("-----------");
         if let Some(makernotes) = process_makernotes(ifd, &tiff_file) {
@@ -119,9 +119,15 @@ fn process_makernotes<'a>(ifd: &'a Ifd<'_>, file: &TiffFile<'a>) -> Option<TiffF
         .filter(|tag| tag.tag == 0x927C)
         .exactly_one()
         .ok()?;
-    let makernotes_content: &[u8] = file.data_for_ifd_entry(makernotes);
+    let makernotes_content = match file.data_for_ifd_entry(makernotes) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Can't read the makernotes: {}", e);
+            return None;
+        }
+    };
     let (_, makernotes_tiff) =
-        parse_tiff_with_options(&makernotes_content, b"FUJIFILM", false).unwrap();
+        parse_tiff_with_options(makernotes_content, b"FUJIFILM", false).ok()?;
 
     Some(makernotes_tiff)
 }
@@ -0,0 +1,448 @@
+//! Batch conversion of raw files to ordinary images or DNGs, for `blitz convert`.
+
+use blitz::context::RenderContext;
+use blitz::dng::dng_options;
+use blitz::render::{fit_within, render_raw_16bit_with_context, render_raw_with_context};
+use blitz::render_settings::RenderSettings;
+use blitz::sidecar::{self, SidecarError};
+use blitz::xmp::{self, XmpError};
+use image::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageError, ImageFormat};
+use libraw::dng::{self, Compression, DngError, DngFile};
+use libraw::raf::{RafError, RafFile};
+use libraw::raw_image::RawImage;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ConvertError {
+        Io(err: io::Error) {
+            from()
+            display("{}", err)
+        }
+        Raf(err: RafError) {
+            from()
+            display("Couldn't read raw file: {:?}", err)
+        }
+        Image(err: ImageError) {
+            from()
+            display("Couldn't write image: {}", err)
+        }
+        Sidecar(err: SidecarError) {
+            from()
+            display("{}", err)
+        }
+        Xmp(err: XmpError) {
+            from()
+            display("{}", err)
+        }
+        Dng(err: DngError) {
+            from()
+            display("{}", err)
+        }
+        AlreadyDng {
+            display("Input is already a DNG")
+        }
+        Pattern(err: glob::PatternError) {
+            from()
+            display("Invalid pattern: {}", err)
+        }
+        NoMatches {
+            display("No such file, or no files match")
+        }
+        DuplicateOutput(other: PathBuf) {
+            display("Would overwrite the output of {}", other.display())
+        }
+        Panicked(message: String) {
+            display("Crashed: {}", message)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Jpeg {
+        quality: u8,
+    },
+    Png {
+        sixteen_bit: bool,
+    },
+    Tiff {
+        sixteen_bit: bool,
+    },
+    /// The raw data itself, rather than a rendering of it.
+    Dng {
+        compression: Compression,
+    },
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg { .. } => "jpg",
+            OutputFormat::Png { .. } => "png",
+            OutputFormat::Tiff { .. } => "tiff",
+            OutputFormat::Dng { .. } => "dng",
+        }
+    }
+
+    fn sixteen_bit(&self) -> bool {
+        match *self {
+            OutputFormat::Jpeg { .. } | OutputFormat::Dng { .. } => false,
+            OutputFormat::Png { sixteen_bit } | OutputFormat::Tiff { sixteen_bit } => sixteen_bit,
+        }
+    }
+}
+
+pub struct ConvertOptions {
+    pub output_dir: PathBuf,
+    /// The output filename, without extension. `{stem}` is replaced with the input's filename
+    /// without extension, `{parent}` with the name of the directory it's in, and `{index}` with
+    /// its position in the batch, starting from 1.
+    pub name_template: String,
+    pub format: OutputFormat,
+    /// If set, outputs are shrunk to fit within this many pixels along their longest edge.
+    pub max_dimension: Option<u32>,
+    /// Used for files without sidecars, or for every file if `use_sidecars` is false.
+    pub settings: RenderSettings,
+    pub use_sidecars: bool,
+    pub skip_existing: bool,
+    /// How many files to convert at once. Each one holds a decoded raw file and its rendering in
+    /// memory, so this bounds how much is used.
+    pub jobs: usize,
+}
+
+/// Rendering already spreads each file over every CPU, so converting a couple at once is enough to
+/// keep them busy while the other is reading or encoding.
+pub const DEFAULT_JOBS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Converted,
+    Skipped,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub converted: usize,
+    pub skipped: usize,
+    pub failures: Vec<(PathBuf, ConvertError)>,
+}
+
+/// Turns the command line inputs into a list of raw files. Directories contribute the raw files
+/// directly inside them, and anything that isn't a file or directory is treated as a glob
+/// pattern. Inputs which don't match anything are returned as failures.
+pub fn expand_inputs(inputs: &[&str]) -> (Vec<PathBuf>, Vec<(PathBuf, ConvertError)>) {
+    let mut files = vec![];
+    let mut failures = vec![];
+    for &input in inputs {
+        let path = Path::new(input);
+        let expanded = if path.is_dir() {
+            raw_files_in(path)
+        } else if path.is_file() {
+            Ok(vec![path.to_path_buf()])
+        } else {
+            glob_raw_files(input)
+        };
+        match expanded {
+            Ok(expanded) if expanded.is_empty() => {
+                failures.push((path.to_path_buf(), ConvertError::NoMatches))
+            }
+            Ok(expanded) => files.extend(expanded),
+            Err(e) => failures.push((path.to_path_buf(), e)),
+        }
+    }
+    (files, failures)
+}
+
+fn has_extension(path: &Path, wanted: &str) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(wanted))
+}
+
+fn is_raw(path: &Path) -> bool {
+    has_extension(path, "raf") || has_extension(path, "dng")
+}
+
+fn raw_files_in(dir: &Path) -> Result<Vec<PathBuf>, ConvertError> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && is_raw(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn glob_raw_files(pattern: &str) -> Result<Vec<PathBuf>, ConvertError> {
+    Ok(glob::glob(pattern)?
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file() && is_raw(path))
+        .collect())
+}
+
+/// Works out where the output for `input` goes. `index` starts from 0.
+pub fn output_path(options: &ConvertOptions, input: &Path, index: usize) -> PathBuf {
+    let name_of = |path: Option<&OsStr>| path.map_or(String::new(), |p| p.to_string_lossy().into());
+    let name = options
+        .name_template
+        .replace("{stem}", &name_of(input.file_stem()))
+        .replace(
+            "{parent}",
+            &name_of(input.parent().and_then(Path::file_name)),
+        )
+        .replace("{index}", &(index + 1).to_string());
+    options
+        .output_dir
+        .join(format!("{}.{}", name, options.format.extension()))
+}
+
+/// Converts every file in `inputs`, `options.jobs` at a time, and reports on how it went.
+/// Progress is printed as each file finishes.
+pub fn convert_all(options: &ConvertOptions, inputs: &[PathBuf]) -> Summary {
+    let mut summary = Summary::default();
+
+    // Two inputs with the same name in different directories would otherwise race to write the
+    // same output.
+    let mut claimed: HashMap<PathBuf, &Path> = HashMap::new();
+    let mut jobs = vec![];
+    for (index, input) in inputs.iter().enumerate() {
+        let output = output_path(options, input, index);
+        if let Some(other) = claimed.get(&output) {
+            let error = ConvertError::DuplicateOutput(other.to_path_buf());
+            summary.failures.push((input.clone(), error));
+        } else {
+            claimed.insert(output.clone(), input);
+            jobs.push((input, output));
+        }
+    }
+
+    // Each job gets a thread of its own rather than going through rayon, whose threads would pick
+    // up more files while waiting for rendering to finish, and so hold more of them in memory.
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(jobs.len()));
+    crossbeam_utils::thread::scope(|scope| {
+        for _ in 0..options.jobs.max(1).min(jobs.len()) {
+            scope.spawn(|_| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let (input, output) = match jobs.get(index) {
+                    Some(job) => job,
+                    None => break,
+                };
+                let result = convert_one(options, input, output);
+                match &result {
+                    Ok(Outcome::Converted) => {
+                        println!("{} -> {}", input.display(), output.display())
+                    }
+                    Ok(Outcome::Skipped) => println!("{} exists, skipping", output.display()),
+                    Err(e) => eprintln!("{}: {}", input.display(), e),
+                }
+                results.lock().unwrap().push((index, *input, result));
+            });
+        }
+    })
+    .expect("Conversions catch their own panics");
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|&(index, _, _)| index);
+    for (_, input, result) in results {
+        match result {
+            Ok(Outcome::Converted) => summary.converted += 1,
+            Ok(Outcome::Skipped) => summary.skipped += 1,
+            Err(e) => summary.failures.push((input.clone(), e)),
+        }
+    }
+    summary
+}
+
+/// Returns the settings to render `input` with: its own sidecar if it has one, otherwise the
+/// default settings with any XMP sidecar applied on top.
+fn settings_for(options: &ConvertOptions, input: &Path) -> Result<RenderSettings, ConvertError> {
+    if !options.use_sidecars {
+        return Ok(options.settings.clone());
+    }
+    if let Some(settings) = sidecar::load_sidecar(input)? {
+        return Ok(settings);
+    }
+    let mut settings = options.settings.clone();
+    xmp::load_xmp(input, &mut settings)?;
+    Ok(settings)
+}
+
+pub fn convert_one(
+    options: &ConvertOptions,
+    input: &Path,
+    output: &Path,
+) -> Result<Outcome, ConvertError> {
+    if options.skip_existing && output.exists() {
+        return Ok(Outcome::Skipped);
+    }
+
+    // Write to a temporary file first, so that an interrupted batch doesn't leave behind partial
+    // images that a later `--skip-existing` run would mistake for finished ones.
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let result = catch_panics(|| write_output(options, input, &partial)).and_then(|()| {
+        fs::rename(&partial, output)?;
+        Ok(Outcome::Converted)
+    });
+    if result.is_err() {
+        // It might not have been created yet, and the original error is more interesting anyway.
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+/// Runs `f`, turning a panic into an error, so that one bad file doesn't stop the whole batch.
+fn catch_panics<T>(f: impl FnOnce() -> Result<T, ConvertError>) -> Result<T, ConvertError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => panic
+                .downcast_ref::<&str>()
+                .map_or("unknown error".to_string(), |message| message.to_string()),
+        };
+        Err(ConvertError::Panicked(message))
+    })
+}
+
+/// Converts `input` and writes it to `path`.
+fn write_output(options: &ConvertOptions, input: &Path, path: &Path) -> Result<(), ConvertError> {
+    if has_extension(input, "dng") {
+        if let OutputFormat::Dng { .. } = options.format {
+            return Err(ConvertError::AlreadyDng);
+        }
+        let dng = DngFile::open(input)?;
+        write_rendered(options, input, &dng, path)
+    } else {
+        let file = RafFile::open(input)?;
+        let parsed = file.parse_raw()?;
+        match options.format {
+            OutputFormat::Dng { compression } => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                dng::write_dng(&parsed, &dng_options(&parsed, compression), &mut writer)?;
+                writer.flush()?;
+                Ok(())
+            }
+            _ => write_rendered(options, input, &parsed, path),
+        }
+    }
+}
+
+/// Renders `raw` and writes it to `path` in one of the ordinary image formats.
+fn write_rendered(
+    options: &ConvertOptions,
+    input: &Path,
+    raw: &dyn RawImage,
+    path: &Path,
+) -> Result<(), ConvertError> {
+    let img = render(options, input, raw)?;
+    match options.format {
+        OutputFormat::Jpeg { quality } => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            JpegEncoder::new_with_quality(&mut writer, quality).encode_image(&img)?;
+        }
+        OutputFormat::Png { .. } => img.save_with_format(path, ImageFormat::Png)?,
+        OutputFormat::Tiff { .. } => img.save_with_format(path, ImageFormat::Tiff)?,
+        OutputFormat::Dng { .. } => unreachable!("DNGs aren't rendered"),
+    }
+    Ok(())
+}
+
+/// Renders `raw` with the settings for `input`, resized if necessary.
+fn render(
+    options: &ConvertOptions,
+    input: &Path,
+    raw: &dyn RawImage,
+) -> Result<DynamicImage, ConvertError> {
+    let settings = settings_for(options, input)?;
+    let ctx = RenderContext::default();
+    let max = options.max_dimension.unwrap_or(u32::MAX);
+    let img = if options.format.sixteen_bit() {
+        let img = render_raw_16bit_with_context(raw, &settings, &ctx)
+            .expect("Default context can't be cancelled");
+        DynamicImage::ImageRgb16(fit_within(img, max, max))
+    } else {
+        let img = render_raw_with_context(raw, &settings, &ctx)
+            .expect("Default context can't be cancelled");
+        DynamicImage::ImageRgb8(fit_within(img, max, max))
+    };
+    Ok(img)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(template: &str) -> ConvertOptions {
+        ConvertOptions {
+            output_dir: PathBuf::from("/out"),
+            name_template: template.to_string(),
+            format: OutputFormat::Jpeg { quality: 90 },
+            max_dimension: None,
+            settings: RenderSettings::auto(),
+            use_sidecars: true,
+            skip_existing: false,
+            jobs: DEFAULT_JOBS,
+        }
+    }
+
+    #[test]
+    fn output_path_expands_template() {
+        let input = Path::new("/photos/2020-05-01/DSCF2279.RAF");
+        assert_eq!(
+            output_path(&options("{parent}-{stem}-{index}"), input, 2),
+            PathBuf::from("/out/2020-05-01-DSCF2279-3.jpg")
+        );
+    }
+
+    #[test]
+    fn duplicate_outputs_fail() {
+        let inputs = vec![
+            PathBuf::from("/a/DSCF2279.RAF"),
+            PathBuf::from("/b/DSCF2279.RAF"),
+        ];
+        let mut opts = options("{stem}");
+        // Skip the conversion itself; we only care about the second input being rejected.
+        opts.skip_existing = true;
+        let dir = tempfile::tempdir().unwrap();
+        opts.output_dir = dir.path().to_path_buf();
+        fs::write(opts.output_dir.join("DSCF2279.jpg"), b"").unwrap();
+
+        let summary = convert_all(&opts, &inputs);
+
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.failures[0].0, inputs[1]);
+    }
+
+    #[test]
+    fn panics_are_failures() {
+        let result: Result<(), _> = catch_panics(|| panic!("bad white balance {}", 0));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Crashed: bad white balance 0"
+        );
+        let result: Result<(), _> = catch_panics(|| panic!("no coefficients"));
+        assert_eq!(result.unwrap_err().to_string(), "Crashed: no coefficients");
+        assert_eq!(catch_panics(|| Ok(1)).unwrap(), 1);
+    }
+
+    #[test]
+    fn missing_inputs_are_failures() {
+        let (files, failures) = expand_inputs(&["/definitely/not/here/*.RAF"]);
+        assert!(files.is_empty());
+        assert_eq!(failures.len(), 1);
+    }
+}
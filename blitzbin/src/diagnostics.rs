@@ -1,31 +1,39 @@
-use image::{DynamicImage, ImageBuffer, ImageOutputFormat, Luma};
+use image::{DynamicImage, ImageBuffer, Luma};
 use std::io::Write;
 
 use crate::histo;
+use crate::terminal::{self, Backend};
 use histogram;
-use iterm2::download_file;
 use resvg::Options;
+use svg::node::element::path::Data;
+use svg::node::element::{Path, Rectangle};
+use svg::Document;
 
 pub trait TermImage {
-    fn draw_to<W: std::io::Write>(&self, writer: &mut W);
+    fn to_image(&self) -> DynamicImage;
+
+    /// Shows the image inline, using whatever graphics the terminal supports.
     fn display(&self) {
-        let mut buf: Vec<u8> = Vec::new();
-        self.draw_to(&mut buf);
-        download_file(&[("inline", "1")], &buf).unwrap();
-        println!()
+        self.display_with(Backend::detect())
+    }
+
+    fn display_with(&self, backend: Backend) {
+        if backend == Backend::None {
+            return;
+        }
+        let stdout = std::io::stdout();
+        terminal::display(&self.to_image(), backend, &mut stdout.lock()).unwrap();
     }
 }
 
 impl TermImage for DynamicImage {
-    fn draw_to<W: std::io::Write>(&self, writer: &mut W) {
-        // TODO: make it possible to choose the format maybe? Or provide a hint
-        //  "lossy" and "lossless"
-        self.write_to(writer, ImageOutputFormat::Png).unwrap();
+    fn to_image(&self) -> DynamicImage {
+        self.clone()
     }
 }
 
 impl TermImage for svg::Document {
-    fn draw_to<W: std::io::Write>(&self, writer: &mut W) {
+    fn to_image(&self) -> DynamicImage {
         let mut buf: Vec<u8> = Vec::new();
         write!(&mut buf, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",).unwrap();
         svg::write(&mut buf, self).unwrap();
@@ -36,8 +44,7 @@ impl TermImage for svg::Document {
         let sz = tree.svg_node().size;
         let rgba = img.make_rgba_vec();
         let data = ImageBuffer::from_vec(sz.width() as u32, sz.height() as u32, rgba).unwrap();
-        let x = DynamicImage::ImageRgba8(data);
-        x.draw_to(writer);
+        DynamicImage::ImageRgba8(data)
     }
 }
 
@@ -55,30 +62,33 @@ pub fn render_tone_curve(h: &histogram::Histogram, width: u32, height: u32) -> i
     DynamicImage::ImageLuma8(buf)
 }
 
-pub fn render_histogram(h: &histo::Histo, height: usize, width: usize) -> impl TermImage {
-    use svg::node::element::path::Data;
-    use svg::node::element::Path;
-    use svg::node::element::Rectangle;
-    use svg::Document;
-    let mut data = Data::new().move_to((0, height));
-    let mut x_pos = 0;
-    let max_bucket = h.iter().map(|bucket| bucket.count).max().unwrap();
-    for bucket in h.iter() {
-        let bucket_height = height * bucket.count / max_bucket;
-        data = data.line_to((x_pos, bucket_height));
-        x_pos += 1;
+/// The outline of `h`, filling a `width` by `height` box with its bottom-left corner at
+/// `(0, bottom)`. Buckets are stretched to fill the width.
+pub fn histogram_path(h: &histo::Histo, width: usize, height: usize, bottom: usize) -> Path {
+    let mut data = Data::new().move_to((0, bottom));
+    let bucket_width = width as f32 / h.iter().len() as f32;
+    let max_bucket = h.iter().map(|bucket| bucket.count).max().unwrap().max(1);
+    for (idx, bucket) in h.iter().enumerate() {
+        let top = bottom - height * bucket.count / max_bucket;
+        data = data
+            .line_to((idx as f32 * bucket_width, top))
+            .line_to(((idx + 1) as f32 * bucket_width, top));
     }
-    data = data.line_to((x_pos, height));
+    data = data.line_to((width, bottom)).close();
 
-    let path = Path::new()
+    Path::new()
         .set("fill", "grey")
         .set("stroke", "black")
         .set("stroke-width", 1)
-        .set("d", data);
+        .set("d", data)
+}
 
-    let document = Document::new()
+pub fn render_histogram(h: &histo::Histo, height: usize, width: usize) -> svg::Document {
+    Document::new()
+        .set("width", width)
+        .set("height", height)
         .set("viewBox", (0, 0, width, height))
-        .add(path)
+        .add(histogram_path(h, width, height, height))
         .add(
             Rectangle::new()
                 .set("width", width)
@@ -86,7 +96,5 @@ pub fn render_histogram(h: &histo::Histo, height: usize, width: usize) -> impl T
                 .set("stroke", "black")
                 .set("fill", "none")
                 .set("stroke-width", 1),
-        );
-
-    document
+        )
 }
@@ -17,8 +17,8 @@ impl Histo {
         let data = vec![0usize; num_buckets];
         Histo {
             data,
-            found_min: std::f32::MIN,
-            found_max: std::f32::MAX,
+            found_min: f32::MAX,
+            found_max: f32::MIN,
             total: 0,
         }
     }
@@ -32,6 +32,11 @@ impl Histo {
     }
 
     pub fn add(&mut self, item: f32) {
+        self.add_count(item, 1)
+    }
+
+    /// Adds `count` copies of `item`, e.g. from an existing table of counts.
+    pub fn add_count(&mut self, item: f32, count: usize) {
         if item > self.found_max {
             self.found_max = item;
         }
@@ -42,8 +47,8 @@ impl Histo {
         if index >= self.data.len() {
             index = self.data.len() - 1;
         }
-        self.data[index] += 1;
-        self.total += 1;
+        self.data[index] += count;
+        self.total += count;
     }
 
     pub fn iter(&self) -> ViewIter {
@@ -1,3 +1,9 @@
+pub mod convert;
 pub mod diagnostics;
 pub mod histo;
 pub mod pathutils;
+pub mod rawstats;
+pub mod terminal;
+
+#[macro_use]
+extern crate quick_error;
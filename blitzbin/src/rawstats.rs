@@ -0,0 +1,403 @@
+//! Statistics of a raw file's sensor data, before any processing, for judging exposure and noise.
+
+use crate::diagnostics::{histogram_path, TermImage};
+use crate::histo::Histo;
+use blitz::render::filter_map;
+use image::{ImageError, ImageFormat};
+use libraw::griditer::IndexWrapped2;
+use libraw::raf::RenderInfo;
+use libraw::Color;
+use rayon::prelude::*;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use svg::node::element::Rectangle;
+use svg::Document;
+
+/// Where the brightest part of the image is taken to be when working out ETTR headroom, as a
+/// percentile, so that a few specular highlights or hot pixels don't use it all up.
+pub const ETTR_PERCENTILE: f64 = 99.9;
+
+/// The number of buckets in each channel's histogram.
+const HISTOGRAM_BUCKETS: usize = 256;
+
+const COLORS: [Color; 3] = [Color::Red, Color::Green, Color::Blue];
+
+/// The size of each channel's histogram when drawn.
+const CHART_WIDTH: usize = 512;
+const CHART_HEIGHT: usize = 128;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SaveError {
+        Io(err: io::Error) {
+            from()
+            display("{}", err)
+        }
+        Image(err: ImageError) {
+            from()
+            display("Couldn't write image: {}", err)
+        }
+        UnknownFormat(path: PathBuf) {
+            display("Don't know how to save {}; use .png or .svg", path.display())
+        }
+    }
+}
+
+/// Values are black-subtracted.
+pub struct ChannelStats {
+    pub color: Color,
+    /// The number of photosites of this colour in the camera's crop.
+    pub count: u64,
+    /// Values as fractions of the range between black and white.
+    pub histogram: Histo,
+    pub mean: f64,
+    pub median: u16,
+    /// The value that `ETTR_PERCENTILE` percent of photosites are at or below.
+    pub bright: u16,
+    pub max: u16,
+    /// Photosites at or above the saturation threshold.
+    pub saturated: u64,
+}
+
+/// The pixels outside the camera's crop. Where they're masked from light, as they are on many
+/// sensors, their spread around the black level estimates the read noise.
+pub struct BorderStats {
+    pub count: u64,
+    /// The mean difference from the black level.
+    pub offset: f64,
+    /// The standard deviation.
+    pub noise: f64,
+}
+
+pub struct RawStats {
+    pub white_level: u16,
+    /// The mean of the black level pattern.
+    pub black_level: f64,
+    /// Red, green and blue, for the pixels in the camera's crop.
+    pub channels: Vec<ChannelStats>,
+    /// `None` if the crop covers the whole sensor.
+    pub border: Option<BorderStats>,
+}
+
+/// Counts of each black-subtracted value for each colour in the crop, and sums over the border.
+struct Counts {
+    values: [Vec<u64>; 3],
+    saturated: [u64; 3],
+    border_count: u64,
+    border_sum: f64,
+    border_sum_sq: f64,
+}
+
+impl Counts {
+    fn new(white_level: u16) -> Self {
+        let values = || vec![0; white_level as usize + 1];
+        Counts {
+            values: [values(), values(), values()],
+            saturated: [0; 3],
+            border_count: 0,
+            border_sum: 0.0,
+            border_sum_sq: 0.0,
+        }
+    }
+
+    fn add(mut self, other: Counts) -> Self {
+        for (ours, theirs) in self.values.iter_mut().zip(other.values.iter()) {
+            for (a, b) in ours.iter_mut().zip(theirs) {
+                *a += b;
+            }
+        }
+        for (a, b) in self.saturated.iter_mut().zip(other.saturated.iter()) {
+            *a += b;
+        }
+        self.border_count += other.border_count;
+        self.border_sum += other.border_sum;
+        self.border_sum_sq += other.border_sum_sq;
+        self
+    }
+}
+
+/// The smallest value which at least `percentile` percent of `counts` are at or below.
+fn percentile(counts: &[u64], total: u64, percentile: f64) -> u16 {
+    let target = ((percentile / 100.0) * total as f64).ceil() as u64;
+    let mut seen = 0;
+    for (value, &count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= target.max(1) {
+            return value as u16;
+        }
+    }
+    (counts.len() - 1) as u16
+}
+
+impl RawStats {
+    pub fn compute(ri: &RenderInfo) -> RawStats {
+        let mapping = filter_map(ri);
+        let (width, height) = (ri.width as usize, ri.height as usize);
+        let white_level = ri.white_level();
+        let threshold = ri.saturation_threshold();
+        let crop = ri.crop_rect;
+
+        let counts = (0..height)
+            .into_par_iter()
+            .fold(
+                || Counts::new(white_level),
+                |mut counts, y| {
+                    let row = &ri.raw_data[y * width..(y + 1) * width];
+                    let in_crop_rows = (crop.top..crop.bottom).contains(&y);
+                    for (x, &val) in row.iter().enumerate() {
+                        let &black = ri.black_levels.index_wrapped(x, y);
+                        if in_crop_rows && (crop.left..crop.right).contains(&x) {
+                            let color = mapping.index_wrapped(x, y).idx();
+                            // Some files have values above what their bit depth allows, which
+                            // are as clipped as the white level itself.
+                            let value = val.min(white_level).saturating_sub(black);
+                            counts.values[color][value as usize] += 1;
+                            if val >= threshold {
+                                counts.saturated[color] += 1;
+                            }
+                        } else {
+                            let diff = val as f64 - black as f64;
+                            counts.border_count += 1;
+                            counts.border_sum += diff;
+                            counts.border_sum_sq += diff * diff;
+                        }
+                    }
+                    counts
+                },
+            )
+            .reduce(|| Counts::new(white_level), Counts::add);
+
+        let black_level = ri.black_levels.iter().map(|&b| b as f64).sum::<f64>()
+            / ri.black_levels.len().max(1) as f64;
+        let range = (white_level as f64 - black_level).max(1.0);
+        let channels = COLORS
+            .iter()
+            .map(|&color| {
+                let values = &counts.values[color.idx()];
+                let count = values.iter().sum();
+                let mut histogram = Histo::with_buckets(HISTOGRAM_BUCKETS);
+                let mut sum = 0.0;
+                for (value, &n) in values.iter().enumerate().filter(|(_, &n)| n > 0) {
+                    histogram.add_count(value as f32 / range as f32, n as usize);
+                    sum += value as f64 * n as f64;
+                }
+                ChannelStats {
+                    color,
+                    count,
+                    histogram,
+                    mean: sum / count.max(1) as f64,
+                    median: percentile(values, count, 50.0),
+                    bright: percentile(values, count, ETTR_PERCENTILE),
+                    max: values.iter().rposition(|&n| n > 0).unwrap_or(0) as u16,
+                    saturated: counts.saturated[color.idx()],
+                }
+            })
+            .collect();
+
+        let border = if counts.border_count > 0 {
+            let n = counts.border_count as f64;
+            let offset = counts.border_sum / n;
+            let variance = (counts.border_sum_sq / n - offset * offset).max(0.0);
+            Some(BorderStats {
+                count: counts.border_count,
+                offset,
+                noise: variance.sqrt(),
+            })
+        } else {
+            None
+        };
+
+        RawStats {
+            white_level,
+            black_level,
+            channels,
+            border,
+        }
+    }
+
+    /// The fraction of photosites in the crop which are saturated, from 0 to 1.
+    pub fn saturated_fraction(&self) -> f64 {
+        let saturated: u64 = self.channels.iter().map(|c| c.saturated).sum();
+        let total: u64 = self.channels.iter().map(|c| c.count).sum();
+        saturated as f64 / total.max(1) as f64
+    }
+
+    /// How many stops `channel` could be brightened by before its brightest part saturated.
+    /// `None` if it's entirely black.
+    pub fn channel_headroom(&self, channel: &ChannelStats) -> Option<f64> {
+        if channel.bright == 0 {
+            return None;
+        }
+        let range = self.white_level as f64 - self.black_level;
+        Some((range / channel.bright as f64).log2().max(0.0))
+    }
+
+    /// How many stops the exposure could be increased by before any channel's brightest part
+    /// saturated, i.e. how far the photo is from being exposed to the right.
+    pub fn ettr_headroom(&self) -> Option<f64> {
+        self.channels
+            .iter()
+            .filter_map(|channel| self.channel_headroom(channel))
+            .fold(None, |min, headroom| {
+                Some(min.map_or(headroom, |min: f64| min.min(headroom)))
+            })
+    }
+
+    /// Draws each channel's histogram in its colour, red at the top.
+    pub fn to_svg(&self) -> Document {
+        let total_height = CHART_HEIGHT * self.channels.len();
+        let mut document = Document::new()
+            .set("width", CHART_WIDTH)
+            .set("height", total_height)
+            .set("viewBox", (0, 0, CHART_WIDTH, total_height));
+        for (idx, channel) in self.channels.iter().enumerate() {
+            let fill = match channel.color {
+                Color::Red => "#d33",
+                Color::Green => "#3a3",
+                Color::Blue => "#36d",
+            };
+            let bottom = CHART_HEIGHT * (idx + 1);
+            document = document
+                .add(
+                    histogram_path(&channel.histogram, CHART_WIDTH, CHART_HEIGHT, bottom)
+                        .set("fill", fill),
+                )
+                .add(
+                    Rectangle::new()
+                        .set("y", bottom - CHART_HEIGHT)
+                        .set("width", CHART_WIDTH)
+                        .set("height", CHART_HEIGHT)
+                        .set("stroke", "black")
+                        .set("fill", "none")
+                        .set("stroke-width", 1),
+                );
+        }
+        document
+    }
+
+    /// Saves `to_svg` to `path`, as an SVG or PNG depending on its extension.
+    pub fn save_histograms(&self, path: &Path) -> Result<(), SaveError> {
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("svg") => svg::save(path, &self.to_svg())?,
+            Some("png") => self
+                .to_svg()
+                .to_image()
+                .save_with_format(path, ImageFormat::Png)?,
+            _ => return Err(SaveError::UnknownFormat(path.to_path_buf())),
+        }
+        Ok(())
+    }
+}
+
+fn format_headroom(headroom: Option<f64>) -> String {
+    headroom.map_or("-".to_string(), |stops| format!("{:.2} stops", stops))
+}
+
+impl fmt::Display for RawStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "White level {}, black level {:.1}",
+            self.white_level, self.black_level
+        )?;
+        match &self.border {
+            Some(border) => writeln!(
+                f,
+                "Border: {} pixels, {:+.2} from black, noise {:.2}",
+                border.count, border.offset, border.noise
+            )?,
+            None => writeln!(f, "Border: none")?,
+        }
+        writeln!(f, "Values are black-subtracted.")?;
+        writeln!(
+            f,
+            "{:<8}{:>12}{:>10}{:>8}{:>8}{:>8}{:>12}{:>14}",
+            "Channel", "Photosites", "Mean", "Median", "99.9%", "Max", "Saturated", "Headroom"
+        )?;
+        for channel in &self.channels {
+            writeln!(
+                f,
+                "{:<8}{:>12}{:>10.1}{:>8}{:>8}{:>8}{:>11.3}%{:>14}",
+                format!("{:?}", channel.color),
+                channel.count,
+                channel.mean,
+                channel.median,
+                channel.bright,
+                channel.max,
+                100.0 * channel.saturated as f64 / channel.count.max(1) as f64,
+                format_headroom(self.channel_headroom(channel)),
+            )?;
+        }
+        writeln!(
+            f,
+            "Saturated: {:.3}% of photosites",
+            100.0 * self.saturated_fraction()
+        )?;
+        write!(
+            f,
+            "ETTR headroom: {}",
+            format_headroom(self.ettr_headroom())
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libraw::raf::CropRect;
+    use libraw::raw_image::{RawImage, TestSensor};
+
+    #[test]
+    fn counts_by_colour_and_border() {
+        // A 12-bit 6x6 Bayer sensor, with a one pixel border around the crop.
+        let mut sensor = TestSensor::bayer(6, 6, 12);
+        sensor.black_level = 256;
+        sensor.crop_rect = CropRect {
+            left: 1,
+            right: 5,
+            top: 1,
+            bottom: 5,
+        };
+        for y in 0..6 {
+            for x in 0..6 {
+                let border = x == 0 || y == 0 || x == 5 || y == 5;
+                sensor.data[x + y * 6] = match (border, sensor.color_at(x, y)) {
+                    // Noise of +/-2 around the black level.
+                    (true, _) if (x + y) % 2 == 0 => 254,
+                    (true, _) => 258,
+                    (false, Color::Red) => 256 + 1000,
+                    (false, Color::Green) => 4095,
+                    (false, Color::Blue) => 100,
+                }
+            }
+        }
+        // A green photosite above the white level, which some uncompressed files have.
+        sensor.data[2 + 6] = 5000;
+        let ri = sensor.render_info();
+
+        let stats = RawStats::compute(&ri);
+        assert_eq!(stats.white_level, 4095);
+        let counts: Vec<_> = stats.channels.iter().map(|c| c.count).collect();
+        assert_eq!(counts, [4, 8, 4]);
+        let red = &stats.channels[0];
+        assert_eq!((red.median, red.bright, red.max), (1000, 1000, 1000));
+        assert_eq!(red.mean, 1000.0);
+        // Blue is below the black level, so it's clamped to 0.
+        assert_eq!(stats.channels[2].max, 0);
+        assert_eq!(stats.channels[1].saturated, 8);
+        assert_eq!(stats.channels[1].max, 4095 - 256);
+        assert_eq!(stats.saturated_fraction(), 0.5);
+        // Green is saturated, and blue is black.
+        assert_eq!(stats.channel_headroom(&stats.channels[1]), Some(0.0));
+        assert_eq!(stats.channel_headroom(&stats.channels[2]), None);
+        assert_eq!(stats.ettr_headroom(), Some(0.0));
+        let red_headroom = stats.channel_headroom(red).unwrap();
+        assert!((red_headroom - (3839.0f64 / 1000.0).log2()).abs() < 1e-9);
+
+        let border = stats.border.unwrap();
+        assert_eq!(border.count, 20);
+        assert_eq!((border.offset, border.noise), (0.0, 2.0));
+    }
+}
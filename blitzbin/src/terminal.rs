@@ -0,0 +1,294 @@
+//! Shows images inline in the terminal, using whichever graphics protocol it supports.
+
+use color_quant::NeuQuant;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageOutputFormat, RgbImage, RgbaImage};
+use std::env;
+use std::io::{self, Write};
+
+/// Overrides auto-detection, e.g. `BLITZ_TERM_GRAPHICS=blocks`.
+const OVERRIDE_VAR: &str = "BLITZ_TERM_GRAPHICS";
+
+/// The maximum number of bytes in each chunk of a kitty graphics command.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// iTerm2's inline image protocol (also understood by WezTerm).
+    Iterm2,
+    /// The kitty graphics protocol.
+    Kitty,
+    Sixel,
+    /// Unicode upper half blocks with truecolour escapes, which work almost everywhere, at two
+    /// pixels per character cell.
+    HalfBlocks,
+    /// Don't display anything.
+    None,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "iterm2" => Some(Backend::Iterm2),
+            "kitty" => Some(Backend::Kitty),
+            "sixel" => Some(Backend::Sixel),
+            "blocks" => Some(Backend::HalfBlocks),
+            "none" => Some(Backend::None),
+            _ => None,
+        }
+    }
+
+    /// Picks a backend for the current terminal. Nothing is displayed if stdout isn't a terminal.
+    pub fn detect() -> Backend {
+        if !atty::is(atty::Stream::Stdout) {
+            return Backend::None;
+        }
+        Backend::detect_from(|name| env::var(name).ok())
+    }
+
+    /// Picks a backend based on environment variables, looked up with `var`.
+    pub fn detect_from(var: impl Fn(&str) -> Option<String>) -> Backend {
+        if let Some(backend) = var(OVERRIDE_VAR).and_then(|name| Backend::from_name(&name)) {
+            return backend;
+        }
+
+        let term = var("TERM").unwrap_or_default();
+        let term_program = var("TERM_PROGRAM").unwrap_or_default();
+        if term_program == "iTerm.app"
+            || term_program == "WezTerm"
+            || var("LC_TERMINAL").as_deref() == Some("iTerm2")
+        {
+            Backend::Iterm2
+        } else if var("KITTY_WINDOW_ID").is_some() || term == "xterm-kitty" {
+            Backend::Kitty
+        } else if term.contains("sixel")
+            || ["mlterm", "foot", "yaft"]
+                .iter()
+                .any(|prefix| term.starts_with(prefix))
+        {
+            Backend::Sixel
+        } else if term == "dumb" {
+            Backend::None
+        } else {
+            Backend::HalfBlocks
+        }
+    }
+}
+
+/// Writes `img` to `out` using `backend`.
+pub fn display(img: &DynamicImage, backend: Backend, out: &mut impl Write) -> io::Result<()> {
+    match backend {
+        Backend::Iterm2 => write_iterm2(img, out),
+        Backend::Kitty => write_kitty(img, out),
+        Backend::Sixel => write_sixel(&img.to_rgba8(), out),
+        Backend::HalfBlocks => {
+            let columns = env::var("COLUMNS")
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(80);
+            write_half_blocks(&fit_columns(img, columns), out)
+        }
+        Backend::None => Ok(()),
+    }
+}
+
+fn write_png(img: &DynamicImage, out: &mut Vec<u8>) -> io::Result<()> {
+    img.write_to(out, ImageOutputFormat::Png)
+        .map_err(io::Error::other)
+}
+
+/// Sends `img` as an inline file, with iTerm2's OSC 1337 sequence.
+fn write_iterm2(img: &DynamicImage, out: &mut impl Write) -> io::Result<()> {
+    let mut png = vec![];
+    write_png(img, &mut png)?;
+    write!(out, "\x1b]1337;File=inline=1;size={}:", png.len())?;
+    out.write_all(base64::encode(&png).as_bytes())?;
+    writeln!(out, "\x07")
+}
+
+fn write_kitty(img: &DynamicImage, out: &mut impl Write) -> io::Result<()> {
+    let mut png = vec![];
+    write_png(img, &mut png)?;
+    let encoded = base64::encode(&png);
+    let chunks: Vec<_> = encoded.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        // Only the first chunk carries the image's properties: transmit and display a PNG.
+        let keys = if i == 0 { "a=T,f=100," } else { "" };
+        write!(out, "\x1b_G{}m={};", keys, more)?;
+        out.write_all(chunk)?;
+        write!(out, "\x1b\\")?;
+    }
+    writeln!(out)
+}
+
+/// Encodes `img` as Sixel graphics, with a palette of up to 256 colours.
+fn write_sixel(img: &RgbaImage, out: &mut impl Write) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    let quant = NeuQuant::new(10, 256, img.as_raw());
+    let indices: Vec<usize> = img.pixels().map(|p| quant.index_of(&p.0)).collect();
+    let palette = quant.color_map_rgb();
+    let colours = palette.len() / 3;
+
+    write!(out, "\x1bPq\"1;1;{};{}", width, height)?;
+    for (i, rgb) in palette.chunks(3).enumerate() {
+        let pct = |c: u8| c as u32 * 100 / 255;
+        write!(
+            out,
+            "#{};2;{};{};{}",
+            i,
+            pct(rgb[0]),
+            pct(rgb[1]),
+            pct(rgb[2])
+        )?;
+    }
+
+    // Each row of sixels covers six rows of pixels. Within a row, we draw each colour in turn,
+    // returning to the start of the row in between.
+    for band in 0..(height as usize).div_ceil(6) {
+        let rows = band * 6..(band * 6 + 6).min(height as usize);
+        let mut sixels = vec![0u8; width as usize];
+        for colour in 0..colours {
+            let mut used = false;
+            for (x, sixel) in sixels.iter_mut().enumerate() {
+                *sixel = 0;
+                for (bit, y) in rows.clone().enumerate() {
+                    if indices[y * width as usize + x] == colour {
+                        *sixel |= 1 << bit;
+                        used = true;
+                    }
+                }
+            }
+            if !used {
+                continue;
+            }
+            write!(out, "#{}", colour)?;
+            write_sixel_runs(&sixels, out)?;
+            write!(out, "$")?;
+        }
+        write!(out, "-")?;
+    }
+    writeln!(out, "\x1b\\")
+}
+
+/// Writes a row of sixels, run-length encoding repeats.
+fn write_sixel_runs(sixels: &[u8], out: &mut impl Write) -> io::Result<()> {
+    let mut i = 0;
+    while i < sixels.len() {
+        let run = sixels[i..].iter().take_while(|&&s| s == sixels[i]).count();
+        let c = (63 + sixels[i]) as char;
+        if run > 3 {
+            write!(out, "!{}{}", run, c)?;
+        } else {
+            for _ in 0..run {
+                write!(out, "{}", c)?;
+            }
+        }
+        i += run;
+    }
+    Ok(())
+}
+
+/// Shrinks `img` so that it's at most `columns` pixels wide, which is how many half blocks fit
+/// across the terminal.
+fn fit_columns(img: &DynamicImage, columns: u32) -> RgbImage {
+    let img = img.to_rgb8();
+    if img.width() <= columns {
+        return img;
+    }
+    let height = (img.height() as u64 * columns as u64 / img.width() as u64).max(1) as u32;
+    imageops::resize(&img, columns, height, FilterType::Triangle)
+}
+
+/// Draws two rows of pixels per line of text: the upper half block takes the foreground colour
+/// from the top pixel, and the background colour from the bottom one.
+fn write_half_blocks(img: &RgbImage, out: &mut impl Write) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let [r, g, b] = img.get_pixel(x, y).0;
+            write!(out, "\x1b[38;2;{};{};{}m", r, g, b)?;
+            if y + 1 < height {
+                let [r, g, b] = img.get_pixel(x, y + 1).0;
+                write!(out, "\x1b[48;2;{};{};{}m", r, g, b)?;
+            } else {
+                write!(out, "\x1b[49m")?;
+            }
+            write!(out, "\u{2580}")?;
+        }
+        writeln!(out, "\x1b[0m")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Rgb, Rgba};
+    use std::collections::HashMap;
+
+    fn detect(vars: &[(&str, &str)]) -> Backend {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        Backend::detect_from(|name| vars.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn detects_from_environment() {
+        assert_eq!(detect(&[("TERM_PROGRAM", "iTerm.app")]), Backend::Iterm2);
+        assert_eq!(detect(&[("TERM", "xterm-kitty")]), Backend::Kitty);
+        assert_eq!(detect(&[("TERM", "foot")]), Backend::Sixel);
+        assert_eq!(detect(&[("TERM", "xterm-256color")]), Backend::HalfBlocks);
+        assert_eq!(
+            detect(&[("TERM", "xterm-kitty"), (OVERRIDE_VAR, "none")]),
+            Backend::None
+        );
+    }
+
+    #[test]
+    fn half_blocks() {
+        let img = RgbImage::from_fn(1, 3, |_, y| Rgb([y as u8, 0, 0]));
+        let mut out = vec![];
+        write_half_blocks(&img, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b[38;2;0;0;0m\x1b[48;2;1;0;0m\u{2580}\x1b[0m\n\
+             \x1b[38;2;2;0;0m\x1b[49m\u{2580}\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn iterm2_image() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(2, 2));
+        let mut png = vec![];
+        write_png(&img, &mut png).unwrap();
+        let mut out = vec![];
+        display(&img, Backend::Iterm2, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "\x1b]1337;File=inline=1;size={}:{}\x07\n",
+                png.len(),
+                base64::encode(&png)
+            )
+        );
+    }
+
+    #[test]
+    fn sixel_runs() {
+        let mut out = vec![];
+        write_sixel_runs(&[0, 0, 0, 0, 0, 1, 63], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "!5?@~");
+    }
+
+    #[test]
+    fn sixel_image() {
+        let img = RgbaImage::from_pixel(2, 7, Rgba([255, 255, 255, 255]));
+        let mut out = vec![];
+        write_sixel(&img, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1bPq\"1;1;2;7#"));
+        // Two bands: six full rows, then one.
+        assert!(out.contains("~~$-#"));
+        assert!(out.ends_with("@@$-\x1b\\\n"));
+    }
+}
@@ -0,0 +1,206 @@
+use blitz::sidecar::SidecarError;
+use blitz::xmp::XmpError;
+use libc::c_char;
+use libraw::raf::RafError;
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+/// The result of a call to the C API. Anything other than `Ok` also sets the message returned by
+/// `blitz_last_error_message`.
+///
+/// cbindgen:prefix-with-name
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BlitzStatus {
+    Ok,
+    /// A required pointer was null, or a string wasn't valid UTF-8.
+    InvalidArgument,
+    /// A file couldn't be read or written.
+    Io,
+    /// A file was read, but isn't something we can decode.
+    InvalidFile,
+    /// There was nothing to load, e.g. no saved settings.
+    NotFound,
+    Cancelled,
+    /// Something went wrong inside blitz. This is always a bug.
+    Panic,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub status: BlitzStatus,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(status: BlitzStatus, message: impl Into<String>) -> Self {
+        Error {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<RafError> for Error {
+    fn from(err: RafError) -> Self {
+        match err {
+            RafError::Io(err) => {
+                Error::new(BlitzStatus::Io, format!("Couldn't read file: {}", err))
+            }
+            RafError::Unknown => {
+                Error::new(BlitzStatus::InvalidFile, "Couldn't decode the RAF file")
+            }
+            RafError::Cancelled => Error::new(BlitzStatus::Cancelled, "Cancelled"),
+        }
+    }
+}
+
+impl From<SidecarError> for Error {
+    fn from(err: SidecarError) -> Self {
+        let status = match err {
+            SidecarError::Io(_) => BlitzStatus::Io,
+            _ => BlitzStatus::InvalidFile,
+        };
+        Error::new(status, err.to_string())
+    }
+}
+
+impl From<XmpError> for Error {
+    fn from(err: XmpError) -> Self {
+        let status = match err {
+            XmpError::Io(_) => BlitzStatus::Io,
+            XmpError::Xml(_) => BlitzStatus::InvalidFile,
+        };
+        Error::new(status, err.to_string())
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+pub fn set_last_error(message: &str) {
+    // Messages shouldn't contain NULs, but if one does, keep everything before it.
+    let message = message.split('\0').next().unwrap_or_default();
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("Internal error: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("Internal error: {}", message)
+    } else {
+        "Internal error".to_string()
+    }
+}
+
+/// Runs `f`, turning a panic into an error. Panics must never unwind into the host app, so
+/// everything that the host app calls goes through here.
+pub fn run<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => Err(Error::new(
+            BlitzStatus::Panic,
+            panic_message(payload.as_ref()),
+        )),
+    }
+}
+
+/// Like `run`, but returns the status, keeping any error message for `blitz_last_error_message`.
+pub fn catch(f: impl FnOnce() -> Result<(), Error>) -> BlitzStatus {
+    match run(f) {
+        Ok(()) => BlitzStatus::Ok,
+        Err(error) => {
+            set_last_error(&error.message);
+            error.status
+        }
+    }
+}
+
+/// Like `catch`, for entry points which have no way of reporting failure.
+pub fn catch_silently(f: impl FnOnce()) {
+    catch(|| {
+        f();
+        Ok(())
+    });
+}
+
+fn null(name: &str) -> Error {
+    Error::new(BlitzStatus::InvalidArgument, format!("{} is null", name))
+}
+
+/// Borrows the object behind a pointer that was passed in by the host app.
+///
+/// # Safety
+///
+/// `ptr` must be null, or point to a valid `T` which isn't being modified for `'a`.
+pub unsafe fn arg<'a, T>(ptr: *const T, name: &str) -> Result<&'a T, Error> {
+    ptr.as_ref().ok_or_else(|| null(name))
+}
+
+/// Checks that an out-parameter isn't null, so that failures are reported before any work is done.
+pub fn out_arg<T>(ptr: *mut T, name: &str) -> Result<*mut T, Error> {
+    if ptr.is_null() {
+        Err(null(name))
+    } else {
+        Ok(ptr)
+    }
+}
+
+/// Returns a description of the most recent failure on the calling thread, or null if nothing has
+/// failed yet. The string is owned by blitz, and is valid until the next failing call on the same
+/// thread.
+#[no_mangle]
+pub extern "C" fn blitz_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn last_message() -> String {
+        let message = blitz_last_error_message();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn errors_and_panics_become_statuses() {
+        assert_eq!(catch(|| Ok(())), BlitzStatus::Ok);
+
+        let status = catch(|| Err(Error::new(BlitzStatus::NotFound, "Nothing here")));
+        assert_eq!(status, BlitzStatus::NotFound);
+        assert_eq!(last_message(), "Nothing here");
+
+        let status = catch(|| panic!("Oh no {}", 1));
+        assert_eq!(status, BlitzStatus::Panic);
+        assert_eq!(last_message(), "Internal error: Oh no 1");
+
+        // Successes leave the last message alone.
+        assert_eq!(catch(|| Ok(())), BlitzStatus::Ok);
+        assert_eq!(last_message(), "Internal error: Oh no 1");
+    }
+
+    #[test]
+    fn null_arguments_are_errors() {
+        let status = catch(|| {
+            unsafe { arg::<u32>(ptr::null(), "renderer") }?;
+            Ok(())
+        });
+        assert_eq!(status, BlitzStatus::InvalidArgument);
+        assert_eq!(last_message(), "renderer is null");
+    }
+}
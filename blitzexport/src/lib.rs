@@ -1,22 +1,65 @@
+// Every entry point checks its pointers for null before using them, but can't check that they
+// point at the right thing; that's up to the host app, as it is for any C API.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
+mod error;
+mod metadata;
 mod render_settings;
 mod structs;
 
-use crate::structs::{ImageAndHistogram, RawImage};
+use crate::error::{arg, catch, catch_silently, out_arg, run, BlitzStatus, Error};
+use crate::structs::{
+    HistogramBins, HistogramScale, Histograms, ImageAndHistogram, Overlay, RawImage,
+    RenderCancellation,
+};
+use blitz::analysis::resolve_levels;
+use blitz::context::{CancellationToken, RenderContext};
 use blitz::diagnostics::histogram::ToHistogram;
-use blitz::render::{render_raw, render_raw_with_settings};
-use libc::c_char;
-use render_settings::RenderSettings;
-use std::ffi::CStr;
-use structs::{Buffer, RawRenderer};
-
-#[no_mangle]
-pub extern "C" fn raw_renderer_new(filename: *const c_char) -> *mut RawRenderer<'static> {
-    let c_str = unsafe {
-        assert!(!filename.is_null());
+use blitz::render::{
+    render_preview, render_raw, render_raw_with_context, render_raw_with_histograms,
+    render_raw_with_settings, render_region,
+};
+use blitz::sidecar::{load_sidecar, save_sidecar};
+use blitz::xmp::{load_xmp, save_xmp};
+use libc::{c_char, c_void};
+use libraw::raf::CropRect;
+use libraw::util::timing::StageTimer;
+use log::warn;
+use metadata::{Metadata, PixelRect};
+use render_settings::{Levels, RenderSettings};
+use std::ffi::{CStr, CString};
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicU64, Ordering};
+use structs::{Buffer, RawRenderer, Source};
 
-        CStr::from_ptr(filename)
+fn with_histogram(img: image::RgbImage) -> ImageAndHistogram {
+    let histo = {
+        let _timer = StageTimer::new("Histogram");
+        img.histogram()
     };
-    Box::into_raw(Box::new(RawRenderer::new(c_str.to_str().unwrap())))
+    ImageAndHistogram {
+        img: RawImage::from_rgb_image(img),
+        histogram: RawImage::from_rgba_image(histo.to_img(256, 128)),
+    }
+}
+
+/// Opens `filename`, and stores a renderer for it in `out`, which must be freed with
+/// `raw_renderer_free`.
+#[no_mangle]
+pub extern "C" fn raw_renderer_new(
+    filename: *const c_char,
+    out: *mut *mut RawRenderer,
+) -> BlitzStatus {
+    catch(|| {
+        let filename = unsafe { arg(filename, "filename") }?;
+        let out = out_arg(out, "out")?;
+        let filename = unsafe { CStr::from_ptr(filename) }
+            .to_str()
+            .map_err(|_| Error::new(BlitzStatus::InvalidArgument, "filename isn't valid UTF-8"))?;
+        let renderer = RawRenderer::new(filename)?;
+        unsafe { out.write(Box::into_raw(Box::new(renderer))) };
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -24,53 +67,564 @@ pub extern "C" fn raw_renderer_free(ptr: *mut RawRenderer) {
     if ptr.is_null() {
         return;
     }
-    unsafe {
-        Box::from_raw(ptr);
-    }
+    catch_silently(|| unsafe { drop(Box::from_raw(ptr)) });
 }
 
+/// Stores a copy of the camera's JPEG preview in `out`.
 #[no_mangle]
-pub extern "C" fn raw_renderer_get_preview(ptr: *mut RawRenderer) -> Buffer {
-    let renderer = unsafe {
-        assert!(!ptr.is_null());
-        &mut *ptr
-    };
-    // this is a copy
-    return Buffer::from_byte_vec(renderer.file.parse_preview().unwrap().to_vec());
+pub extern "C" fn raw_renderer_get_preview(ptr: *mut RawRenderer, out: *mut Buffer) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let preview = renderer.source().file.parse_preview()?.to_vec();
+        unsafe { out.write(Buffer::from_byte_vec(preview)) };
+        Ok(())
+    })
 }
 
+/// Stores the camera, exposure and sensor details in `out`, which must be freed with
+/// `free_metadata`. This doesn't decode the image, so it's quick.
 #[no_mangle]
-pub extern "C" fn raw_renderer_render_image(ptr: *mut RawRenderer) -> RawImage {
-    let renderer = unsafe {
-        assert!(!ptr.is_null());
-        &mut *ptr
-    };
-    let img = render_raw(renderer.ensure_parsed());
-    RawImage::from_rgb_image(img)
+pub extern "C" fn raw_renderer_get_metadata(
+    ptr: *const RawRenderer,
+    out: *mut Metadata,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let metadata = Metadata::load(&renderer.source().file)?;
+        unsafe { out.write(metadata) };
+        Ok(())
+    })
+}
+
+/// Renders the image with default settings into `out`.
+#[no_mangle]
+pub extern "C" fn raw_renderer_render_image(
+    ptr: *mut RawRenderer,
+    out: *mut RawImage,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let img = render_raw(&*renderer.source().ensure_parsed()?);
+        unsafe { out.write(RawImage::from_rgb_image(img)) };
+        Ok(())
+    })
 }
 
+/// Renders the image with `settings` into `out`.
 #[no_mangle]
 pub extern "C" fn raw_renderer_render_with_settings(
     ptr: *mut RawRenderer,
     settings: RenderSettings,
-) -> ImageAndHistogram {
-    let renderer = unsafe {
-        assert!(!ptr.is_null());
-        &mut *ptr
-    };
+    out: *mut ImageAndHistogram,
+) -> BlitzStatus {
+    raw_renderer_render_with_progress(
+        ptr,
+        settings,
+        None,
+        std::ptr::null_mut(),
+        std::ptr::null(),
+        out,
+    )
+}
 
-    let img = render_raw_with_settings(renderer.ensure_parsed(), &settings.to_blitz_settings());
-    println!("Computing histograms");
-    let histo = img.histogram();
+/// The most bins `raw_renderer_render_with_histograms` will count into.
+const MAX_HISTOGRAM_BINS: u32 = 1 << 16;
 
-    ImageAndHistogram {
-        img: RawImage::from_rgb_image(img),
-        histogram: RawImage::from_rgba_image(histo.to_img(256, 128)),
+/// Renders the image with `settings` into `out`, and stores histograms of it in `histograms`,
+/// which must be freed with `free_histograms`. Each histogram has `bin_count` bins, which must be
+/// between 1 and 65536, with bar heights scaled by `scale`.
+#[no_mangle]
+pub extern "C" fn raw_renderer_render_with_histograms(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+    bin_count: u32,
+    scale: HistogramScale,
+    out: *mut RawImage,
+    histograms: *mut Histograms,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let histograms = out_arg(histograms, "histograms")?;
+        if !(1..=MAX_HISTOGRAM_BINS).contains(&bin_count) {
+            return Err(Error::new(
+                BlitzStatus::InvalidArgument,
+                format!("bin_count must be between 1 and {}", MAX_HISTOGRAM_BINS),
+            ));
+        }
+
+        let parsed = renderer.source().ensure_parsed()?;
+        let (img, bins) = render_raw_with_histograms(
+            &*parsed,
+            &unsafe { settings.to_blitz_settings() },
+            &RenderContext::new(),
+            bin_count as usize,
+        )
+        .map_err(|_| Error::new(BlitzStatus::Cancelled, "Cancelled"))?;
+        unsafe {
+            out.write(RawImage::from_rgb_image(img));
+            histograms.write(Histograms {
+                linear: HistogramBins::new(&bins.linear, scale),
+                output: HistogramBins::new(&bins.output, scale),
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Renders the image with `settings` into `out`, and draws `overlay` for it into `overlay_out`, as
+/// an RGBA image of the same size.
+#[no_mangle]
+pub extern "C" fn raw_renderer_render_with_overlay(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+    overlay: Overlay,
+    out: *mut RawImage,
+    overlay_out: *mut RawImage,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let overlay_out = out_arg(overlay_out, "overlay_out")?;
+        let parsed = renderer.source().ensure_parsed()?;
+        let settings = unsafe { settings.to_blitz_settings() };
+        let img = render_raw_with_settings(&*parsed, &settings);
+        let mask =
+            blitz::diagnostics::overlay::Overlay::from(overlay).draw(&*parsed, &settings, &img);
+        unsafe {
+            out.write(RawImage::from_rgb_image(img));
+            overlay_out.write(RawImage::from_rgba_image(mask));
+        }
+        Ok(())
+    })
+}
+
+/// Renders a quick, downscaled version of the image with `settings` into `out`, no larger than
+/// `max_dimension` pixels along its longest edge.
+#[no_mangle]
+pub extern "C" fn raw_renderer_render_preview(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+    max_dimension: u32,
+    out: *mut RawImage,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let parsed = renderer.source().ensure_parsed()?;
+        let settings = unsafe { settings.to_blitz_settings() };
+        let img = render_preview(&*parsed, &settings, max_dimension);
+        unsafe { out.write(RawImage::from_rgb_image(img)) };
+        Ok(())
+    })
+}
+
+/// Renders just `rect` of the image with `settings` into `out`, scaled by `scale` (at most 1), for
+/// zooming in without rendering everything. `rect` is in pixels of the camera's crop, ignoring
+/// the crop and orientation in `settings`.
+#[no_mangle]
+pub extern "C" fn raw_renderer_render_region(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+    rect: PixelRect,
+    scale: f32,
+    out: *mut RawImage,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        if scale.is_nan() || scale <= 0.0 {
+            return Err(Error::new(
+                BlitzStatus::InvalidArgument,
+                "scale must be greater than 0",
+            ));
+        }
+        let parsed = renderer.source().ensure_parsed()?;
+        let settings = unsafe { settings.to_blitz_settings() };
+        let rect = CropRect {
+            left: rect.left as usize,
+            right: rect.left as usize + rect.width as usize,
+            top: rect.top as usize,
+            bottom: rect.top as usize + rect.height as usize,
+        };
+        let img = render_region(&*parsed, &settings, rect, scale);
+        unsafe { out.write(RawImage::from_rgb_image(img)) };
+        Ok(())
+    })
+}
+
+/// Stores the levels that rendering with `settings` would use in `out`. With auto-contrast on,
+/// these show what it measured, so that the host app can turn it off and tweak them.
+#[no_mangle]
+pub extern "C" fn raw_renderer_resolve_levels(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+    out: *mut Levels,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let parsed = renderer.source().ensure_parsed()?;
+        let levels = resolve_levels(&*parsed, &unsafe { settings.to_blitz_settings() });
+        unsafe { out.write(levels.into()) };
+        Ok(())
+    })
+}
+
+/// Loads the edits saved next to the raw file into `out`, which must be freed with
+/// `free_render_settings`. Returns `NotFound`, leaving `out` untouched, if there aren't any.
+#[no_mangle]
+pub extern "C" fn raw_renderer_load_settings(
+    ptr: *const RawRenderer,
+    out: *mut RenderSettings,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+        let settings = load_sidecar(&renderer.path)?
+            .ok_or_else(|| Error::new(BlitzStatus::NotFound, "No saved settings"))?;
+        unsafe { out.write(RenderSettings::from_blitz_settings(&settings)) };
+        Ok(())
+    })
+}
+
+/// Saves `settings` next to the raw file, so that `raw_renderer_load_settings` can restore them
+/// later.
+#[no_mangle]
+pub extern "C" fn raw_renderer_save_settings(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+
+        // Keep whatever the host app can't see, e.g. edits made with other tools.
+        let base = match load_sidecar(&renderer.path) {
+            Ok(existing) => existing.unwrap_or_default(),
+            Err(e) => {
+                warn!("Replacing unreadable sidecar: {}", e);
+                Default::default()
+            }
+        };
+        let settings = unsafe { settings.to_blitz_settings_over(base) };
+        save_sidecar(&renderer.path, &settings)?;
+        Ok(())
+    })
+}
+
+/// Saves `settings` to an XMP sidecar next to the raw file, for other editors to read. Anything
+/// already in the sidecar that blitz doesn't write is kept.
+#[no_mangle]
+pub extern "C" fn raw_renderer_save_xmp(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+
+        // As for saving settings, but the XMP sidecar itself may have what the host app can't
+        // see.
+        let base = match load_sidecar(&renderer.path)? {
+            Some(existing) => existing,
+            None => {
+                let mut base = Default::default();
+                load_xmp(&renderer.path, &mut base)?;
+                base
+            }
+        };
+        let settings = unsafe { settings.to_blitz_settings_over(base) };
+        save_xmp(&renderer.path, &settings)?;
+        Ok(())
+    })
+}
+
+/// Called with the name of the current stage, the fraction of that stage which is complete, and the
+/// `user_data` pointer that was passed in. It's called from worker threads, possibly several at
+/// once, so it must be thread-safe.
+pub type ProgressCallback =
+    Option<extern "C" fn(stage: *const c_char, fraction: f32, user_data: *mut c_void)>;
+
+struct HostProgress {
+    callback: extern "C" fn(*const c_char, f32, *mut c_void),
+    user_data: *mut c_void,
+}
+
+// The host promises that the callback and user_data can be used from any thread.
+unsafe impl Send for HostProgress {}
+unsafe impl Sync for HostProgress {}
+
+/// Stores a new cancellation token in `out`, which must be freed with `cancellation_free`.
+#[no_mangle]
+pub extern "C" fn cancellation_new(out: *mut *mut RenderCancellation) -> BlitzStatus {
+    catch(|| {
+        let out = out_arg(out, "out")?;
+        let cancellation = RenderCancellation(CancellationToken::new());
+        unsafe { out.write(Box::into_raw(Box::new(cancellation))) };
+        Ok(())
+    })
+}
+
+/// Requests that any render using this token stops as soon as possible. Safe to call from any
+/// thread while the render is running.
+#[no_mangle]
+pub extern "C" fn cancellation_cancel(ptr: *const RenderCancellation) -> BlitzStatus {
+    catch(|| {
+        let cancellation = unsafe { arg(ptr, "cancellation") }?;
+        cancellation.0.cancel();
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn cancellation_free(ptr: *mut RenderCancellation) {
+    if ptr.is_null() {
+        return;
     }
+    catch_silently(|| unsafe { drop(Box::from_raw(ptr)) });
+}
+
+/// Like `raw_renderer_render_with_settings`, but reports progress through `on_progress` (which
+/// may be null), and can be cancelled through `cancellation` (which may also be null).
+///
+/// Returns `Cancelled` if the render was cancelled, in which case `out` is left untouched.
+#[no_mangle]
+pub extern "C" fn raw_renderer_render_with_progress(
+    ptr: *mut RawRenderer,
+    settings: RenderSettings,
+    on_progress: ProgressCallback,
+    user_data: *mut c_void,
+    cancellation: *const RenderCancellation,
+    out: *mut ImageAndHistogram,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let out = out_arg(out, "out")?;
+
+        let mut ctx = RenderContext::new();
+        if let Some(callback) = on_progress {
+            let host = HostProgress {
+                callback,
+                user_data,
+            };
+            ctx = ctx.with_progress(move |stage, fraction| {
+                let stage = CString::new(stage).unwrap_or_default();
+                (host.callback)(stage.as_ptr(), fraction, host.user_data);
+            });
+        }
+        if let Some(cancellation) = unsafe { cancellation.as_ref() } {
+            ctx = ctx.with_cancellation(cancellation.0.clone());
+        }
+
+        let img = render(
+            renderer.source(),
+            &unsafe { settings.to_blitz_settings() },
+            &ctx,
+        )?;
+        unsafe { out.write(img) };
+        Ok(())
+    })
+}
+
+fn render(
+    source: &Source,
+    settings: &blitz::render_settings::RenderSettings,
+    ctx: &RenderContext,
+) -> Result<ImageAndHistogram, Error> {
+    let parsed = source.ensure_parsed_with_progress(ctx)?;
+    let img = render_raw_with_context(&*parsed, settings, ctx)
+        .map_err(|_| Error::new(BlitzStatus::Cancelled, "Cancelled"))?;
+    Ok(with_histogram(img))
+}
+
+/// Called once for every job started by `raw_renderer_render_async`, from a worker thread.
+///
+/// If `status` is `Ok`, `result` points to the rendered image, whose buffers now belong to the
+/// host app, which frees them with `free_buffer`. Otherwise `result` is null, and `message` says
+/// what went wrong. Both pointers are only valid until the callback returns.
+pub type RenderCallback = Option<
+    extern "C" fn(
+        job_id: u64,
+        status: BlitzStatus,
+        result: *mut ImageAndHistogram,
+        message: *const c_char,
+        user_data: *mut c_void,
+    ),
+>;
+
+struct HostCallback {
+    callback: extern "C" fn(u64, BlitzStatus, *mut ImageAndHistogram, *const c_char, *mut c_void),
+    user_data: *mut c_void,
+}
+
+// As for `HostProgress`.
+unsafe impl Send for HostCallback {}
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Starts rendering the image with `settings` on a background thread, and returns straight away,
+/// storing the job's id in `job_id`. `callback` is called with the result.
+///
+/// Starting a job cancels the previous one for this renderer, so that only the latest settings
+/// are rendered when, say, the user drags a slider. Cancelled jobs still get their callback, with
+/// `Cancelled`.
+///
+/// If the job couldn't be started, `callback` isn't called, and the previous job carries on.
+#[no_mangle]
+pub extern "C" fn raw_renderer_render_async(
+    ptr: *const RawRenderer,
+    settings: RenderSettings,
+    callback: RenderCallback,
+    user_data: *mut c_void,
+    job_id: *mut u64,
+) -> BlitzStatus {
+    catch(|| {
+        let renderer = unsafe { arg(ptr, "renderer") }?;
+        let job_id_out = out_arg(job_id, "job_id")?;
+        let callback =
+            callback.ok_or_else(|| Error::new(BlitzStatus::InvalidArgument, "callback is null"))?;
+        let host = HostCallback {
+            callback,
+            user_data,
+        };
+        // The host's tone curves are only valid during this call. Converting them comes before
+        // starting the job, which cancels the previous one, so that bad settings leave it running.
+        let settings = unsafe { settings.to_blitz_settings() };
+        let job_id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+        let ctx = RenderContext::new().with_cancellation(renderer.start_job());
+        let source = renderer.source().clone();
+
+        rayon::spawn(move || {
+            let host = host;
+            match run(|| render(&source, &settings, &ctx)) {
+                Ok(img) => {
+                    // The buffers are the host's now, and are freed with `free_buffer`.
+                    let mut img = ManuallyDrop::new(img);
+                    (host.callback)(
+                        job_id,
+                        BlitzStatus::Ok,
+                        &mut *img,
+                        std::ptr::null(),
+                        host.user_data,
+                    )
+                }
+                Err(error) => {
+                    let message = CString::new(error.message.replace('\0', "")).unwrap_or_default();
+                    (host.callback)(
+                        job_id,
+                        error.status,
+                        std::ptr::null_mut(),
+                        message.as_ptr(),
+                        host.user_data,
+                    )
+                }
+            }
+        });
+        unsafe { job_id_out.write(job_id) };
+        Ok(())
+    })
+}
+
+/// Cancels the renderer's latest job from `raw_renderer_render_async`, if it's still running.
+#[no_mangle]
+pub extern "C" fn raw_renderer_cancel_async(ptr: *const RawRenderer) -> BlitzStatus {
+    catch(|| {
+        unsafe { arg(ptr, "renderer") }?.cancel_jobs();
+        Ok(())
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn free_buffer(buf: Buffer) {
     // do this explicitly so the containing method doesn't get erased.
-    drop(buf)
+    catch_silently(|| drop(buf));
+}
+
+#[no_mangle]
+pub extern "C" fn free_histograms(histograms: Histograms) {
+    catch_silently(|| drop(histograms));
+}
+
+#[no_mangle]
+pub extern "C" fn free_metadata(metadata: Metadata) {
+    catch_silently(|| drop(metadata));
+}
+
+/// Frees settings from `raw_renderer_load_settings`. Settings made by the host app must not be
+/// passed here.
+#[no_mangle]
+pub extern "C" fn free_render_settings(settings: RenderSettings) {
+    catch_silently(|| unsafe { settings.free() });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+
+    extern "C" fn ignore(
+        _: u64,
+        _: BlitzStatus,
+        _: *mut ImageAndHistogram,
+        _: *const c_char,
+        _: *mut c_void,
+    ) {
+        panic!("Jobs that don't start shouldn't call back");
+    }
+
+    #[test]
+    fn histograms_are_scaled() {
+        let mut bins = blitz::diagnostics::histogram::Bins::new(2);
+        bins.record([0.0, 0.0, 1.0]);
+        bins.record([0.0, 1.0, 1.0]);
+        let histogram = HistogramBins::new(&bins, HistogramScale::Linear);
+        assert_eq!(histogram.bin_count, 2);
+        let heights = |values| unsafe { std::slice::from_raw_parts(values, 2) };
+        assert_eq!(heights(histogram.red), [1.0, 0.0]);
+        assert_eq!(heights(histogram.green), [0.5, 0.5]);
+        assert_eq!(heights(histogram.blue), [0.0, 1.0]);
+    }
+
+    #[test]
+    fn cancellation() {
+        let mut cancellation = ptr::null_mut();
+        assert_eq!(cancellation_new(&mut cancellation), BlitzStatus::Ok);
+        assert_eq!(cancellation_cancel(cancellation), BlitzStatus::Ok);
+        assert!(unsafe { &*cancellation }.0.is_cancelled());
+        cancellation_free(cancellation);
+
+        assert_eq!(
+            cancellation_new(ptr::null_mut()),
+            BlitzStatus::InvalidArgument
+        );
+        assert_eq!(
+            cancellation_cancel(ptr::null()),
+            BlitzStatus::InvalidArgument
+        );
+        assert_eq!(
+            raw_renderer_cancel_async(ptr::null()),
+            BlitzStatus::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn async_jobs_need_a_renderer() {
+        let settings = RenderSettings::from_blitz_settings(&Default::default());
+        let mut job_id = 0;
+        // Like the host, this keeps ownership of the settings it passes.
+        let status = raw_renderer_render_async(
+            ptr::null(),
+            unsafe { ptr::read(&settings) },
+            Some(ignore),
+            ptr::null_mut(),
+            &mut job_id,
+        );
+        assert_eq!(status, BlitzStatus::InvalidArgument);
+        assert_eq!(job_id, 0);
+        let message = unsafe { CStr::from_ptr(error::blitz_last_error_message()) };
+        assert_eq!(message.to_str().unwrap(), "renderer is null");
+        unsafe { settings.free() };
+    }
 }
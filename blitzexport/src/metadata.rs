@@ -0,0 +1,171 @@
+use libc::c_char;
+use libraw::exif::ExifSummary;
+use libraw::fuji_meta::{load_focus_point, FocusPoint};
+use libraw::raf::{RafError, RafFile, RafInfo};
+use log::warn;
+use std::ffi::CString;
+use std::ptr;
+
+/// A rectangle in sensor pixels.
+#[repr(C)]
+pub struct PixelRect {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Everything we know about a raw file, for showing to the user. Strings are null, and numbers 0,
+/// when the file doesn't say. It must be freed with `free_metadata`.
+#[repr(C)]
+pub struct Metadata {
+    pub model: *mut c_char,
+    pub firmware: *mut c_char,
+    pub lens_model: *mut c_char,
+    /// When the photo was taken, as "YYYY:MM:DD HH:MM:SS" in the camera's time zone.
+    pub date_time_original: *mut c_char,
+    /// The shutter speed in seconds, as a fraction, e.g. 1/250.
+    pub exposure_time_numerator: u32,
+    pub exposure_time_denominator: u32,
+    pub f_number: f64,
+    pub iso: u32,
+    /// In millimetres.
+    pub focal_length: f64,
+    /// The size of the sensor data, before cropping.
+    pub sensor_width: u32,
+    pub sensor_height: u32,
+    /// The part of the sensor data that makes up the image.
+    pub crop: PixelRect,
+    /// The camera's as-shot white balance multipliers.
+    pub white_balance_red: u16,
+    pub white_balance_green: u16,
+    pub white_balance_blue: u16,
+    pub has_focus_point: bool,
+    /// Where the camera focused, in pixels of the camera's full-size JPEG.
+    pub focus_x: u16,
+    pub focus_y: u16,
+}
+
+fn to_c_string(s: &str) -> *mut c_char {
+    CString::new(s.replace('\0', ""))
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+fn optional_c_string(s: Option<&str>) -> *mut c_char {
+    s.map_or(ptr::null_mut(), to_c_string)
+}
+
+impl Metadata {
+    /// Reads `file`'s metadata. Only the RAF structure itself is required; anything missing from
+    /// the EXIF or makernotes is left blank.
+    pub fn load(file: &RafFile) -> Result<Metadata, RafError> {
+        let info = file.parse_info()?;
+        let exif = file.parse_exif().unwrap_or_else(|e| {
+            warn!("Couldn't read EXIF: {}", e);
+            ExifSummary::default()
+        });
+        let focus_point = load_focus_point(file).unwrap_or_else(|e| {
+            warn!("Couldn't read focus point: {}", e);
+            None
+        });
+        Ok(Metadata::new(&info, &exif, focus_point))
+    }
+
+    fn new(info: &RafInfo, exif: &ExifSummary, focus_point: Option<FocusPoint>) -> Metadata {
+        let (crop_width, crop_height) = info.crop_rect.size();
+        let exposure_time = exif.exposure_time.map_or((0, 0), |time| (time.0, time.1));
+        Metadata {
+            model: to_c_string(&info.model),
+            firmware: to_c_string(&info.firmware),
+            lens_model: optional_c_string(exif.lens_model.as_deref()),
+            date_time_original: optional_c_string(exif.date_time_original.as_deref()),
+            exposure_time_numerator: exposure_time.0,
+            exposure_time_denominator: exposure_time.1,
+            f_number: exif.f_number.unwrap_or(0.0),
+            iso: exif.iso.unwrap_or(0),
+            focal_length: exif.focal_length.unwrap_or(0.0),
+            sensor_width: info.width.into(),
+            sensor_height: info.height.into(),
+            crop: PixelRect {
+                left: info.crop_rect.left as u32,
+                top: info.crop_rect.top as u32,
+                width: crop_width as u32,
+                height: crop_height as u32,
+            },
+            white_balance_red: info.white_bal.red,
+            white_balance_green: info.white_bal.green,
+            white_balance_blue: info.white_bal.blue,
+            has_focus_point: focus_point.is_some(),
+            focus_x: focus_point.map_or(0, |point| point.x),
+            focus_y: focus_point.map_or(0, |point| point.y),
+        }
+    }
+}
+
+impl Drop for Metadata {
+    fn drop(&mut self) {
+        for &s in &[
+            self.model,
+            self.firmware,
+            self.lens_model,
+            self.date_time_original,
+        ] {
+            if !s.is_null() {
+                unsafe { drop(CString::from_raw(s)) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libraw::raf::{CropRect, WhiteBalCoefficients};
+    use libraw::tiff::Rational;
+    use std::ffi::CStr;
+
+    #[test]
+    fn converts_metadata() {
+        let info = RafInfo {
+            model: "X-T3".to_string(),
+            firmware: "0300".to_string(),
+            width: 6384,
+            height: 4182,
+            crop_rect: CropRect {
+                left: 12,
+                right: 6252,
+                top: 4,
+                bottom: 4164,
+            },
+            white_bal: WhiteBalCoefficients {
+                red: 302,
+                green: 550,
+                blue: 700,
+            },
+        };
+        let exif = ExifSummary {
+            exposure_time: Some(Rational(1, 250)),
+            iso: Some(160),
+            lens_model: Some("XF35mmF2 R WR".to_string()),
+            ..Default::default()
+        };
+        let metadata = Metadata::new(&info, &exif, Some(FocusPoint { x: 3000, y: 2000 }));
+
+        let string = |s| unsafe { CStr::from_ptr(s) }.to_str().unwrap();
+        assert_eq!(string(metadata.model), "X-T3");
+        assert_eq!(string(metadata.lens_model), "XF35mmF2 R WR");
+        assert!(metadata.date_time_original.is_null());
+        assert_eq!(
+            (
+                metadata.exposure_time_numerator,
+                metadata.exposure_time_denominator
+            ),
+            (1, 250)
+        );
+        assert_eq!(metadata.f_number, 0.0);
+        assert_eq!((metadata.crop.width, metadata.crop.height), (6240, 4160));
+        assert!(metadata.has_focus_point);
+        assert_eq!((metadata.focus_x, metadata.focus_y), (3000, 2000));
+    }
+}
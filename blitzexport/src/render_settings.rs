@@ -1,40 +1,244 @@
 use blitz::render_settings as brs;
 use blitz::render_settings::LensCorrections;
+use std::slice;
+
+/// cbindgen:prefix-with-name
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub enum LevelsSpace {
+    Linear,
+    Display,
+}
+
+/// See `blitz::render_settings::Levels`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Levels {
+    pub input_black: f32,
+    pub input_white: f32,
+    pub gamma: f32,
+    pub output_black: f32,
+    pub output_white: f32,
+    pub space: LevelsSpace,
+}
+
+impl From<brs::Levels> for Levels {
+    fn from(levels: brs::Levels) -> Self {
+        Levels {
+            input_black: levels.input_black,
+            input_white: levels.input_white,
+            gamma: levels.gamma,
+            output_black: levels.output_black,
+            output_white: levels.output_white,
+            space: match levels.space {
+                brs::LevelsSpace::Linear => LevelsSpace::Linear,
+                brs::LevelsSpace::Display => LevelsSpace::Display,
+            },
+        }
+    }
+}
+
+impl From<Levels> for brs::Levels {
+    fn from(levels: Levels) -> Self {
+        brs::Levels {
+            input_black: levels.input_black,
+            input_white: levels.input_white,
+            gamma: levels.gamma,
+            output_black: levels.output_black,
+            output_white: levels.output_white,
+            space: match levels.space {
+                LevelsSpace::Linear => brs::LevelsSpace::Linear,
+                LevelsSpace::Display => brs::LevelsSpace::Display,
+            },
+        }
+    }
+}
+
+/// A control point of a `Curve`, with both coordinates from 0 to 1.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CurvePoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// A curve through `point_count` points at `points`. No points, or a null `points`, is the
+/// identity curve. See `blitz::render_settings::Curve`.
+///
+/// Curves passed in by the host app are only read during the call. Curves in settings from blitz
+/// belong to those settings, and are freed with `free_render_settings`.
+#[repr(C)]
+pub struct Curve {
+    pub points: *mut CurvePoint,
+    pub point_count: u32,
+}
+
+impl Curve {
+    /// # Safety
+    ///
+    /// `points` must be null or point to `point_count` points.
+    unsafe fn to_blitz(&self) -> brs::Curve {
+        if self.points.is_null() || self.point_count == 0 {
+            return brs::Curve::default();
+        }
+        let points: Vec<_> = slice::from_raw_parts(self.points, self.point_count as usize)
+            .iter()
+            .map(|point| brs::CurvePoint::new(point.x, point.y))
+            .collect();
+        brs::Curve::new(&points)
+    }
+
+    fn from_blitz(curve: &brs::Curve) -> Self {
+        let points: Box<[_]> = curve
+            .points()
+            .iter()
+            .map(|point| CurvePoint {
+                x: point.x,
+                y: point.y,
+            })
+            .collect();
+        Curve {
+            point_count: points.len() as u32,
+            points: Box::into_raw(points) as *mut CurvePoint,
+        }
+    }
+
+    /// Frees the points of a curve made by `from_blitz`.
+    unsafe fn free(&self) {
+        if !self.points.is_null() {
+            let points = slice::from_raw_parts_mut(self.points, self.point_count as usize);
+            drop(Box::from_raw(points as *mut [CurvePoint]));
+        }
+    }
+}
+
+/// See `blitz::render_settings::ToneCurve`.
+#[repr(C)]
+pub struct ToneCurve {
+    pub luminance: Curve,
+    pub red: Curve,
+    pub green: Curve,
+    pub blue: Curve,
+}
+
+impl ToneCurve {
+    fn curves(&self) -> [&Curve; 4] {
+        [&self.luminance, &self.red, &self.green, &self.blue]
+    }
+}
 
 #[repr(C)]
 pub struct RenderSettings {
-    tone_curve: [f32; 5],
+    tone_curve: ToneCurve,
     exposure_basis: f32,
+    /// When set, the input points of `levels` are measured from the image.
     auto_contrast: bool,
+    levels: Levels,
     saturation_boost: f32,
     vignette_correction: bool,
 }
 
-const TONE_CURVE_CONST: f32 = 2.0;
+/// Exposure is given to us in stops.
+const EXPOSURE_BASE: f32 = 2.0;
 
 impl RenderSettings {
-    pub fn to_blitz_settings(&self) -> brs::RenderSettings {
-        let coefs: Vec<_> = self
-            .tone_curve
-            .iter()
-            .copied()
-            .map(|x| TONE_CURVE_CONST.powf(x))
-            .collect();
-        let tc = brs::ToneCurve::new(&coefs);
-        /*
-        println!("Tonecurve: {:?}", tc);
-        for i in 0..100 {
-            println!("{},{}", i, tc.apply(i as f32 / 100.));
-        }*/
+    /// # Safety
+    ///
+    /// The tone curves' points must be valid, as for `Curve`.
+    pub unsafe fn to_blitz_settings(&self) -> brs::RenderSettings {
+        self.to_blitz_settings_over(brs::RenderSettings::default())
+    }
 
+    /// Like `to_blitz_settings`, but settings which the host app doesn't know about (crop,
+    /// annotations, ...) are kept from `base`.
+    ///
+    /// # Safety
+    ///
+    /// As for `to_blitz_settings`.
+    pub unsafe fn to_blitz_settings_over(&self, base: brs::RenderSettings) -> brs::RenderSettings {
+        let tone_curve = &self.tone_curve;
         brs::RenderSettings {
-            tone_curve: tc,
-            exposure_basis: TONE_CURVE_CONST.powf(self.exposure_basis),
+            tone_curve: brs::ToneCurve {
+                luminance: tone_curve.luminance.to_blitz(),
+                red: tone_curve.red.to_blitz(),
+                green: tone_curve.green.to_blitz(),
+                blue: tone_curve.blue.to_blitz(),
+            },
+            exposure_basis: EXPOSURE_BASE.powf(self.exposure_basis),
             auto_contrast: self.auto_contrast,
+            levels: self.levels.into(),
             saturation_boost: self.saturation_boost,
             lens_corrections: LensCorrections {
                 vignette: self.vignette_correction,
             },
+            ..base
+        }
+    }
+
+    /// The inverse of `to_blitz_settings`. The result owns its tone curves' points, so must be
+    /// freed with `free`.
+    pub fn from_blitz_settings(settings: &brs::RenderSettings) -> Self {
+        let tone_curve = &settings.tone_curve;
+        RenderSettings {
+            tone_curve: ToneCurve {
+                luminance: Curve::from_blitz(&tone_curve.luminance),
+                red: Curve::from_blitz(&tone_curve.red),
+                green: Curve::from_blitz(&tone_curve.green),
+                blue: Curve::from_blitz(&tone_curve.blue),
+            },
+            exposure_basis: settings.exposure_basis.log(EXPOSURE_BASE),
+            auto_contrast: settings.auto_contrast,
+            levels: settings.levels.into(),
+            saturation_boost: settings.saturation_boost,
+            vignette_correction: settings.lens_corrections.vignette,
+        }
+    }
+
+    /// Frees settings made by `from_blitz_settings`.
+    ///
+    /// # Safety
+    ///
+    /// The settings must have come from `from_blitz_settings`, and not have been freed already.
+    pub unsafe fn free(self) {
+        for curve in self.tone_curve.curves().iter() {
+            curve.free();
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_tone_curves() {
+        let mut settings = brs::RenderSettings::auto();
+        settings.tone_curve.luminance = brs::Curve::new(&[
+            brs::CurvePoint::new(0.0, 0.1),
+            brs::CurvePoint::new(0.5, 0.6),
+            brs::CurvePoint::new(1.0, 0.9),
+        ]);
+        settings.exposure_basis = 4.0;
+
+        let converted = RenderSettings::from_blitz_settings(&settings);
+        assert_eq!(converted.tone_curve.luminance.point_count, 3);
+        assert_eq!(converted.tone_curve.red.point_count, 2);
+        assert_eq!(converted.exposure_basis, 2.0);
+        let back = unsafe { converted.to_blitz_settings_over(settings.clone()) };
+        assert_eq!(back, settings);
+        unsafe { converted.free() };
+
+        let mut points = [CurvePoint { x: 0.5, y: 0.0 }, CurvePoint { x: 0.0, y: 0.2 }];
+        let host = Curve {
+            points: points.as_mut_ptr(),
+            point_count: 2,
+        };
+        let curve = unsafe { host.to_blitz() };
+        assert_eq!(curve.points()[0], brs::CurvePoint::new(0.0, 0.2));
+        let empty = Curve {
+            points: std::ptr::null_mut(),
+            point_count: 5,
+        };
+        assert!(unsafe { empty.to_blitz() }.is_identity());
+    }
+}
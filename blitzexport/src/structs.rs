@@ -1,4 +1,12 @@
-use libraw::raf::{ParsedRafFile, RafFile};
+use blitz::context::CancellationToken;
+use blitz::diagnostics::histogram::{Bins, CountScale};
+use blitz::diagnostics::overlay;
+use libraw::raf::{RafError, RafFile, RafImage};
+use libraw::util::progress::{ProgressReporter, Silent};
+use libraw::util::timing::StageTimer;
+use log::{debug, trace};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
 
 #[repr(C)]
 pub struct Buffer {
@@ -12,6 +20,98 @@ pub struct ImageAndHistogram {
     pub histogram: RawImage,
 }
 
+/// How histogram counts are turned into bar heights.
+///
+/// cbindgen:prefix-with-name
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub enum HistogramScale {
+    Linear,
+    /// Scales by the log of the count, so that small counts stay visible.
+    Log,
+}
+
+impl From<HistogramScale> for CountScale {
+    fn from(scale: HistogramScale) -> Self {
+        match scale {
+            HistogramScale::Linear => CountScale::Linear,
+            HistogramScale::Log => CountScale::Log,
+        }
+    }
+}
+
+/// A diagnostic overlay, drawn at the size of the rendered image. Pixels with nothing to show are
+/// transparent.
+///
+/// cbindgen:prefix-with-name
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub enum Overlay {
+    /// Where the sensor saturated, coloured by the channels affected.
+    RawSaturation,
+    /// Output pixels with a channel at white (red) or black (blue).
+    Clipping,
+    /// Exposure zones, in false colour. Every pixel is opaque.
+    ExposureZones,
+}
+
+impl From<Overlay> for overlay::Overlay {
+    fn from(overlay: Overlay) -> Self {
+        match overlay {
+            Overlay::RawSaturation => overlay::Overlay::RawSaturation,
+            Overlay::Clipping => overlay::Overlay::Clipping,
+            Overlay::ExposureZones => overlay::Overlay::ExposureZones,
+        }
+    }
+}
+
+/// Bar heights from 0 to 1, relative to the tallest bar. Each array has `bin_count` values, for
+/// equal-width bins from black to white.
+#[repr(C)]
+pub struct HistogramBins {
+    pub bin_count: u32,
+    pub red: *mut f32,
+    pub green: *mut f32,
+    pub blue: *mut f32,
+    pub luminance: *mut f32,
+}
+
+#[repr(C)]
+pub struct Histograms {
+    /// Of the image before any tone adjustments.
+    pub linear: HistogramBins,
+    /// Of the rendered image.
+    pub output: HistogramBins,
+}
+
+fn into_raw_floats(values: Vec<f32>) -> *mut f32 {
+    Box::into_raw(values.into_boxed_slice()) as *mut f32
+}
+
+impl HistogramBins {
+    pub fn new(bins: &Bins, scale: HistogramScale) -> Self {
+        let heights = bins.scale(scale.into());
+        HistogramBins {
+            bin_count: heights.bin_count() as u32,
+            red: into_raw_floats(heights.red),
+            green: into_raw_floats(heights.green),
+            blue: into_raw_floats(heights.blue),
+            luminance: into_raw_floats(heights.luminance),
+        }
+    }
+}
+
+impl Drop for HistogramBins {
+    fn drop(&mut self) {
+        for &values in &[self.red, self.green, self.blue, self.luminance] {
+            if !values.is_null() {
+                let s = std::ptr::slice_from_raw_parts_mut(values, self.bin_count as usize);
+                unsafe { drop(Box::from_raw(s)) };
+            }
+        }
+    }
+}
+
 #[repr(C)]
 pub enum ImageFormat {
     Rgb,
@@ -32,8 +132,8 @@ impl RawImage {
         let height = img.height();
         RawImage {
             data: Buffer::from_byte_vec(img.into_vec()),
-            width: width,
-            height: height,
+            width,
+            height,
             pixel_format: ImageFormat::Rgb,
         }
     }
@@ -43,8 +143,8 @@ impl RawImage {
         let height = img.height();
         RawImage {
             data: Buffer::from_byte_vec(img.into_vec()),
-            width: width,
-            height: height,
+            width,
+            height,
             pixel_format: ImageFormat::Rgba,
         }
     }
@@ -68,7 +168,7 @@ impl Buffer {
             let data = buf.as_mut_ptr();
             let len = buf.len();
             std::mem::forget(buf);
-            println!("Supplying {} bytes at {:p}", len, data);
+            trace!("Supplying {} bytes at {:p}", len, data);
             Buffer { data, len }
         }
     }
@@ -77,39 +177,93 @@ impl Buffer {
 impl Drop for Buffer {
     fn drop(&mut self) {
         if !self.data.is_null() {
-            let s = unsafe { std::slice::from_raw_parts_mut(self.data, self.len) };
-            let s = s.as_mut_ptr();
+            // This must be freed as the boxed slice it was allocated as, not as a single byte.
+            let s = std::ptr::slice_from_raw_parts_mut(self.data, self.len);
             unsafe {
-                Box::from_raw(s);
+                drop(Box::from_raw(s));
             }
         }
     }
 }
 
-pub struct RawRenderer<'a> {
+pub struct RawRenderer {
+    pub path: PathBuf,
+    source: Arc<Source>,
+    /// Cancels the most recent background render.
+    latest_job: Mutex<Option<CancellationToken>>,
+}
+
+/// The file and, once it's been decoded, the image. Background renders share this, so that they
+/// can outlive the `RawRenderer` that started them.
+pub struct Source {
     pub file: RafFile,
-    parsed: Option<ParsedRafFile<'a>>,
+    image: Mutex<Option<Arc<RafImage>>>,
 }
 
-impl<'a> RawRenderer<'a> {
-    pub fn new(filename: &str) -> Self {
-        let file = RafFile::open(filename).unwrap();
-        RawRenderer { file, parsed: None }
+impl Source {
+    pub fn ensure_parsed(&self) -> Result<Arc<RafImage>, RafError> {
+        self.ensure_parsed_with_progress(&Silent)
     }
 
-    pub fn ensure_parsed(&'a mut self) -> &ParsedRafFile {
-        if self.parsed.is_none() {
-            println!(
-                "Parsing: {}...",
-                self.file
-                    .path()
-                    .file_name()
-                    .and_then(|x| x.to_str())
-                    .unwrap()
-            );
-            self.parsed = Some(self.file.parse_raw().unwrap());
-            println!("...done!");
+    pub fn ensure_parsed_with_progress(
+        &self,
+        progress: &dyn ProgressReporter,
+    ) -> Result<Arc<RafImage>, RafError> {
+        // Holding the lock while parsing means concurrent renders wait for one parse, rather than
+        // each doing their own.
+        let mut image = self.image.lock().unwrap_or_else(PoisonError::into_inner);
+        if image.is_none() {
+            let _timer = StageTimer::new("Parse");
+            debug!("Parsing...");
+            *image = Some(Arc::new(self.file.load_with_progress(progress)?));
         }
-        self.parsed.as_ref().unwrap()
+        Ok(image.as_ref().unwrap().clone())
     }
 }
+
+impl RawRenderer {
+    pub fn new(filename: &str) -> Result<Self, RafError> {
+        let path = PathBuf::from(filename);
+        let file = RafFile::open(&path)?;
+        Ok(RawRenderer {
+            path,
+            source: Arc::new(Source {
+                file,
+                image: Mutex::new(None),
+            }),
+            latest_job: Mutex::new(None),
+        })
+    }
+
+    pub fn source(&self) -> &Arc<Source> {
+        &self.source
+    }
+
+    /// Cancels the previous background render, if it's still going, and returns the token for a
+    /// new one.
+    pub fn start_job(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        let mut latest = self
+            .latest_job
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(previous) = latest.replace(token.clone()) {
+            previous.cancel();
+        }
+        token
+    }
+
+    /// Cancels the most recent background render, if it's still going.
+    pub fn cancel_jobs(&self) {
+        let latest = self
+            .latest_job
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(token) = &*latest {
+            token.cancel();
+        }
+    }
+}
+
+/// Lets the host app cancel renders from another thread.
+pub struct RenderCancellation(pub CancellationToken);
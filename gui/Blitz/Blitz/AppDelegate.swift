@@ -135,7 +135,9 @@ class Workspace : ObservableObject, Codable {
         
         let enumerator = fm.enumerator(at: self.directory!, includingPropertiesForKeys: nil)!
         for file in enumerator.filter({($0 as! URL).path.lowercased().hasSuffix(".raf")}) {
-            previews.append(ImageThumbnail(path: file as! URL))
+            if let preview = ImageThumbnail(path: file as! URL) {
+                previews.append(preview)
+            }
         }
     }
     
@@ -118,7 +118,7 @@ struct ImageTile_Previews: PreviewProvider {
         
         let filePath = "/Users/fabian/Downloads/camera/raw/DSCF2406.raf";
         
-        let thumb = ImageThumbnail(path: URL(fileURLWithPath: filePath));
+        let thumb = ImageThumbnail(path: URL(fileURLWithPath: filePath))!;
         return ImageTile(image: thumb);
     }
 }
@@ -15,10 +15,15 @@ struct ImageThumbnail: Identifiable {
     let previewBytes: Data
     let renderer: Renderer
     
-    init(path: URL) {
+    init?(path: URL) {
         self.path = path;
-        self.renderer = Renderer(fromFilename: path.path)
-        self.previewBytes = self.renderer.loadPreviewBytes();
+        do {
+            self.renderer = try Renderer(fromFilename: path.path)
+            self.previewBytes = try self.renderer.loadPreviewBytes();
+        } catch {
+            print("Couldn't load \(path.path): \(error)")
+            return nil
+        }
     }
 }
 
@@ -28,31 +33,40 @@ class AsyncImage : ObservableObject {
     @Published var histogram: NSImage?
     
     var renderer: Renderer
-    private var loading = false
     
     init(_ renderer: Renderer) {
         self.renderer = renderer
     }
     
     func loadWithSettings(settings: RenderSettings) {
-        if !loading {
-            print("Loading, with settings...")
+        print("Loading, with settings...")
+        if image != nil {
             lastImage = image
-            image = nil
-            loading = true
-            DispatchQueue.global().async {
-                let (image, histo) = self.renderer.render(withSettings: settings)
-                DispatchQueue.main.async {
+        }
+        image = nil
+        // Any render that's still going is superseded by this one.
+        renderer.renderAsync(withSettings: settings) { result in
+            DispatchQueue.main.async {
+                switch result {
+                case .success(let (image, histo)):
                     self.image = image
                     self.histogram = histo
-                    self.loading = false
+                case .failure(let error) where error.status == BlitzStatus_Cancelled:
+                    break
+                case .failure(let error):
+                    print("Couldn't render: \(error)")
+                    self.image = self.lastImage
                 }
             }
         }
     }
 
     func cancel() {
-        // Ignored
+        do {
+            try renderer.cancelAsync()
+        } catch {
+            print("Couldn't cancel: \(error)")
+        }
     }
     
 }
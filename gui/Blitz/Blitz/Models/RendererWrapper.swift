@@ -57,6 +57,21 @@ class BridgeRawBitmapImageRep : NSBitmapImageRep {
     
 }
 
+struct BlitzError: Error, CustomStringConvertible {
+    let status: BlitzStatus
+    let message: String
+
+    var description: String { message }
+
+    // Throws if a call into blitz failed, with the message it left behind.
+    static func check(_ status: BlitzStatus) throws {
+        if status != BlitzStatus_Ok {
+            let message = blitz_last_error_message().map { String(cString: $0) } ?? "Unknown error"
+            throw BlitzError(status: status, message: message)
+        }
+    }
+}
+
 extension RawImage {
     func toNSImage() -> NSImage {
         let data = self.data.toData()
@@ -67,29 +82,203 @@ extension RawImage {
     }
 }
 
+extension Levels {
+    // Levels which leave the image as it is.
+    static let identity = Levels(input_black: 0, input_white: 1, gamma: 1, output_black: 0, output_white: 1, space: LevelsSpace_Linear)
+}
+
+extension Curve {
+    init(_ points: UnsafeMutableBufferPointer<CurvePoint>) {
+        self.init(points: points.baseAddress, point_count: UInt32(points.count))
+    }
+}
+
+// Tone curve control points, kept in Swift. Blitz only reads a `ToneCurve`'s points during the
+// call it's passed to, so `withToneCurve` lends one out for just as long as `body` runs. Empty
+// curves leave the image as it is.
+struct ToneCurvePoints {
+    var luminance: [CurvePoint] = []
+    var red: [CurvePoint] = []
+    var green: [CurvePoint] = []
+    var blue: [CurvePoint] = []
+
+    func withToneCurve<R>(_ body: (ToneCurve) throws -> R) rethrows -> R {
+        var (luminance, red, green, blue) = (self.luminance, self.red, self.green, self.blue)
+        return try luminance.withUnsafeMutableBufferPointer { l in
+            try red.withUnsafeMutableBufferPointer { r in
+                try green.withUnsafeMutableBufferPointer { g in
+                    try blue.withUnsafeMutableBufferPointer { b in
+                        try body(ToneCurve(luminance: Curve(l), red: Curve(r), green: Curve(g), blue: Curve(b)))
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A Swift copy of blitz's `Metadata`, so that nothing needs freeing.
+struct PhotoMetadata {
+    let model: String
+    let firmware: String
+    let lensModel: String?
+    let dateTimeOriginal: String?
+    // In seconds, as (numerator, denominator), e.g. (1, 250).
+    let exposureTime: (UInt32, UInt32)?
+    let fNumber: Double?
+    let iso: UInt32?
+    let focalLength: Double?
+    let sensorSize: (width: UInt32, height: UInt32)
+    let crop: PixelRect
+    let whiteBalance: (red: UInt16, green: UInt16, blue: UInt16)
+    let focusPoint: (x: UInt16, y: UInt16)?
+
+    init(_ m: Metadata) {
+        let string = { (s: UnsafeMutablePointer<CChar>?) in s.map { String(cString: $0) } }
+        model = string(m.model) ?? ""
+        firmware = string(m.firmware) ?? ""
+        lensModel = string(m.lens_model)
+        dateTimeOriginal = string(m.date_time_original)
+        exposureTime = m.exposure_time_denominator == 0 ? nil : (m.exposure_time_numerator, m.exposure_time_denominator)
+        fNumber = m.f_number == 0 ? nil : m.f_number
+        iso = m.iso == 0 ? nil : m.iso
+        focalLength = m.focal_length == 0 ? nil : m.focal_length
+        sensorSize = (m.sensor_width, m.sensor_height)
+        crop = m.crop
+        whiteBalance = (m.white_balance_red, m.white_balance_green, m.white_balance_blue)
+        focusPoint = m.has_focus_point ? (m.focus_x, m.focus_y) : nil
+    }
+}
+
+// Bar heights from 0 to 1, copied out of blitz's `HistogramBins`.
+struct HistogramData {
+    let red: [Float]
+    let green: [Float]
+    let blue: [Float]
+    let luminance: [Float]
+
+    init(_ bins: HistogramBins) {
+        let count = Int(bins.bin_count)
+        let copy = { (values: UnsafeMutablePointer<Float>?) in Array(UnsafeBufferPointer(start: values, count: count)) }
+        red = copy(bins.red)
+        green = copy(bins.green)
+        blue = copy(bins.blue)
+        luminance = copy(bins.luminance)
+    }
+}
+
+// Carries a completion handler through blitz's `user_data` pointer.
+private class RenderCompletion {
+    let completion: (Result<(NSImage, NSImage), BlitzError>) -> Void
+
+    init(_ completion: @escaping (Result<(NSImage, NSImage), BlitzError>) -> Void) {
+        self.completion = completion
+    }
+}
+
 class Renderer {
     var renderer: OpaquePointer
     
-    init(fromFilename filename: String) {
-        self.renderer = raw_renderer_new(filename)!;
+    init(fromFilename filename: String) throws {
+        var renderer: OpaquePointer? = nil
+        try BlitzError.check(raw_renderer_new(filename, &renderer))
+        self.renderer = renderer!
     }
     
-    func loadPreviewBytes() -> Data {
-        let preview = raw_renderer_get_preview(self.renderer);
+    func loadPreviewBytes() throws -> Data {
+        var preview = Buffer()
+        try BlitzError.check(raw_renderer_get_preview(self.renderer, &preview))
         return preview.toData();
         
     }
     
-    func render() -> NSImage {
-        let result = raw_renderer_render_image(self.renderer);
+    func metadata() throws -> PhotoMetadata {
+        var metadata = Metadata()
+        try BlitzError.check(raw_renderer_get_metadata(self.renderer, &metadata))
+        defer { free_metadata(metadata) }
+        return PhotoMetadata(metadata)
+    }
+    
+    func render() throws -> NSImage {
+        var result = RawImage()
+        try BlitzError.check(raw_renderer_render_image(self.renderer, &result))
         return result.toNSImage()
     }
     
-    func render(withSettings: RenderSettings) -> (NSImage, NSImage) {
-        let result = raw_renderer_render_with_settings(self.renderer, withSettings);
+    func render(withSettings: RenderSettings) throws -> (NSImage, NSImage) {
+        var result = ImageAndHistogram()
+        try BlitzError.check(raw_renderer_render_with_settings(self.renderer, withSettings, &result))
         return (result.img.toNSImage(), result.histogram.toNSImage())
     }
     
+    // Returns the rendered image, and histograms of the linear and rendered images.
+    func render(withSettings settings: RenderSettings, histogramBins: UInt32, scale: HistogramScale) throws -> (NSImage, linear: HistogramData, output: HistogramData) {
+        var result = RawImage()
+        var histograms = Histograms()
+        try BlitzError.check(raw_renderer_render_with_histograms(self.renderer, settings, histogramBins, scale, &result, &histograms))
+        defer { free_histograms(histograms) }
+        return (result.toNSImage(), HistogramData(histograms.linear), HistogramData(histograms.output))
+    }
+    
+    // Returns the rendered image, and `overlay` drawn at the same size, to show on top of it.
+    func render(withSettings settings: RenderSettings, overlay: Overlay) throws -> (NSImage, overlay: NSImage) {
+        var result = RawImage()
+        var overlayResult = RawImage()
+        try BlitzError.check(raw_renderer_render_with_overlay(self.renderer, settings, overlay, &result, &overlayResult))
+        return (result.toNSImage(), overlayResult.toNSImage())
+    }
+    
+    // A quick, downscaled render, no larger than `maxDimension` pixels along its longest edge.
+    func renderPreview(withSettings settings: RenderSettings, maxDimension: UInt32) throws -> NSImage {
+        var result = RawImage()
+        try BlitzError.check(raw_renderer_render_preview(self.renderer, settings, maxDimension, &result))
+        return result.toNSImage()
+    }
+
+    // Renders just `rect` (in pixels of the camera's crop) scaled by `scale`, for zooming in.
+    func renderRegion(withSettings settings: RenderSettings, rect: PixelRect, scale: Float) throws -> NSImage {
+        var result = RawImage()
+        try BlitzError.check(raw_renderer_render_region(self.renderer, settings, rect, scale, &result))
+        return result.toNSImage()
+    }
+
+    // The levels that rendering with `settings` would use, including what auto-contrast chose.
+    func resolveLevels(withSettings settings: RenderSettings) throws -> Levels {
+        var levels = Levels.identity
+        try BlitzError.check(raw_renderer_resolve_levels(self.renderer, settings, &levels))
+        return levels
+    }
+    
+    // Saves `settings` to an XMP sidecar for other editors, keeping whatever else is already in it.
+    func saveXmp(withSettings settings: RenderSettings) throws {
+        try BlitzError.check(raw_renderer_save_xmp(self.renderer, settings))
+    }
+    
+    // Renders in the background, cancelling any earlier render that's still going. `completion`
+    // is called on a background thread; cancelled renders fail with `BlitzStatus_Cancelled`.
+    func renderAsync(withSettings settings: RenderSettings, completion: @escaping (Result<(NSImage, NSImage), BlitzError>) -> Void) {
+        let userData = Unmanaged.passRetained(RenderCompletion(completion)).toOpaque()
+        var jobId: UInt64 = 0
+        let status = raw_renderer_render_async(self.renderer, settings, { (_, status, result, message, userData) in
+            let completion = Unmanaged<RenderCompletion>.fromOpaque(userData!).takeRetainedValue().completion
+            if status == BlitzStatus_Ok, let result = result {
+                completion(.success((result.pointee.img.toNSImage(), result.pointee.histogram.toNSImage())))
+            } else {
+                let message = message.map { String(cString: $0) } ?? "Unknown error"
+                completion(.failure(BlitzError(status: status, message: message)))
+            }
+        }, userData, &jobId)
+        if status != BlitzStatus_Ok {
+            // The job never started, so nothing else will release the completion.
+            let completion = Unmanaged<RenderCompletion>.fromOpaque(userData).takeRetainedValue().completion
+            let message = blitz_last_error_message().map { String(cString: $0) } ?? "Unknown error"
+            completion(.failure(BlitzError(status: status, message: message)))
+        }
+    }
+    
+    func cancelAsync() throws {
+        try BlitzError.check(raw_renderer_cancel_async(self.renderer))
+    }
+    
     deinit {
         raw_renderer_free(self.renderer);
     }
@@ -3,18 +3,143 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+/**
+ * The result of a call to the C API. Anything other than `Ok` also sets the message returned by
+ * `blitz_last_error_message`.
+ */
+typedef enum {
+  BlitzStatus_Ok,
+  /**
+   * A required pointer was null, or a string wasn't valid UTF-8.
+   */
+  BlitzStatus_InvalidArgument,
+  /**
+   * A file couldn't be read or written.
+   */
+  BlitzStatus_Io,
+  /**
+   * A file was read, but isn't something we can decode.
+   */
+  BlitzStatus_InvalidFile,
+  /**
+   * There was nothing to load, e.g. no saved settings.
+   */
+  BlitzStatus_NotFound,
+  BlitzStatus_Cancelled,
+  /**
+   * Something went wrong inside blitz. This is always a bug.
+   */
+  BlitzStatus_Panic,
+} BlitzStatus;
+
+/**
+ * How histogram counts are turned into bar heights.
+ */
+typedef enum {
+  HistogramScale_Linear,
+  /**
+   * Scales by the log of the count, so that small counts stay visible.
+   */
+  HistogramScale_Log,
+} HistogramScale;
+
 typedef enum {
   Rgb,
   Rgba,
 } ImageFormat;
 
+typedef enum {
+  LevelsSpace_Linear,
+  LevelsSpace_Display,
+} LevelsSpace;
+
+/**
+ * A diagnostic overlay, drawn at the size of the rendered image. Pixels with nothing to show are
+ * transparent.
+ */
+typedef enum {
+  /**
+   * Where the sensor saturated, coloured by the channels affected.
+   */
+  Overlay_RawSaturation,
+  /**
+   * Output pixels with a channel at white (red) or black (blue).
+   */
+  Overlay_Clipping,
+  /**
+   * Exposure zones, in false colour. Every pixel is opaque.
+   */
+  Overlay_ExposureZones,
+} Overlay;
+
 typedef struct RawRenderer RawRenderer;
 
+/**
+ * Lets the host app cancel renders from another thread.
+ */
+typedef struct RenderCancellation RenderCancellation;
+
 typedef struct {
   uint8_t *data;
   uintptr_t len;
 } Buffer;
 
+/**
+ * A rectangle in sensor pixels.
+ */
+typedef struct {
+  uint32_t left;
+  uint32_t top;
+  uint32_t width;
+  uint32_t height;
+} PixelRect;
+
+/**
+ * Everything we know about a raw file, for showing to the user. Strings are null, and numbers 0,
+ * when the file doesn't say. It must be freed with `free_metadata`.
+ */
+typedef struct {
+  char *model;
+  char *firmware;
+  char *lens_model;
+  /**
+   * When the photo was taken, as "YYYY:MM:DD HH:MM:SS" in the camera's time zone.
+   */
+  char *date_time_original;
+  /**
+   * The shutter speed in seconds, as a fraction, e.g. 1/250.
+   */
+  uint32_t exposure_time_numerator;
+  uint32_t exposure_time_denominator;
+  double f_number;
+  uint32_t iso;
+  /**
+   * In millimetres.
+   */
+  double focal_length;
+  /**
+   * The size of the sensor data, before cropping.
+   */
+  uint32_t sensor_width;
+  uint32_t sensor_height;
+  /**
+   * The part of the sensor data that makes up the image.
+   */
+  PixelRect crop;
+  /**
+   * The camera's as-shot white balance multipliers.
+   */
+  uint16_t white_balance_red;
+  uint16_t white_balance_green;
+  uint16_t white_balance_blue;
+  bool has_focus_point;
+  /**
+   * Where the camera focused, in pixels of the camera's full-size JPEG.
+   */
+  uint16_t focus_x;
+  uint16_t focus_y;
+} Metadata;
+
 typedef struct {
   Buffer data;
   uint32_t width;
@@ -22,27 +147,269 @@ typedef struct {
   ImageFormat pixel_format;
 } RawImage;
 
+/**
+ * A control point of a `Curve`, with both coordinates from 0 to 1.
+ */
 typedef struct {
-  RawImage img;
-  RawImage histogram;
-} ImageAndHistogram;
+  float x;
+  float y;
+} CurvePoint;
 
+/**
+ * A curve through `point_count` points at `points`. No points, or a null `points`, is the
+ * identity curve. See `blitz::render_settings::Curve`.
+ *
+ * Curves passed in by the host app are only read during the call. Curves in settings from blitz
+ * belong to those settings, and are freed with `free_render_settings`.
+ */
 typedef struct {
-  float tone_curve[5];
+  CurvePoint *points;
+  uint32_t point_count;
+} Curve;
+
+/**
+ * See `blitz::render_settings::ToneCurve`.
+ */
+typedef struct {
+  Curve luminance;
+  Curve red;
+  Curve green;
+  Curve blue;
+} ToneCurve;
+
+/**
+ * See `blitz::render_settings::Levels`.
+ */
+typedef struct {
+  float input_black;
+  float input_white;
+  float gamma;
+  float output_black;
+  float output_white;
+  LevelsSpace space;
+} Levels;
+
+typedef struct {
+  ToneCurve tone_curve;
   float exposure_basis;
+  /**
+   * When set, the input points of `levels` are measured from the image.
+   */
   bool auto_contrast;
+  Levels levels;
   float saturation_boost;
   bool vignette_correction;
 } RenderSettings;
 
+typedef struct {
+  RawImage img;
+  RawImage histogram;
+} ImageAndHistogram;
+
+/**
+ * Bar heights from 0 to 1, relative to the tallest bar. Each array has `bin_count` values, for
+ * equal-width bins from black to white.
+ */
+typedef struct {
+  uint32_t bin_count;
+  float *red;
+  float *green;
+  float *blue;
+  float *luminance;
+} HistogramBins;
+
+typedef struct {
+  /**
+   * Of the image before any tone adjustments.
+   */
+  HistogramBins linear;
+  /**
+   * Of the rendered image.
+   */
+  HistogramBins output;
+} Histograms;
+
+/**
+ * Called with the name of the current stage, the fraction of that stage which is complete, and the
+ * `user_data` pointer that was passed in. It's called from worker threads, possibly several at
+ * once, so it must be thread-safe.
+ */
+typedef void (*ProgressCallback)(const char *stage, float fraction, void *user_data);
+
+/**
+ * Called once for every job started by `raw_renderer_render_async`, from a worker thread.
+ *
+ * If `status` is `Ok`, `result` points to the rendered image, whose buffers now belong to the
+ * host app, which frees them with `free_buffer`. Otherwise `result` is null, and `message` says
+ * what went wrong. Both pointers are only valid until the callback returns.
+ */
+typedef void (*RenderCallback)(uint64_t job_id,
+                               BlitzStatus status,
+                               ImageAndHistogram *result,
+                               const char *message,
+                               void *user_data);
+
+/**
+ * Returns a description of the most recent failure on the calling thread, or null if nothing has
+ * failed yet. The string is owned by blitz, and is valid until the next failing call on the same
+ * thread.
+ */
+const char *blitz_last_error_message(void);
+
+/**
+ * Requests that any render using this token stops as soon as possible. Safe to call from any
+ * thread while the render is running.
+ */
+BlitzStatus cancellation_cancel(const RenderCancellation *ptr);
+
+void cancellation_free(RenderCancellation *ptr);
+
+/**
+ * Stores a new cancellation token in `out`, which must be freed with `cancellation_free`.
+ */
+BlitzStatus cancellation_new(RenderCancellation **out);
+
 void free_buffer(Buffer buf);
 
+void free_histograms(Histograms histograms);
+
+void free_metadata(Metadata metadata);
+
+/**
+ * Frees settings from `raw_renderer_load_settings`. Settings made by the host app must not be
+ * passed here.
+ */
+void free_render_settings(RenderSettings settings);
+
+/**
+ * Cancels the renderer's latest job from `raw_renderer_render_async`, if it's still running.
+ */
+BlitzStatus raw_renderer_cancel_async(const RawRenderer *ptr);
+
 void raw_renderer_free(RawRenderer *ptr);
 
-Buffer raw_renderer_get_preview(RawRenderer *ptr);
+/**
+ * Stores the camera, exposure and sensor details in `out`, which must be freed with
+ * `free_metadata`. This doesn't decode the image, so it's quick.
+ */
+BlitzStatus raw_renderer_get_metadata(const RawRenderer *ptr, Metadata *out);
+
+/**
+ * Stores a copy of the camera's JPEG preview in `out`.
+ */
+BlitzStatus raw_renderer_get_preview(RawRenderer *ptr, Buffer *out);
+
+/**
+ * Loads the edits saved next to the raw file into `out`, which must be freed with
+ * `free_render_settings`. Returns `NotFound`, leaving `out` untouched, if there aren't any.
+ */
+BlitzStatus raw_renderer_load_settings(const RawRenderer *ptr, RenderSettings *out);
+
+/**
+ * Opens `filename`, and stores a renderer for it in `out`, which must be freed with
+ * `raw_renderer_free`.
+ */
+BlitzStatus raw_renderer_new(const char *filename, RawRenderer **out);
+
+/**
+ * Starts rendering the image with `settings` on a background thread, and returns straight away,
+ * storing the job's id in `job_id`. `callback` is called with the result.
+ *
+ * Starting a job cancels the previous one for this renderer, so that only the latest settings
+ * are rendered when, say, the user drags a slider. Cancelled jobs still get their callback, with
+ * `Cancelled`.
+ *
+ * If the job couldn't be started, `callback` isn't called, and the previous job carries on.
+ */
+BlitzStatus raw_renderer_render_async(const RawRenderer *ptr,
+                                      RenderSettings settings,
+                                      RenderCallback callback,
+                                      void *user_data,
+                                      uint64_t *job_id);
+
+/**
+ * Renders the image with default settings into `out`.
+ */
+BlitzStatus raw_renderer_render_image(RawRenderer *ptr, RawImage *out);
+
+/**
+ * Renders a quick, downscaled version of the image with `settings` into `out`, no larger than
+ * `max_dimension` pixels along its longest edge.
+ */
+BlitzStatus raw_renderer_render_preview(const RawRenderer *ptr,
+                                        RenderSettings settings,
+                                        uint32_t max_dimension,
+                                        RawImage *out);
+
+/**
+ * Renders just `rect` of the image with `settings` into `out`, scaled by `scale` (at most 1), for
+ * zooming in without rendering everything. `rect` is in pixels of the camera's crop, ignoring
+ * the crop and orientation in `settings`.
+ */
+BlitzStatus raw_renderer_render_region(const RawRenderer *ptr,
+                                       RenderSettings settings,
+                                       PixelRect rect,
+                                       float scale,
+                                       RawImage *out);
+
+/**
+ * Renders the image with `settings` into `out`, and stores histograms of it in `histograms`,
+ * which must be freed with `free_histograms`. Each histogram has `bin_count` bins, which must be
+ * between 1 and 65536, with bar heights scaled by `scale`.
+ */
+BlitzStatus raw_renderer_render_with_histograms(const RawRenderer *ptr,
+                                                RenderSettings settings,
+                                                uint32_t bin_count,
+                                                HistogramScale scale,
+                                                RawImage *out,
+                                                Histograms *histograms);
+
+/**
+ * Renders the image with `settings` into `out`, and draws `overlay` for it into `overlay_out`, as
+ * an RGBA image of the same size.
+ */
+BlitzStatus raw_renderer_render_with_overlay(const RawRenderer *ptr,
+                                             RenderSettings settings,
+                                             Overlay overlay,
+                                             RawImage *out,
+                                             RawImage *overlay_out);
+
+/**
+ * Like `raw_renderer_render_with_settings`, but reports progress through `on_progress` (which
+ * may be null), and can be cancelled through `cancellation` (which may also be null).
+ *
+ * Returns `Cancelled` if the render was cancelled, in which case `out` is left untouched.
+ */
+BlitzStatus raw_renderer_render_with_progress(RawRenderer *ptr,
+                                              RenderSettings settings,
+                                              ProgressCallback on_progress,
+                                              void *user_data,
+                                              const RenderCancellation *cancellation,
+                                              ImageAndHistogram *out);
+
+/**
+ * Renders the image with `settings` into `out`.
+ */
+BlitzStatus raw_renderer_render_with_settings(RawRenderer *ptr,
+                                              RenderSettings settings,
+                                              ImageAndHistogram *out);
 
-RawRenderer *raw_renderer_new(const char *filename);
+/**
+ * Stores the levels that rendering with `settings` would use in `out`. With auto-contrast on,
+ * these show what it measured, so that the host app can turn it off and tweak them.
+ */
+BlitzStatus raw_renderer_resolve_levels(const RawRenderer *ptr,
+                                        RenderSettings settings,
+                                        Levels *out);
 
-RawImage raw_renderer_render_image(RawRenderer *ptr);
+/**
+ * Saves `settings` next to the raw file, so that `raw_renderer_load_settings` can restore them
+ * later.
+ */
+BlitzStatus raw_renderer_save_settings(const RawRenderer *ptr, RenderSettings settings);
 
-ImageAndHistogram raw_renderer_render_with_settings(RawRenderer *ptr, RenderSettings settings);
+/**
+ * Saves `settings` to an XMP sidecar next to the raw file, for other editors to read. Anything
+ * already in the sidecar that blitz doesn't write is kept.
+ */
+BlitzStatus raw_renderer_save_xmp(const RawRenderer *ptr, RenderSettings settings);
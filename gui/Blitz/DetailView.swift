@@ -80,9 +80,18 @@ struct RenderControlsView: View {
     var body: some View {
         VStack {
             Button(action: {
-                let tone_curve = (Float(self.curve0), Float(self.curve1), Float(self.curve2), Float(self.curve3), Float(self.curve4))
-                let rs = RenderSettings(tone_curve: tone_curve, exposure_basis: Float(self.exposure), auto_contrast: autoContrast, saturation_boost: Float(saturation), vignette_correction: devignette)
-                self.onUpdateClicked(rs)
+                // Each slider brightens or darkens the middle of its fifth of the range, in stops.
+                let sliders = [self.curve0, self.curve1, self.curve2, self.curve3, self.curve4]
+                var toneCurve = ToneCurvePoints()
+                toneCurve.luminance = [CurvePoint(x: 0, y: 0)] + sliders.enumerated().map { (i, stops) in
+                    let x = (Float(i) + 0.5) / Float(sliders.count)
+                    return CurvePoint(x: x, y: min(1, x * powf(2, Float(stops))))
+                } + [CurvePoint(x: 1, y: 1)]
+                // The settings are only valid in here, which is fine as rendering reads them straight away.
+                toneCurve.withToneCurve { tone_curve in
+                    let rs = RenderSettings(tone_curve: tone_curve, exposure_basis: Float(self.exposure), auto_contrast: autoContrast, levels: Levels.identity, saturation_boost: Float(saturation), vignette_correction: devignette)
+                    self.onUpdateClicked(rs)
+                }
                 
             }){
                 Text("Render!")
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libraw::fuji_compressed::load_fuji_compressed;
+use libraw::util::progress::Silent;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = load_fuji_compressed(data, &Silent);
+});
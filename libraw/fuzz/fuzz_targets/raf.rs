@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libraw::raf::ParsedRafFile;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(raf) = ParsedRafFile::parse(data) {
+        let _ = raf.render_info();
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libraw::tiff::parse_tiff_with_options;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, tiff)) = parse_tiff_with_options(data, b"II*\0", true) {
+        for entry in tiff.ifds.iter().flatten() {
+            let _ = entry.value(&tiff);
+        }
+    }
+});
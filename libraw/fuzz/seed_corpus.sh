@@ -0,0 +1,25 @@
+#!/bin/bash
+# Seeds each fuzz target's corpus from the test data. Pass any RAF files you'd like to start the
+# raf target from, since there are none in the tree.
+set -e
+cd "$(dirname "$0")"
+mkdir -p corpus/tiff corpus/raf corpus/fuji_compressed
+
+cp ../res/*.tiff.dat corpus/tiff/
+
+# The test block has no header, so give it one describing a single 768x4038 stripe.
+be16() { printf "\\x$(printf %02x $(($1 >> 8)))\\x$(printf %02x $(($1 & 255)))"; }
+block=../src/fuji_compressed/testdata/DSCF2279-block0.compressed.bin
+size=$(wc -c < "$block")
+{
+    printf '\x49\x53\x01\x00\x0e'
+    be16 4038; be16 768; be16 768; be16 768
+    printf '\x01'
+    be16 673
+    be16 $((size >> 16)); be16 $((size & 65535))
+    cat "$block"
+} > corpus/fuji_compressed/DSCF2279-block0
+
+for raf in "$@"; do
+    cp "$raf" corpus/raf/
+done
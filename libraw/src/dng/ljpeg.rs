@@ -0,0 +1,646 @@
+//! Lossless JPEG (ITU T.81, process 14), which is how DNG compresses raw data.
+//!
+//! The encoder only does what we need to write DNGs: one component, and predictor 1, which
+//! predicts each sample from the one to its left. The decoder handles everything DNG writers use:
+//! interleaved components, all seven predictors and point transforms.
+
+use super::DngError;
+
+const SOI: u8 = 0xD8;
+const SOF0: u8 = 0xC0;
+const SOF15: u8 = 0xCF;
+const JPG: u8 = 0xC8;
+const DAC: u8 = 0xCC;
+const DRI: u8 = 0xDD;
+const SOF3: u8 = 0xC3;
+const DHT: u8 = 0xC4;
+const SOS: u8 = 0xDA;
+const EOI: u8 = 0xD9;
+
+/// Predict from the sample to the left (Ra).
+const PREDICTOR: u8 = 1;
+
+/// Differences are coded as a category (how many bits they need), followed by that many bits.
+/// There are 17 categories; the last is only used for a difference of 32768.
+const CATEGORIES: usize = 17;
+
+/// Returns the number of bits needed to represent `diff`.
+fn category(diff: i32) -> usize {
+    (32 - diff.abs().leading_zeros()) as usize
+}
+
+/// Returns the difference between `sample` and `prediction`, modulo 2^16, in the range
+/// -32767..=32768.
+fn difference(sample: u16, prediction: u16) -> i32 {
+    let diff = (sample as i32 - prediction as i32).rem_euclid(1 << 16);
+    if diff > 1 << 15 {
+        diff - (1 << 16)
+    } else {
+        diff
+    }
+}
+
+/// Calls `f` with the difference for every sample in a `width` x `height` image stored in
+/// row-major order.
+fn for_each_difference(data: &[u16], width: usize, precision: u8, mut f: impl FnMut(i32)) {
+    for (i, &sample) in data.iter().enumerate() {
+        let (x, y) = (i % width, i / width);
+        let prediction = match (x, y) {
+            (0, 0) => 1 << (precision - 1),
+            // The first column predicts from the sample above.
+            (0, _) => data[i - width],
+            _ => data[i - 1],
+        };
+        f(difference(sample, prediction))
+    }
+}
+
+/// A Huffman table, as the number of codes of each length (1-16) and the symbols in order of
+/// increasing code length.
+#[derive(Debug, PartialEq)]
+struct HuffmanTable {
+    bits: [u8; 16],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    /// Builds an optimal table for the given symbol frequencies, following T.81 annex K.2.
+    fn from_frequencies(frequencies: &[u32; CATEGORIES]) -> HuffmanTable {
+        // One extra symbol with the lowest possible frequency reserves the all-ones code, which
+        // isn't allowed.
+        let reserved = CATEGORIES;
+        let mut freq: Vec<u64> = frequencies.iter().map(|&f| f as u64).collect();
+        freq.push(1);
+        let mut code_size = vec![0usize; CATEGORIES + 1];
+        let mut others: Vec<Option<usize>> = vec![None; CATEGORIES + 1];
+
+        // Repeatedly merge the two least frequent trees. Ties go to the highest symbol, as in
+        // the spec.
+        let least = |freq: &[u64], except: Option<usize>| {
+            (0..freq.len())
+                .filter(|&i| freq[i] > 0 && Some(i) != except)
+                .min_by_key(|&i| (freq[i], std::cmp::Reverse(i)))
+        };
+        while let Some(v1) = least(&freq, None) {
+            let v2 = match least(&freq, Some(v1)) {
+                Some(v2) => v2,
+                None => break,
+            };
+            freq[v1] += freq[v2];
+            freq[v2] = 0;
+
+            let mut v = v1;
+            code_size[v] += 1;
+            while let Some(next) = others[v] {
+                v = next;
+                code_size[v] += 1;
+            }
+            others[v] = Some(v2);
+            let mut v = v2;
+            code_size[v] += 1;
+            while let Some(next) = others[v] {
+                v = next;
+                code_size[v] += 1;
+            }
+        }
+
+        let mut bits = [0u32; 33];
+        for &size in &code_size {
+            if size > 0 {
+                bits[size] += 1;
+            }
+        }
+        // Limit code lengths to 16 bits.
+        for i in (17..=32).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        // Drop the reserved symbol, which has the longest code.
+        let longest = (1..=16).rev().find(|&i| bits[i] > 0).unwrap();
+        bits[longest] -= 1;
+
+        let mut symbols: Vec<usize> = (0..CATEGORIES).filter(|&s| code_size[s] > 0).collect();
+        symbols.sort_by_key(|&s| code_size[s]);
+        debug_assert!(!symbols.contains(&reserved));
+
+        let mut table_bits = [0u8; 16];
+        for (i, count) in bits[1..=16].iter().enumerate() {
+            table_bits[i] = *count as u8;
+        }
+        HuffmanTable {
+            bits: table_bits,
+            values: symbols.iter().map(|&s| s as u8).collect(),
+        }
+    }
+
+    /// Returns the (code, length) for each symbol.
+    fn codes(&self) -> [(u16, u8); CATEGORIES] {
+        let mut codes = [(0, 0); CATEGORIES];
+        let mut code = 0u16;
+        let mut values = self.values.iter();
+        for (i, &count) in self.bits.iter().enumerate() {
+            for _ in 0..count {
+                let symbol = *values.next().unwrap() as usize;
+                codes[symbol] = (code, i as u8 + 1);
+                code += 1;
+            }
+            code <<= 1;
+        }
+        codes
+    }
+}
+
+/// Writes entropy-coded data, stuffing a zero byte after every 0xFF.
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn new(out: Vec<u8>) -> Self {
+        BitWriter {
+            out,
+            acc: 0,
+            len: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        self.acc = (self.acc << bits) | (value as u64 & ((1 << bits) - 1));
+        self.len += bits;
+        while self.len >= 8 {
+            let byte = (self.acc >> (self.len - 8)) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0);
+            }
+            self.len -= 8;
+        }
+    }
+
+    /// Pads the last byte with ones.
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            let pad = 8 - self.len;
+            self.write((1 << pad) - 1, pad);
+        }
+        self.out
+    }
+}
+
+fn marker(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    if !payload.is_empty() {
+        out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(payload);
+    }
+}
+
+/// Encodes a `width` x `height` image stored in row-major order, with `precision` bits per
+/// sample.
+pub fn encode(data: &[u16], width: usize, height: usize, precision: u8) -> Vec<u8> {
+    assert!(!data.is_empty());
+    assert_eq!(data.len(), width * height);
+    assert!((2..=16).contains(&precision));
+
+    let mut frequencies = [0u32; CATEGORIES];
+    for_each_difference(data, width, precision, |diff| {
+        frequencies[category(diff)] += 1
+    });
+    let table = HuffmanTable::from_frequencies(&frequencies);
+    let codes = table.codes();
+
+    let mut out = vec![];
+    marker(&mut out, SOI, &[]);
+
+    let mut frame = vec![precision];
+    frame.extend_from_slice(&(height as u16).to_be_bytes());
+    frame.extend_from_slice(&(width as u16).to_be_bytes());
+    // One component: ID 1, no subsampling, no quantization table.
+    frame.extend_from_slice(&[1, 1, 0x11, 0]);
+    marker(&mut out, SOF3, &frame);
+
+    // DC table 0.
+    let mut huffman = vec![0x00];
+    huffman.extend_from_slice(&table.bits);
+    huffman.extend_from_slice(&table.values);
+    marker(&mut out, DHT, &huffman);
+
+    // One component using table 0; the predictor goes where DCT scans put the spectral start.
+    marker(&mut out, SOS, &[1, 1, 0x00, PREDICTOR, 0, 0]);
+
+    let mut writer = BitWriter::new(out);
+    for_each_difference(data, width, precision, |diff| {
+        let category = category(diff);
+        let (code, length) = codes[category];
+        writer.write(code as u32, length as u32);
+        // Category 16 has no extra bits.
+        if category > 0 && category < 16 {
+            let bits = if diff < 0 { diff - 1 } else { diff };
+            writer.write(bits as u32, category as u32);
+        }
+    });
+    let mut out = writer.finish();
+    marker(&mut out, EOI, &[]);
+    out
+}
+
+/// Looks up Huffman codes while decoding, as described in T.81 section F.2.2.3.
+struct DecodeTable {
+    /// For each code length, the largest code of that length, or -1 if there are none.
+    max_code: [i32; 17],
+    /// For each code length, the index into `values` of the first code, minus that code.
+    offset: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl DecodeTable {
+    fn new(table: &HuffmanTable) -> DecodeTable {
+        let mut max_code = [-1; 17];
+        let mut offset = [0; 17];
+        let mut code = 0i32;
+        let mut index = 0i32;
+        for (i, &count) in table.bits.iter().enumerate() {
+            let length = i + 1;
+            if count > 0 {
+                offset[length] = index - code;
+                code += count as i32;
+                index += count as i32;
+                max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        DecodeTable {
+            max_code,
+            offset,
+            values: table.values.clone(),
+        }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8, DngError> {
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = (code << 1) | reader.bit() as i32;
+            if code <= self.max_code[length] {
+                let index = (self.offset[length] + code) as usize;
+                return self
+                    .values
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| invalid("bad code"));
+            }
+        }
+        Err(invalid("bad code"))
+    }
+}
+
+/// Reads entropy-coded data, skipping stuffed zero bytes. Once it reaches a marker (or the end of
+/// the data), it produces zeros, as other decoders do for truncated files.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    len: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            pos: 0,
+            acc: 0,
+            len: 0,
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        match self.data.get(self.pos..self.pos + 2) {
+            Some([0xFF, 0x00]) => {
+                self.pos += 2;
+                0xFF
+            }
+            Some([0xFF, _]) => 0,
+            _ => match self.data.get(self.pos) {
+                Some(&byte) => {
+                    self.pos += 1;
+                    byte
+                }
+                None => 0,
+            },
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        self.bits(1)
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        debug_assert!(count <= 16);
+        while self.len < count {
+            self.acc = (self.acc << 8) | self.next_byte() as u32;
+            self.len += 8;
+        }
+        self.len -= count;
+        (self.acc >> self.len) & ((1 << count) - 1)
+    }
+
+    /// Reads a difference coded as a category followed by extra bits.
+    fn difference(&mut self, table: &DecodeTable) -> Result<i32, DngError> {
+        let category = table.decode(self)? as u32;
+        Ok(match category {
+            0 => 0,
+            16 => 32768,
+            1..=15 => {
+                let bits = self.bits(category) as i32;
+                // Values in the lower half of the range are negative.
+                if bits < 1 << (category - 1) {
+                    bits - (1 << category) + 1
+                } else {
+                    bits
+                }
+            }
+            _ => return Err(invalid("bad difference category")),
+        })
+    }
+}
+
+fn invalid(reason: &str) -> DngError {
+    DngError::Invalid(format!("lossless JPEG: {}", reason))
+}
+
+/// A decoded lossless JPEG.
+#[derive(Debug, PartialEq)]
+pub struct Decoded {
+    pub width: usize,
+    pub height: usize,
+    /// The number of samples per pixel. They're interleaved, so each row has `width * components`
+    /// samples.
+    pub components: usize,
+    pub samples: Vec<u16>,
+}
+
+struct Frame {
+    precision: u8,
+    width: usize,
+    height: usize,
+    components: usize,
+}
+
+fn segment(data: &[u8], pos: usize) -> Result<&[u8], DngError> {
+    let length = data
+        .get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+        .ok_or_else(|| invalid("truncated"))?;
+    data.get(pos + 2..pos + length.max(2))
+        .ok_or_else(|| invalid("truncated"))
+}
+
+/// Decodes a lossless JPEG.
+pub fn decode(data: &[u8]) -> Result<Decoded, DngError> {
+    if data.get(..2) != Some(&[0xFF, SOI]) {
+        return Err(invalid("missing start of image"));
+    }
+    let mut pos = 2;
+    let mut frame = None;
+    let mut tables: [Option<DecodeTable>; 4] = [None, None, None, None];
+    loop {
+        // Markers can be preceded by any number of fill bytes.
+        while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = match data.get(pos..pos + 2) {
+            Some([0xFF, marker]) => *marker,
+            _ => return Err(invalid("expected a marker")),
+        };
+        pos += 2;
+        let payload = match marker {
+            EOI => return Err(invalid("no scan")),
+            _ => segment(data, pos)?,
+        };
+        match marker {
+            SOF3 => {
+                let header = payload.get(..6).ok_or_else(|| invalid("truncated frame"))?;
+                let components = header[5] as usize;
+                if components == 0 || payload.len() < 6 + 3 * components {
+                    return Err(invalid("bad frame"));
+                }
+                frame = Some(Frame {
+                    precision: header[0],
+                    height: u16::from_be_bytes([header[1], header[2]]) as usize,
+                    width: u16::from_be_bytes([header[3], header[4]]) as usize,
+                    components,
+                });
+            }
+            SOF0..=SOF15 if marker != DHT && marker != JPG && marker != DAC => {
+                return Err(DngError::Unsupported(
+                    "JPEG compression other than lossless".to_string(),
+                ));
+            }
+            DHT => {
+                // A segment can define several tables.
+                let mut rest = payload;
+                while !rest.is_empty() {
+                    let id = (rest[0] & 0x0F) as usize;
+                    let bits = rest.get(1..17).ok_or_else(|| invalid("truncated table"))?;
+                    let count: usize = bits.iter().map(|&b| b as usize).sum();
+                    let values = rest
+                        .get(17..17 + count)
+                        .ok_or_else(|| invalid("truncated table"))?;
+                    let mut table_bits = [0; 16];
+                    table_bits.copy_from_slice(bits);
+                    let table = HuffmanTable {
+                        bits: table_bits,
+                        values: values.to_vec(),
+                    };
+                    *tables.get_mut(id).ok_or_else(|| invalid("bad table ID"))? =
+                        Some(DecodeTable::new(&table));
+                    rest = &rest[17 + count..];
+                }
+            }
+            DRI if payload.get(..2).is_some_and(|b| b != [0, 0]) => {
+                return Err(DngError::Unsupported("restart intervals".to_string()));
+            }
+            SOS => {
+                let frame = frame.as_ref().ok_or_else(|| invalid("scan before frame"))?;
+                let scan_components = *payload.first().ok_or_else(|| invalid("bad scan"))? as usize;
+                if scan_components != frame.components {
+                    return Err(DngError::Unsupported(
+                        "lossless JPEG with several scans".to_string(),
+                    ));
+                }
+                let selectors = payload
+                    .get(1..1 + 2 * scan_components)
+                    .ok_or_else(|| invalid("bad scan"))?;
+                let mut component_tables = vec![];
+                for selector in selectors.chunks(2) {
+                    let id = (selector[1] >> 4) as usize;
+                    let table = tables.get(id).and_then(Option::as_ref);
+                    component_tables.push(table.ok_or_else(|| invalid("missing table"))?);
+                }
+                let params = payload
+                    .get(1 + 2 * scan_components..4 + 2 * scan_components)
+                    .ok_or_else(|| invalid("bad scan"))?;
+                let (predictor, point_transform) = (params[0], params[2] & 0x0F);
+                let entropy = &data[pos + 2 + payload.len()..];
+                return decode_scan(
+                    frame,
+                    &component_tables,
+                    predictor,
+                    point_transform,
+                    entropy,
+                );
+            }
+            _ => {}
+        }
+        pos += 2 + payload.len();
+    }
+}
+
+fn decode_scan(
+    frame: &Frame,
+    tables: &[&DecodeTable],
+    predictor: u8,
+    point_transform: u8,
+    entropy: &[u8],
+) -> Result<Decoded, DngError> {
+    if !(1..=7).contains(&predictor) {
+        return Err(invalid("bad predictor"));
+    }
+    if frame.precision <= point_transform || frame.precision > 16 {
+        return Err(invalid("bad precision"));
+    }
+    let components = frame.components;
+    let row_len = frame.width * components;
+    let mut samples = vec![0u16; row_len * frame.height];
+    let mut reader = BitReader::new(entropy);
+    let initial = 1i32 << (frame.precision - point_transform - 1);
+
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            for (c, table) in tables.iter().enumerate() {
+                let i = y * row_len + x * components + c;
+                let prediction = if y == 0 && x == 0 {
+                    initial
+                } else if y == 0 {
+                    samples[i - components] as i32
+                } else if x == 0 {
+                    samples[i - row_len] as i32
+                } else {
+                    let ra = samples[i - components] as i32;
+                    let rb = samples[i - row_len] as i32;
+                    let rc = samples[i - row_len - components] as i32;
+                    match predictor {
+                        1 => ra,
+                        2 => rb,
+                        3 => rc,
+                        4 => ra + rb - rc,
+                        5 => ra + ((rb - rc) >> 1),
+                        6 => rb + ((ra - rc) >> 1),
+                        _ => (ra + rb) >> 1,
+                    }
+                };
+                let diff = reader.difference(table)?;
+                samples[i] = (prediction + diff) as u16;
+            }
+        }
+    }
+
+    if point_transform > 0 {
+        for sample in &mut samples {
+            *sample <<= point_transform;
+        }
+    }
+    Ok(Decoded {
+        width: frame.width,
+        height: frame.height,
+        components,
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn categories() {
+        assert_eq!(category(0), 0);
+        assert_eq!(category(1), 1);
+        assert_eq!(category(-1), 1);
+        assert_eq!(category(-255), 8);
+        assert_eq!(category(256), 9);
+        assert_eq!(category(32768), 16);
+        assert_eq!(difference(0, 65535), 1);
+        assert_eq!(difference(32768, 0), 32768);
+    }
+
+    #[test]
+    fn huffman_codes_are_prefix_free() {
+        let table = HuffmanTable::from_frequencies(&[
+            1000, 500, 250, 125, 60, 30, 15, 8, 4, 2, 1, 1, 1, 1, 1, 1, 1,
+        ]);
+        let codes: Vec<_> = table
+            .codes()
+            .iter()
+            .copied()
+            .filter(|&(_, len)| len > 0)
+            .collect();
+        assert_eq!(codes.len(), CATEGORIES);
+        for &(code, len) in &codes {
+            assert!(len <= 16);
+            // No code is all ones.
+            assert_ne!(code as u32, (1 << len) - 1);
+            for &(other, other_len) in &codes {
+                if (other, other_len) != (code, len) && other_len >= len {
+                    assert_ne!(other >> (other_len - len), code);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_markers() {
+        let data = [100, 101, 102, 103, 100, 100, 100, 100];
+        let encoded = encode(&data, 4, 2, 14);
+        assert_eq!(&encoded[..2], &[0xFF, SOI]);
+        assert_eq!(&encoded[2..4], &[0xFF, SOF3]);
+        // Precision, height, width.
+        assert_eq!(&encoded[6..11], &[14, 0, 2, 0, 4]);
+        assert_eq!(&encoded[encoded.len() - 2..], &[0xFF, EOI]);
+    }
+
+    #[test]
+    fn round_trip() {
+        // Includes big jumps, to exercise the longer codes and byte stuffing.
+        let data: Vec<u16> = (0..600u32)
+            .map(|i| ((i * 7919) % 65536) as u16 ^ if i % 5 == 0 { 0xFFFF } else { 0 })
+            .collect();
+        for &precision in &[14, 16] {
+            let data: Vec<u16> = data.iter().map(|&x| x >> (16 - precision)).collect();
+            let decoded = decode(&encode(&data, 30, 20, precision)).unwrap();
+            assert_eq!(
+                (decoded.width, decoded.height, decoded.components),
+                (30, 20, 1)
+            );
+            assert_eq!(decoded.samples, data);
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0xFF, SOI, 0xFF, SOS, 0, 2]).is_err());
+        let encoded = encode(&[1, 2, 3, 4], 2, 2, 12);
+        assert!(decode(&encoded[..encoded.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,577 @@
+//! Reads DNGs, and writes raw data out as DNG 1.4 so that it can be opened by editors which
+//! don't understand RAF files.
+//!
+//! When writing, the raw image goes in IFD0, with the camera's JPEG preview in a SubIFD and the
+//! EXIF data copied over from it.
+
+pub mod ljpeg;
+mod reader;
+
+pub use reader::DngFile;
+
+use crate::raf::{find_exif_tiff, CropRect, ParsedRafFile, RenderInfo};
+use crate::tiff::{
+    parse_ifd_with, parse_tiff, Endian, IfdEntry, Rational, SRational, TiffBuilder, TiffValue,
+    NESTED_IFD_TAGS,
+};
+use crate::util::timing::StageTimer;
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+const NEW_SUBFILE_TYPE: u16 = 0x00FE;
+const IMAGE_WIDTH: u16 = 0x0100;
+const IMAGE_LENGTH: u16 = 0x0101;
+const BITS_PER_SAMPLE: u16 = 0x0102;
+const COMPRESSION: u16 = 0x0103;
+const PHOTOMETRIC_INTERPRETATION: u16 = 0x0106;
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+const STRIP_OFFSETS: u16 = 0x0111;
+const SAMPLES_PER_PIXEL: u16 = 0x0115;
+const ROWS_PER_STRIP: u16 = 0x0116;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const PLANAR_CONFIGURATION: u16 = 0x011C;
+const SOFTWARE: u16 = 0x0131;
+const SUB_IFDS: u16 = 0x014A;
+const CFA_REPEAT_PATTERN_DIM: u16 = 0x828D;
+const CFA_PATTERN: u16 = 0x828E;
+const EXIF_IFD: u16 = 0x8769;
+const DNG_VERSION: u16 = 0xC612;
+const DNG_BACKWARD_VERSION: u16 = 0xC613;
+const UNIQUE_CAMERA_MODEL: u16 = 0xC614;
+const CFA_PLANE_COLOR: u16 = 0xC616;
+const CFA_LAYOUT: u16 = 0xC617;
+const BLACK_LEVEL_REPEAT_DIM: u16 = 0xC619;
+const BLACK_LEVEL: u16 = 0xC61A;
+const WHITE_LEVEL: u16 = 0xC61D;
+const COLOR_MATRIX: [u16; 2] = [0xC621, 0xC622];
+const AS_SHOT_NEUTRAL: u16 = 0xC628;
+const CALIBRATION_ILLUMINANT: [u16; 2] = [0xC65A, 0xC65B];
+const ACTIVE_AREA: u16 = 0xC68D;
+const FORWARD_MATRIX: [u16; 2] = [0xC714, 0xC715];
+const PREVIEW_COLOR_SPACE: u16 = 0xC71A;
+const OPCODE_LIST_3: u16 = 0xC74E;
+
+/// Tags copied from the preview's IFD0, when it has them.
+const COPIED_IFD0_TAGS: [u16; 6] = [
+    MAKE, MODEL, 0x0112, // Orientation
+    0x0132, // DateTime
+    0x013B, // Artist
+    0x8298, // Copyright
+];
+
+const COMPRESSION_NONE: u16 = 1;
+const COMPRESSION_JPEG: u16 = 7;
+const PHOTOMETRIC_YCBCR: u16 = 6;
+const PHOTOMETRIC_CFA: u16 = 32803;
+
+/// DNG opcode IDs, from chapter 7 of the DNG spec.
+const OPCODE_FIX_VIGNETTE_RADIAL: u32 = 3;
+/// The DNG version that introduced the opcodes we use.
+const OPCODE_DNG_VERSION: [u8; 4] = [1, 3, 0, 0];
+
+/// Matrices are written as fractions with this denominator.
+const MATRIX_DENOMINATOR: i32 = 10_000;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum DngError {
+        Io(err: io::Error) {
+            from()
+            display("I/O error: {}", err)
+        }
+        InvalidOptions(reason: &'static str) {
+            display("Invalid DNG options: {}", reason)
+        }
+        Invalid(reason: String) {
+            display("Invalid DNG: {}", reason)
+        }
+        Unsupported(what: String) {
+            display("Unsupported DNG feature: {}", what)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Uncompressed,
+    LosslessJpeg,
+}
+
+/// The camera's colour response under one illuminant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// The EXIF LightSource code of the illuminant, e.g. 21 for D65.
+    pub illuminant: u16,
+    /// Maps XYZ to (non-white-balanced) camera values. Row-major.
+    pub color_matrix: [f32; 9],
+    /// Maps white-balanced camera values to XYZ D50. Row-major.
+    pub forward_matrix: Option<[f32; 9]>,
+}
+
+/// Vignetting correction as a gain of `1 + k0 r^2 + k1 r^4 + ... + k4 r^10`, where `r` is the
+/// distance from the centre of the sensor divided by `radius` pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RadialVignette {
+    pub coefficients: [f64; 5],
+    pub radius: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngOptions {
+    pub compression: Compression,
+    /// One or two calibrations, for different illuminants. libraw doesn't know about colour, so
+    /// these have to come from elsewhere.
+    pub calibrations: Vec<Calibration>,
+    pub vignette: Option<RadialVignette>,
+}
+
+/// Writes `raf` as a DNG.
+pub fn write_dng(
+    raf: &ParsedRafFile,
+    options: &DngOptions,
+    out: &mut impl Write,
+) -> Result<(), DngError> {
+    write_render_info(&raf.render_info(), raf.model(), raf.preview(), options, out)
+}
+
+fn write_render_info(
+    info: &RenderInfo,
+    model: &str,
+    preview: &[u8],
+    options: &DngOptions,
+    out: &mut impl Write,
+) -> Result<(), DngError> {
+    let _timer = StageTimer::new("Write DNG");
+    if options.calibrations.is_empty() || options.calibrations.len() > 2 {
+        return Err(DngError::InvalidOptions("needs one or two calibrations"));
+    }
+
+    let mut writer = TiffBuilder::new(Endian::Little);
+    let raw = writer.add_ifd();
+    let (width, height) = (info.width as u32, info.height as u32);
+
+    writer.set(raw, NEW_SUBFILE_TYPE, TiffValue::Long(vec![0]));
+    writer.set(raw, IMAGE_WIDTH, TiffValue::Long(vec![width]));
+    writer.set(raw, IMAGE_LENGTH, TiffValue::Long(vec![height]));
+    writer.set(raw, BITS_PER_SAMPLE, TiffValue::Short(vec![16]));
+    writer.set(
+        raw,
+        PHOTOMETRIC_INTERPRETATION,
+        TiffValue::Short(vec![PHOTOMETRIC_CFA]),
+    );
+    writer.set(raw, SAMPLES_PER_PIXEL, TiffValue::Short(vec![1]));
+    writer.set(raw, PLANAR_CONFIGURATION, TiffValue::Short(vec![1]));
+    writer.set(raw, ROWS_PER_STRIP, TiffValue::Long(vec![height]));
+
+    let (compression, data) = match options.compression {
+        Compression::Uncompressed => (
+            COMPRESSION_NONE,
+            info.raw_data
+                .iter()
+                .flat_map(|x| x.to_le_bytes().to_vec())
+                .collect(),
+        ),
+        Compression::LosslessJpeg => (
+            COMPRESSION_JPEG,
+            ljpeg::encode(info.raw_data, width as usize, height as usize, 16),
+        ),
+    };
+    writer.set(raw, COMPRESSION, TiffValue::Short(vec![compression]));
+    writer.set(
+        raw,
+        STRIP_BYTE_COUNTS,
+        TiffValue::Long(vec![data.len() as u32]),
+    );
+    let strip = writer.add_blob(data);
+    writer.set_blob_offsets(raw, STRIP_OFFSETS, vec![strip]);
+
+    let crop = info.crop_rect;
+    writer.set(raw, CFA_REPEAT_PATTERN_DIM, TiffValue::Short(vec![6, 6]));
+    let cfa = active_area_pattern(crop, |x, y| info.xtrans_mapping[x + 6 * y].idx() as u8);
+    writer.set(raw, CFA_PATTERN, TiffValue::Byte(cfa));
+    writer.set(raw, CFA_PLANE_COLOR, TiffValue::Byte(vec![0, 1, 2]));
+    writer.set(raw, CFA_LAYOUT, TiffValue::Short(vec![1]));
+    writer.set(raw, BLACK_LEVEL_REPEAT_DIM, TiffValue::Short(vec![6, 6]));
+    writer.set(
+        raw,
+        BLACK_LEVEL,
+        TiffValue::Long(active_area_pattern(crop, |x, y| {
+            info.black_levels[(x, y)] as u32
+        })),
+    );
+    writer.set(
+        raw,
+        WHITE_LEVEL,
+        TiffValue::Long(vec![(1 << info.bit_depth as u32) - 1]),
+    );
+    writer.set(
+        raw,
+        ACTIVE_AREA,
+        TiffValue::Long(vec![
+            crop.top as u32,
+            crop.left as u32,
+            crop.bottom as u32,
+            crop.right as u32,
+        ]),
+    );
+
+    // The white balance coefficients multiply each channel; the neutral is their reciprocal,
+    // relative to green.
+    let wb = info.white_bal;
+    writer.set(
+        raw,
+        AS_SHOT_NEUTRAL,
+        TiffValue::Rational(vec![
+            Rational(wb.green as u32, wb.red as u32),
+            Rational(1, 1),
+            Rational(wb.green as u32, wb.blue as u32),
+        ]),
+    );
+    for (i, calibration) in options.calibrations.iter().enumerate() {
+        writer.set(
+            raw,
+            CALIBRATION_ILLUMINANT[i],
+            TiffValue::Short(vec![calibration.illuminant]),
+        );
+        writer.set(raw, COLOR_MATRIX[i], matrix(&calibration.color_matrix));
+        if let Some(forward) = &calibration.forward_matrix {
+            writer.set(raw, FORWARD_MATRIX[i], matrix(forward));
+        }
+    }
+
+    if let Some(vignette) = &options.vignette {
+        writer.set(
+            raw,
+            OPCODE_LIST_3,
+            TiffValue::Undefined(vignette_opcodes(vignette, info)),
+        );
+    }
+
+    writer.set(raw, DNG_VERSION, TiffValue::Byte(vec![1, 4, 0, 0]));
+    let backward_version = if options.vignette.is_some() {
+        OPCODE_DNG_VERSION.to_vec()
+    } else {
+        vec![1, 1, 0, 0]
+    };
+    writer.set(raw, DNG_BACKWARD_VERSION, TiffValue::Byte(backward_version));
+    writer.set(
+        raw,
+        UNIQUE_CAMERA_MODEL,
+        TiffValue::Ascii(format!("Fujifilm {}", model)),
+    );
+    writer.set(raw, SOFTWARE, TiffValue::Ascii("blitz".to_string()));
+
+    if let Some((preview_width, preview_height)) = jpeg_dimensions(preview) {
+        let ifd = writer.add_sub_ifd(raw, SUB_IFDS);
+        writer.set(ifd, NEW_SUBFILE_TYPE, TiffValue::Long(vec![1]));
+        writer.set(
+            ifd,
+            IMAGE_WIDTH,
+            TiffValue::Long(vec![preview_width as u32]),
+        );
+        writer.set(
+            ifd,
+            IMAGE_LENGTH,
+            TiffValue::Long(vec![preview_height as u32]),
+        );
+        writer.set(ifd, BITS_PER_SAMPLE, TiffValue::Short(vec![8, 8, 8]));
+        writer.set(ifd, COMPRESSION, TiffValue::Short(vec![COMPRESSION_JPEG]));
+        writer.set(
+            ifd,
+            PHOTOMETRIC_INTERPRETATION,
+            TiffValue::Short(vec![PHOTOMETRIC_YCBCR]),
+        );
+        writer.set(ifd, SAMPLES_PER_PIXEL, TiffValue::Short(vec![3]));
+        writer.set(ifd, PLANAR_CONFIGURATION, TiffValue::Short(vec![1]));
+        writer.set(
+            ifd,
+            ROWS_PER_STRIP,
+            TiffValue::Long(vec![preview_height as u32]),
+        );
+        writer.set(
+            ifd,
+            STRIP_BYTE_COUNTS,
+            TiffValue::Long(vec![preview.len() as u32]),
+        );
+        let strip = writer.add_blob(Cow::Borrowed(preview));
+        writer.set_blob_offsets(ifd, STRIP_OFFSETS, vec![strip]);
+        // sRGB
+        writer.set(ifd, PREVIEW_COLOR_SPACE, TiffValue::Long(vec![2]));
+    }
+
+    if let Some((ifd0_entries, exif_entries)) = exif_from_preview(preview) {
+        for (tag, value) in ifd0_entries {
+            writer.set(raw, tag, value);
+        }
+        let exif = writer.add_sub_ifd(raw, EXIF_IFD);
+        for (tag, value) in exif_entries {
+            writer.set(exif, tag, value);
+        }
+    }
+    if !writer.contains(raw, MAKE) {
+        writer.set(raw, MAKE, TiffValue::Ascii("FUJIFILM".to_string()));
+    }
+    if !writer.contains(raw, MODEL) {
+        writer.set(raw, MODEL, TiffValue::Ascii(model.to_string()));
+    }
+
+    writer.write(out)?;
+    Ok(())
+}
+
+/// Converts a 6x6 pattern, where `at(x, y)` gives the value at sensor position (x, y) modulo 6,
+/// into DNG's layout: row by row, starting from the top-left of the active area.
+fn active_area_pattern<T>(crop: CropRect, at: impl Fn(usize, usize) -> T) -> Vec<T> {
+    (0..36)
+        .map(|i| at((crop.left + i % 6) % 6, (crop.top + i / 6) % 6))
+        .collect()
+}
+
+fn matrix(values: &[f32; 9]) -> TiffValue {
+    TiffValue::SRational(
+        values
+            .iter()
+            .map(|&x| {
+                SRational(
+                    (x * MATRIX_DENOMINATOR as f32).round() as i32,
+                    MATRIX_DENOMINATOR,
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Builds an opcode list with a FixVignetteRadial opcode equivalent to `vignette`.
+///
+/// DNG measures distances relative to the active area, normalised so that its farthest corner
+/// from the centre is at 1, so the coefficients have to be rescaled.
+fn vignette_opcodes(vignette: &RadialVignette, info: &RenderInfo) -> Vec<u8> {
+    let crop = info.crop_rect;
+    let (active_width, active_height) = crop.size();
+    // The centre of the sensor, relative to the active area.
+    let cx = (info.width / 2) as f64 - crop.left as f64;
+    let cy = (info.height / 2) as f64 - crop.top as f64;
+    let max_distance = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+        .iter()
+        .map(|&(x, y)| {
+            let dx = x * active_width as f64 - cx;
+            let dy = y * active_height as f64 - cy;
+            (dx * dx + dy * dy).sqrt()
+        })
+        .fold(0.0, f64::max);
+    let scale = (max_distance / vignette.radius).powi(2);
+
+    let mut params = vec![];
+    for (i, k) in vignette.coefficients.iter().enumerate() {
+        params.push(k * scale.powi(i as i32 + 1));
+    }
+    params.push(cx / active_width as f64);
+    params.push(cy / active_height as f64);
+
+    // Opcode lists are always big-endian.
+    let mut out = vec![];
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(&OPCODE_FIX_VIGNETTE_RADIAL.to_be_bytes());
+    out.extend_from_slice(&OPCODE_DNG_VERSION);
+    // Flags: optional, so readers which can't apply it can still show the image.
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(&(params.len() as u32 * 8).to_be_bytes());
+    for param in params {
+        out.extend_from_slice(&param.to_be_bytes());
+    }
+    out
+}
+
+/// Finds the width and height of a JPEG from its start of frame marker.
+fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u16, u16)> {
+    if jpeg.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut pos = 2;
+    loop {
+        let marker = jpeg.get(pos..pos + 4)?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        let length = u16::from_be_bytes([marker[2], marker[3]]) as usize;
+        match marker[1] {
+            // Every SOFn apart from DHT, JPG and DAC.
+            0xC0..=0xCF if ![0xC4, 0xC8, 0xCC].contains(&marker[1]) => {
+                let frame = jpeg.get(pos + 5..pos + 9)?;
+                let height = u16::from_be_bytes([frame[0], frame[1]]);
+                let width = u16::from_be_bytes([frame[2], frame[3]]);
+                return Some((width, height));
+            }
+            // Start of scan: the frame should have come first.
+            0xDA => return None,
+            _ => pos += 2 + length,
+        }
+    }
+}
+
+type Entries = Vec<(u16, TiffValue)>;
+
+/// Copies the useful parts of IFD0, and the whole EXIF IFD, from the JPEG preview.
+fn exif_from_preview(preview: &[u8]) -> Option<(Entries, Entries)> {
+    let (_, tiff_data) = find_exif_tiff(preview).ok()?;
+    let (_, tiff) = parse_tiff(tiff_data).ok()?;
+    let ifd0 = tiff.ifds.first()?;
+
+    let ifd0_entries = ifd0
+        .iter()
+        .filter(|e| COPIED_IFD0_TAGS.contains(&e.tag))
+        .filter_map(|e| Some((e.tag, e.value(&tiff).ok()?)))
+        .collect();
+
+    let exif_offset = ifd0.iter().find(|e| e.tag == EXIF_IFD)?.val_u32()? as usize;
+    let exif_data = tiff_data.get(exif_offset..)?;
+    let (_, (exif, _)) = parse_ifd_with(exif_data, tiff.endian, tiff.big_tiff).ok()?;
+    let exif_entries = exif
+        .iter()
+        // Pointers to other IFDs would point at the wrong place.
+        .filter(|e| !NESTED_IFD_TAGS.contains(&e.tag))
+        .filter_map(|e| Some((e.tag, e.value(&tiff).ok()?)))
+        .collect();
+    Some((ifd0_entries, exif_entries))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raf::WhiteBalCoefficients;
+    use crate::tiff::parse_tiff;
+    use crate::Color;
+    use ndarray::Array2;
+    use std::collections::HashMap;
+
+    fn options(compression: Compression) -> DngOptions {
+        DngOptions {
+            compression,
+            calibrations: vec![Calibration {
+                illuminant: 21,
+                color_matrix: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+                forward_matrix: None,
+            }],
+            vignette: Some(RadialVignette {
+                coefficients: [0.1, 0.0, 0.0, 0.0, 0.0],
+                radius: 6.0,
+            }),
+        }
+    }
+
+    fn write(compression: Compression) -> Vec<u8> {
+        let raw_data: Vec<u16> = (0..144).map(|x| 1000 + x * 3).collect();
+        let info = RenderInfo {
+            width: 12,
+            height: 12,
+            bit_depth: 14,
+            black_levels: Array2::from_shape_fn((6, 6), |(x, y)| (x + 10 * y) as u16),
+            white_bal: WhiteBalCoefficients {
+                red: 604,
+                green: 302,
+                blue: 755,
+            },
+            xtrans_mapping: (0..36)
+                .map(|i| Color::from((i % 3) as i8).unwrap())
+                .collect(),
+            crop_rect: CropRect {
+                left: 1,
+                right: 11,
+                top: 2,
+                bottom: 12,
+            },
+            raw_data: &raw_data,
+        };
+        let mut out = vec![];
+        write_render_info(&info, "X-T3", &[], &options(compression), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn uncompressed_dng() {
+        let out = write(Compression::Uncompressed);
+        let (_, tiff) = parse_tiff(&out).unwrap();
+        let ifd0: HashMap<u16, &IfdEntry> = tiff.ifds[0].iter().map(|e| (e.tag, e)).collect();
+
+        assert_eq!(ifd0[&COMPRESSION].val_u32(), Some(1));
+        assert_eq!(
+            tiff.data_for_ifd_entry(ifd0[&DNG_VERSION]).unwrap(),
+            &[1, 4, 0, 0]
+        );
+        assert_eq!(tiff.data_for_ifd_entry(ifd0[&MODEL]).unwrap(), b"X-T3\0");
+        // Patterns start from the top-left of the active area, which is at (1, 2).
+        let cfa = tiff.data_for_ifd_entry(ifd0[&CFA_PATTERN]).unwrap();
+        assert_eq!(&cfa[..3], &[1, 2, 0]);
+        let u32s = |tag| ifd0[&tag].value(&tiff).unwrap().into_u32s().unwrap();
+        let black_levels = u32s(BLACK_LEVEL);
+        assert_eq!(&black_levels[..3], &[21, 22, 23]);
+        assert_eq!(black_levels[6], 31);
+        assert_eq!(u32s(ACTIVE_AREA), vec![2, 1, 12, 11]);
+
+        let offset = ifd0[&STRIP_OFFSETS].val_u32().unwrap() as usize;
+        assert_eq!(ifd0[&STRIP_BYTE_COUNTS].val_u32(), Some(288));
+        assert_eq!(&out[offset..offset + 4], &[0xE8, 0x03, 0xEB, 0x03]);
+    }
+
+    #[test]
+    fn lossless_jpeg_dng() {
+        let out = write(Compression::LosslessJpeg);
+        let (_, tiff) = parse_tiff(&out).unwrap();
+        let ifd0: HashMap<u16, &IfdEntry> = tiff.ifds[0].iter().map(|e| (e.tag, e)).collect();
+        assert_eq!(ifd0[&COMPRESSION].val_u32(), Some(7));
+        let offset = ifd0[&STRIP_OFFSETS].val_u32().unwrap() as usize;
+        assert_eq!(&out[offset..offset + 2], &[0xFF, 0xD8]);
+    }
+
+    #[test]
+    fn vignette_is_rescaled() {
+        let raw_data = vec![];
+        let info = RenderInfo {
+            width: 8,
+            height: 6,
+            bit_depth: 14,
+            black_levels: Array2::zeros((6, 6)),
+            white_bal: WhiteBalCoefficients {
+                red: 1,
+                green: 1,
+                blue: 1,
+            },
+            xtrans_mapping: vec![],
+            crop_rect: CropRect {
+                left: 0,
+                right: 8,
+                top: 0,
+                bottom: 6,
+            },
+            raw_data: &raw_data,
+        };
+        // The corners are 5 pixels from the centre; the coefficients assume 10.
+        let vignette = RadialVignette {
+            coefficients: [1.0, 1.0, 0.0, 0.0, 0.0],
+            radius: 10.0,
+        };
+        let opcodes = vignette_opcodes(&vignette, &info);
+        let param = |i: usize| {
+            let start = 20 + i * 8;
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&opcodes[start..start + 8]);
+            f64::from_be_bytes(bytes)
+        };
+        assert_eq!(opcodes.len(), 20 + 7 * 8);
+        assert_eq!(param(0), 0.25);
+        assert_eq!(param(1), 0.0625);
+        assert_eq!((param(5), param(6)), (0.5, 0.5));
+    }
+
+    #[test]
+    fn jpeg_dimensions_from_frame() {
+        let jpeg = [
+            0xFF, 0xD8, // SOI
+            0xFF, 0xE1, 0x00, 0x04, 0x00, 0x00, // APP1
+            0xFF, 0xC0, 0x00, 0x11, 0x08, 0x0F, 0xA0, 0x17, 0x70, // SOF0
+        ];
+        assert_eq!(jpeg_dimensions(&jpeg), Some((6000, 4000)));
+        assert_eq!(jpeg_dimensions(&[]), None);
+    }
+}
@@ -0,0 +1,567 @@
+//! Reads the raw image and colour calibration out of a DNG, so it can be rendered like a RAF.
+
+use super::*;
+use crate::griditer::BlackPattern;
+use crate::raf::WhiteBalCoefficients;
+use crate::tiff::{parse_tiff_with_options, Endian, Ifd, TiffFile, TiffValue};
+use crate::Color;
+use log::warn;
+use ndarray::{Array2, ShapeBuilder};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+const TILE_WIDTH: u16 = 0x0142;
+const TILE_LENGTH: u16 = 0x0143;
+const TILE_OFFSETS: u16 = 0x0144;
+const TILE_BYTE_COUNTS: u16 = 0x0145;
+const LINEARIZATION_TABLE: u16 = 0xC618;
+const DEFAULT_CROP_ORIGIN: u16 = 0xC61F;
+const DEFAULT_CROP_SIZE: u16 = 0xC620;
+
+const PHOTOMETRIC_LINEAR_RAW: u32 = 34892;
+
+/// The size of the patterns in `RenderInfo`. DNG patterns which divide into it are repeated to
+/// fill it.
+const PATTERN_SIZE: usize = 6;
+
+/// What the white balance coefficients are scaled to for green, since they're integers.
+const WB_SCALE: f64 = 1024.0;
+
+fn invalid(reason: &str) -> DngError {
+    DngError::Invalid(reason.to_string())
+}
+
+fn unsupported(what: &str) -> DngError {
+    DngError::Unsupported(what.to_string())
+}
+
+/// The entries of one IFD, with typed access to their values.
+struct Tags<'a> {
+    tiff: &'a TiffFile<'a>,
+    entries: HashMap<u16, &'a IfdEntry<'a>>,
+}
+
+impl<'a> Tags<'a> {
+    fn new(tiff: &'a TiffFile<'a>, ifd: &'a Ifd<'a>) -> Self {
+        Tags {
+            tiff,
+            entries: ifd.iter().map(|e| (e.tag, e)).collect(),
+        }
+    }
+
+    fn value(&self, tag: u16) -> Option<TiffValue> {
+        self.entries.get(&tag)?.value(self.tiff).ok()
+    }
+
+    fn bytes(&self, tag: u16) -> Option<Vec<u8>> {
+        match self.value(tag)? {
+            TiffValue::Byte(bytes) | TiffValue::Undefined(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn ascii(&self, tag: u16) -> Option<String> {
+        match self.value(tag)? {
+            TiffValue::Ascii(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Reads an unsigned integer value, whichever integer type it's stored as.
+    fn uints(&self, tag: u16) -> Option<Vec<u32>> {
+        self.value(tag)?.into_u32s()
+    }
+
+    fn uint(&self, tag: u16) -> Option<u32> {
+        self.uints(tag)?.first().copied()
+    }
+
+    /// Reads a numeric value of any type as floating point.
+    fn reals(&self, tag: u16) -> Option<Vec<f64>> {
+        self.value(tag)?.into_f64s()
+    }
+
+    /// Reads a 3x3 matrix, as used for colour matrices.
+    fn matrix(&self, tag: u16) -> Option<[f32; 9]> {
+        let values = self.reals(tag)?;
+        if values.len() != 9 {
+            return None;
+        }
+        let mut matrix = [0.0; 9];
+        for (out, value) in matrix.iter_mut().zip(values) {
+            *out = value as f32;
+        }
+        Some(matrix)
+    }
+}
+
+/// A DNG's raw image, decoded, with everything needed to render it.
+#[derive(Debug)]
+pub struct DngFile {
+    model: String,
+    width: u16,
+    height: u16,
+    bit_depth: u16,
+    black_levels: BlackPattern,
+    white_bal: WhiteBalCoefficients,
+    cfa: Vec<Color>,
+    crop_rect: CropRect,
+    calibrations: Vec<Calibration>,
+    raw_data: Vec<u16>,
+}
+
+impl DngFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DngFile, DngError> {
+        DngFile::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<DngFile, DngError> {
+        let _timer = StageTimer::new("Parse DNG");
+        let (_, tiff) = parse_tiff_with_options(data, b"II*\0", true)
+            .map_err(|_| invalid("not a TIFF file"))?;
+        let main = Tags::new(&tiff, tiff.ifds.first().ok_or_else(|| invalid("no IFDs"))?);
+        if main.bytes(DNG_VERSION).is_none() {
+            return Err(invalid("missing DNGVersion"));
+        }
+
+        let photometric = |tags: &Tags| tags.uint(PHOTOMETRIC_INTERPRETATION);
+        let all: Vec<Tags> = tiff.ifds.iter().map(|ifd| Tags::new(&tiff, ifd)).collect();
+        let raw = all
+            .iter()
+            .find(|tags| {
+                tags.uint(NEW_SUBFILE_TYPE).unwrap_or(0) == 0
+                    && photometric(tags) == Some(PHOTOMETRIC_CFA as u32)
+            })
+            .ok_or_else(|| {
+                if all
+                    .iter()
+                    .any(|tags| photometric(tags) == Some(PHOTOMETRIC_LINEAR_RAW))
+                {
+                    unsupported("demosaiced (linear) raw data")
+                } else {
+                    invalid("no raw image")
+                }
+            })?;
+
+        let width = raw
+            .uint(IMAGE_WIDTH)
+            .ok_or_else(|| invalid("missing width"))?;
+        let height = raw
+            .uint(IMAGE_LENGTH)
+            .ok_or_else(|| invalid("missing height"))?;
+        let (width, height) = match (width.try_into(), height.try_into()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => (width, height),
+            _ => return Err(unsupported("images this size")),
+        };
+        if raw.uint(SAMPLES_PER_PIXEL).unwrap_or(1) != 1 {
+            return Err(unsupported("more than one sample per pixel"));
+        }
+
+        let active_area = match raw.uints(ACTIVE_AREA) {
+            Some(area) if area.len() == 4 => CropRect {
+                top: area[0] as usize,
+                left: area[1] as usize,
+                bottom: (area[2] as usize).min(height as usize),
+                right: (area[3] as usize).min(width as usize),
+            },
+            _ => CropRect {
+                top: 0,
+                left: 0,
+                bottom: height as usize,
+                right: width as usize,
+            },
+        };
+        if active_area.left >= active_area.right || active_area.top >= active_area.bottom {
+            return Err(invalid("empty active area"));
+        }
+
+        let mut raw_data = read_raw_data(data, raw, tiff.endian, width as usize, height as usize)?;
+        if let Some(table) = raw.uints(LINEARIZATION_TABLE).filter(|t| !t.is_empty()) {
+            let last = table.len() - 1;
+            for x in &mut raw_data {
+                *x = table[(*x as usize).min(last)] as u16;
+            }
+        }
+
+        let white_level = raw
+            .uint(WHITE_LEVEL)
+            .unwrap_or_else(|| (1 << raw.uint(BITS_PER_SAMPLE).unwrap_or(16)) - 1);
+        let bit_depth = (32 - white_level.leading_zeros()) as u16;
+
+        Ok(DngFile {
+            model: model(&main),
+            width,
+            height,
+            bit_depth,
+            black_levels: black_levels(raw, active_area)?,
+            white_bal: white_balance(&main),
+            cfa: cfa_pattern(raw, active_area)?,
+            crop_rect: default_crop(raw, active_area),
+            calibrations: calibrations(&main),
+            raw_data,
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// The colour calibrations stored in the file, of which there may be none.
+    pub fn calibrations(&self) -> &[Calibration] {
+        &self.calibrations
+    }
+
+    pub fn render_info(&self) -> RenderInfo<'_> {
+        RenderInfo {
+            width: self.width,
+            height: self.height,
+            bit_depth: self.bit_depth,
+            black_levels: self.black_levels.clone(),
+            white_bal: self.white_bal,
+            xtrans_mapping: self.cfa.clone(),
+            crop_rect: self.crop_rect,
+            raw_data: &self.raw_data,
+        }
+    }
+}
+
+fn model(main: &Tags) -> String {
+    main.ascii(UNIQUE_CAMERA_MODEL)
+        .or_else(|| main.ascii(MODEL))
+        .unwrap_or_default()
+}
+
+/// Reads the strips or tiles holding the raw data, and assembles them into one row-major image.
+fn read_raw_data(
+    data: &[u8],
+    raw: &Tags,
+    endian: Endian,
+    width: usize,
+    height: usize,
+) -> Result<Vec<u16>, DngError> {
+    // Strips are treated as tiles which span the whole width.
+    let (tile_width, tile_length, offsets, byte_counts) = match raw.uint(TILE_WIDTH) {
+        Some(tile_width) => (
+            tile_width as usize,
+            raw.uint(TILE_LENGTH)
+                .ok_or_else(|| invalid("missing tile length"))? as usize,
+            raw.uints(TILE_OFFSETS),
+            raw.uints(TILE_BYTE_COUNTS),
+        ),
+        None => (
+            width,
+            raw.uint(ROWS_PER_STRIP)
+                .map_or(height, |rows| rows as usize),
+            raw.uints(STRIP_OFFSETS),
+            raw.uints(STRIP_BYTE_COUNTS),
+        ),
+    };
+    let offsets = offsets.ok_or_else(|| invalid("missing image data offsets"))?;
+    let byte_counts = byte_counts.ok_or_else(|| invalid("missing image data lengths"))?;
+    if tile_width == 0 || tile_length == 0 || offsets.len() != byte_counts.len() {
+        return Err(invalid("bad image data layout"));
+    }
+    let tiles_across = width.div_ceil(tile_width);
+    let tiles_down = height.div_ceil(tile_length);
+    if offsets.len() < tiles_across * tiles_down {
+        return Err(invalid("missing tiles"));
+    }
+
+    let compression = raw.uint(COMPRESSION).unwrap_or(COMPRESSION_NONE as u32);
+    let bits = raw.uint(BITS_PER_SAMPLE).unwrap_or(1);
+    let mut out = vec![0u16; width * height];
+    for (i, (&offset, &count)) in offsets.iter().zip(&byte_counts).enumerate() {
+        let (x0, y0) = (
+            (i % tiles_across) * tile_width,
+            (i / tiles_across) * tile_length,
+        );
+        if y0 >= height {
+            break;
+        }
+        let bytes = data
+            .get(offset as usize..offset as usize + count as usize)
+            .ok_or_else(|| invalid("image data out of bounds"))?;
+        let rows = tile_length.min(height - y0);
+        let samples = match compression as u16 {
+            COMPRESSION_NONE => unpack(bytes, bits, endian, tile_width, rows)?,
+            COMPRESSION_JPEG => ljpeg::decode(bytes)?.samples,
+            _ => return Err(unsupported(&format!("compression type {}", compression))),
+        };
+        if samples.len() < tile_width * rows {
+            return Err(invalid("not enough image data"));
+        }
+        // Edge tiles can extend past the image.
+        let columns = tile_width.min(width - x0);
+        for (row, tile_row) in samples.chunks(tile_width).take(rows).enumerate() {
+            let start = (y0 + row) * width + x0;
+            out[start..start + columns].copy_from_slice(&tile_row[..columns]);
+        }
+    }
+    Ok(out)
+}
+
+/// Unpacks uncompressed samples. 16-bit samples are in the file's byte order; other sizes are
+/// packed big-endian, with each row starting on a byte boundary.
+fn unpack(
+    bytes: &[u8],
+    bits: u32,
+    endian: Endian,
+    width: usize,
+    rows: usize,
+) -> Result<Vec<u16>, DngError> {
+    match bits {
+        8 => Ok(bytes.iter().map(|&x| x as u16).collect()),
+        16 => Ok(bytes.chunks_exact(2).map(|x| endian.u16(x)).collect()),
+        1..=15 => {
+            let row_bytes = (width * bits as usize).div_ceil(8);
+            let mut out = Vec::with_capacity(width * rows);
+            for row in bytes.chunks(row_bytes).take(rows) {
+                let (mut acc, mut len) = (0u32, 0);
+                let mut row = row.iter();
+                for _ in 0..width {
+                    while len < bits {
+                        acc = (acc << 8) | *row.next().unwrap_or(&0) as u32;
+                        len += 8;
+                    }
+                    len -= bits;
+                    out.push(((acc >> len) & ((1 << bits) - 1)) as u16);
+                }
+            }
+            Ok(out)
+        }
+        _ => Err(unsupported(&format!("{} bits per sample", bits))),
+    }
+}
+
+/// Reads a repeating pattern, which DNG stores row by row from the top-left of the active area,
+/// and returns it as a 6x6 array indexed by sensor position.
+fn sensor_pattern<T: Copy>(
+    values: &[T],
+    (rows, columns): (usize, usize),
+    active_area: CropRect,
+) -> Result<Vec<T>, DngError> {
+    if rows == 0
+        || columns == 0
+        || !PATTERN_SIZE.is_multiple_of(rows)
+        || !PATTERN_SIZE.is_multiple_of(columns)
+        || values.len() < rows * columns
+    {
+        return Err(unsupported(&format!("{}x{} patterns", rows, columns)));
+    }
+    let (left, top) = (active_area.left % columns, active_area.top % rows);
+    Ok((0..PATTERN_SIZE * PATTERN_SIZE)
+        .map(|i| {
+            let (x, y) = (i % PATTERN_SIZE, i / PATTERN_SIZE);
+            let row = (y + rows - top) % rows;
+            let column = (x + columns - left) % columns;
+            values[row * columns + column]
+        })
+        .collect())
+}
+
+fn pattern_dim(tags: &Tags, tag: u16) -> Option<(usize, usize)> {
+    match tags.uints(tag)?.as_slice() {
+        &[rows, columns] => Some((rows as usize, columns as usize)),
+        _ => None,
+    }
+}
+
+fn cfa_pattern(raw: &Tags, active_area: CropRect) -> Result<Vec<Color>, DngError> {
+    let dim =
+        pattern_dim(raw, CFA_REPEAT_PATTERN_DIM).ok_or_else(|| invalid("missing CFA size"))?;
+    let pattern = raw
+        .bytes(CFA_PATTERN)
+        .ok_or_else(|| invalid("missing CFA pattern"))?;
+    let plane_colors = raw.bytes(CFA_PLANE_COLOR).unwrap_or_else(|| vec![0, 1, 2]);
+    let colors = pattern
+        .iter()
+        .map(|&plane| {
+            plane_colors
+                .get(plane as usize)
+                .and_then(|&c| Color::from(c as i8))
+                .ok_or_else(|| unsupported("colour filters other than RGB"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    sensor_pattern(&colors, dim, active_area)
+}
+
+fn black_levels(raw: &Tags, active_area: CropRect) -> Result<BlackPattern, DngError> {
+    let dim = pattern_dim(raw, BLACK_LEVEL_REPEAT_DIM).unwrap_or((1, 1));
+    let levels: Vec<u16> = raw
+        .reals(BLACK_LEVEL)
+        .unwrap_or_else(|| vec![0.0])
+        .iter()
+        .map(|&x| x.round() as u16)
+        .collect();
+    let pattern = sensor_pattern(&levels, dim, active_area)?;
+    Ok(Array2::from_shape_vec((PATTERN_SIZE, PATTERN_SIZE).set_f(true), pattern).unwrap())
+}
+
+/// Converts AsShotNeutral, which is the camera's response to a neutral colour, into multipliers.
+fn white_balance(main: &Tags) -> WhiteBalCoefficients {
+    match main.reals(AS_SHOT_NEUTRAL).as_deref() {
+        Some(&[red, green, blue]) if red > 0.0 && green > 0.0 && blue > 0.0 => {
+            let scale = |x: f64| (WB_SCALE * green / x).round().min(u16::MAX as f64) as u16;
+            WhiteBalCoefficients {
+                red: scale(red),
+                green: scale(green),
+                blue: scale(blue),
+            }
+        }
+        _ => {
+            warn!("No usable AsShotNeutral; not white balancing");
+            let neutral = WB_SCALE as u16;
+            WhiteBalCoefficients {
+                red: neutral,
+                green: neutral,
+                blue: neutral,
+            }
+        }
+    }
+}
+
+/// The default crop is relative to the active area. Without one, we use the whole active area.
+fn default_crop(raw: &Tags, active_area: CropRect) -> CropRect {
+    let pair = |tag| match raw.reals(tag)?.as_slice() {
+        &[x, y] => Some((x.round() as usize, y.round() as usize)),
+        _ => None,
+    };
+    let (width, height) = active_area.size();
+    let (x, y) = pair(DEFAULT_CROP_ORIGIN).unwrap_or((0, 0));
+    let (crop_width, crop_height) = pair(DEFAULT_CROP_SIZE).unwrap_or((width, height));
+    let left = active_area.left + x.min(width);
+    let top = active_area.top + y.min(height);
+    CropRect {
+        left,
+        top,
+        right: (left + crop_width).min(active_area.right),
+        bottom: (top + crop_height).min(active_area.bottom),
+    }
+}
+
+fn calibrations(main: &Tags) -> Vec<Calibration> {
+    (0..2)
+        .filter_map(|i| {
+            Some(Calibration {
+                // 0 is "unknown".
+                illuminant: main.uint(CALIBRATION_ILLUMINANT[i]).unwrap_or(0) as u16,
+                color_matrix: main.matrix(COLOR_MATRIX[i])?,
+                forward_matrix: main.matrix(FORWARD_MATRIX[i]),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::raf::WhiteBalCoefficients;
+
+    fn write(compression: Compression, raw_data: &[u16]) -> Vec<u8> {
+        let raw_data = raw_data.to_vec();
+        let info = RenderInfo {
+            width: 12,
+            height: 10,
+            bit_depth: 14,
+            black_levels: Array2::from_shape_fn((6, 6), |(x, y)| (x + 10 * y) as u16),
+            white_bal: WhiteBalCoefficients {
+                red: 604,
+                green: 302,
+                blue: 755,
+            },
+            xtrans_mapping: (0..36)
+                .map(|i| Color::from((i % 3) as i8).unwrap())
+                .collect(),
+            crop_rect: CropRect {
+                left: 1,
+                right: 11,
+                top: 2,
+                bottom: 10,
+            },
+            raw_data: &raw_data,
+        };
+        let options = DngOptions {
+            compression,
+            calibrations: vec![Calibration {
+                illuminant: 21,
+                color_matrix: [1.0, 0.5, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+                forward_matrix: Some([0.5, 0.25, 0.25, 0.25, 0.5, 0.25, 0.0, 0.0, 1.0]),
+            }],
+            vignette: None,
+        };
+        let mut out = vec![];
+        write_render_info(&info, "X-T3", &[], &options, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn reads_what_we_write() {
+        let raw_data: Vec<u16> = (0..120).map(|x| 1000 + x * 97 % 1000).collect();
+        for &compression in &[Compression::Uncompressed, Compression::LosslessJpeg] {
+            let dng = DngFile::from_bytes(&write(compression, &raw_data)).unwrap();
+            let info = dng.render_info();
+            assert_eq!((info.width, info.height, info.bit_depth), (12, 10, 14));
+            assert_eq!(info.raw_data, &raw_data);
+            assert_eq!(info.black_levels[(4, 3)], 34);
+            assert_eq!(info.xtrans_mapping[2], Color::Blue);
+            assert_eq!(
+                info.crop_rect,
+                CropRect {
+                    left: 1,
+                    right: 11,
+                    top: 2,
+                    bottom: 10,
+                }
+            );
+            assert_eq!(
+                info.white_bal,
+                WhiteBalCoefficients {
+                    red: 2048,
+                    green: 1024,
+                    blue: 2560,
+                }
+            );
+            assert_eq!(dng.model(), "Fujifilm X-T3");
+            assert_eq!(dng.calibrations().len(), 1);
+            assert_eq!(dng.calibrations()[0].color_matrix[1], 0.5);
+        }
+    }
+
+    #[test]
+    fn bayer_pattern_fills_six_by_six() {
+        let area = CropRect {
+            left: 1,
+            right: 10,
+            top: 0,
+            bottom: 10,
+        };
+        // RGGB, starting one column in: sensor column 0 is the pattern's second column.
+        let pattern = sensor_pattern(&[0, 1, 1, 2], (2, 2), area).unwrap();
+        assert_eq!(&pattern[..6], &[1, 0, 1, 0, 1, 0]);
+        assert_eq!(&pattern[6..12], &[2, 1, 2, 1, 2, 1]);
+        assert!(sensor_pattern(&[0; 16], (4, 4), area).is_err());
+    }
+
+    #[test]
+    fn unpacks_twelve_bit_rows() {
+        let samples = unpack(&[0xAB, 0xCD, 0xEF, 0x12, 0x30], 12, Endian::Little, 3, 1).unwrap();
+        assert_eq!(samples, vec![0xABC, 0xDEF, 0x123]);
+    }
+
+    #[test]
+    fn sixteen_bit_samples_follow_byte_order() {
+        let bytes = [0x12, 0x34];
+        assert_eq!(
+            unpack(&bytes, 16, Endian::Little, 1, 1).unwrap(),
+            vec![0x3412]
+        );
+        assert_eq!(unpack(&bytes, 16, Endian::Big, 1, 1).unwrap(), vec![0x1234]);
+    }
+
+    #[test]
+    fn rejects_non_dngs() {
+        assert!(DngFile::from_bytes(b"not a tiff").is_err());
+    }
+}
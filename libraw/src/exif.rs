@@ -0,0 +1,110 @@
+//! A summary of the EXIF metadata that cameras attach to their JPEGs, and so to a RAF's preview.
+
+use crate::tiff::{parse_tiff_with_options, Rational, TiffFile, TiffValue};
+
+pub const EXPOSURE_TIME_TAG_ID: u16 = 0x829A;
+pub const F_NUMBER_TAG_ID: u16 = 0x829D;
+pub const ISO_TAG_ID: u16 = 0x8827;
+pub const DATE_TIME_ORIGINAL_TAG_ID: u16 = 0x9003;
+pub const FOCAL_LENGTH_TAG_ID: u16 = 0x920A;
+pub const LENS_MODEL_TAG_ID: u16 = 0xA434;
+
+/// The exposure details of a photo. Anything the file doesn't record, or records in a form we
+/// don't understand, is `None`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExifSummary {
+    /// In seconds, as a fraction so that e.g. 1/250 can be shown as such.
+    pub exposure_time: Option<Rational>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    /// In millimetres.
+    pub focal_length: Option<f64>,
+    pub lens_model: Option<String>,
+    /// When the photo was taken, in EXIF's "YYYY:MM:DD HH:MM:SS" format, in the camera's time zone.
+    pub date_time_original: Option<String>,
+}
+
+impl ExifSummary {
+    /// Summarises a TIFF-formatted EXIF block, such as `FileParts::jpeg_exif_tiff`. Returns `None`
+    /// if it isn't a TIFF at all.
+    pub fn parse(exif_tiff: &[u8]) -> Option<ExifSummary> {
+        let (_, tiff) = parse_tiff_with_options(exif_tiff, b"II*\0", true).ok()?;
+        Some(ExifSummary::from_tiff(&tiff))
+    }
+
+    /// Summarises the tags of `tiff`, which should include its EXIF IFD.
+    pub fn from_tiff(tiff: &TiffFile) -> ExifSummary {
+        let value = |tag| {
+            tiff.all_fields()
+                .find(|entry| entry.tag == tag)
+                .and_then(|entry| entry.value(tiff).ok())
+        };
+        let number = |tag| value(tag)?.into_f64s()?.first().copied();
+        let text = |tag| match value(tag)? {
+            TiffValue::Ascii(text) if !text.trim().is_empty() => Some(text.trim().to_string()),
+            _ => None,
+        };
+        let exposure_time = match value(EXPOSURE_TIME_TAG_ID) {
+            Some(TiffValue::Rational(times)) => times.first().copied().filter(|time| time.1 != 0),
+            _ => None,
+        };
+        let iso = value(ISO_TAG_ID)
+            .and_then(TiffValue::into_u32s)
+            .and_then(|isos| isos.first().copied());
+
+        ExifSummary {
+            exposure_time,
+            f_number: number(F_NUMBER_TAG_ID).filter(|f| f.is_finite()),
+            iso,
+            focal_length: number(FOCAL_LENGTH_TAG_ID).filter(|f| f.is_finite()),
+            lens_model: text(LENS_MODEL_TAG_ID),
+            date_time_original: text(DATE_TIME_ORIGINAL_TAG_ID),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tiff::{Endian, TiffBuilder, EXIF_IFD_TAG_ID};
+
+    #[test]
+    fn summarises_exif_ifd() {
+        let mut builder = TiffBuilder::new(Endian::Big);
+        let ifd0 = builder.add_ifd();
+        let exif = builder.add_sub_ifd(ifd0, EXIF_IFD_TAG_ID);
+        builder.set(
+            exif,
+            EXPOSURE_TIME_TAG_ID,
+            TiffValue::Rational(vec![Rational(1, 250)]),
+        );
+        builder.set(
+            exif,
+            F_NUMBER_TAG_ID,
+            TiffValue::Rational(vec![Rational(28, 10)]),
+        );
+        builder.set(exif, ISO_TAG_ID, TiffValue::Short(vec![640]));
+        builder.set(
+            exif,
+            DATE_TIME_ORIGINAL_TAG_ID,
+            TiffValue::Ascii("2020:05:17 14:03:11".to_string()),
+        );
+        builder.set(exif, LENS_MODEL_TAG_ID, TiffValue::Ascii("  ".to_string()));
+
+        let summary = ExifSummary::parse(&builder.to_bytes()).unwrap();
+        assert_eq!(
+            summary,
+            ExifSummary {
+                exposure_time: Some(Rational(1, 250)),
+                f_number: Some(2.8),
+                iso: Some(640),
+                focal_length: None,
+                // Blank strings count as missing.
+                lens_model: None,
+                date_time_original: Some("2020:05:17 14:03:11".to_string()),
+            }
+        );
+
+        assert_eq!(ExifSummary::parse(b"JPEG"), None);
+    }
+}
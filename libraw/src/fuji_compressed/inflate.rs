@@ -6,6 +6,8 @@ use crate::fuji_compressed::sample::{Grad, Gradients, Sample};
 use crate::fuji_compressed::zip_with_offset::zip_with_offset;
 use crate::util::bitreader::BitReader;
 use crate::util::colored::Colored;
+use crate::util::progress::{Cancelled, ProgressReporter};
+use crate::util::timing::StageTimer;
 use crate::Color;
 use crate::Color::{Blue, Green, Red};
 use itertools::Itertools;
@@ -16,6 +18,7 @@ use ndarray::{Array2, ArrayView1, ArrayViewMut1, ArrayViewMut2, Axis, ShapeBuild
 use rayon::prelude::*;
 use std::io::Cursor;
 use std::iter::repeat;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub static VERTICAL: Axis = Axis(1);
 pub static HORIZONTAL: Axis = Axis(0);
@@ -33,27 +36,57 @@ pub fn make_color_map() -> FilterMap {
     .unwrap()
 }
 
+#[derive(Debug)]
+pub enum InflateError {
+    Cancelled,
+    /// A block ran out of data before all of its lines were decoded.
+    Truncated,
+    /// Reading a block failed for some other reason.
+    Io(io::Error),
+}
+
+impl From<Cancelled> for InflateError {
+    fn from(_: Cancelled) -> Self {
+        InflateError::Cancelled
+    }
+}
+
+impl From<io::Error> for InflateError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => InflateError::Truncated,
+            _ => InflateError::Io(error),
+        }
+    }
+}
+
 pub fn inflate(
     img_width: usize,
     img_height: usize,
     stripe_width: usize,
     blocks: Vec<Cursor<&[u8]>>,
     color_map: &FilterMap,
-) -> Vec<u16> {
+    progress: &dyn ProgressReporter,
+) -> Result<Vec<u16>, InflateError> {
+    let _timer = StageTimer::new("Decompressing");
     let output = vec![0; img_width * img_height];
     let mut mg = Array2::from_shape_vec((img_width, img_height).set_f(true), output).unwrap();
     // Split into 8 vertical stripes of stripe_width.
     let mut chunks = mg
         .axis_chunks_iter_mut(HORIZONTAL, stripe_width)
         .collect_vec();
-    chunks
-        .par_iter_mut()
-        .zip(blocks)
-        .enumerate()
-        .for_each(|(_block_num, (stripe, block))| {
-            inflate_stripe(block, color_map, stripe_width, stripe);
-        });
-    mg.into_raw_vec()
+    let num_stripes = chunks.len();
+    let stripes_done = AtomicUsize::new(0);
+    chunks.par_iter_mut().zip(blocks).enumerate().try_for_each(
+        |(_block_num, (stripe, block))| {
+            progress.check()?;
+            inflate_stripe(block, color_map, stripe_width, stripe)?;
+            let done = stripes_done.fetch_add(1, Ordering::SeqCst) + 1;
+            progress.report("Decompressing", done as f32 / num_stripes as f32);
+            Ok::<_, InflateError>(())
+        },
+    )?;
+    Ok(mg.into_raw_vec())
 }
 
 pub fn inflate_stripe<Reader: io::Read>(
@@ -64,7 +97,7 @@ pub fn inflate_stripe<Reader: io::Read>(
     // decompresses using the same size.
     stripe_width: usize,
     output: &mut ndarray::ArrayViewMut2<u16>,
-) {
+) -> io::Result<()> {
     let mut r: BitReader<_> = BitReader::new(reader);
 
     // As per Xtrans matrix, there's a max of 4 green pixels out of every 6, so
@@ -86,7 +119,7 @@ pub fn inflate_stripe<Reader: io::Read>(
     let num_lines = stripe_height / 6;
 
     for line in 0..num_lines {
-        let results = inflate_line(&mut r, &mut gradients, &prev_lines);
+        let results = inflate_line(&mut r, &mut gradients, &prev_lines)?;
         prev_lines = collect_carry_lines(&results);
         copy_line_to_xtrans(
             color_map,
@@ -95,6 +128,7 @@ pub fn inflate_stripe<Reader: io::Read>(
             results,
         )
     }
+    Ok(())
 }
 
 fn copy_line_to_xtrans(
@@ -138,7 +172,7 @@ fn inflate_line<R: io::Read>(
     reader: &mut BitReader<R>,
     gradients: &mut (Gradients, Gradients),
     carry_results: &Colored<Vec<Vec<u16>>>,
-) -> Colored<Vec<Vec<u16>>> {
+) -> io::Result<Colored<Vec<Vec<u16>>>> {
     let mut colors = Colored::new(
         vec![vec![UNSET; 512]; 3],
         vec![vec![UNSET; 512]; 6],
@@ -168,14 +202,14 @@ fn inflate_line<R: io::Read>(
                             *color,
                             *idx,
                             *grad_set_idx,
-                        )
+                        )?
                     };
                     colors[*color][*row][*idx] = value;
                 }
             }
         }
     }
-    colors
+    Ok(colors)
 }
 
 fn interpolate_value(
@@ -208,7 +242,7 @@ fn compute_value_and_update_gradients<R: io::Read>(
     color: Color,
     idx: usize,
     grad_set: usize,
-) -> u16 {
+) -> io::Result<u16> {
     let is_even = idx % 2 == 0;
     // Setup. Choose coefficients based on color / row etc
     let carry_results = &carry_results[color];
@@ -236,7 +270,7 @@ fn compute_value_and_update_gradients<R: io::Read>(
 
     let dec_bits = grad.bit_diff() as usize;
 
-    let sample = read_sample(reader, dec_bits).unwrap();
+    let sample = read_sample(reader, dec_bits)?;
 
     let delta = sample_to_delta(sample);
     // Finally: update gradient.
@@ -249,7 +283,7 @@ fn compute_value_and_update_gradients<R: io::Read>(
     grad.update_from_value(delta.abs());
 
     // huh, this is actually necessary.
-    actual_value.rem_euclid(1 << 14) as u16
+    Ok(actual_value.rem_euclid(1 << 14) as u16)
 }
 
 fn read_sample<T: io::Read>(reader: &mut BitReader<T>, lower_bits: usize) -> io::Result<Sample> {
@@ -299,13 +333,14 @@ fn sample_to_delta(sample: Sample) -> i32 {
 
 #[cfg(test)]
 mod test {
-    use crate::fuji_compressed::inflate::{inflate_stripe, make_color_map};
+    use crate::fuji_compressed::inflate::{inflate_stripe, make_color_map, InflateError};
     use crate::fuji_compressed::process_common::UNSET;
 
     use itertools::Itertools;
     use ndarray::prelude::*;
     use ndarray::Array2;
     use std::convert::TryInto;
+    use std::io;
 
     const STRIPE_WIDTH: usize = 768;
     const NUM_LINES: usize = 673;
@@ -329,9 +364,22 @@ mod test {
             &make_color_map(),
             STRIPE_WIDTH,
             &mut output.slice_mut(s![.., ..]),
-        );
+        )
+        .unwrap();
         let outdata = output.into_raw_vec();
         assert_eq!(outdata.len(), expected.len());
         assert_eq!(outdata, expected.as_slice());
     }
+
+    #[test]
+    fn only_running_out_of_data_is_truncation() {
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+        assert!(matches!(InflateError::from(eof), InflateError::Truncated));
+
+        let other = io::Error::other("disk on fire");
+        match InflateError::from(other) {
+            InflateError::Io(error) => assert_eq!(error.to_string(), "disk on fire"),
+            error => panic!("expected an I/O error, got {:?}", error),
+        }
+    }
 }
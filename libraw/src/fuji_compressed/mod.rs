@@ -8,13 +8,17 @@ mod zip_with_offset;
 use nom::bytes::complete::take;
 use nom::bytes::streaming::tag;
 use nom::combinator::map;
+use nom::error::ErrorKind;
 use nom::multi::count;
 use nom::number::complete::{be_u16, be_u32, be_u8};
 use nom::sequence::tuple;
 use nom::IResult;
 
+use crate::util::progress::{Cancelled, ProgressReporter};
 pub use compress::compress;
+use inflate::InflateError;
 use itertools::Itertools;
+use log::warn;
 use std::io::Cursor;
 
 #[derive(Debug)]
@@ -42,6 +46,20 @@ struct FujiCompressedHeader {
 
 type I<'a> = &'a [u8];
 
+// The decoder assumes X-Trans stripes of this width, holding 14-bit samples.
+const BLOCK_WIDTH: u16 = 768;
+const RAW_BITS: u8 = 14;
+
+impl FujiCompressedHeader {
+    /// Whether this is a layout that `inflate` knows how to decode.
+    fn is_supported(&self) -> bool {
+        self.block_width == BLOCK_WIDTH
+            && self.raw_bits == RAW_BITS
+            && self.num_blocks > 0
+            && self.raw_width as usize <= self.block_width as usize * self.num_blocks as usize
+    }
+}
+
 fn parse_fuji_header(input: I) -> IResult<I, FujiCompressedHeader> {
     map(
         tuple((
@@ -98,12 +116,27 @@ fn read_blocks<'a>(input: I<'a>, block_sizes: &[u32]) -> IResult<I<'a>, Vec<&'a
     Ok((i, blocks))
 }
 
-pub fn load_fuji_compressed(input: &[u8]) -> IResult<I, Vec<u16>> {
+/// Decodes Fuji's compressed raw format. The inner result is `Err` if `progress` cancelled
+/// decoding.
+pub fn load_fuji_compressed<'a>(
+    input: I<'a>,
+    progress: &dyn ProgressReporter,
+) -> IResult<I<'a>, Result<Vec<u16>, Cancelled>> {
     let i = input;
     let (i, header) = parse_fuji_header(i)?;
+    if !header.is_supported() {
+        return Err(nom::Err::Error((input, ErrorKind::Verify)));
+    }
     // TODO: build quantisation tables
     let (i, block_sizes) = block_sizes(i, header.num_blocks)?;
     let (_i, blocks) = read_blocks(i, &block_sizes)?;
+    // Every sample takes at least one bit, and each group of 6 lines in a stripe holds more samples
+    // than the stripe is wide, so a block which is smaller than that can't be valid. This bounds
+    // the size of the image by the size of the input, so tiny inputs can't claim enormous images.
+    let min_bits = header.raw_height as usize / 6 * header.block_width as usize;
+    if blocks.iter().any(|block| block.len() * 8 < min_bits) {
+        return Err(nom::Err::Error((input, ErrorKind::Eof)));
+    }
     let blocks = blocks.iter().map(|x| Cursor::new(*x)).collect_vec();
     let output = inflate::inflate(
         header.raw_width as usize,
@@ -111,6 +144,66 @@ pub fn load_fuji_compressed(input: &[u8]) -> IResult<I, Vec<u16>> {
         header.block_width as usize,
         blocks,
         &inflate::make_color_map(),
+        progress,
     );
-    Ok((input, output))
+    match output {
+        Ok(output) => Ok((input, Ok(output))),
+        Err(InflateError::Cancelled) => Ok((input, Err(Cancelled))),
+        Err(InflateError::Truncated) => Err(nom::Err::Error((input, ErrorKind::Eof))),
+        Err(InflateError::Io(error)) => {
+            warn!("Failed to read compressed data: {}", error);
+            Err(nom::Err::Error((input, ErrorKind::Verify)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_fuji_compressed;
+    use crate::util::progress::Silent;
+
+    const BLOCK: &[u8] = include_bytes!("testdata/DSCF2279-block0.compressed.bin");
+
+    fn header(raw_bits: u8, block_width: u16, num_blocks: u8) -> Vec<u8> {
+        let mut data = vec![0x49, 0x53, 1, 0, raw_bits];
+        // Height, rounded width, width, block width.
+        for value in &[4038u16, 768, 768, block_width] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.push(num_blocks);
+        data.extend_from_slice(&673u16.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn rejects_unsupported_headers() {
+        for data in &[header(12, 768, 1), header(14, 0, 1), header(14, 768, 0)] {
+            assert!(load_fuji_compressed(data, &Silent).is_err());
+        }
+    }
+
+    #[test]
+    fn blocks_must_be_big_enough_for_the_image() {
+        // 673 groups of lines, 768 samples wide, needs at least 64608 bytes.
+        let block = &BLOCK[..64000];
+        let mut data = header(14, 768, 1);
+        data.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        data.extend_from_slice(block);
+        assert!(load_fuji_compressed(&data, &Silent).is_err());
+    }
+
+    #[test]
+    fn truncated_blocks_are_errors() {
+        let block = &BLOCK[..BLOCK.len() / 2];
+        let mut data = header(14, 768, 1);
+        data.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        data.extend_from_slice(block);
+        assert!(load_fuji_compressed(&data, &Silent).is_err());
+
+        // And a block that's shorter than it claims to be.
+        let mut data = header(14, 768, 1);
+        data.extend_from_slice(&(BLOCK.len() as u32).to_be_bytes());
+        data.extend_from_slice(block);
+        assert!(load_fuji_compressed(&data, &Silent).is_err());
+    }
 }
@@ -1,6 +1,6 @@
 use crate::raf::RafFile;
 use crate::tiff;
-use crate::tiff::{parse_tiff_with_options, IfdEntry, MAKERNOTES_TAG_ID};
+use crate::tiff::{parse_tiff_with_options, IfdEntry, TiffFile, MAKERNOTES_TAG_ID};
 use itertools::Itertools;
 use num_traits::FromPrimitive;
 use std::collections::HashMap;
@@ -43,8 +43,17 @@ fn get_field_val(hm: &HashMap<u16, &IfdEntry>, tag: u16) -> Result<u32, String>
     Ok(val)
 }
 
-pub fn load_focus_info(raf_file: &RafFile) -> Result<FocusInfo, Box<dyn Error + '_>> {
-    let exif_bytes = raf_file.file_parts()?.jpeg_exif_tiff;
+/// Where the camera focused, in pixels of the full-size JPEG it would have produced.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FocusPoint {
+    pub x: u16,
+    pub y: u16,
+}
+
+const FOCUS_PIXEL_TAG_ID: u16 = 0x1023;
+
+/// Parses Fuji's makernotes out of a TIFF-formatted EXIF block.
+fn parse_makernotes(exif_bytes: &[u8]) -> Result<TiffFile, Box<dyn Error + '_>> {
     let (_, tiff) = tiff::parse_tiff_with_options(exif_bytes, b"II*\0", true)?;
     let makernotes = tiff
         .all_fields()
@@ -52,9 +61,18 @@ pub fn load_focus_info(raf_file: &RafFile) -> Result<FocusInfo, Box<dyn Error +
         .exactly_one()
         .ok()
         .ok_or("Couldn't find exactly one MakerNotes field.")?;
-    let makernotes_content = tiff.data_for_ifd_entry(makernotes);
-    let (_, makernotes_tiff) =
-        parse_tiff_with_options(&makernotes_content, b"FUJIFILM", false).unwrap();
+    // Makernotes are always too big to be inlined.
+    let makernotes_content = makernotes
+        .val_as_offset()
+        .and_then(|start| exif_bytes.get(start..))
+        .and_then(|data| data.get(..makernotes.count as usize))
+        .ok_or("MakerNotes field is outside the EXIF data.")?;
+    let (_, makernotes_tiff) = parse_tiff_with_options(makernotes_content, b"FUJIFILM", false)?;
+    Ok(makernotes_tiff)
+}
+
+pub fn load_focus_info(raf_file: &RafFile) -> Result<FocusInfo, Box<dyn Error + '_>> {
+    let makernotes_tiff = parse_makernotes(raf_file.file_parts()?.jpeg_exif_tiff)?;
 
     let hm: HashMap<u16, &IfdEntry> = makernotes_tiff
         .all_fields()
@@ -79,8 +97,6 @@ pub fn load_focus_info(raf_file: &RafFile) -> Result<FocusInfo, Box<dyn Error +
     // TODO: check this
     let focus_area_zone_size = ((settings & 0xF0000) >> 16) as u16;
 
-    // TODO: focus pixels. Requires some refactoring.
-
     Ok(FocusInfo {
         afs_priority,
         afc_priority,
@@ -90,3 +106,65 @@ pub fn load_focus_info(raf_file: &RafFile) -> Result<FocusInfo, Box<dyn Error +
         focus_area_zone_size,
     })
 }
+
+/// Loads the focus point, or `None` if the camera didn't record one, e.g. because it was focused
+/// manually.
+pub fn load_focus_point(raf_file: &RafFile) -> Result<Option<FocusPoint>, Box<dyn Error + '_>> {
+    focus_point(raf_file.file_parts()?.jpeg_exif_tiff)
+}
+
+fn focus_point(exif_bytes: &[u8]) -> Result<Option<FocusPoint>, Box<dyn Error + '_>> {
+    let makernotes_tiff = parse_makernotes(exif_bytes)?;
+    let entry = match makernotes_tiff
+        .all_fields()
+        .find(|entry| entry.tag == FOCUS_PIXEL_TAG_ID)
+    {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let value = entry.value(&makernotes_tiff)?.into_u32s();
+    match value.as_deref() {
+        Some(&[x, y]) => Ok(Some(FocusPoint {
+            x: x as u16,
+            y: y as u16,
+        })),
+        _ => Err("Invalid 0x1023".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tiff::{Endian, TiffBuilder, TiffValue, EXIF_IFD_TAG_ID};
+
+    fn exif_with_makernotes(makernote_fields: &[u8]) -> Vec<u8> {
+        // A Fuji makernote is "FUJIFILM", then the offset of its only IFD, relative to itself.
+        let mut makernotes = b"FUJIFILM".to_vec();
+        makernotes.extend_from_slice(&12u32.to_le_bytes());
+        makernotes.extend_from_slice(makernote_fields);
+        makernotes.extend_from_slice(&[0; 4]);
+
+        let mut builder = TiffBuilder::new(Endian::Little);
+        let ifd0 = builder.add_ifd();
+        let exif = builder.add_sub_ifd(ifd0, EXIF_IFD_TAG_ID);
+        builder.set(exif, MAKERNOTES_TAG_ID, TiffValue::Undefined(makernotes));
+        builder.to_bytes()
+    }
+
+    #[test]
+    fn reads_focus_point() {
+        // One Short[2] entry holding (1504, 1000).
+        let fields = [1, 0, 0x23, 0x10, 3, 0, 2, 0, 0, 0, 0xE0, 0x05, 0xE8, 0x03];
+        let exif = exif_with_makernotes(&fields);
+        let point = focus_point(&exif).unwrap();
+        assert_eq!(point, Some(FocusPoint { x: 1504, y: 1000 }));
+
+        let exif = exif_with_makernotes(&[0, 0]);
+        assert_eq!(focus_point(&exif).unwrap(), None);
+
+        // Makernotes which run off the end are errors.
+        let mut exif = exif_with_makernotes(&fields);
+        exif.truncate(exif.len() - 8);
+        assert!(focus_point(&exif).is_err());
+    }
+}
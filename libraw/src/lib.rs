@@ -1,9 +1,12 @@
 #![allow(clippy::just_underscores_and_digits, clippy::too_many_arguments)]
 
+pub mod dng;
+pub mod exif;
 pub mod fuji_compressed;
 pub mod fuji_meta;
 pub mod griditer;
 pub mod raf;
+pub mod raw_image;
 pub mod tiff;
 pub mod tifflabels;
 pub mod util;
@@ -1,14 +1,18 @@
+use crate::exif::ExifSummary;
 use crate::griditer::BlackPattern;
 use crate::raf::EncodingType::{Compressed, Uncompressed, Unknown};
 use crate::raf::Tag::XTransMapping;
-use crate::tiff::{IfdEntry, SRational};
+use crate::tiff::{IfdEntry, SRational, TiffValue};
+use crate::util::progress::{Cancelled, ProgressReporter, Silent};
+use crate::util::timing::StageTimer;
 use crate::{fuji_compressed, tiff, Color};
 use itertools::Itertools;
+use log::warn;
 use memmap::Mmap;
 use ndarray::{Array2, ShapeBuilder};
 use nom::bytes::streaming::{tag, take};
 use nom::combinator::all_consuming;
-use nom::error::ParseError;
+use nom::error::{ErrorKind, ParseError};
 use nom::lib::std::collections::HashMap;
 use nom::multi::count;
 use nom::number::complete::{be_u16, be_u32, le_u16};
@@ -16,7 +20,10 @@ use nom::sequence::tuple;
 use nom::IResult;
 use std::fmt::Debug;
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 type I<'a> = &'a [u8];
 
@@ -32,6 +39,9 @@ quick_error! {
         // I don't know how to capture nom:Err here, so we're stuck with this.
         Unknown {
         }
+        Cancelled {
+            display("Cancelled")
+        }
     }
 }
 
@@ -41,12 +51,12 @@ struct Header<'a> {
     fw_version: &'a str,
 }
 
-fn str_from_fixed_len_buf(input: I) -> &str {
-    if let Some(idx) = input.iter().position(|&elem| elem == 0) {
-        std::str::from_utf8(&input[0..idx]).unwrap()
-    } else {
-        std::str::from_utf8(input).unwrap()
-    }
+fn str_from_fixed_len_buf<'a>(input: I<'a>) -> Result<&'a str, nom::Err<(I<'a>, ErrorKind)>> {
+    let end = input
+        .iter()
+        .position(|&elem| elem == 0)
+        .unwrap_or(input.len());
+    std::str::from_utf8(&input[..end]).map_err(|_| nom::Err::Error((input, ErrorKind::Verify)))
 }
 
 fn header(input: I) -> IResult<I, Header> {
@@ -60,8 +70,8 @@ fn header(input: I) -> IResult<I, Header> {
         count(tag(b"\0"), 16),
     ))(input)?;
     let (more, (_, model, fw_version, _)) = res;
-    let model = str_from_fixed_len_buf(model);
-    let fw_version = str_from_fixed_len_buf(fw_version);
+    let model = str_from_fixed_len_buf(model)?;
+    let fw_version = str_from_fixed_len_buf(fw_version)?;
     Ok((more, Header { model, fw_version }))
 }
 
@@ -86,23 +96,29 @@ pub struct FileParts<'a> {
 }
 
 // TODO: this isn't polished or resilient; maybe I should use a library for this.
-fn find_exif_tiff(jpeg_data: &[u8]) -> IResult<I, &[u8]> {
+pub(crate) fn find_exif_tiff(jpeg_data: &[u8]) -> IResult<I, &[u8]> {
     let (i, (_tag, length, _tag2, _exif_version)) =
         tuple((tag(b"\xFF\xD8\xFF\xE1"), be_u16, tag(b"Exif"), be_u16))(jpeg_data)?;
-    Ok((i, &i[..(length as usize - 2)]))
+    // The segment length counts itself and the "Exif\0\0" marker as well as the TIFF.
+    let tiff_length = (length as usize)
+        .checked_sub(8)
+        .ok_or(nom::Err::Error((i, ErrorKind::Verify)))?;
+    take(tiff_length)(i)
 }
 
 impl<'a> FileParts<'a> {
-    fn from_offsets(data: &'a [u8], offsets: &Offsets) -> FileParts<'a> {
-        let jpeg_data = offsets.jpeg.apply(data);
-        // TODO: this is in a gross spot, and we shouldn't call unwrap.
-        let (_, exif_tiff) = find_exif_tiff(jpeg_data).unwrap();
-        FileParts {
-            jpeg: jpeg_data,
-            jpeg_exif_tiff: exif_tiff,
-            metadata: offsets.metadata.apply(data),
-            raw: offsets.raw.apply(data),
-        }
+    fn from_offsets(data: &'a [u8], offsets: &Offsets) -> IResult<I<'a>, FileParts<'a>> {
+        let jpeg_data = offsets.jpeg.apply(data)?;
+        let (_, exif_tiff) = find_exif_tiff(jpeg_data)?;
+        Ok((
+            data,
+            FileParts {
+                jpeg: jpeg_data,
+                jpeg_exif_tiff: exif_tiff,
+                metadata: offsets.metadata.apply(data)?,
+                raw: offsets.raw.apply(data)?,
+            },
+        ))
     }
 }
 
@@ -124,10 +140,11 @@ fn offset_sizes(input: I) -> IResult<I, Offsets> {
 }
 
 impl OffsetLength {
-    fn apply(self, input: &[u8]) -> &[u8] {
+    fn apply(self, input: I) -> Result<I, nom::Err<(I, ErrorKind)>> {
         let start = self.offset as usize;
-        let end = (self.offset + self.length) as usize;
-        &input[start..end]
+        let end = start.checked_add(self.length as usize);
+        end.and_then(|end| input.get(start..end))
+            .ok_or(nom::Err::Error((input, ErrorKind::Eof)))
     }
 }
 
@@ -214,10 +231,46 @@ pub struct ParsedRafFile<'a> {
     jpg_preview: &'a [u8],
     // This is in the middle RAF section
     pub metadata: ImgMeta<'a>,
+    metadata_section: &'a [u8],
+    decoded: DecodedRaw,
+}
+
+/// A RAF file which owns everything it needs, so unlike `ParsedRafFile` it can outlive the
+/// `RafFile` it was loaded from.
+#[derive(Debug)]
+pub struct RafImage {
+    model: String,
+    firmware: String,
+    preview: Vec<u8>,
+    metadata_section: Vec<u8>,
+    decoded: DecodedRaw,
+}
+
+/// The decoded sensor data, along with everything needed to render it.
+#[derive(Debug)]
+struct DecodedRaw {
     tiffish: TiffishData,
+    xtrans_mapping: Vec<Color>,
+    black_levels: BlackPattern,
+    crop_rect: CropRect,
 }
 
-fn extract_xtrans_mapping(metadata: &ImgMeta) -> Vec<Color> {
+impl DecodedRaw {
+    fn render_info(&self) -> RenderInfo<'_> {
+        RenderInfo {
+            width: self.tiffish.width,
+            height: self.tiffish.height,
+            bit_depth: self.tiffish.bit_depth,
+            black_levels: self.black_levels.clone(),
+            white_bal: self.tiffish.white_bal,
+            xtrans_mapping: self.xtrans_mapping.clone(),
+            crop_rect: self.crop_rect,
+            raw_data: &self.tiffish.raw_data,
+        }
+    }
+}
+
+fn extract_xtrans_mapping(metadata: &ImgMeta) -> Option<Vec<Color>> {
     // Oh boy
     let mut xtrans: Vec<Color> = metadata
         .iter()
@@ -226,13 +279,16 @@ fn extract_xtrans_mapping(metadata: &ImgMeta) -> Vec<Color> {
             _ => None,
         })
         .exactly_one()
-        .unwrap()
+        .ok()?
         .iter()
-        .map(|num| Color::from(*num as i8).unwrap())
-        .collect();
+        .map(|num| Color::from(*num as i8))
+        .collect::<Option<_>>()?;
+    if xtrans.len() != 36 {
+        return None;
+    }
     // This is _backwards_ in the file.
     xtrans.reverse();
-    xtrans
+    Some(xtrans)
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -244,7 +300,7 @@ pub struct CropRect {
 }
 
 impl CropRect {
-    fn new(metadata: &ImgMeta) -> Self {
+    fn new(metadata: &ImgMeta) -> Option<Self> {
         let (top, left) = metadata
             .iter()
             .filter_map(|it| match it {
@@ -252,7 +308,7 @@ impl CropRect {
                 _ => None,
             })
             .exactly_one()
-            .unwrap();
+            .ok()?;
         let (width, height) = metadata
             .iter()
             .filter_map(|it| match it {
@@ -260,45 +316,143 @@ impl CropRect {
                 _ => None,
             })
             .exactly_one()
-            .unwrap();
+            .ok()?;
         let left = left as usize;
         let top = top as usize;
         let right = left + width as usize;
         let bottom = top + height as usize;
-        CropRect {
+        Some(CropRect {
             left,
             right,
             top,
             bottom,
-        }
+        })
+    }
+
+    /// Like `new`, but only if the crop fits within a `width` by `height` sensor.
+    fn within(metadata: &ImgMeta, width: Width, height: Height) -> Option<Self> {
+        Some(CropRect::new(metadata)?)
+            .filter(|crop| crop.right <= width as usize && crop.bottom <= height as usize)
     }
+
     pub fn size(&self) -> (usize, usize) {
         (self.right - self.left, self.bottom - self.top)
     }
 }
 
 impl<'a> ParsedRafFile<'a> {
+    /// Parses a whole RAF file held in memory.
+    pub fn parse(input: &'a [u8]) -> Result<ParsedRafFile<'a>, RafError> {
+        Self::parse_with_progress(input, &Silent)
+    }
+
+    /// Like `parse`, but reports decoding progress to `progress`, and stops early with
+    /// `RafError::Cancelled` if it asks us to.
+    pub fn parse_with_progress(
+        input: &'a [u8],
+        progress: &dyn ProgressReporter,
+    ) -> Result<ParsedRafFile<'a>, RafError> {
+        match parse_all(input, progress) {
+            Ok((_, Ok(parsed))) => Ok(parsed),
+            Ok((_, Err(Cancelled))) => Err(RafError::Cancelled),
+            Err(_) => Err(RafError::Unknown),
+        }
+    }
+
     pub fn render_info(&self) -> RenderInfo {
-        let xtrans_mapping = extract_xtrans_mapping(&self.metadata);
-        let black_levels =
-            Array2::from_shape_vec((6, 6).set_f(true), self.tiffish.black_levels.clone()).unwrap();
-        let crop_rect = CropRect::new(&self.metadata);
+        self.decoded.render_info()
+    }
 
-        RenderInfo {
-            width: self.tiffish.width,
-            height: self.tiffish.height,
-            bit_depth: self.tiffish.bit_depth,
-            black_levels,
-            white_bal: self.tiffish.white_bal.clone(),
-            xtrans_mapping,
-            crop_rect,
-            raw_data: &self.tiffish.raw_data,
+    pub fn vignette_attenuation(&self) -> &[SRational] {
+        &self.decoded.tiffish.vignette_attenuation
+    }
+
+    pub fn model(&self) -> &str {
+        self.header.model
+    }
+
+    pub fn firmware(&self) -> &str {
+        self.header.fw_version
+    }
+
+    /// The camera's JPEG preview, which carries the EXIF metadata.
+    pub fn preview(&self) -> &[u8] {
+        self.jpg_preview
+    }
+
+    /// Copies out everything that borrows from the file. The decoded image is moved, not copied.
+    pub fn into_owned(self) -> RafImage {
+        RafImage {
+            model: self.header.model.to_string(),
+            firmware: self.header.fw_version.to_string(),
+            preview: self.jpg_preview.to_vec(),
+            metadata_section: self.metadata_section.to_vec(),
+            decoded: self.decoded,
         }
     }
+}
+
+impl<'a> From<ParsedRafFile<'a>> for RafImage {
+    fn from(parsed: ParsedRafFile<'a>) -> Self {
+        parsed.into_owned()
+    }
+}
+
+impl RafImage {
+    /// Parses a whole RAF file held in memory, copying what it needs out of `input`.
+    pub fn parse(input: &[u8]) -> Result<RafImage, RafError> {
+        ParsedRafFile::parse(input).map(RafImage::from)
+    }
+
+    /// Like `parse`, but reports decoding progress to `progress`, and stops early with
+    /// `RafError::Cancelled` if it asks us to.
+    pub fn parse_with_progress(
+        input: &[u8],
+        progress: &dyn ProgressReporter,
+    ) -> Result<RafImage, RafError> {
+        ParsedRafFile::parse_with_progress(input, progress).map(RafImage::from)
+    }
+
+    /// The tags from the middle RAF section, as in `ParsedRafFile::metadata`.
+    pub fn metadata(&self) -> ImgMeta {
+        let (_, metadata) =
+            parse_metadata(&self.metadata_section).expect("metadata was checked when parsing");
+        metadata
+    }
+
+    pub fn render_info(&self) -> RenderInfo {
+        self.decoded.render_info()
+    }
 
     pub fn vignette_attenuation(&self) -> &[SRational] {
-        &self.tiffish.vignette_attenuation
+        &self.decoded.tiffish.vignette_attenuation
     }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn firmware(&self) -> &str {
+        &self.firmware
+    }
+
+    /// The camera's JPEG preview, which carries the EXIF metadata.
+    pub fn preview(&self) -> &[u8] {
+        &self.preview
+    }
+}
+
+/// What's known about a RAF file without decoding its image data.
+#[derive(Debug, PartialEq, Clone)]
+pub struct RafInfo {
+    pub model: String,
+    pub firmware: String,
+    /// The size of the sensor data, before cropping.
+    pub width: Width,
+    pub height: Height,
+    pub crop_rect: CropRect,
+    /// The camera's as-shot white balance.
+    pub white_bal: WhiteBalCoefficients,
 }
 
 #[derive(Debug)]
@@ -323,6 +477,20 @@ pub struct RenderInfo<'a> {
     pub raw_data: &'a Vec<u16>,
 }
 
+impl<'a> RenderInfo<'a> {
+    /// The largest value the sensor can record.
+    pub fn white_level(&self) -> u16 {
+        ((1u32 << self.bit_depth) - 1) as u16
+    }
+
+    /// The raw value at or above which a photosite counts as saturated. Saturated photosites
+    /// don't always read exactly the maximum, so this leaves a 1% margin.
+    pub fn saturation_threshold(&self) -> u16 {
+        let white_level = self.white_level();
+        white_level - white_level / 100
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct WhiteBalCoefficients {
     pub red: u16,
@@ -348,28 +516,68 @@ impl From<u32> for EncodingType {
     }
 }
 
-fn parse_tiffish(raw: &[u8]) -> IResult<I, TiffishData> {
+/// The raw section's IFD, with the image data still encoded.
+struct RawIfd<'a> {
+    width: Width,
+    height: Height,
+    bit_depth: u16,
+    black_levels: Vec<u16>,
+    white_bal: WhiteBalCoefficients,
+    vignette_attenuation: Vec<SRational>,
+    encoding: EncodingType,
+    img_bytes: &'a [u8],
+}
+
+fn parse_raw_ifd(raw: I) -> IResult<I, RawIfd> {
+    let invalid = || nom::Err::Error((raw, ErrorKind::Verify));
     let (_, tiff) = tiff::parse_tiff(raw)?;
-    let ifd_block = &tiff.ifds[0][0];
-    let (_, (ifd, next)) = tiff::parse_ifd(&raw[(ifd_block.val_u32().unwrap() as usize)..])?;
-    assert!(next.is_none());
+    let ifd_block = tiff
+        .ifds
+        .first()
+        .and_then(|ifd| ifd.first())
+        .ok_or_else(invalid)?;
+    let ifd_offset = ifd_block.val_u32().ok_or_else(invalid)? as usize;
+    let (_, (ifd, next)) = tiff::parse_ifd(raw.get(ifd_offset..).ok_or_else(invalid)?)?;
+    if next.is_some() {
+        return Err(invalid());
+    }
 
     let hm: HashMap<u16, &IfdEntry> = ifd.iter().map(|item| (item.tag, item)).collect();
-    let width = hm[&61441].val_u32().unwrap() as Width;
-    let height = hm[&61442].val_u32().unwrap() as Height;
-    let bit_depth = hm[&61443].val_u32().unwrap() as u16;
+    let value = |tag| {
+        hm.get(&tag)
+            .and_then(|entry| entry.value(&tiff).ok())
+            .ok_or_else(invalid)
+    };
+    let uint = |tag| {
+        hm.get(&tag)
+            .and_then(|entry| entry.val_u32())
+            .ok_or_else(invalid)
+    };
+    let u32s = |tag| value(tag)?.into_u32s().ok_or_else(invalid);
+    let srationals = |tag| match value(tag)? {
+        TiffValue::SRational(values) => Ok(values),
+        _ => Err(invalid()),
+    };
+    let width = uint(61441)? as Width;
+    let height = uint(61442)? as Height;
+    let bit_depth = uint(61443)? as u16;
+    if bit_depth == 0 || bit_depth > 16 {
+        return Err(invalid());
+    }
     // _Maybe_ data offset + length for compressed?
     // Pretty sure this is data offset
-    let img_byte_offset = hm[&61447].val_u32().unwrap() as usize;
+    let img_byte_offset = uint(61447)? as usize;
     // 20743472 is this number, it's very large. 449024 is where the TIFF starts
     // 20743472 + 449024 = 21192496 ... is in middle of data, + 2048 is end of file.
     // it's the length (in bytes) of the data section.
-    let img_byte_count = hm[&61448].val_u32().unwrap() as usize;
-    let img_num_u16 = img_byte_count / 2;
-    let img_encoding_type = EncodingType::from(hm[&61449].val_u32().unwrap());
+    let img_byte_count = uint(61448)? as usize;
+    let img_encoding_type = EncodingType::from(uint(61449)?);
 
-    let black_levels: Vec<u32> = tiff.load_offset_data(hm[&61450]).unwrap();
+    let black_levels = u32s(61450)?;
     let black_levels: Vec<u16> = black_levels.iter().map(|x| *x as u16).collect();
+    if black_levels.len() != 36 {
+        return Err(invalid());
+    }
 
     // I think these are colorspace-conversion related.
     // 8 vals in two pairs, e.g.
@@ -383,13 +591,16 @@ fn parse_tiffish(raw: &[u8]) -> IResult<I, TiffishData> {
     // and then a calibration matrix for tuning (in DNG, it's the CameraCalibration tags, in Libraw,
     // it's the colorinfo.ccm field)
     // Once I zeroed these out and converted to DNG, the CameraCalibration tags had been removed.
-    let _52: Vec<u32> = tiff.load_offset_data(hm[&61452]).unwrap();
+    let _52 = u32s(61452)?;
 
     // Note that tag 61454 had the same values on all the files I tested -
     // not sure what the difference is. DCRAW uses '54 and not '53.
     // Alright, on my COMPRESSED RAW FILE test (2827), '54 and '53 were the same
     // values. On the uncompressed test (6281) they're different, and '53 isn't right.
-    let wb: Vec<u32> = tiff.load_offset_data(hm[&61454]).unwrap();
+    let wb = u32s(61454)?;
+    if wb.len() < 3 {
+        return Err(invalid());
+    }
     let wb = WhiteBalCoefficients {
         // The order here in the RAF file is green, red, blue.
         // TODO: maybe this is similar to how TIFF does it?
@@ -398,70 +609,159 @@ fn parse_tiffish(raw: &[u8]) -> IResult<I, TiffishData> {
         blue: wb[2] as u16,
     };
 
-    let img_bytes = &raw[img_byte_offset..(img_byte_offset + img_byte_count)];
-
-    let decode_result = match img_encoding_type {
-        Compressed => fuji_compressed::load_fuji_compressed(img_bytes),
-        _ => all_consuming(count(le_u16, img_num_u16))(img_bytes),
-    };
-
-    let (_, img_data) = decode_result?;
+    let img_bytes = img_byte_offset
+        .checked_add(img_byte_count)
+        .and_then(|end| raw.get(img_byte_offset..end))
+        .ok_or_else(invalid)?;
 
     // '51, '55, '56 all look like some kind of curve.
     // The first number looks like x/y axis lengths, then x positions, then y positions.
-    let _51: Vec<SRational> = tiff.load_offset_data(hm[&61451]).unwrap();
-    let _55: Vec<SRational> = tiff.load_offset_data(hm[&61455]).unwrap();
-    let vignette_attentuation: Vec<SRational> = tiff.load_offset_data(hm[&61456]).unwrap();
+    let _51 = srationals(61451)?;
+    let _55 = srationals(61455)?;
+    let vignette_attentuation = srationals(61456)?;
 
     Ok((
         raw,
-        TiffishData {
+        RawIfd {
             width,
             height,
             bit_depth,
             black_levels,
             white_bal: wb,
-            raw_data: img_data,
             vignette_attenuation: vignette_attentuation,
+            encoding: img_encoding_type,
+            img_bytes,
         },
     ))
 }
 
+/// Parses the raw section. The inner result is `Err` if `progress` cancelled decoding.
+fn parse_tiffish<'a>(
+    raw: I<'a>,
+    progress: &dyn ProgressReporter,
+) -> IResult<I<'a>, Result<TiffishData, Cancelled>> {
+    let (_, ifd) = parse_raw_ifd(raw)?;
+    let img_bytes = ifd.img_bytes;
+    let decode_result = match ifd.encoding {
+        Compressed => fuji_compressed::load_fuji_compressed(img_bytes, progress),
+        _ => all_consuming(count(le_u16, img_bytes.len() / 2))(img_bytes)
+            .map(|(i, data)| (i, Ok(data))),
+    };
+
+    let img_data = match decode_result? {
+        (_, Ok(img_data)) => img_data,
+        (_, Err(Cancelled)) => return Ok((raw, Err(Cancelled))),
+    };
+    if img_data.len() != ifd.width as usize * ifd.height as usize {
+        return Err(nom::Err::Error((raw, ErrorKind::Verify)));
+    }
+
+    Ok((
+        raw,
+        Ok(TiffishData {
+            width: ifd.width,
+            height: ifd.height,
+            bit_depth: ifd.bit_depth,
+            black_levels: ifd.black_levels,
+            white_bal: ifd.white_bal,
+            raw_data: img_data,
+            vignette_attenuation: ifd.vignette_attenuation,
+        }),
+    ))
+}
+
 fn parse_preview(input: I) -> IResult<I, &[u8]> {
     let (i, (_header, offsets)) = tuple((header, offset_sizes))(input)?;
-    Ok((i, offsets.jpeg.apply(input)))
+    Ok((i, offsets.jpeg.apply(input)?))
 }
 
 fn parse_only_metadata(input: I) -> IResult<I, ImgMeta> {
     let (_, (_, offsets)) = tuple((header, offset_sizes))(input)?;
-    let metadata = offsets.metadata.apply(input);
+    let metadata = offsets.metadata.apply(input)?;
     let (i, metadata) = parse_metadata(metadata)?;
     Ok((i, metadata))
 }
 
-fn parse_all(input: I) -> IResult<I, ParsedRafFile> {
+fn parse_info(input: I) -> IResult<I, RafInfo> {
     let (_, (header, offsets)) = tuple((header, offset_sizes))(input)?;
-    let jpg_preview = offsets.jpeg.apply(input);
-    let metadata = offsets.metadata.apply(input);
-    let raw = offsets.raw.apply(input);
-    let (_, tiffish) = parse_tiffish(raw)?;
-    let (i, metadata) = parse_metadata(metadata)?;
+    let (_, ifd) = parse_raw_ifd(offsets.raw.apply(input)?)?;
+    let (i, metadata) = parse_metadata(offsets.metadata.apply(input)?)?;
+    let crop_rect = CropRect::within(&metadata, ifd.width, ifd.height)
+        .ok_or(nom::Err::Error((input, ErrorKind::Verify)))?;
+    Ok((
+        i,
+        RafInfo {
+            model: header.model.to_string(),
+            firmware: header.fw_version.to_string(),
+            width: ifd.width,
+            height: ifd.height,
+            crop_rect,
+            white_bal: ifd.white_bal,
+        },
+    ))
+}
+
+fn parse_all<'a>(
+    input: I<'a>,
+    progress: &dyn ProgressReporter,
+) -> IResult<I<'a>, Result<ParsedRafFile<'a>, Cancelled>> {
+    let (_, (header, offsets)) = tuple((header, offset_sizes))(input)?;
+    let jpg_preview = offsets.jpeg.apply(input)?;
+    let metadata_section = offsets.metadata.apply(input)?;
+    let raw = offsets.raw.apply(input)?;
+    let (_, tiffish) = parse_tiffish(raw, progress)?;
+    let tiffish = match tiffish {
+        Ok(tiffish) => tiffish,
+        Err(Cancelled) => return Ok((input, Err(Cancelled))),
+    };
+    let (i, metadata) = parse_metadata(metadata_section)?;
+
+    // Check everything rendering relies on now, so that `render_info` can't fail.
+    let invalid = || nom::Err::Error((input, ErrorKind::Verify));
+    let xtrans_mapping = extract_xtrans_mapping(&metadata).ok_or_else(invalid)?;
+    let crop_rect =
+        CropRect::within(&metadata, tiffish.width, tiffish.height).ok_or_else(invalid)?;
+    let black_levels =
+        Array2::from_shape_vec((6, 6).set_f(true), tiffish.black_levels.clone()).unwrap();
     Ok((
         i,
-        ParsedRafFile {
+        Ok(ParsedRafFile {
             header,
             jpg_preview,
             metadata,
-            tiffish,
-        },
+            metadata_section,
+            decoded: DecodedRaw {
+                tiffish,
+                xtrans_mapping,
+                black_levels,
+                crop_rect,
+            },
+        }),
     ))
 }
 
+/// Where a `RafFile`'s bytes live.
+#[derive(Debug)]
+enum Data {
+    Mapped(Mmap),
+    Shared(Arc<[u8]>),
+}
+
+impl Deref for Data {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Data::Mapped(mmap) => mmap,
+            Data::Shared(bytes) => bytes,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RafFile {
-    file: File,
-    path: PathBuf,
-    mmap: Mmap,
+    path: Option<PathBuf>,
+    data: Data,
 }
 
 impl RafFile {
@@ -469,15 +769,51 @@ impl RafFile {
         let file = File::open(&path)?;
         let mmap = unsafe { Mmap::map(&file) }?;
         let path = PathBuf::from(path.as_ref());
-        Ok(RafFile { file, path, mmap })
+        Ok(RafFile {
+            path: Some(path),
+            data: Data::Mapped(mmap),
+        })
+    }
+
+    /// Wraps a RAF file that's already in memory, e.g. an upload. Pass an `Arc<[u8]>` to share
+    /// the bytes with their other owners rather than copying them.
+    pub fn from_bytes<B: Into<Arc<[u8]>>>(bytes: B) -> RafFile {
+        RafFile {
+            path: None,
+            data: Data::Shared(bytes.into()),
+        }
+    }
+
+    /// Reads a RAF file from the current position of `reader` to its end, e.g. from an entry in
+    /// an archive.
+    pub fn from_reader<R: Read + Seek>(mut reader: R) -> Result<RafFile, RafError> {
+        let start = reader.stream_position()?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+        let mut bytes = Vec::with_capacity(end.saturating_sub(start) as usize);
+        reader.read_to_end(&mut bytes)?;
+        Ok(RafFile::from_bytes(bytes))
+    }
+
+    /// The file this was opened from, or `None` if it was loaded from memory.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The whole file.
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
     }
 
-    pub fn path(&self) -> &Path {
-        &self.path
+    fn name(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "in-memory RAF".to_string(),
+        }
     }
 
     pub fn parse_meta(&self) -> Result<ImgMeta, RafError> {
-        let result = parse_only_metadata(&self.mmap);
+        let result = parse_only_metadata(&self.data);
         match result {
             Ok((_, parsed)) => Ok(parsed),
             Err(_) => Err(RafError::Unknown),
@@ -485,24 +821,250 @@ impl RafFile {
     }
 
     pub fn parse_preview(&self) -> Result<&[u8], RafError> {
-        parse_preview(&self.mmap)
+        parse_preview(&self.data)
             .map(|(_, jpg)| jpg)
             .map_err(|_| RafError::Unknown)
     }
 
+    /// Reads the camera and sensor details, which is much quicker than `parse_raw` as the image
+    /// data isn't decoded.
+    pub fn parse_info(&self) -> Result<RafInfo, RafError> {
+        parse_info(&self.data)
+            .map(|(_, info)| info)
+            .map_err(|_| RafError::Unknown)
+    }
+
+    /// Summarises the EXIF metadata which the camera stored in the JPEG preview.
+    pub fn parse_exif(&self) -> Result<ExifSummary, RafError> {
+        let exif_tiff = self.file_parts()?.jpeg_exif_tiff;
+        ExifSummary::parse(exif_tiff).ok_or(RafError::Unknown)
+    }
+
     pub fn parse_raw(&self) -> Result<ParsedRafFile, RafError> {
-        let result = parse_all(&self.mmap);
-        match result {
-            Ok((_, parsed)) => Ok(parsed),
-            Err(_) => Err(RafError::Unknown),
+        self.parse_raw_with_progress(&Silent)
+    }
+
+    /// Like `parse_raw`, but reports decoding progress to `progress`, and stops early with
+    /// `RafError::Cancelled` if it asks us to.
+    pub fn parse_raw_with_progress(
+        &self,
+        progress: &dyn ProgressReporter,
+    ) -> Result<ParsedRafFile, RafError> {
+        let _timer = StageTimer::new("Parse RAF");
+        let result = ParsedRafFile::parse_with_progress(&self.data, progress);
+        if let Err(RafError::Unknown) = result {
+            warn!("Failed to parse {}", self.name());
         }
+        result
+    }
+
+    /// Parses and decodes the file into a `RafImage`, which doesn't borrow from `self`.
+    pub fn load(&self) -> Result<RafImage, RafError> {
+        self.load_with_progress(&Silent)
+    }
+
+    /// Like `load`, but reports decoding progress to `progress`, and stops early with
+    /// `RafError::Cancelled` if it asks us to.
+    pub fn load_with_progress(
+        &self,
+        progress: &dyn ProgressReporter,
+    ) -> Result<RafImage, RafError> {
+        self.parse_raw_with_progress(progress).map(RafImage::from)
     }
 
     pub fn file_parts(&self) -> Result<FileParts, RafError> {
-        let result = tuple((header, offset_sizes))(&self.mmap);
+        let result = tuple((header, offset_sizes))(&self.data);
         match result {
-            Ok((_, (_, offsets))) => Ok(FileParts::from_offsets(&self.mmap, &offsets)),
+            Ok((_, (_, offsets))) => FileParts::from_offsets(&self.data, &offsets)
+                .map(|(_, parts)| parts)
+                .map_err(|_| RafError::Unknown),
             Err(_) => Err(RafError::Unknown),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{find_exif_tiff, CropRect, ParsedRafFile, RafFile, RafImage, Tag};
+    use crate::tiff::{Endian, SRational, TiffBuilder, TiffValue, FUJI_RAW_SECTION_TAG_ID};
+    use crate::Color;
+    use std::io::{Cursor, Seek, SeekFrom};
+    use std::sync::Arc;
+
+    fn raf_with_offsets(offsets: &[(u32, u32)]) -> Vec<u8> {
+        let mut data = b"FUJIFILMCCD-RAW 0201FF129502".to_vec();
+        data.extend_from_slice(&[b'X'; 32]);
+        data.extend_from_slice(b"0100\0\0\0\0");
+        data.extend_from_slice(&[0; 16]);
+        for (offset, length) in offsets {
+            data.extend_from_slice(&offset.to_be_bytes());
+            data.extend_from_slice(&length.to_be_bytes());
+        }
+        data
+    }
+
+    /// A complete, uncompressed 12x6 RAF.
+    fn synthetic_raf() -> Vec<u8> {
+        let pixels: Vec<u8> = (0..72u16).flat_map(|x| (1000 + x).to_le_bytes()).collect();
+        let mut raw = TiffBuilder::new(Endian::Little);
+        let main = raw.add_ifd();
+        let fuji = raw.add_sub_ifd(main, FUJI_RAW_SECTION_TAG_ID);
+        let pixels = raw.add_blob(pixels);
+        raw.set_blob_offsets(fuji, 61447, vec![pixels]);
+        for &(tag, value) in &[
+            (61441, 12),
+            (61442, 6),
+            (61443, 14),
+            (61448, 144),
+            (61449, 136),
+        ] {
+            raw.set(fuji, tag, TiffValue::Long(vec![value]));
+        }
+        raw.set(fuji, 61450, TiffValue::Long(vec![1024; 36]));
+        raw.set(fuji, 61452, TiffValue::Long(vec![0; 8]));
+        raw.set(fuji, 61454, TiffValue::Long(vec![302, 550, 700]));
+        for &tag in &[61451, 61455, 61456] {
+            raw.set(fuji, tag, TiffValue::SRational(vec![SRational(1, 1)]));
+        }
+        let raw = raw.to_bytes();
+
+        let mut metadata = vec![0, 0, 0, 3];
+        metadata.extend_from_slice(&[0x01, 0x31, 0, 36]);
+        metadata.extend((0..36).map(|i| (i % 3) as u8));
+        // Crop from (2, 1), 8 wide and 4 high.
+        metadata.extend_from_slice(&[0x01, 0x10, 0, 4, 0, 1, 0, 2]);
+        metadata.extend_from_slice(&[0x01, 0x11, 0, 4, 0, 4, 0, 8]);
+
+        let jpeg = b"JPEG";
+        let jpeg_offset = 108;
+        let metadata_offset = jpeg_offset + jpeg.len() as u32;
+        let raw_offset = metadata_offset + metadata.len() as u32;
+        let mut data = raf_with_offsets(&[
+            (jpeg_offset, jpeg.len() as u32),
+            (metadata_offset, metadata.len() as u32),
+            (raw_offset, raw.len() as u32),
+        ]);
+        data.extend_from_slice(jpeg);
+        data.extend_from_slice(&metadata);
+        data.extend_from_slice(&raw);
+        data
+    }
+
+    #[test]
+    fn parses_synthetic_file() {
+        let data = synthetic_raf();
+        let parsed = ParsedRafFile::parse(&data).unwrap();
+        let info = parsed.render_info();
+        assert_eq!((info.width, info.height, info.bit_depth), (12, 6, 14));
+        assert_eq!(info.raw_data[..3], [1000, 1001, 1002]);
+        assert_eq!(info.white_bal.red, 550);
+        // The mapping is stored backwards.
+        assert_eq!(info.xtrans_mapping[0], Color::Blue);
+        assert_eq!(info.xtrans_mapping[1], Color::Green);
+        assert_eq!(
+            info.crop_rect,
+            CropRect {
+                left: 2,
+                right: 10,
+                top: 1,
+                bottom: 5
+            }
+        );
+        assert_eq!(parsed.preview(), b"JPEG");
+
+        // Any truncation is an error rather than a panic.
+        for len in (0..data.len()).step_by(7) {
+            assert!(ParsedRafFile::parse(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn owned_images_outlive_their_file() {
+        let image = {
+            let file = RafFile::from_bytes(synthetic_raf());
+            let image = file.load().unwrap();
+            let parsed = file.parse_raw().unwrap();
+            assert_eq!(
+                image.render_info().crop_rect,
+                parsed.render_info().crop_rect
+            );
+            image
+        };
+        assert_eq!(image.preview(), b"JPEG");
+        assert_eq!(image.model(), "X".repeat(32));
+        assert_eq!(image.render_info().raw_data.len(), 72);
+        assert!(image
+            .metadata()
+            .iter()
+            .any(|tag| matches!(tag, Tag::HeightWidthCrop(4, 8))));
+        assert!(RafImage::parse(&synthetic_raf()[..200]).is_err());
+    }
+
+    #[test]
+    fn info_matches_decoded_file() {
+        let file = RafFile::from_bytes(synthetic_raf());
+        let info = file.parse_info().unwrap();
+        let image = file.load().unwrap();
+        let render_info = image.render_info();
+        assert_eq!(info.model, image.model());
+        assert_eq!(info.firmware, "0100");
+        assert_eq!(image.firmware(), "0100");
+        assert_eq!((info.width, info.height), (12, 6));
+        assert_eq!(info.crop_rect, render_info.crop_rect);
+        assert_eq!(info.white_bal, render_info.white_bal);
+        // The synthetic preview has no EXIF.
+        assert!(file.parse_exif().is_err());
+    }
+
+    #[test]
+    fn bad_files_are_errors() {
+        assert!(ParsedRafFile::parse(&[]).is_err());
+        let valid = raf_with_offsets(&[(0, 8), (0, 8), (0, 8)]);
+        for len in 0..valid.len() {
+            assert!(ParsedRafFile::parse(&valid[..len]).is_err());
+        }
+        // Sections which run off the end of the file, or past the end of memory.
+        for &(offset, length) in &[(0, 1000), (1000, 0), (u32::MAX, u32::MAX)] {
+            let data = raf_with_offsets(&[(offset, length); 3]);
+            assert!(ParsedRafFile::parse(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn exif_segment_length_is_checked() {
+        let segment = |length: u16| {
+            let mut data = b"\xFF\xD8\xFF\xE1".to_vec();
+            data.extend_from_slice(&length.to_be_bytes());
+            data.extend_from_slice(b"Exif\0\0II*\0");
+            data
+        };
+        assert_eq!(find_exif_tiff(&segment(12)).unwrap().1, b"II*\0");
+        assert!(find_exif_tiff(&segment(2)).is_err());
+        assert!(find_exif_tiff(&segment(100)).is_err());
+    }
+
+    #[test]
+    fn loads_from_memory() {
+        // A preview section directly after the header.
+        let mut data = raf_with_offsets(&[(108, 4), (0, 0), (0, 0)]);
+        data.extend_from_slice(b"JPEG");
+
+        let from_vec = RafFile::from_bytes(data.clone());
+        assert_eq!(from_vec.path(), None);
+        assert_eq!(from_vec.parse_preview().unwrap(), b"JPEG");
+
+        let shared: Arc<[u8]> = data.clone().into();
+        let from_arc = RafFile::from_bytes(shared.clone());
+        assert_eq!(from_arc.parse_preview().unwrap(), b"JPEG");
+        assert_eq!(from_arc.bytes().as_ptr(), shared.as_ptr());
+
+        // Readers are read from wherever they're positioned.
+        let mut archive = b"junk".to_vec();
+        archive.extend_from_slice(&data);
+        let mut reader = Cursor::new(archive);
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        let from_reader = RafFile::from_reader(reader).unwrap();
+        assert_eq!(from_reader.bytes(), data.as_slice());
+        assert_eq!(from_reader.parse_preview().unwrap(), b"JPEG");
+    }
+}
@@ -0,0 +1,146 @@
+//! What the renderer needs from a raw file, whichever format it came from.
+
+use crate::dng::{Calibration, DngFile};
+use crate::raf::{ParsedRafFile, RafImage, RenderInfo};
+use crate::tiff::SRational;
+#[cfg(any(test, feature = "test-support"))]
+use {
+    crate::raf::{CropRect, WhiteBalCoefficients},
+    crate::Color,
+    ndarray::Array2,
+};
+
+pub trait RawImage {
+    fn render_info(&self) -> RenderInfo;
+
+    /// Fuji's vignetting curve, for files which have one.
+    fn vignette_attenuation(&self) -> Option<&[SRational]> {
+        None
+    }
+
+    /// The file's own colour calibration. Renderers fall back to a built-in one when this is
+    /// empty.
+    fn calibrations(&self) -> &[Calibration] {
+        &[]
+    }
+}
+
+impl<'a> RawImage for ParsedRafFile<'a> {
+    fn render_info(&self) -> RenderInfo {
+        ParsedRafFile::render_info(self)
+    }
+
+    fn vignette_attenuation(&self) -> Option<&[SRational]> {
+        Some(ParsedRafFile::vignette_attenuation(self))
+    }
+}
+
+impl RawImage for RafImage {
+    fn render_info(&self) -> RenderInfo {
+        RafImage::render_info(self)
+    }
+
+    fn vignette_attenuation(&self) -> Option<&[SRational]> {
+        Some(RafImage::vignette_attenuation(self))
+    }
+}
+
+impl RawImage for DngFile {
+    fn render_info(&self) -> RenderInfo {
+        DngFile::render_info(self)
+    }
+
+    fn calibrations(&self) -> &[Calibration] {
+        DngFile::calibrations(self)
+    }
+}
+
+/// A sensor held in memory, with neutral white balance. It stands in for a raw file when testing
+/// renderers and tools, and is only built for tests and the `test-support` feature.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestSensor {
+    pub width: usize,
+    pub height: usize,
+    pub bit_depth: u16,
+    pub black_level: u16,
+    /// The colour filter's 6x6 repeating pattern, row by row.
+    pub pattern: Vec<Color>,
+    pub crop_rect: CropRect,
+    /// Row by row.
+    pub data: Vec<u16>,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl TestSensor {
+    /// An RGGB Bayer sensor, with red at (0, 0) and blue at (1, 1), which reads black everywhere
+    /// and is cropped to the whole of itself.
+    pub fn bayer(width: usize, height: usize, bit_depth: u16) -> Self {
+        let pattern = (0..36)
+            .map(|i| match (i % 2, i / 6 % 2) {
+                (0, 0) => Color::Red,
+                (1, 1) => Color::Blue,
+                _ => Color::Green,
+            })
+            .collect();
+        TestSensor::with_pattern(width, height, bit_depth, pattern)
+    }
+
+    /// An X-Trans sensor, laid out like Fuji's, which reads black everywhere and is cropped to
+    /// the whole of itself.
+    pub fn xtrans(width: usize, height: usize, bit_depth: u16) -> Self {
+        use Color::{Blue, Green, Red};
+        #[rustfmt::skip]
+        let pattern = vec![
+            Green, Green, Red, Green, Green, Blue,
+            Green, Green, Blue, Green, Green, Red,
+            Blue, Red, Green, Red, Blue, Green,
+            Green, Green, Blue, Green, Green, Red,
+            Green, Green, Red, Green, Green, Blue,
+            Red, Blue, Green, Blue, Red, Green,
+        ];
+        TestSensor::with_pattern(width, height, bit_depth, pattern)
+    }
+
+    fn with_pattern(width: usize, height: usize, bit_depth: u16, pattern: Vec<Color>) -> Self {
+        TestSensor {
+            width,
+            height,
+            bit_depth,
+            black_level: 0,
+            pattern,
+            crop_rect: CropRect {
+                left: 0,
+                right: width,
+                top: 0,
+                bottom: height,
+            },
+            data: vec![0; width * height],
+        }
+    }
+
+    /// The colour of the photosite at `(x, y)`.
+    pub fn color_at(&self, x: usize, y: usize) -> Color {
+        self.pattern[x % 6 + y % 6 * 6]
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl RawImage for TestSensor {
+    fn render_info(&self) -> RenderInfo<'_> {
+        RenderInfo {
+            width: self.width as u16,
+            height: self.height as u16,
+            bit_depth: self.bit_depth,
+            black_levels: Array2::from_elem((6, 6), self.black_level),
+            white_bal: WhiteBalCoefficients {
+                red: 1,
+                green: 1,
+                blue: 1,
+            },
+            xtrans_mapping: self.pattern.clone(),
+            crop_rect: self.crop_rect,
+            raw_data: &self.data,
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! Assembles TIFF files, such as DNGs, or the EXIF blocks of JPEGs.
+//!
+//! IFDs added with `TiffBuilder::add_ifd` form the main chain, starting with IFD0. Sub-IFDs are
+//! reached through a pointer tag in their parent instead, such as the EXIF IFD or DNG's SubIFDs.
+//! Everything is written in one go by `TiffBuilder::write`: the header, then each IFD followed by
+//! the values too big to fit in its entries, then any blobs, such as image data.
+
+use super::{Endian, FieldType, TiffValue, FUJI_RAW_SECTION_TAG_ID};
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/// Refers to an IFD added to a `TiffBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IfdId(usize);
+
+/// Refers to a block of data added with `TiffBuilder::add_blob`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobId(usize);
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Value(TiffValue),
+    /// Data which is already encoded, such as values of unknown types copied from another file.
+    Raw {
+        field_type: FieldType,
+        count: u32,
+        data: Vec<u8>,
+    },
+    /// The offsets of other IFDs, as used by pointer tags.
+    Ifds(FieldType, Vec<IfdId>),
+    /// The offsets of blobs, as used by StripOffsets.
+    Blobs(Vec<BlobId>),
+}
+
+impl Entry {
+    fn field_type(&self) -> FieldType {
+        match self {
+            Entry::Value(value) => value.field_type(),
+            Entry::Raw { field_type, .. } | Entry::Ifds(field_type, _) => *field_type,
+            Entry::Blobs(_) => FieldType::Long,
+        }
+    }
+
+    fn count(&self) -> u32 {
+        match self {
+            Entry::Value(value) => value.count() as u32,
+            Entry::Raw { count, .. } => *count,
+            Entry::Ifds(_, ids) => ids.len() as u32,
+            Entry::Blobs(ids) => ids.len() as u32,
+        }
+    }
+
+    /// Encodes the value, looking up offsets in `layout`.
+    fn encode(&self, endian: Endian, layout: &Layout) -> Vec<u8> {
+        let mut out = Encoder {
+            endian,
+            out: vec![],
+        };
+        match self {
+            Entry::Value(value) => out.value(value),
+            Entry::Raw { data, .. } => out.out.extend_from_slice(data),
+            Entry::Ifds(_, ids) => ids.iter().for_each(|id| out.u32(layout.ifd_offsets[id.0])),
+            Entry::Blobs(ids) => ids.iter().for_each(|id| out.u32(layout.blob_offsets[id.0])),
+        }
+        out.out
+    }
+
+    /// The encoded size in bytes, which doesn't depend on the layout.
+    fn byte_size(&self) -> usize {
+        match self {
+            Entry::Value(value) => value.count() * value.field_type().type_size().unwrap(),
+            Entry::Raw { data, .. } => data.len(),
+            Entry::Ifds(_, ids) => 4 * ids.len(),
+            Entry::Blobs(ids) => 4 * ids.len(),
+        }
+    }
+}
+
+struct Encoder {
+    endian: Endian,
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn u16(&mut self, x: u16) {
+        let bytes = match self.endian {
+            Endian::Little => x.to_le_bytes(),
+            Endian::Big => x.to_be_bytes(),
+        };
+        self.out.extend_from_slice(&bytes);
+    }
+
+    fn u32(&mut self, x: u32) {
+        let bytes = match self.endian {
+            Endian::Little => x.to_le_bytes(),
+            Endian::Big => x.to_be_bytes(),
+        };
+        self.out.extend_from_slice(&bytes);
+    }
+
+    fn u64(&mut self, x: u64) {
+        let bytes = match self.endian {
+            Endian::Little => x.to_le_bytes(),
+            Endian::Big => x.to_be_bytes(),
+        };
+        self.out.extend_from_slice(&bytes);
+    }
+
+    fn value(&mut self, value: &TiffValue) {
+        match value {
+            TiffValue::Byte(v) | TiffValue::Undefined(v) => self.out.extend_from_slice(v),
+            TiffValue::Ascii(s) => {
+                self.out.extend_from_slice(s.as_bytes());
+                self.out.push(0);
+            }
+            TiffValue::SByte(v) => self.out.extend(v.iter().map(|&x| x as u8)),
+            TiffValue::Short(v) => v.iter().for_each(|&x| self.u16(x)),
+            TiffValue::SShort(v) => v.iter().for_each(|&x| self.u16(x as u16)),
+            TiffValue::Long(v) => v.iter().for_each(|&x| self.u32(x)),
+            TiffValue::SLong(v) => v.iter().for_each(|&x| self.u32(x as u32)),
+            TiffValue::Rational(v) => v.iter().for_each(|x| {
+                self.u32(x.0);
+                self.u32(x.1);
+            }),
+            TiffValue::SRational(v) => v.iter().for_each(|x| {
+                self.u32(x.0 as u32);
+                self.u32(x.1 as u32);
+            }),
+            TiffValue::Float(v) => v.iter().for_each(|x| self.u32(x.to_bits())),
+            TiffValue::Double(v) => v.iter().for_each(|x| self.u64(x.to_bits())),
+            TiffValue::Long8(v) => v.iter().for_each(|&x| self.u64(x)),
+            TiffValue::SLong8(v) => v.iter().for_each(|&x| self.u64(x as u64)),
+        }
+    }
+}
+
+pub struct TiffBuilder<'a> {
+    endian: Endian,
+    /// The entries of each IFD, kept sorted by tag.
+    ifds: Vec<Vec<(u16, Entry)>>,
+    /// The IFDs in the main chain, in order.
+    chain: Vec<IfdId>,
+    blobs: Vec<Cow<'a, [u8]>>,
+}
+
+/// Where everything ends up in the file.
+struct Layout {
+    ifd_offsets: Vec<u32>,
+    blob_offsets: Vec<u32>,
+}
+
+const HEADER_SIZE: usize = 8;
+
+fn ifd_size(entries: &[(u16, Entry)]) -> usize {
+    2 + 12 * entries.len() + 4
+}
+
+/// The size of the out-of-line values for an IFD, each padded to a word boundary.
+fn ifd_data_size(entries: &[(u16, Entry)]) -> usize {
+    entries
+        .iter()
+        .map(|(_, entry)| entry.byte_size())
+        .filter(|&size| size > 4)
+        .map(padded)
+        .sum()
+}
+
+fn padded(size: usize) -> usize {
+    size + size % 2
+}
+
+impl<'a> TiffBuilder<'a> {
+    pub fn new(endian: Endian) -> Self {
+        TiffBuilder {
+            endian,
+            ifds: vec![],
+            chain: vec![],
+            blobs: vec![],
+        }
+    }
+
+    fn new_ifd(&mut self) -> IfdId {
+        self.ifds.push(vec![]);
+        IfdId(self.ifds.len() - 1)
+    }
+
+    /// Adds an empty IFD to the end of the main chain. The first one added is IFD0.
+    pub fn add_ifd(&mut self) -> IfdId {
+        let id = self.new_ifd();
+        self.chain.push(id);
+        id
+    }
+
+    /// Adds an empty IFD which `tag` in `parent` points to. If `tag` already points to IFDs, the
+    /// new one is added after them, as for DNG's SubIFDs.
+    ///
+    /// Pointers are Longs, apart from Fuji's raw section pointer, which has type 13 (IFD) in RAFs.
+    pub fn add_sub_ifd(&mut self, parent: IfdId, tag: u16) -> IfdId {
+        let id = self.new_ifd();
+        let mut ids = match self.take(parent, tag) {
+            Some(Entry::Ifds(_, ids)) => ids,
+            _ => vec![],
+        };
+        ids.push(id);
+        let field_type = if tag == FUJI_RAW_SECTION_TAG_ID {
+            FieldType::Unknown(13)
+        } else {
+            FieldType::Long
+        };
+        self.insert(parent, tag, Entry::Ifds(field_type, ids));
+        id
+    }
+
+    fn take(&mut self, ifd: IfdId, tag: u16) -> Option<Entry> {
+        let entries = &mut self.ifds[ifd.0];
+        let index = entries.binary_search_by_key(&tag, |(t, _)| *t).ok()?;
+        Some(entries.remove(index).1)
+    }
+
+    fn insert(&mut self, ifd: IfdId, tag: u16, entry: Entry) {
+        let entries = &mut self.ifds[ifd.0];
+        match entries.binary_search_by_key(&tag, |(t, _)| *t) {
+            Ok(index) => entries[index].1 = entry,
+            Err(index) => entries.insert(index, (tag, entry)),
+        }
+    }
+
+    /// Sets `tag` in `ifd`, replacing any existing value.
+    pub fn set(&mut self, ifd: IfdId, tag: u16, value: TiffValue) {
+        self.insert(ifd, tag, Entry::Value(value));
+    }
+
+    /// Sets `tag` in `ifd` to already-encoded data, which must be in the builder's byte order.
+    pub fn set_raw(
+        &mut self,
+        ifd: IfdId,
+        tag: u16,
+        field_type: FieldType,
+        count: u32,
+        data: Vec<u8>,
+    ) {
+        self.insert(
+            ifd,
+            tag,
+            Entry::Raw {
+                field_type,
+                count,
+                data,
+            },
+        );
+    }
+
+    /// Sets `tag` in `ifd` to the offsets of `blobs`, as for StripOffsets.
+    pub fn set_blob_offsets(&mut self, ifd: IfdId, tag: u16, blobs: Vec<BlobId>) {
+        self.insert(ifd, tag, Entry::Blobs(blobs));
+    }
+
+    pub fn contains(&self, ifd: IfdId, tag: u16) -> bool {
+        self.ifds[ifd.0]
+            .binary_search_by_key(&tag, |(t, _)| *t)
+            .is_ok()
+    }
+
+    /// Adds a block of data, such as image data, which is written after all the IFDs.
+    pub fn add_blob(&mut self, data: impl Into<Cow<'a, [u8]>>) -> BlobId {
+        self.blobs.push(data.into());
+        BlobId(self.blobs.len() - 1)
+    }
+
+    /// Works out where everything goes, or fails if the file would be too big for TIFF's 32-bit
+    /// offsets.
+    fn layout(&self) -> io::Result<Layout> {
+        let mut offset = HEADER_SIZE;
+        let mut ifd_offsets = vec![];
+        for entries in &self.ifds {
+            ifd_offsets.push(offset);
+            offset += ifd_size(entries) + ifd_data_size(entries);
+        }
+        let mut blob_offsets = vec![];
+        for blob in &self.blobs {
+            blob_offsets.push(offset);
+            offset += padded(blob.len());
+        }
+        if offset > u32::MAX as usize + 1 {
+            return Err(invalid_input("TIFF files can't be bigger than 4GB"));
+        }
+        // Everything starts before the end, so fits now.
+        let to_u32 = |offsets: Vec<usize>| offsets.into_iter().map(|x| x as u32).collect();
+        Ok(Layout {
+            ifd_offsets: to_u32(ifd_offsets),
+            blob_offsets: to_u32(blob_offsets),
+        })
+    }
+
+    /// Writes the whole file to `out`. Fails with `InvalidInput` if there are no IFDs in the main
+    /// chain, or if the file would be bigger than 4GB.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.chain.is_empty() {
+            return Err(invalid_input("A TIFF file needs at least one IFD"));
+        }
+        let layout = self.layout()?;
+        let mut header = Encoder {
+            endian: self.endian,
+            out: match self.endian {
+                Endian::Little => b"II".to_vec(),
+                Endian::Big => b"MM".to_vec(),
+            },
+        };
+        header.u16(42);
+        header.u32(layout.ifd_offsets[self.chain[0].0]);
+        out.write_all(&header.out)?;
+
+        for (index, entries) in self.ifds.iter().enumerate() {
+            let start = layout.ifd_offsets[index] as usize;
+            let mut data_offset = start + ifd_size(entries);
+            let mut data = vec![];
+            let mut ifd = Encoder {
+                endian: self.endian,
+                out: vec![],
+            };
+            ifd.u16(entries.len() as u16);
+            for (tag, entry) in entries {
+                let encoded = entry.encode(self.endian, &layout);
+                ifd.u16(*tag);
+                ifd.u16(entry.field_type().id());
+                ifd.u32(entry.count());
+                if encoded.len() <= 4 {
+                    let mut inline = [0u8; 4];
+                    inline[..encoded.len()].copy_from_slice(&encoded);
+                    ifd.out.extend_from_slice(&inline);
+                } else {
+                    ifd.u32(data_offset as u32);
+                    data_offset += padded(encoded.len());
+                    data.extend(pad(encoded));
+                }
+            }
+            let position = self.chain.iter().position(|id| id.0 == index);
+            let next = position
+                .and_then(|i| self.chain.get(i + 1))
+                .map_or(0, |id| layout.ifd_offsets[id.0]);
+            ifd.u32(next);
+            out.write_all(&ifd.out)?;
+            out.write_all(&data)?;
+        }
+
+        for blob in &self.blobs {
+            out.write_all(blob)?;
+            if blob.len() % 2 == 1 {
+                out.write_all(&[0])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the file into a new buffer. Panics if `write` would fail.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write(&mut out).expect("Invalid TIFF file");
+        out
+    }
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn pad(mut data: Vec<u8>) -> Vec<u8> {
+    if data.len() % 2 == 1 {
+        data.push(0);
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tiff::{
+        parse_ifd_with, parse_tiff, parse_tiff_with_options, IfdEntry, Rational, SRational,
+        TiffFile, EXIF_IFD_TAG_ID, SUB_IFDS_TAG_ID,
+    };
+
+    /// Every entry in `ifd`, with its value decoded.
+    fn values(tiff: &TiffFile, ifd: &[IfdEntry]) -> Vec<(u16, TiffValue)> {
+        ifd.iter()
+            .map(|e| (e.tag, e.value(tiff).unwrap()))
+            .collect()
+    }
+
+    fn sample(endian: Endian) -> TiffBuilder<'static> {
+        let mut builder = TiffBuilder::new(endian);
+        let ifd0 = builder.add_ifd();
+        // Added out of order, to check they get sorted.
+        builder.set(ifd0, 0x0110, TiffValue::Ascii("X-T3".to_string()));
+        builder.set(ifd0, 0x0100, TiffValue::Short(vec![6240]));
+        builder.set(ifd0, 0x011A, TiffValue::Rational(vec![Rational(72, 1)]));
+        builder.set(ifd0, 0x9204, TiffValue::SRational(vec![SRational(-1, 3)]));
+        builder.set(ifd0, 0xC612, TiffValue::Byte(vec![1, 4, 0, 0]));
+        builder.set(ifd0, 0xC61A, TiffValue::Float(vec![0.25, 1.5]));
+        let exif = builder.add_sub_ifd(ifd0, EXIF_IFD_TAG_ID);
+        builder.set(exif, 0x829A, TiffValue::Rational(vec![Rational(1, 250)]));
+        builder.set(exif, 0x8827, TiffValue::Short(vec![200]));
+        let ifd1 = builder.add_ifd();
+        builder.set(ifd1, 0x0103, TiffValue::Short(vec![6]));
+        builder
+    }
+
+    #[test]
+    fn round_trips_values() {
+        for &endian in &[Endian::Little, Endian::Big] {
+            let out = sample(endian).to_bytes();
+            let (_, tiff) = parse_tiff_with_options(&out, b"II*\0", true).unwrap();
+            assert_eq!(tiff.endian, endian);
+            // IFD0 and IFD1 from the chain, then the EXIF IFD.
+            assert_eq!(tiff.ifds.len(), 3);
+            let ifd0 = values(&tiff, &tiff.ifds[0]);
+            let tags: Vec<u16> = ifd0.iter().map(|(tag, _)| *tag).collect();
+            assert_eq!(
+                tags,
+                vec![0x0100, 0x0110, 0x011A, 0x8769, 0x9204, 0xC612, 0xC61A]
+            );
+            assert_eq!(ifd0[1].1, TiffValue::Ascii("X-T3".to_string()));
+            assert_eq!(ifd0[4].1, TiffValue::SRational(vec![SRational(-1, 3)]));
+            assert_eq!(ifd0[6].1, TiffValue::Float(vec![0.25, 1.5]));
+            assert_eq!(
+                values(&tiff, &tiff.ifds[1]),
+                vec![(0x0103, TiffValue::Short(vec![6]))]
+            );
+            assert_eq!(
+                values(&tiff, &tiff.ifds[2]),
+                vec![
+                    (0x829A, TiffValue::Rational(vec![Rational(1, 250)])),
+                    (0x8827, TiffValue::Short(vec![200])),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn rebuilding_from_parsed_entries_gives_same_entries() {
+        let out = sample(Endian::Little).to_bytes();
+        let (_, tiff) = parse_tiff_with_options(&out, b"II*\0", true).unwrap();
+
+        let mut builder = TiffBuilder::new(Endian::Little);
+        let ifd0 = builder.add_ifd();
+        for entry in &tiff.ifds[0] {
+            if entry.tag == EXIF_IFD_TAG_ID {
+                let exif = builder.add_sub_ifd(ifd0, entry.tag);
+                for entry in &tiff.ifds[2] {
+                    builder.set(exif, entry.tag, entry.value(&tiff).unwrap());
+                }
+            } else {
+                builder.set(ifd0, entry.tag, entry.value(&tiff).unwrap());
+            }
+        }
+        let ifd1 = builder.add_ifd();
+        for entry in &tiff.ifds[1] {
+            builder.set(ifd1, entry.tag, entry.value(&tiff).unwrap());
+        }
+        let rebuilt = builder.to_bytes();
+        let (_, reparsed) = parse_tiff_with_options(&rebuilt, b"II*\0", true).unwrap();
+        assert_eq!(reparsed.ifds, tiff.ifds);
+        assert_eq!(rebuilt, out);
+    }
+
+    #[test]
+    fn raw_entries_are_copied_as_they_are() {
+        let mut builder = TiffBuilder::new(Endian::Big);
+        let ifd0 = builder.add_ifd();
+        builder.set_raw(
+            ifd0,
+            0x0100,
+            FieldType::Unknown(99),
+            6,
+            vec![1, 2, 3, 4, 5, 6],
+        );
+        builder.set_raw(ifd0, 0x0101, FieldType::Short, 1, vec![0x01, 0x02]);
+
+        let out = builder.to_bytes();
+        let (_, tiff) = parse_tiff(&out).unwrap();
+        let ifd0 = &tiff.ifds[0];
+        assert_eq!(ifd0[0].field_type, FieldType::Unknown(99));
+        assert_eq!(ifd0[0].count, 6);
+        assert_eq!(
+            tiff.data_for_ifd_entry(&ifd0[0]),
+            Ok(&[1, 2, 3, 4, 5, 6][..])
+        );
+        assert_eq!(ifd0[1].val_u32(), Some(0x0102));
+    }
+
+    #[test]
+    fn empty_files_are_errors() {
+        let mut builder = TiffBuilder::new(Endian::Little);
+        builder.new_ifd();
+        let error = builder.write(&mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn files_over_4gb_are_errors() {
+        // Blobs are only borrowed, so this doesn't need 4GB of memory until it's written.
+        let big = vec![0; 1 << 31];
+        let mut builder = TiffBuilder::new(Endian::Little);
+        let ifd0 = builder.add_ifd();
+        let blobs = vec![builder.add_blob(&big[..]), builder.add_blob(&big[..])];
+        builder.set_blob_offsets(ifd0, 0x0111, blobs);
+        let error = builder.write(&mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn sub_ifds_and_blobs() {
+        let mut builder = TiffBuilder::new(Endian::Little);
+        let ifd0 = builder.add_ifd();
+        let first = builder.add_sub_ifd(ifd0, SUB_IFDS_TAG_ID);
+        let second = builder.add_sub_ifd(ifd0, SUB_IFDS_TAG_ID);
+        let raw = builder.add_sub_ifd(ifd0, FUJI_RAW_SECTION_TAG_ID);
+        builder.set(first, 0x0100, TiffValue::Long(vec![1]));
+        builder.set(second, 0x0100, TiffValue::Long(vec![2]));
+        builder.set(raw, 0xF001, TiffValue::Long(vec![3]));
+        let blob = builder.add_blob(vec![1, 2, 3]);
+        builder.set_blob_offsets(ifd0, 0x0111, vec![blob]);
+        assert!(builder.contains(ifd0, 0x0111));
+        assert!(!builder.contains(first, 0x0111));
+
+        let out = builder.to_bytes();
+        let (_, tiff) = parse_tiff(&out).unwrap();
+        assert_eq!(tiff.ifds.len(), 1);
+        let ifd0 = &tiff.ifds[0];
+        assert_eq!(ifd0[0].tag, 0x0111);
+        let strip = ifd0[0].val_u32().unwrap() as usize;
+        assert_eq!(&out[strip..strip + 3], &[1, 2, 3]);
+
+        assert_eq!(ifd0[1].tag, SUB_IFDS_TAG_ID);
+        let offsets = ifd0[1].value(&tiff).unwrap().into_u32s().unwrap();
+        assert_eq!(offsets.len(), 2);
+        for (&offset, expected) in offsets.iter().zip(1..) {
+            let (_, (sub, _)) =
+                parse_ifd_with(&out[offset as usize..], Endian::Little, false).unwrap();
+            assert_eq!(sub[0].val_u32(), Some(expected));
+        }
+
+        assert_eq!(ifd0[2].tag, FUJI_RAW_SECTION_TAG_ID);
+        assert_eq!(ifd0[2].field_type, FieldType::Unknown(13));
+        let offset = ifd0[2].val_u32().unwrap() as usize;
+        let (_, (sub, _)) = parse_ifd_with(&out[offset..], Endian::Little, false).unwrap();
+        assert_eq!(sub[0].val_u32(), Some(3));
+    }
+}
@@ -0,0 +1,1149 @@
+use itertools::Itertools;
+use nom::bytes::streaming::{tag, take};
+use nom::combinator::{map, map_opt};
+use nom::error::ErrorKind;
+use nom::multi::count;
+use nom::number::complete::{be_u16, be_u32, be_u64, le_u16, le_u32, le_u64};
+use nom::sequence::tuple;
+use nom::{Err, IResult};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::iter::Flatten;
+use std::marker::PhantomData;
+use tristate::TriState;
+
+mod builder;
+
+pub use builder::{BlobId, IfdId, TiffBuilder};
+
+pub type I<'a> = &'a [u8];
+
+pub const MAKERNOTES_TAG_ID: u16 = 0x927C;
+pub const SUB_IFDS_TAG_ID: u16 = 0x014A;
+pub const EXIF_IFD_TAG_ID: u16 = 0x8769;
+pub const FUJI_RAW_SECTION_TAG_ID: u16 = 0xF000;
+
+lazy_static! {
+    pub static ref NESTED_IFD_TAGS: HashSet<u16> =
+        [
+            SUB_IFDS_TAG_ID,  // ?? Probably DNG-ish
+            FUJI_RAW_SECTION_TAG_ID, // Fuji RAW Section Pointer
+            0xA005, // Interoperability IFD Pointer
+            EXIF_IFD_TAG_ID, // EXIF IFD Pointer
+        ].iter().cloned().collect();
+}
+
+/// The byte order of a TIFF file, given by the first two bytes of its header: "II" for little-
+/// endian (Intel), "MM" for big-endian (Motorola).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+type NumParser<'a, T> = fn(I<'a>) -> IResult<I<'a>, T>;
+
+impl Endian {
+    pub fn u16(self, data: &[u8]) -> u16 {
+        let bytes = data[..2].try_into().unwrap();
+        match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn u32(self, data: &[u8]) -> u32 {
+        let bytes = data[..4].try_into().unwrap();
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn u64(self, data: &[u8]) -> u64 {
+        let bytes = data[..8].try_into().unwrap();
+        match self {
+            Endian::Little => u64::from_le_bytes(bytes),
+            Endian::Big => u64::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn i16(self, data: &[u8]) -> i16 {
+        self.u16(data) as i16
+    }
+
+    pub fn i32(self, data: &[u8]) -> i32 {
+        self.u32(data) as i32
+    }
+
+    pub fn i64(self, data: &[u8]) -> i64 {
+        self.u64(data) as i64
+    }
+
+    pub fn f32(self, data: &[u8]) -> f32 {
+        f32::from_bits(self.u32(data))
+    }
+
+    pub fn f64(self, data: &[u8]) -> f64 {
+        f64::from_bits(self.u64(data))
+    }
+
+    fn parse_u16<'a>(self) -> NumParser<'a, u16> {
+        match self {
+            Endian::Little => le_u16,
+            Endian::Big => be_u16,
+        }
+    }
+
+    fn parse_u32<'a>(self) -> NumParser<'a, u32> {
+        match self {
+            Endian::Little => le_u32,
+            Endian::Big => be_u32,
+        }
+    }
+
+    fn parse_u64<'a>(self) -> NumParser<'a, u64> {
+        match self {
+            Endian::Little => le_u64,
+            Endian::Big => be_u64,
+        }
+    }
+}
+
+/// How an IFD was reached while parsing.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IfdParent {
+    /// The first IFD, which the header points to.
+    Header,
+    /// Follows the IFD at this index in `TiffFile::ifds`, in the same chain.
+    Previous(usize),
+    /// Pointed to by `tag` in the IFD at index `ifd`, such as the EXIF IFD or one of the SubIFDs.
+    Pointer { ifd: usize, tag: u16 },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IfdOrigin {
+    /// Where the IFD starts, relative to the start of the TIFF data.
+    pub offset: usize,
+    pub parent: IfdParent,
+}
+
+pub struct TiffFile<'a> {
+    /// The main chain of IFDs, in order, followed by any nested IFDs if they were loaded.
+    pub ifds: Vec<Ifd<'a>>,
+    /// Where each of `ifds` came from.
+    pub origins: Vec<IfdOrigin>,
+    pub data: &'a [u8],
+    pub endian: Endian,
+    /// Whether this is a BigTIFF, with 64-bit offsets and counts.
+    pub big_tiff: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IfdEntry<'a> {
+    pub tag: u16,
+    pub field_type: FieldType,
+    /// BigTIFF allows 64-bit counts, but we don't accept anything that doesn't fit in 32 bits.
+    pub count: u32,
+    /// The last field of the entry, which holds the value itself if it fits (see
+    /// `value_inlined`), otherwise the offset of the value. It's 4 bytes long, or 8 in a BigTIFF,
+    /// and stored in the file's byte order.
+    pub value_offset: &'a [u8],
+    pub endian: Endian,
+}
+
+#[derive(Debug)]
+pub struct TypedIfdEntry<'a, T> {
+    pub tag: u16,
+    pub field_type: FieldType,
+    pub value: &'a [T],
+    // private thing just to prevent instantiation
+    p: PhantomData<T>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FieldType {
+    Byte,
+    Ascii,
+    Short,
+    Long,
+    Rational,
+    SByte,
+    Undefined,
+    SShort,
+    SLong,
+    SRational,
+    Float,
+    Double,
+    // BigTIFF types
+    Long8,
+    SLong8,
+    Ifd8,
+    Unknown(u16),
+}
+
+impl FieldType {
+    fn type_size(self) -> Option<usize> {
+        match self {
+            FieldType::Byte => Some(1),
+            FieldType::Ascii => Some(1),
+            FieldType::Short => Some(2),
+            FieldType::Long => Some(4),
+            FieldType::Rational => Some(8),
+            FieldType::SByte => Some(1),
+            FieldType::Undefined => Some(1),
+            FieldType::SShort => Some(2),
+            FieldType::SLong => Some(4),
+            FieldType::SRational => Some(8),
+            FieldType::Float => Some(4),
+            FieldType::Double => Some(8),
+            FieldType::Long8 => Some(8),
+            FieldType::SLong8 => Some(8),
+            FieldType::Ifd8 => Some(8),
+            FieldType::Unknown(_) => None,
+        }
+    }
+
+    fn debug_repr(self, data: &[u8], endian: Endian) -> String {
+        match self {
+            FieldType::Byte | FieldType::SByte | FieldType::Undefined | FieldType::Unknown(_) => {
+                // Treat as bytes
+                let mut data = hex::encode(data);
+                data.make_ascii_uppercase();
+                data.as_bytes()
+                    .chunks(64)
+                    .map(|chunk| {
+                        chunk
+                            .chunks(8)
+                            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+                            .join(" ")
+                    })
+                    .join("\n")
+            }
+            FieldType::Ascii => {
+                // Treat as string
+                String::from_utf8(data.to_vec()).unwrap()
+            }
+            FieldType::Short
+            | FieldType::Long
+            | FieldType::SShort
+            | FieldType::SLong
+            | FieldType::Float
+            | FieldType::Double
+            | FieldType::SRational
+            | FieldType::Rational
+            | FieldType::Long8
+            | FieldType::SLong8
+            | FieldType::Ifd8 => {
+                let chunks = data.chunks_exact(self.type_size().unwrap());
+                assert_eq!(chunks.remainder().len(), 0);
+                if chunks.len() > 1 {
+                    format!(
+                        "[{}]",
+                        chunks.map(|x| self.debug_repr_single(x, endian)).join(", ")
+                    )
+                } else {
+                    chunks
+                        .map(|x| self.debug_repr_single(x, endian))
+                        .exactly_one()
+                        .unwrap()
+                }
+            }
+        }
+    }
+
+    fn debug_repr_single(self, data: &[u8], endian: Endian) -> String {
+        assert_eq!(self.type_size().unwrap(), data.len());
+        match self {
+            FieldType::Short => format!("{}", endian.u16(data)),
+            FieldType::Long => format!("{}", endian.u32(data)),
+            FieldType::Rational => format!("{}/{}", endian.u32(data), endian.u32(&data[4..])),
+            FieldType::SShort => format!("{}", endian.i16(data)),
+            FieldType::SLong => format!("{}", endian.i32(data)),
+            FieldType::SRational => format!("{}/{}", endian.i32(data), endian.i32(&data[4..])),
+            FieldType::Float => format!("{}", endian.f32(data)),
+            FieldType::Double => format!("{}", endian.f64(data)),
+            FieldType::Long8 | FieldType::Ifd8 => format!("{}", endian.u64(data)),
+            FieldType::SLong8 => format!("{}", endian.i64(data)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl FieldType {
+    /// The type's numeric code, as stored in an IFD entry.
+    pub fn id(self) -> u16 {
+        match self {
+            FieldType::Byte => 1,
+            FieldType::Ascii => 2,
+            FieldType::Short => 3,
+            FieldType::Long => 4,
+            FieldType::Rational => 5,
+            FieldType::SByte => 6,
+            FieldType::Undefined => 7,
+            FieldType::SShort => 8,
+            FieldType::SLong => 9,
+            FieldType::SRational => 10,
+            FieldType::Float => 11,
+            FieldType::Double => 12,
+            FieldType::Long8 => 16,
+            FieldType::SLong8 => 17,
+            FieldType::Ifd8 => 18,
+            FieldType::Unknown(val) => val,
+        }
+    }
+}
+
+impl From<u16> for FieldType {
+    fn from(val: u16) -> Self {
+        match val {
+            1 => FieldType::Byte,
+            2 => FieldType::Ascii,
+            3 => FieldType::Short,
+            4 => FieldType::Long,
+            5 => FieldType::Rational,
+            6 => FieldType::SByte,
+            7 => FieldType::Undefined,
+            8 => FieldType::SShort,
+            9 => FieldType::SLong,
+            10 => FieldType::SRational,
+            11 => FieldType::Float,
+            12 => FieldType::Double,
+            16 => FieldType::Long8,
+            17 => FieldType::SLong8,
+            18 => FieldType::Ifd8,
+            val => FieldType::Unknown(val),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Rational(pub u32, pub u32);
+
+impl Rational {
+    pub fn into_f64(self) -> f64 {
+        self.0 as f64 / self.1 as f64
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SRational(pub i32, pub i32);
+
+impl SRational {
+    pub fn into_f32(self) -> f32 {
+        self.0 as f32 / self.1 as f32
+    }
+    pub fn into_f64(self) -> f64 {
+        self.0 as f64 / self.1 as f64
+    }
+}
+
+quick_error! {
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum TiffError {
+        UnknownType(tag: u16, field_type: u16) {
+            display("Tag {:04X} has unknown type {}", tag, field_type)
+        }
+        OutOfBounds(tag: u16) {
+            display("The value of tag {:04X} is outside the file", tag)
+        }
+    }
+}
+
+/// The value of an IFD entry, decoded according to its type.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TiffValue {
+    Byte(Vec<u8>),
+    /// NUL-terminated in the file; the terminator isn't included.
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<Rational>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<SRational>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    /// Long8 or Ifd8, from a BigTIFF.
+    Long8(Vec<u64>),
+    SLong8(Vec<i64>),
+}
+
+impl TiffValue {
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            TiffValue::Byte(_) => FieldType::Byte,
+            TiffValue::Ascii(_) => FieldType::Ascii,
+            TiffValue::Short(_) => FieldType::Short,
+            TiffValue::Long(_) => FieldType::Long,
+            TiffValue::Rational(_) => FieldType::Rational,
+            TiffValue::SByte(_) => FieldType::SByte,
+            TiffValue::Undefined(_) => FieldType::Undefined,
+            TiffValue::SShort(_) => FieldType::SShort,
+            TiffValue::SLong(_) => FieldType::SLong,
+            TiffValue::SRational(_) => FieldType::SRational,
+            TiffValue::Float(_) => FieldType::Float,
+            TiffValue::Double(_) => FieldType::Double,
+            TiffValue::Long8(_) => FieldType::Long8,
+            TiffValue::SLong8(_) => FieldType::SLong8,
+        }
+    }
+
+    /// The number of values, as stored in an IFD entry.
+    pub fn count(&self) -> usize {
+        match self {
+            TiffValue::Byte(v) | TiffValue::Undefined(v) => v.len(),
+            // Includes the NUL terminator.
+            TiffValue::Ascii(s) => s.len() + 1,
+            TiffValue::Short(v) => v.len(),
+            TiffValue::Long(v) => v.len(),
+            TiffValue::Rational(v) => v.len(),
+            TiffValue::SByte(v) => v.len(),
+            TiffValue::SShort(v) => v.len(),
+            TiffValue::SLong(v) => v.len(),
+            TiffValue::SRational(v) => v.len(),
+            TiffValue::Float(v) => v.len(),
+            TiffValue::Double(v) => v.len(),
+            TiffValue::Long8(v) => v.len(),
+            TiffValue::SLong8(v) => v.len(),
+        }
+    }
+
+    /// Returns the values as `u32`s, if they're unsigned integers that fit.
+    pub fn into_u32s(self) -> Option<Vec<u32>> {
+        match self {
+            TiffValue::Byte(v) => Some(v.into_iter().map(u32::from).collect()),
+            TiffValue::Short(v) => Some(v.into_iter().map(u32::from).collect()),
+            TiffValue::Long(v) => Some(v),
+            TiffValue::Long8(v) => v.into_iter().map(|x| x.try_into().ok()).collect(),
+            _ => None,
+        }
+    }
+
+    /// Returns the values as `f64`s, if they're numbers of any kind.
+    pub fn into_f64s(self) -> Option<Vec<f64>> {
+        let values = match self {
+            TiffValue::Rational(v) => v.into_iter().map(Rational::into_f64).collect(),
+            TiffValue::SRational(v) => v.into_iter().map(SRational::into_f64).collect(),
+            TiffValue::SByte(v) => v.into_iter().map(f64::from).collect(),
+            TiffValue::SShort(v) => v.into_iter().map(f64::from).collect(),
+            TiffValue::SLong(v) => v.into_iter().map(f64::from).collect(),
+            TiffValue::SLong8(v) => v.into_iter().map(|x| x as f64).collect(),
+            TiffValue::Float(v) => v.into_iter().map(f64::from).collect(),
+            TiffValue::Double(v) => v,
+            TiffValue::Long8(v) => v.into_iter().map(|x| x as f64).collect(),
+            other => other.into_u32s()?.into_iter().map(f64::from).collect(),
+        };
+        Some(values)
+    }
+}
+
+impl<'a> IfdEntry<'a> {
+    pub fn value_byte_size(&self) -> Option<usize> {
+        let item_size = self.field_type.type_size()?;
+        item_size.checked_mul(self.count as usize)
+    }
+
+    pub fn value_inlined(&self) -> TriState {
+        match self.value_byte_size() {
+            Some(size_) if size_ > self.value_offset.len() => TriState::No,
+            Some(_) => TriState::Yes,
+            None => TriState::Unknown,
+        }
+    }
+
+    /// Reads the last field of the entry as a whole offset, 32 or 64 bits depending on the file.
+    fn raw_offset(&self) -> u64 {
+        match self.value_offset.len() {
+            8 => self.endian.u64(self.value_offset),
+            _ => self.endian.u32(self.value_offset) as u64,
+        }
+    }
+
+    /// Returns a single unsigned integer value, or `None` if the entry holds anything else.
+    /// Unknown types are read as offsets, since that's what Fuji's IFD pointers use.
+    pub fn val_u64(&self) -> Option<u64> {
+        if self.count != 1 {
+            return None;
+        }
+        let value = self.value_offset;
+        match self.field_type {
+            FieldType::Byte => Some(value[0] as u64),
+            FieldType::Short => Some(self.endian.u16(value) as u64),
+            FieldType::Long => Some(self.endian.u32(value) as u64),
+            FieldType::Long8 | FieldType::Ifd8 if value.len() == 8 => Some(self.endian.u64(value)),
+            FieldType::Unknown(_) => Some(self.raw_offset()),
+            _ => None,
+        }
+    }
+
+    pub fn val_u32(&self) -> Option<u32> {
+        self.val_u64()?.try_into().ok()
+    }
+
+    pub fn val_as_offset(&self) -> Option<usize> {
+        if self.value_inlined() == TriState::Yes {
+            None
+        } else {
+            self.raw_offset().try_into().ok()
+        }
+    }
+
+    /// Decodes the entry's value, which is either inline or stored elsewhere in `file`.
+    pub fn value(&self, file: &TiffFile) -> Result<TiffValue, TiffError> {
+        self.value_in(file.data)
+    }
+
+    /// Like `value`, with offsets relative to `tiff_data`.
+    fn value_in(&self, tiff_data: &[u8]) -> Result<TiffValue, TiffError> {
+        let size = self
+            .value_byte_size()
+            .ok_or_else(|| TiffError::UnknownType(self.tag, self.field_type.id()))?;
+        let data = match self.val_as_offset() {
+            Some(offset) => tiff_data.get(offset..).and_then(|data| data.get(..size)),
+            None => self.value_offset.get(..size),
+        };
+        let data = data.ok_or(TiffError::OutOfBounds(self.tag))?;
+
+        let endian = self.endian;
+        let words = |size| data.chunks_exact(size);
+        let value = match self.field_type {
+            FieldType::Byte => TiffValue::Byte(data.to_vec()),
+            FieldType::Ascii => {
+                let end = data.iter().rposition(|&x| x != 0).map_or(0, |i| i + 1);
+                TiffValue::Ascii(String::from_utf8_lossy(&data[..end]).into_owned())
+            }
+            FieldType::Short => TiffValue::Short(words(2).map(|x| endian.u16(x)).collect()),
+            FieldType::Long => TiffValue::Long(words(4).map(|x| endian.u32(x)).collect()),
+            FieldType::Rational => TiffValue::Rational(
+                words(8)
+                    .map(|x| Rational(endian.u32(x), endian.u32(&x[4..])))
+                    .collect(),
+            ),
+            FieldType::SByte => TiffValue::SByte(data.iter().map(|&x| x as i8).collect()),
+            FieldType::Undefined => TiffValue::Undefined(data.to_vec()),
+            FieldType::SShort => TiffValue::SShort(words(2).map(|x| endian.i16(x)).collect()),
+            FieldType::SLong => TiffValue::SLong(words(4).map(|x| endian.i32(x)).collect()),
+            FieldType::SRational => TiffValue::SRational(
+                words(8)
+                    .map(|x| SRational(endian.i32(x), endian.i32(&x[4..])))
+                    .collect(),
+            ),
+            FieldType::Float => TiffValue::Float(words(4).map(|x| endian.f32(x)).collect()),
+            FieldType::Double => TiffValue::Double(words(8).map(|x| endian.f64(x)).collect()),
+            FieldType::Long8 | FieldType::Ifd8 => {
+                TiffValue::Long8(words(8).map(|x| endian.u64(x)).collect())
+            }
+            FieldType::SLong8 => TiffValue::SLong8(words(8).map(|x| endian.i64(x)).collect()),
+            FieldType::Unknown(_) => unreachable!("Unknown types have no size"),
+        };
+        Ok(value)
+    }
+}
+
+impl<'a> TiffFile<'a> {
+    /// Returns the raw bytes of `ifd_entry`'s value, which is either inline or stored elsewhere in
+    /// the file. Entries of unknown type are treated as one byte per item.
+    pub fn data_for_ifd_entry(&self, ifd_entry: &'a IfdEntry) -> Result<&'a [u8], TiffError> {
+        let out_of_bounds = TiffError::OutOfBounds(ifd_entry.tag);
+        let byte_size = (ifd_entry.count as usize)
+            .checked_mul(ifd_entry.field_type.type_size().unwrap_or(1))
+            .ok_or(out_of_bounds)?;
+        if byte_size <= ifd_entry.value_offset.len() {
+            Ok(&ifd_entry.value_offset[0..byte_size])
+        } else {
+            let start = ifd_entry.val_as_offset().ok_or(out_of_bounds)?;
+            let end = start.checked_add(byte_size).ok_or(out_of_bounds)?;
+            self.data.get(start..end).ok_or(out_of_bounds)
+        }
+    }
+
+    /// Formats `ifd`'s value for debugging, or describes why it can't be read.
+    pub fn debug_value_for_ifd_entry(&self, ifd: &'a IfdEntry) -> String {
+        match self.data_for_ifd_entry(ifd) {
+            Ok(data) => ifd.field_type.debug_repr(data, ifd.endian),
+            Err(e) => format!("<{}>", e),
+        }
+    }
+
+    pub fn all_fields(&self) -> Flatten<core::slice::Iter<Vec<IfdEntry>>> {
+        self.ifds.iter().flatten()
+    }
+}
+
+pub type Ifd<'a> = Vec<IfdEntry<'a>>;
+
+const TIFF_MAGIC: u16 = 42;
+const BIG_TIFF_MAGIC: u16 = 43;
+
+fn ifd_entry<'a>(endian: Endian, big_tiff: bool) -> impl Fn(I<'a>) -> IResult<I<'a>, IfdEntry<'a>> {
+    let u16 = endian.parse_u16();
+    let u32 = endian.parse_u32();
+    let u64 = endian.parse_u64();
+    move |input| {
+        let (input, (tag, field_type)) = tuple((u16, u16))(input)?;
+        let (input, count) = if big_tiff {
+            map_opt(u64, |x| x.try_into().ok())(input)?
+        } else {
+            u32(input)?
+        };
+        let (input, value_offset) = take(if big_tiff { 8usize } else { 4 })(input)?;
+        let entry = IfdEntry {
+            tag,
+            field_type: FieldType::from(field_type),
+            count,
+            value_offset,
+            endian,
+        };
+        Ok((input, entry))
+    }
+}
+
+/// Parses a little-endian IFD, as found in RAFs.
+pub fn parse_ifd(input: I) -> IResult<I, (Ifd, Option<usize>)> {
+    parse_ifd_with(input, Endian::Little, false)
+}
+
+/// Parses an IFD in the given byte order. BigTIFF IFDs have 64-bit entry counts and offsets.
+pub fn parse_ifd_with(
+    input: I,
+    endian: Endian,
+    big_tiff: bool,
+) -> IResult<I, (Ifd, Option<usize>)> {
+    let (input, num_fields) = if big_tiff {
+        endian.parse_u64()(input)?
+    } else {
+        map(endian.parse_u16(), u64::from)(input)?
+    };
+    // Make sure the entries are all there before making space for them.
+    let entry_size = if big_tiff { 20 } else { 12 };
+    if num_fields.saturating_mul(entry_size) > input.len() as u64 {
+        return Err(Err::Error((input, ErrorKind::Eof)));
+    }
+    let (input, ifd) = count(ifd_entry(endian, big_tiff), num_fields as usize)(input)?;
+    let (input, next_ifd) = if big_tiff {
+        endian.parse_u64()(input)?
+    } else {
+        map(endian.parse_u32(), u64::from)(input)?
+    };
+    let next_ifd = if next_ifd != 0 {
+        next_ifd.try_into().ok()
+    } else {
+        None
+    };
+    Ok((input, (ifd, next_ifd)))
+}
+
+/// Parses a TIFF header, returning the byte order, whether it's a BigTIFF, and the offset of the
+/// first IFD.
+fn tiff_header(input: I) -> IResult<I, (Endian, bool, u64)> {
+    let (rest, order) = take(2usize)(input)?;
+    let endian = match order {
+        b"II" => Endian::Little,
+        b"MM" => Endian::Big,
+        _ => return Err(Err::Error((input, ErrorKind::Tag))),
+    };
+    let (rest, magic) = endian.parse_u16()(rest)?;
+    match magic {
+        TIFF_MAGIC => map(endian.parse_u32(), |offset| (endian, false, offset as u64))(rest),
+        BIG_TIFF_MAGIC => {
+            // The offset size, which is always 8, then two bytes of padding.
+            let (rest, _) = tag(if endian == Endian::Little {
+                b"\x08\0\0\0"
+            } else {
+                b"\0\x08\0\0"
+            })(rest)?;
+            map(endian.parse_u64(), |offset| (endian, true, offset))(rest)
+        }
+        _ => Err(Err::Error((input, ErrorKind::Tag))),
+    }
+}
+
+pub fn parse_tiff(input: I) -> IResult<I, TiffFile> {
+    parse_tiff_with_options(input, b"II*\0", false)
+}
+
+/// Parses a TIFF file, or something shaped like one.
+///
+/// If `prefix` is a TIFF header ("II*\0" or "MM\0*"), any TIFF or BigTIFF header is accepted and
+/// the byte order comes from the file. Any other prefix, such as the "FUJIFILM" at the start of
+/// Fuji's makernotes, must match exactly, and is followed by a little-endian offset.
+pub fn parse_tiff_with_options<'b>(
+    input: I<'b>,
+    prefix: &'_ [u8],
+    load_subifds: bool,
+) -> IResult<I<'b>, TiffFile<'b>> {
+    let (endian, big_tiff, first_ifd_offset) = if prefix == b"II*\0" || prefix == b"MM\0*" {
+        tiff_header(input)?.1
+    } else {
+        let (_, (_tag, offset)) = tuple((tag(prefix), le_u32))(input)?;
+        (Endian::Little, false, offset as u64)
+    };
+    let mut parser = IfdParser {
+        input,
+        endian,
+        big_tiff,
+        ifds: vec![],
+        origins: vec![],
+        visited: HashSet::new(),
+    };
+
+    // The main chain has to be there, but a loop back to an earlier IFD just ends it.
+    let mut next = first_ifd_offset.try_into().ok();
+    let mut parent = IfdParent::Header;
+    while let Some(offset) = next {
+        if !parser.visited.insert(offset) {
+            break;
+        }
+        let ifd_input = input
+            .get(offset..)
+            .ok_or(Err::Error((input, ErrorKind::Eof)))?;
+        let (_, (ifd, next_ifd)) = parse_ifd_with(ifd_input, endian, big_tiff)?;
+        parser.push(ifd, IfdOrigin { offset, parent });
+        parent = IfdParent::Previous(parser.ifds.len() - 1);
+        next = next_ifd;
+    }
+
+    // Scan subifds here! TODO: rename param to load_nested / recursive etc
+    if load_subifds {
+        for index in 0..parser.ifds.len() {
+            parser.load_nested(index);
+        }
+    }
+
+    Ok((
+        input,
+        TiffFile {
+            ifds: parser.ifds,
+            origins: parser.origins,
+            data: input,
+            endian,
+            big_tiff,
+        },
+    ))
+}
+
+/// Collects IFDs as they're found, keeping track of where they've come from.
+struct IfdParser<'a> {
+    input: I<'a>,
+    endian: Endian,
+    big_tiff: bool,
+    ifds: Vec<Ifd<'a>>,
+    origins: Vec<IfdOrigin>,
+    /// The offsets of every IFD parsed so far, so that loops in malformed files end.
+    visited: HashSet<usize>,
+}
+
+impl<'a> IfdParser<'a> {
+    fn push(&mut self, ifd: Ifd<'a>, origin: IfdOrigin) {
+        self.ifds.push(ifd);
+        self.origins.push(origin);
+    }
+
+    /// The IFDs which `ifd` points to with one of the `NESTED_IFD_TAGS`, in order.
+    fn pointers(&self, ifd: usize) -> Vec<IfdOrigin> {
+        let mut pointers = vec![];
+        for entry in self.ifds[ifd]
+            .iter()
+            .filter(|e| NESTED_IFD_TAGS.contains(&e.tag))
+        {
+            // Fuji's pointer has an unknown type, so it can only be read as a single offset.
+            let offsets = match entry.value_in(self.input) {
+                Ok(value) => value.into_u32s().unwrap_or_default(),
+                Err(_) => entry.val_u32().into_iter().collect(),
+            };
+            let parent = IfdParent::Pointer {
+                ifd,
+                tag: entry.tag,
+            };
+            pointers.extend(offsets.into_iter().map(|offset| IfdOrigin {
+                offset: offset as usize,
+                parent,
+            }));
+        }
+        pointers
+    }
+
+    /// Loads the IFDs nested inside the one at index `ifd`, depth first, along with the rest of
+    /// their chains. Nested IFDs which are missing or malformed are skipped.
+    fn load_nested(&mut self, ifd: usize) {
+        let mut pending: Vec<IfdOrigin> = self.pointers(ifd);
+        pending.reverse();
+        while let Some(origin) = pending.pop() {
+            if !self.visited.insert(origin.offset) {
+                continue;
+            }
+            let parsed = self
+                .input
+                .get(origin.offset..)
+                .and_then(|input| parse_ifd_with(input, self.endian, self.big_tiff).ok());
+            let (ifd, next_ifd) = match parsed {
+                Some((_, parsed)) => parsed,
+                None => continue,
+            };
+            self.push(ifd, origin);
+            let index = self.ifds.len() - 1;
+            // The rest of this IFD's chain comes after everything nested inside it.
+            if let Some(offset) = next_ifd {
+                pending.push(IfdOrigin {
+                    offset,
+                    parent: IfdParent::Previous(index),
+                });
+            }
+            pending.extend(self.pointers(index).into_iter().rev());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tiff::{
+        parse_ifd, parse_tiff, parse_tiff_with_options, Endian, FieldType, IfdEntry, IfdOrigin,
+        IfdParent, Rational, SRational, TiffBuilder, TiffError, TiffValue, SUB_IFDS_TAG_ID,
+    };
+
+    #[test]
+    fn test_raf_tiff_header() {
+        let data = include_bytes!("../../res/6281.tiff.dat");
+        let result = parse_tiff(data);
+        assert!(result.is_ok());
+        let (_, result) = result.unwrap();
+        assert_eq!(result.ifds.len(), 1);
+        let ifd = &result.ifds[0];
+        assert_eq!(ifd.len(), 1);
+        let ifde = &ifd[0];
+        assert_eq!(ifde.tag, 0xF000);
+        assert_eq!(ifde.field_type, FieldType::Unknown(13));
+        assert_eq!(ifde.count, 1);
+        assert_eq!(ifde.value_offset, b"\x1A\0\0\0");
+    }
+
+    /// This method returns the value encoded as big-endian. Note that what we're
+    /// testing is often little-endian! But in this case, we want to be able to copy-paste hex
+    /// bytes and prefix with 0x, so we use big-endian no matter what.
+    fn h(val: u32) -> [u8; 4] {
+        val.to_be_bytes()
+    }
+
+    #[test]
+    fn test_fuji_tiff_block() {
+        let data = include_bytes!("../../res/6281_fuji_custom_ifd.tiff.dat");
+        let result = parse_ifd(data);
+        let (_, (ifd, next)) = result.unwrap();
+
+        // no more IFDs
+        assert_eq!(next, None);
+        // Should be 16 thingies
+        assert_eq!(ifd.len(), 16);
+        // Three boring ones
+        assert_eq!(
+            ifd[0],
+            IfdEntry {
+                tag: 0xF001,
+                field_type: FieldType::Long,
+                count: 1,
+                value_offset: &h(0x10180000),
+                endian: Endian::Little,
+            }
+        );
+        assert_eq!(
+            ifd[1],
+            IfdEntry {
+                tag: 0xF002,
+                field_type: FieldType::Long,
+                count: 1,
+                value_offset: &h(0xC00F_0000),
+                endian: Endian::Little,
+            }
+        );
+        assert_eq!(
+            ifd[2],
+            IfdEntry {
+                tag: 0xF003,
+                field_type: FieldType::Long,
+                count: 1,
+                value_offset: &h(0x0E000000),
+                endian: Endian::Little,
+            }
+        );
+        // Different count
+        assert_eq!(
+            ifd[9],
+            IfdEntry {
+                tag: 0xF00A,
+                field_type: FieldType::Long,
+                count: 36,
+                // This is an offset
+                value_offset: &h(0xE0000000),
+                endian: Endian::Little,
+            }
+        );
+        // Different count + type
+        assert_eq!(
+            ifd[10],
+            IfdEntry {
+                tag: 0xF00B,
+                field_type: FieldType::SRational,
+                count: 23,
+                // This one too?
+                value_offset: &h(0x70010000),
+                endian: Endian::Little,
+            }
+        );
+    }
+
+    #[test]
+    fn big_endian_tiff() {
+        let mut data = b"MM\0*\0\0\0\x08".to_vec();
+        data.extend(&[0, 2]);
+        // ImageWidth: one short, inline
+        data.extend(&[0x01, 0x00, 0, 3, 0, 0, 0, 1, 0x18, 0x60, 0, 0]);
+        // StripOffsets: two longs, at 38
+        data.extend(&[0x01, 0x11, 0, 4, 0, 0, 0, 2, 0, 0, 0, 38]);
+        data.extend(&[0, 0, 0, 0]);
+        data.extend(&[0, 0, 0, 1, 1, 2, 3, 4]);
+
+        let (_, tiff) = parse_tiff(&data).unwrap();
+        assert_eq!(tiff.endian, Endian::Big);
+        assert!(!tiff.big_tiff);
+        let ifd = &tiff.ifds[0];
+        assert_eq!(ifd[0].tag, 0x0100);
+        assert_eq!(ifd[0].val_u32(), Some(6240));
+        assert_eq!(ifd[1].tag, 0x0111);
+        assert_eq!(ifd[1].val_as_offset(), Some(38));
+        assert_eq!(
+            ifd[1].value(&tiff),
+            Ok(TiffValue::Long(vec![1, 0x01020304]))
+        );
+        assert_eq!(tiff.debug_value_for_ifd_entry(&ifd[1]), "[1, 16909060]");
+    }
+
+    fn big_tiff(endian: Endian) -> Vec<u8> {
+        let u16 = |x: u16| match endian {
+            Endian::Little => x.to_le_bytes().to_vec(),
+            Endian::Big => x.to_be_bytes().to_vec(),
+        };
+        let u64 = |x: u64| match endian {
+            Endian::Little => x.to_le_bytes().to_vec(),
+            Endian::Big => x.to_be_bytes().to_vec(),
+        };
+        let order = if endian == Endian::Little {
+            b"II"
+        } else {
+            b"MM"
+        };
+        let mut data = order.to_vec();
+        data.extend(u16(43));
+        data.extend(u16(8));
+        data.extend(u16(0));
+        data.extend(u64(16));
+        data.extend(u64(2));
+        // ImageWidth: one short, inline
+        data.extend(u16(0x0100));
+        data.extend(u16(3));
+        data.extend(u64(1));
+        data.extend(u16(6240));
+        data.extend(&[0; 6]);
+        // StripOffsets: one Long8, inline
+        data.extend(u16(0x0111));
+        data.extend(u16(16));
+        data.extend(u64(1));
+        data.extend(u64(0x1_0000_0000));
+        data.extend(u64(0));
+        data
+    }
+
+    #[test]
+    fn big_tiff_either_byte_order() {
+        for &endian in &[Endian::Little, Endian::Big] {
+            let data = big_tiff(endian);
+            let (_, tiff) = parse_tiff(&data).unwrap();
+            assert_eq!(tiff.endian, endian);
+            assert!(tiff.big_tiff);
+            let ifd = &tiff.ifds[0];
+            assert_eq!(ifd.len(), 2);
+            assert_eq!(ifd[0].val_u32(), Some(6240));
+            assert_eq!(ifd[1].field_type, FieldType::Long8);
+            assert_eq!(ifd[1].value_inlined(), tristate::TriState::Yes);
+            assert_eq!(ifd[1].val_u64(), Some(0x1_0000_0000));
+            // Too big for 32 bits
+            assert_eq!(ifd[1].val_u32(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(parse_tiff(b"II+\0\x08\0\0\0").is_err());
+        assert!(parse_tiff(b"IM*\0\x08\0\0\0").is_err());
+        // A BigTIFF claiming an IFD with more entries than the file could hold
+        let mut data = big_tiff(Endian::Little);
+        data[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse_tiff(&data).is_err());
+    }
+
+    /// Builds a little-endian TIFF with one IFD holding `entries`, followed by `data`, which
+    /// starts at offset 8 + 2 + 12 * entries.len() + 4.
+    fn tiff_with(entries: &[(u16, u16, u32, u32)], data: &[u8]) -> Vec<u8> {
+        let mut out = b"II*\0\x08\0\0\0".to_vec();
+        out.extend(&(entries.len() as u16).to_le_bytes());
+        for &(tag, field_type, count, value) in entries {
+            out.extend(&tag.to_le_bytes());
+            out.extend(&field_type.to_le_bytes());
+            out.extend(&count.to_le_bytes());
+            out.extend(&value.to_le_bytes());
+        }
+        out.extend(&[0; 4]);
+        out.extend(data);
+        out
+    }
+
+    #[test]
+    fn typed_values() {
+        // Data starts at 8 + 2 + 12 * 7 + 4 = 98
+        let mut data = vec![];
+        data.extend(b"X-T3\0\0");
+        data.extend(&[1, 0, 0, 0, 3, 0, 0, 0]);
+        data.extend(&(-1i32).to_le_bytes());
+        data.extend(&2i32.to_le_bytes());
+        data.extend(&1.5f64.to_le_bytes());
+        let tiff_data = tiff_with(
+            &[
+                (0x0110, 2, 6, 98),
+                (0x0100, 3, 2, 0x0002_0001),
+                (0x0101, 1, 3, 0x00FF_0201),
+                (0x0102, 6, 1, 0xFF),
+                (0x011A, 5, 1, 104),
+                (0x9204, 10, 1, 112),
+                (0x0103, 12, 1, 120),
+            ],
+            &data,
+        );
+        let (_, tiff) = parse_tiff(&tiff_data).unwrap();
+        let values: Vec<TiffValue> = tiff.ifds[0]
+            .iter()
+            .map(|e| e.value(&tiff).unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                TiffValue::Ascii("X-T3".to_string()),
+                TiffValue::Short(vec![1, 2]),
+                TiffValue::Byte(vec![1, 2, 0xFF]),
+                TiffValue::SByte(vec![-1]),
+                TiffValue::Rational(vec![Rational(1, 3)]),
+                TiffValue::SRational(vec![SRational(-1, 2)]),
+                TiffValue::Double(vec![1.5]),
+            ]
+        );
+        assert_eq!(values[1].clone().into_u32s(), Some(vec![1, 2]));
+        assert_eq!(values[5].clone().into_f64s(), Some(vec![-0.5]));
+        assert_eq!(values[0].clone().into_f64s(), None);
+    }
+
+    #[test]
+    fn bad_values() {
+        let tiff_data = tiff_with(&[(0x0110, 2, 100, 26), (0x0111, 99, 1, 0)], &[]);
+        let (_, tiff) = parse_tiff(&tiff_data).unwrap();
+        let ifd = &tiff.ifds[0];
+        assert_eq!(ifd[0].value(&tiff), Err(TiffError::OutOfBounds(0x0110)));
+        assert_eq!(ifd[1].value(&tiff), Err(TiffError::UnknownType(0x0111, 99)));
+        assert_eq!(
+            tiff.data_for_ifd_entry(&ifd[0]),
+            Err(TiffError::OutOfBounds(0x0110))
+        );
+        assert!(tiff
+            .debug_value_for_ifd_entry(&ifd[0])
+            .contains("outside the file"));
+    }
+
+    /// A little-endian IFD with one Long or Short entry, and the given next IFD offset.
+    fn ifd_bytes(tag: u16, field_type: u16, value: u32, next: u32) -> Vec<u8> {
+        let mut out = vec![1, 0];
+        out.extend(&tag.to_le_bytes());
+        out.extend(&field_type.to_le_bytes());
+        out.extend(&1u32.to_le_bytes());
+        out.extend(&value.to_le_bytes());
+        out.extend(&next.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn loops_end() {
+        // IFD0, at 8, is both its own next IFD and its own SubIFD.
+        let mut data = b"II*\0\x08\0\0\0".to_vec();
+        data.extend(ifd_bytes(SUB_IFDS_TAG_ID, 4, 8, 8));
+        let (_, tiff) = parse_tiff_with_options(&data, b"II*\0", true).unwrap();
+        assert_eq!(tiff.ifds.len(), 1);
+    }
+
+    #[test]
+    fn nested_chains() {
+        // IFD0 at 8 points to an EXIF IFD at 26, which is followed by another at 44, which
+        // points back to the one at 26.
+        let mut data = b"II*\0\x08\0\0\0".to_vec();
+        data.extend(ifd_bytes(0x8769, 4, 26, 0));
+        data.extend(ifd_bytes(0x0100, 3, 1, 44));
+        data.extend(ifd_bytes(0x0100, 3, 2, 26));
+        let (_, tiff) = parse_tiff_with_options(&data, b"II*\0", true).unwrap();
+        assert_eq!(tiff.ifds.len(), 3);
+        assert_eq!(tiff.ifds[2][0].val_u32(), Some(2));
+        assert_eq!(
+            tiff.origins,
+            vec![
+                IfdOrigin {
+                    offset: 8,
+                    parent: IfdParent::Header
+                },
+                IfdOrigin {
+                    offset: 26,
+                    parent: IfdParent::Pointer {
+                        ifd: 0,
+                        tag: 0x8769
+                    }
+                },
+                IfdOrigin {
+                    offset: 44,
+                    parent: IfdParent::Previous(1)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn every_sub_ifd() {
+        let mut builder = TiffBuilder::new(Endian::Little);
+        let ifd0 = builder.add_ifd();
+        for width in 1..=3 {
+            let sub = builder.add_sub_ifd(ifd0, SUB_IFDS_TAG_ID);
+            builder.set(sub, 0x0100, TiffValue::Long(vec![width]));
+        }
+        let data = builder.to_bytes();
+        let (_, tiff) = parse_tiff_with_options(&data, b"II*\0", true).unwrap();
+        assert_eq!(tiff.ifds.len(), 4);
+        for (ifd, width) in tiff.ifds[1..].iter().zip(1..) {
+            assert_eq!(ifd[0].val_u32(), Some(width));
+        }
+        let parent = IfdParent::Pointer {
+            ifd: 0,
+            tag: SUB_IFDS_TAG_ID,
+        };
+        assert!(tiff.origins[1..].iter().all(|o| o.parent == parent));
+    }
+
+    #[test]
+    fn bad_offsets() {
+        // The first IFD is missing.
+        assert!(parse_tiff(b"II*\0\xFF\0\0\0").is_err());
+
+        // The SubIFD and next IFD are past the end, which only matters for the main chain.
+        let mut data = b"II*\0\x08\0\0\0".to_vec();
+        data.extend(ifd_bytes(SUB_IFDS_TAG_ID, 4, 1000, 0));
+        let (_, tiff) = parse_tiff_with_options(&data, b"II*\0", true).unwrap();
+        assert_eq!(tiff.ifds.len(), 1);
+        let mut data = b"II*\0\x08\0\0\0".to_vec();
+        data.extend(ifd_bytes(0x0100, 4, 1, 1000));
+        assert!(parse_tiff(&data).is_err());
+    }
+}
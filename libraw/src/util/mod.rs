@@ -1,2 +1,4 @@
 pub mod bitreader;
 pub mod colored;
+pub mod progress;
+pub mod timing;
@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Returned by long-running operations which were cancelled before they finished.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cancelled;
+
+/// Receives progress updates from long-running operations (decoding, rendering), and tells them
+/// when to give up early.
+///
+/// Operations check `is_cancelled` between chunks of work, so cancellation isn't instantaneous.
+pub trait ProgressReporter: Sync {
+    /// Called with the name of the current stage, and how far through that stage we are, from 0
+    /// to 1. May be called from multiple threads at once.
+    fn report(&self, stage: &str, fraction: f32);
+    fn is_cancelled(&self) -> bool;
+
+    /// Convenience method for bailing out with `?`.
+    fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A `ProgressReporter` which ignores progress and never cancels.
+pub struct Silent;
+
+impl ProgressReporter for Silent {
+    fn report(&self, _stage: &str, _fraction: f32) {}
+
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// A flag that can be shared between threads to request cancellation. Clones refer to the same
+/// flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::Instant;
+
+/// Logs how long a stage took when it's dropped, at debug level under the `timing` target.
+///
+/// Enable with e.g. `RUST_LOG=timing=debug` to see where render time goes:
+///
+/// ```
+/// # use libraw::util::timing::StageTimer;
+/// let _timer = StageTimer::new("Demosaicing");
+/// // ... do the work; the elapsed time is logged at the end of the scope.
+/// ```
+pub struct StageTimer<'a> {
+    stage: &'a str,
+    start: Instant,
+}
+
+impl<'a> StageTimer<'a> {
+    pub fn new(stage: &'a str) -> Self {
+        log::trace!(target: "timing", "{} started", stage);
+        StageTimer {
+            stage,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for StageTimer<'_> {
+    fn drop(&mut self) {
+        log::debug!(target: "timing", "{} took {:?}", self.stage, self.start.elapsed());
+    }
+}